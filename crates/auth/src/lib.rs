@@ -0,0 +1,177 @@
+use anyhow::Result;
+use config::{ApiKeyConfig, AuthConfig, KeyFile, LlamaServerConfig};
+use ring::digest::{digest, SHA256};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+// Policy of an authenticated caller
+pub type ApiKey = Arc<ApiKeyConfig>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("Missing API key")]
+    Missing,
+    #[error("Invalid API key")]
+    Invalid,
+    #[error("API key {0} is disabled")]
+    Disabled(String),
+    #[error("API key {0} may not use model {1}")]
+    ModelNotAllowed(String, String),
+}
+
+// API keys from the config and the key file, the key file is reloaded when it changes
+pub struct KeyStore {
+    config: AuthConfig,
+    keys: RwLock<HashMap<String, ApiKey>>,
+    key_file_modified: RwLock<Option<SystemTime>>,
+}
+
+impl KeyStore {
+    pub fn load(config: AuthConfig) -> Result<Self> {
+        let store = Self {
+            config,
+            keys: RwLock::new(HashMap::new()),
+            key_file_modified: RwLock::new(None),
+        };
+        store.reload()?;
+        Ok(store)
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    // Re-read the key file, keys of the config are kept as they are
+    pub fn reload(&self) -> Result<()> {
+        let mut keys = self.config.keys.clone();
+        if let Some(path) = &self.config.key_file {
+            let modified = std::fs::metadata(path)?.modified().ok();
+            let file: KeyFile = toml::from_str(&std::fs::read_to_string(path)?)?;
+            keys.extend(file.keys);
+            *self.key_file_modified.write().unwrap() = modified;
+        }
+
+        let mut loaded = HashMap::new();
+        for mut key in keys {
+            if key.key.is_empty() {
+                warn!("Ignoring API key {:?} without a key", key.name);
+                continue;
+            }
+            if key.name.is_empty() {
+                key.name = format!("{}...", key.key.chars().take(6).collect::<String>());
+            }
+            loaded.insert(key.key.clone(), Arc::new(key));
+        }
+        info!("Loaded {} API keys", loaded.len());
+        *self.keys.write().unwrap() = loaded;
+        Ok(())
+    }
+
+    // Look up the key presented by a caller
+    pub fn authenticate(&self, key: Option<&str>) -> Result<ApiKey, AuthError> {
+        let key = key.filter(|k| !k.is_empty()).ok_or(AuthError::Missing)?;
+        let api_key = self.keys.read().unwrap()
+            .get(key)
+            .cloned()
+            .ok_or(AuthError::Invalid)?;
+        if !api_key.enabled {
+            return Err(AuthError::Disabled(api_key.name.clone()));
+        }
+        Ok(api_key)
+    }
+
+    // Poll the key file and reload it after it was modified
+    pub fn spawn_watcher(self: Arc<Self>) -> Option<tokio::task::JoinHandle<()>> {
+        let path = self.config.key_file.clone()?;
+        let interval = Duration::from_secs(self.config.reload_interval_secs.max(1));
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                let modified = std::fs::metadata(&path).and_then(|m| m.modified()).ok();
+                if modified.is_none() || modified == *self.key_file_modified.read().unwrap() {
+                    continue;
+                }
+                info!("Key file {} changed, reloading", path.display());
+                if let Err(e) = self.reload() {
+                    warn!("Failed to reload key file {}: {}", path.display(), e);
+                }
+            }
+        }))
+    }
+}
+
+// Reject models the key does not allow. A model is allowed by any of its names, the configs
+// resolve an alias to the model it belongs to, as /v1/models does
+pub fn authorize_model(key: &ApiKeyConfig, model: Option<&str>, configs: &[LlamaServerConfig]) -> Result<(), AuthError> {
+    let by_alias = || {
+        model.filter(|m| !m.is_empty()).is_some_and(|model| {
+            configs.iter().any(|c| c.serves(model) && key.allows_names(c.names()))
+        })
+    };
+    if key.allows_model(model) || by_alias() {
+        return Ok(());
+    }
+    Err(AuthError::ModelNotAllowed(key.name.clone(), model.unwrap_or_default().to_string()))
+}
+
+// Stable identity of a key for what it owns, names are for display and may change or repeat
+pub fn key_id(key: &ApiKeyConfig) -> String {
+    let digest = digest(&SHA256, key.key.as_bytes());
+    digest.as_ref().iter().take(12).fold("key_".to_string(), |mut id, b| {
+        id.push_str(&format!("{:02x}", b));
+        id
+    })
+}
+
+// The token of an `Authorization: Bearer` value
+pub fn bearer(value: &str) -> Option<&str> {
+    let (scheme, token) = value.split_once(' ')?;
+    scheme.eq_ignore_ascii_case("bearer").then(|| token.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_id_follows_the_secret_not_the_name() {
+        let key = |secret: &str, name: &str| ApiKeyConfig {
+            key: secret.to_string(),
+            name: name.to_string(),
+            ..ApiKeyConfig::default()
+        };
+        let id = key_id(&key("sk-a", "alice"));
+        assert_eq!(id.len(), 4 + 24);
+        assert!(id.starts_with("key_") && id[4..].chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(key_id(&key("sk-a", "renamed")), id);
+        assert_ne!(key_id(&key("sk-b", "alice")), id);
+        assert!(!id.contains("sk-a"));
+    }
+
+    #[test]
+    fn models_are_allowed_by_any_of_their_names() {
+        let key = ApiKeyConfig { models: vec!["llama".to_string()], ..ApiKeyConfig::default() };
+        let configs = [LlamaServerConfig {
+            name: "llama".to_string(),
+            chat_model_path: None,
+            embedding_model_path: None,
+            tts_model_path: None,
+            config_path: None,
+            aliases: vec!["gpt-4o".to_string()],
+            owned_by: None,
+            context_length: None,
+        }];
+        assert!(authorize_model(&key, Some("llama"), &configs).is_ok());
+        assert!(authorize_model(&key, Some("gpt-4o"), &configs).is_ok());
+        assert!(authorize_model(&key, Some("gpt-4o"), &[]).is_err());
+        assert!(authorize_model(&key, Some("mistral"), &configs).is_err());
+        assert!(authorize_model(&key, None, &configs).is_err());
+
+        // a key allowing the alias may use the model by its name too
+        let key = ApiKeyConfig { models: vec!["gpt-4o".to_string()], ..key };
+        assert!(authorize_model(&key, Some("llama"), &configs).is_ok());
+    }
+}
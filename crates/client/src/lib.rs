@@ -1,24 +1,45 @@
-use std::time::Duration;
-
 use anyhow::{anyhow, Result};
-use futures_util::{SinkExt, StreamExt};
-use protos::grpc::model::{MessageType, ServerMessage, StreamingRequest};
-use protos::grpc::model::{
-    model_service_client::ModelServiceClient, ChatMessage, Role, SpeechRequest, TextRequest,
-};
-use protos::grpc::mserver::{
-    server_service_client::ServerServiceClient, Empty, ModelListResponse, ModelStatusRequest,
+use futures::{SinkExt, StreamExt};
+use protos::assistant::{
+    assistant_service_client::AssistantServiceClient, ChatCompletionRequest, ChatMessage,
+    ListModelsRequest, ListModelsResponse, ModelStatus, Role, SpeechRequest,
 };
+use serde::Deserialize;
+use serde_json::{json, Value};
 use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async, tungstenite::protocol::Message};
-use tonic::transport::Channel;
-use url::Url;
-use uuid::Uuid;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, http::HeaderValue, protocol::Message},
+};
+use tonic::{metadata::MetadataValue, transport::Channel, Request};
 
 /// Assistant client for interacting with the gRPC service
 pub struct AssistantClient {
-    server_client: ServerServiceClient<Channel>,
-    model_client: ModelServiceClient<Channel>,
+    client: AssistantServiceClient<Channel>,
+    model: String,
+    ws_url: Option<String>,
+    api_key: Option<String>,
+}
+
+/// Kind of a message sent on the chat WebSocket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MessageType {
+    Stream,
+    Done,
+    Error,
+}
+
+/// A message sent on the chat WebSocket
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerMessage {
+    pub msg_type: MessageType,
+    pub id: Option<String>,
+    pub content: Option<String>,
+    pub tool_calls: Option<Value>,
+    pub finish_reason: Option<String>,
+    pub usage: Option<Value>,
+    pub error: Option<Value>,
 }
 
 impl AssistantClient {
@@ -28,130 +49,208 @@ impl AssistantClient {
             .connect()
             .await?;
 
-        let server_client = ServerServiceClient::new(channel.clone());
-        let model_client = ModelServiceClient::new(channel);
-
         Ok(Self {
-            server_client,
-            model_client,
+            client: AssistantServiceClient::new(channel),
+            model: String::new(),
+            ws_url: None,
+            api_key: None,
         })
     }
 
+    /// Select the model used for chat requests, the server default is used when empty
+    pub fn with_model(mut self, model: &str) -> Self {
+        self.model = model.to_string();
+        self
+    }
+
+    /// Stream chats over the WebSocket at this URL, e.g. `ws://127.0.0.1:8080/v1/chat/ws`
+    pub fn with_ws_url(mut self, url: &str) -> Self {
+        self.ws_url = Some(url.to_string()).filter(|u| !u.is_empty());
+        self
+    }
+
+    /// Present this API key on every gRPC call and on the WebSocket upgrade
+    pub fn with_api_key(mut self, key: &str) -> Self {
+        self.api_key = Some(key.to_string()).filter(|k| !k.is_empty());
+        self
+    }
+
     /// Query available models from the server
-    pub async fn query_models(&mut self) -> Result<ModelListResponse> {
-        let response = self.server_client.query_models(Empty {}).await?;
+    pub async fn query_models(&mut self) -> Result<ListModelsResponse> {
+        let request = self.request(ListModelsRequest {});
+        let response = self.client.list_models(request).await?;
         Ok(response.into_inner())
     }
 
     /// Query the status of a specific model
     pub async fn query_model_status(&mut self, model_id: &str) -> Result<String> {
-        let request = ModelStatusRequest {
-            model_id: model_id.to_string(),
-        };
+        let models = self.query_models().await?;
+        let model = models.models
+            .into_iter()
+            .find(|m| m.id == model_id)
+            .ok_or_else(|| anyhow!("Model {} not found", model_id))?;
 
-        let response = self.server_client.query_model_status(request).await?;
-        let status = response.into_inner();
-
-        // Convert status code to string
-        let status_str = match status.status {
-            0 => "Unknown",
-            1 => "Loading",
-            2 => "Ready",
-            3 => "Error",
-            _ => "Invalid status",
-        };
+        Ok(Self::status_name(model.status).to_string())
+    }
 
-        if !status.error.is_empty() {
-            Ok(format!("{} (Error: {})", status_str, status.error))
-        } else {
-            Ok(status_str.to_string())
+    /// Convert a model status code to a readable name
+    pub fn status_name(status: i32) -> &'static str {
+        match ModelStatus::try_from(status) {
+            Ok(ModelStatus::Starting) => "Starting",
+            Ok(ModelStatus::Running) => "Running",
+            Ok(ModelStatus::Failed) => "Failed",
+            Ok(ModelStatus::Stopped) => "Stopped",
+            Ok(ModelStatus::Unspecified) => "Unknown",
+            Err(_) => "Invalid status",
         }
     }
 
     /// Send a chat message to the model and get a response
     pub async fn chat(&mut self, messages: Vec<ChatMessage>) -> Result<String> {
-        let request = TextRequest { messages };
-        let response = self.model_client.text_chat(request).await?;
-        Ok(response.into_inner().text)
-    }
+        let request = self.request(self.chat_request(messages));
+        let response = self.client.chat_completion(request).await?.into_inner();
 
-    /// Chat with streaming response - returns WebSocket URL
-    pub async fn get_streaming_url(&mut self, messages: Vec<ChatMessage>) -> Result<String> {
-        let request = StreamingRequest {
-            messages,
-            session_id: Uuid::new_v4().to_string(),
-        };
-        let response = self.model_client.streaming_text_chat(request).await?;
-        Ok(response.into_inner().streaming_url)
+        response.choices
+            .into_iter()
+            .next()
+            .and_then(|c| c.message)
+            .map(|m| m.content)
+            .ok_or_else(|| anyhow!("Empty response from the model"))
     }
 
-    /// Chat with streaming response - connects to WebSocket and returns a channel with responses
+    /// Chat with streaming response - returns a channel with the content deltas
     pub async fn chat_stream(
         &mut self,
         messages: Vec<ChatMessage>,
     ) -> Result<mpsc::Receiver<String>> {
-        let ws_url = self.get_streaming_url(messages).await?;
+        if let Some(url) = self.ws_url.clone() {
+            return self.chat_stream_ws(&url, messages).await;
+        }
+        let request = self.request(self.chat_request(messages));
+        let mut stream = self.client.chat_completion_stream(request).await?.into_inner();
         let (tx, rx) = mpsc::channel(32);
 
-        let url = Url::parse(&ws_url).map_err(|e| anyhow!("Invalid WebSocket URL: {}", e))?;
-
-        // Connect to WebSocket and handle messages
         tokio::spawn(async move {
-            match connect_async(url).await {
-                Ok((ws_stream, _)) => {
-                    let (_, mut read) = ws_stream.split();
-
-                    while let Some(message) = read.next().await {
-                        match message {
-                            Ok(Message::Text(text)) => {
-                                // println!("Received message: {}", text);
-                                // Analyze the message
-                                match serde_json::from_str::<ServerMessage>(&text) {
-                                    Ok(server_message) => {
-                                        let message_type = server_message.msg_type;
-                                        if message_type == MessageType::Stream as i32 {
-                                            if let Some(content) = server_message.content {
-                                                if tx.send(content).await.is_err() {
-                                                    break;
-                                                }
-                                            }
-                                        } else if message_type == MessageType::Done as i32 {
-                                            break;
-                                        }
-                                    }
-                                    Err(e) => {
-                                        let _ = tx.send(format!("Error: {}", e)).await;
-                                        break;
-                                    }
-                                }
-                            }
-                            Ok(Message::Close(_)) => {
-                                break;
-                            }
-                            Err(e) => {
-                                let _ = tx.send(format!("Error: {}", e)).await;
-                                break;
-                            }
-                            _ => {} 
+            loop {
+                match stream.message().await {
+                    Ok(Some(chunk)) => {
+                        if chunk.delta.is_empty() {
+                            continue;
                         }
+                        if tx.send(chunk.delta).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(e) => {
+                        let _ = tx.send(format!("Error: {}", e.message())).await;
+                        break;
                     }
                 }
-                Err(e) => {
-                    let _ = tx
-                        .send(format!("Failed to connect to WebSocket: {}", e))
-                        .await;
+            }
+        });
+
+        Ok(rx)
+    }
+
+    /// Chat with streaming response over the WebSocket - returns a channel with the content deltas
+    async fn chat_stream_ws(
+        &self,
+        url: &str,
+        messages: Vec<ChatMessage>,
+    ) -> Result<mpsc::Receiver<String>> {
+        let mut request = url.into_client_request()
+            .map_err(|e| anyhow!("Invalid WebSocket URL: {}", e))?;
+        if let Some(key) = &self.api_key {
+            request.headers_mut().insert("authorization", HeaderValue::from_str(&format!("Bearer {}", key))?);
+        }
+        let (ws_stream, _) = connect_async(request).await
+            .map_err(|e| anyhow!("Failed to connect to WebSocket: {}", e))?;
+        let (mut write, mut read) = ws_stream.split();
+
+        let messages: Vec<Value> = messages.iter()
+            .map(|m| json!({ "role": Self::role_name(m.role), "content": m.content }))
+            .collect();
+        let mut chat = json!({ "messages": messages });
+        if !self.model.is_empty() {
+            chat["model"] = json!(self.model);
+        }
+        let turn = json!({ "type": "chat", "id": "turn-1", "request": chat });
+        write.send(Message::Text(turn.to_string())).await?;
+
+        let (tx, rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            while let Some(message) = read.next().await {
+                let text = match message {
+                    Ok(Message::Text(text)) => text,
+                    Ok(Message::Close(_)) => break,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        let _ = tx.send(format!("Error: {}", e)).await;
+                        break;
+                    }
+                };
+                match serde_json::from_str::<ServerMessage>(&text) {
+                    Ok(ServerMessage { msg_type: MessageType::Stream, content, .. }) => {
+                        let content = content.unwrap_or_default();
+                        if !content.is_empty() && tx.send(content).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(ServerMessage { msg_type: MessageType::Done, .. }) => break,
+                    Ok(ServerMessage { error, .. }) => {
+                        let message = error.as_ref()
+                            .and_then(|e| e["message"].as_str())
+                            .unwrap_or("unknown error")
+                            .to_string();
+                        let _ = tx.send(format!("Error: {}", message)).await;
+                        break;
+                    }
+                    Err(e) => {
+                        let _ = tx.send(format!("Error: {}", e)).await;
+                        break;
+                    }
                 }
             }
+            let _ = write.close().await;
         });
 
         Ok(rx)
     }
 
+    fn role_name(role: i32) -> &'static str {
+        match Role::try_from(role) {
+            Ok(Role::System) => "system",
+            Ok(Role::Assistant) => "assistant",
+            Ok(Role::Tool) => "tool",
+            _ => "user",
+        }
+    }
+
+    /// A gRPC request with the API key in its `authorization` metadata
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        let value = self.api_key.as_ref().and_then(|k| MetadataValue::try_from(format!("Bearer {}", k)).ok());
+        if let Some(value) = value {
+            request.metadata_mut().insert("authorization", value);
+        }
+        request
+    }
+
+    fn chat_request(&self, messages: Vec<ChatMessage>) -> ChatCompletionRequest {
+        ChatCompletionRequest {
+            model: self.model.clone(),
+            messages,
+            ..Default::default()
+        }
+    }
+
     /// Helper function to create a user message
     pub fn create_user_message(content: &str) -> ChatMessage {
         ChatMessage {
             role: Role::User as i32,
             content: content.to_string(),
+            name: String::new(),
         }
     }
 
@@ -160,6 +259,7 @@ impl AssistantClient {
         ChatMessage {
             role: Role::System as i32,
             content: content.to_string(),
+            name: String::new(),
         }
     }
 
@@ -168,17 +268,42 @@ impl AssistantClient {
         ChatMessage {
             role: Role::Assistant as i32,
             content: content.to_string(),
+            name: String::new(),
         }
     }
 
-    /// Convert speech to text (not fully implemented in the server yet)
-    pub async fn speech_to_text(&mut self, audio_data: &str, audio_format: &str) -> Result<String> {
+    /// Convert text to speech, returns the audio bytes and their content type
+    pub async fn text_to_speech(&mut self, input: &str, voice: &str) -> Result<(Vec<u8>, String)> {
         let request = SpeechRequest {
-            audio_data: audio_data.to_string(),
-            audio_format: audio_format.to_string(),
+            model: String::new(),
+            input: input.to_string(),
+            voice: voice.to_string(),
+            response_format: String::new(),
+            speed: None,
+        };
+
+        let response = self.client.speech(self.request(request)).await?.into_inner();
+        Ok((response.audio, response.content_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn grpc_requests_carry_the_api_key() {
+        let channel = Channel::from_static("http://127.0.0.1:1").connect_lazy();
+        let client = AssistantClient {
+            client: AssistantServiceClient::new(channel),
+            model: String::new(),
+            ws_url: None,
+            api_key: None,
         };
+        assert!(client.request(ListModelsRequest {}).metadata().get("authorization").is_none());
 
-        let response = self.model_client.speech_to_text(request).await?;
-        Ok(response.into_inner().text)
+        let client = client.with_api_key("sk-1");
+        let request = client.request(ListModelsRequest {});
+        assert_eq!(request.metadata().get("authorization").unwrap(), "Bearer sk-1");
     }
 }
@@ -2,7 +2,7 @@ use anyhow::Result;
 use assistant_client::AssistantClient;
 use clap::{Parser, Subcommand};
 use log::{error, info};
-use protos::grpc::model::ChatMessage;
+use protos::assistant::ChatMessage;
 use std::io::{self, BufRead, Write};
 
 #[derive(Parser)]
@@ -12,6 +12,18 @@ struct Cli {
     #[arg(short, long, default_value = "http://127.0.0.1:50051")]
     endpoint: String,
 
+    /// Model used for chat, the server default when omitted
+    #[arg(short, long, default_value = "")]
+    model: String,
+
+    /// Stream chats over this WebSocket, e.g. ws://127.0.0.1:8080/v1/chat/ws
+    #[arg(long, default_value = "")]
+    ws_url: String,
+
+    /// API key presented on every gRPC call and on the WebSocket
+    #[arg(long, default_value = "")]
+    api_key: String,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -56,7 +68,10 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
 
     // Connect to the server
-    let mut client = AssistantClient::connect(&cli.endpoint).await?;
+    let mut client = AssistantClient::connect(&cli.endpoint).await?
+        .with_model(&cli.model)
+        .with_ws_url(&cli.ws_url)
+        .with_api_key(&cli.api_key);
 
     // Process commands
     match &cli.command {
@@ -66,18 +81,12 @@ async fn main() -> Result<()> {
 
             println!("Available models:");
             for model in models.models {
-                let status = match model.status {
-                    0 => "Unknown",
-                    1 => "Loading",
-                    2 => "Ready",
-                    3 => "Error",
-                    _ => "Invalid status",
-                };
-
-                println!("- ID: {}", model.model_id);
-                println!("  Name: {}", model.name);
-                println!("  Description: {}", model.description);
-                println!("  Status: {}", status);
+                println!("- ID: {}", model.id);
+                println!("  Owned by: {}", model.owned_by);
+                if !model.node_id.is_empty() {
+                    println!("  Node: {}", model.node_id);
+                }
+                println!("  Status: {}", AssistantClient::status_name(model.status));
                 println!();
             }
         }
@@ -109,7 +118,9 @@ async fn main() -> Result<()> {
                 stdout.flush()?;
 
                 let mut input = String::new();
-                stdin.lock().read_line(&mut input)?;
+                if stdin.lock().read_line(&mut input)? == 0 {
+                    break;
+                }
 
                 let input = input.trim();
                 if input.eq_ignore_ascii_case("exit") {
@@ -130,8 +141,7 @@ async fn main() -> Result<()> {
                         // receive stream response
                         while let Some(chunk) = stream.recv().await {
                             // check if error
-                            if chunk.starts_with("Error:") || chunk.starts_with("Failed to connect")
-                            {
+                            if chunk.starts_with("Error:") {
                                 error!("{}", chunk);
                                 println!("\nError: Failed to get streaming response.");
                                 break;
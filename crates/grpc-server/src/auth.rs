@@ -0,0 +1,51 @@
+use ::auth::{ApiKey, AuthError, KeyStore};
+use config::{LlamaServerConfig, Priority};
+use std::sync::Arc;
+use tonic::{metadata::MetadataValue, Request, Status};
+
+// Check the bearer key of every call, the key policy is left in the request extensions
+pub fn interceptor(
+    keys: Option<Arc<KeyStore>>,
+) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |mut request: Request<()>| {
+        let keys = match &keys {
+            Some(keys) if keys.enabled() => keys,
+            _ => return Ok(request),
+        };
+        let token = request.metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(::auth::bearer);
+        let key = keys.authenticate(token).map_err(auth_status)?;
+        request.extensions_mut().insert(key);
+        Ok(request)
+    }
+}
+
+// Reject a model the caller's key may not use, aliases resolve through the configs
+pub fn authorize_model<T>(request: &Request<T>, model: Option<&str>, configs: &[LlamaServerConfig]) -> Result<(), Status> {
+    match request.extensions().get::<ApiKey>() {
+        Some(key) => ::auth::authorize_model(key, model, configs).map_err(auth_status),
+        None => Ok(()),
+    }
+}
+
+// Priority of the caller's key, normal without one
+pub fn priority<T>(request: &Request<T>) -> Priority {
+    request.extensions().get::<ApiKey>().map(|key| key.priority).unwrap_or_default()
+}
+
+// Present an API key on an outgoing call
+pub fn set_api_key<T>(request: &mut Request<T>, key: Option<&str>) {
+    let value = key.and_then(|k| MetadataValue::try_from(format!("Bearer {}", k)).ok());
+    if let Some(value) = value {
+        request.metadata_mut().insert("authorization", value);
+    }
+}
+
+fn auth_status(e: AuthError) -> Status {
+    match e {
+        AuthError::ModelNotAllowed(..) => Status::permission_denied(e.to_string()),
+        _ => Status::unauthenticated(e.to_string()),
+    }
+}
@@ -0,0 +1,240 @@
+use futures::future::join_all;
+use protos::assistant::{
+    assistant_service_client::AssistantServiceClient, HeartbeatRequest, JoinRequest, NodeInfo,
+};
+use scheduler::Scheduler;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::RwLock;
+use tonic::transport::{Channel, ClientTlsConfig};
+use tracing::{debug, info, warn};
+
+#[derive(Clone)]
+pub struct ClusterOptions {
+    pub node_id: String,
+    pub advertise_addr: String,
+    pub seeds: Vec<String>,
+    pub weight: u32,
+    pub heartbeat_interval: Duration,
+    pub peer_timeout: Duration,
+    // TLS used to dial peers, plaintext when unset
+    pub tls: Option<ClientTlsConfig>,
+    // API key presented to peers
+    pub api_key: Option<String>,
+}
+
+struct Peer {
+    info: NodeInfo,
+    last_seen: Instant,
+}
+
+// Membership table of the cluster, kept up to date by heartbeats
+pub struct Membership {
+    options: ClusterOptions,
+    scheduler: Arc<Scheduler>,
+    max_load: f32,
+    peers: RwLock<HashMap<String, Peer>>,
+    // Recently expired peers, ignored in gossip until the others expire them too
+    departed: RwLock<HashMap<String, Instant>>,
+}
+
+impl Membership {
+    pub fn new(options: ClusterOptions, scheduler: Arc<Scheduler>, max_load: f32) -> Self {
+        Self {
+            options,
+            scheduler,
+            max_load,
+            peers: RwLock::new(HashMap::new()),
+            departed: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn node_id(&self) -> &str {
+        &self.options.node_id
+    }
+
+    pub fn tls(&self) -> Option<&ClientTlsConfig> {
+        self.options.tls.as_ref()
+    }
+
+    pub fn api_key(&self) -> Option<&str> {
+        self.options.api_key.as_deref()
+    }
+
+    // Describe this node as advertised to peers
+    pub async fn local_info(&self) -> NodeInfo {
+        let load = self.scheduler.check_load().await;
+        NodeInfo {
+            node_id: self.options.node_id.clone(),
+            grpc_addr: self.options.advertise_addr.clone(),
+            models: self.scheduler.list_models().await,
+            load,
+            busy: load >= self.max_load,
+            weight: self.options.weight,
+        }
+    }
+
+    // Record a node that contacted us or answered us directly
+    pub async fn observe(&self, info: NodeInfo) {
+        if info.node_id.is_empty() || info.node_id == self.options.node_id {
+            return;
+        }
+        self.departed.write().await.remove(&info.node_id);
+        let mut peers = self.peers.write().await;
+        if !peers.contains_key(&info.node_id) {
+            info!("Peer {} joined at {}", info.node_id, info.grpc_addr);
+        }
+        peers.insert(info.node_id.clone(), Peer {
+            info,
+            last_seen: Instant::now(),
+        });
+    }
+
+    // Record nodes learned second hand, known peers are only refreshed by direct contact
+    pub async fn observe_gossip(&self, nodes: Vec<NodeInfo>) {
+        let departed = self.departed.read().await;
+        let mut peers = self.peers.write().await;
+        for info in nodes {
+            if info.node_id.is_empty()
+                || info.node_id == self.options.node_id
+                || peers.contains_key(&info.node_id)
+                || departed.contains_key(&info.node_id)
+            {
+                continue;
+            }
+            debug!("Learned about peer {} at {}", info.node_id, info.grpc_addr);
+            peers.insert(info.node_id.clone(), Peer {
+                info,
+                last_seen: Instant::now(),
+            });
+        }
+    }
+
+    // Get all known peers
+    pub async fn peers(&self) -> Vec<NodeInfo> {
+        let peers = self.peers.read().await;
+        peers.values().map(|p| p.info.clone()).collect()
+    }
+
+    // Peers eligible for offloading, least loaded first
+    pub async fn candidates(&self, model: Option<&str>) -> Vec<NodeInfo> {
+        let mut candidates: Vec<NodeInfo> = self.peers().await
+            .into_iter()
+            .filter(|p| !p.busy)
+            .filter(|p| match model {
+                Some(model) => p.models.iter().any(|m| m == model),
+                None => !p.models.is_empty(),
+            })
+            .collect();
+        candidates.sort_by(|a, b| {
+            a.load.partial_cmp(&b.load)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(b.weight.cmp(&a.weight))
+        });
+        candidates
+    }
+
+    // Join through the seeds, then heartbeat every known peer periodically
+    pub fn spawn(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            self.join_seeds().await;
+            let mut interval = tokio::time::interval(self.options.heartbeat_interval);
+            loop {
+                interval.tick().await;
+                self.heartbeat_round().await;
+                self.expire().await;
+            }
+        })
+    }
+
+    async fn join_seeds(&self) {
+        let local = self.local_info().await;
+        for seed in &self.options.seeds {
+            if *seed == self.options.advertise_addr {
+                continue;
+            }
+            let result = async {
+                let mut client = self.connect(seed).await?;
+                let mut request = tonic::Request::new(JoinRequest { node: Some(local.clone()) });
+                crate::auth::set_api_key(&mut request, self.api_key());
+                client.join(request).await
+            }.await;
+            match result {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    if let Some(node) = response.node {
+                        self.observe(node).await;
+                    }
+                    self.observe_gossip(response.peers).await;
+                }
+                Err(e) => warn!("Failed to join seed {}: {}", seed, e),
+            }
+        }
+    }
+
+    async fn heartbeat_round(&self) {
+        let local = self.local_info().await;
+        let peers = self.peers().await;
+
+        // seeds that are not members yet are retried, so late starting seeds are picked up
+        let mut targets: Vec<String> = peers.iter().map(|p| p.grpc_addr.clone()).collect();
+        for seed in &self.options.seeds {
+            if *seed != self.options.advertise_addr && !targets.contains(seed) {
+                targets.push(seed.clone());
+            }
+        }
+
+        let results = join_all(targets.iter().map(|addr| {
+            let mut request = tonic::Request::new(HeartbeatRequest {
+                node: Some(local.clone()),
+                peers: peers.clone(),
+            });
+            crate::auth::set_api_key(&mut request, self.api_key());
+            async move {
+                let mut client = self.connect(addr).await?;
+                client.heartbeat(request).await
+            }
+        })).await;
+
+        for (addr, result) in targets.iter().zip(results) {
+            match result {
+                Ok(response) => {
+                    let response = response.into_inner();
+                    if let Some(node) = response.node {
+                        self.observe(node).await;
+                    }
+                    self.observe_gossip(response.peers).await;
+                }
+                Err(e) => debug!("Heartbeat to {} failed: {}", addr, e),
+            }
+        }
+    }
+
+    async fn expire(&self) {
+        let timeout = self.options.peer_timeout;
+        let mut departed = self.departed.write().await;
+        departed.retain(|_, since| since.elapsed() < timeout * 2);
+
+        let mut peers = self.peers.write().await;
+        peers.retain(|id, peer| {
+            let alive = peer.last_seen.elapsed() < timeout;
+            if !alive {
+                info!("Peer {} at {} timed out", id, peer.info.grpc_addr);
+                departed.insert(id.clone(), Instant::now());
+            }
+            alive
+        });
+    }
+
+    async fn connect(&self, addr: &str) -> Result<AssistantServiceClient<Channel>, tonic::Status> {
+        let channel = crate::tls::endpoint(addr, self.tls())
+            .map_err(|e| tonic::Status::invalid_argument(e.to_string()))?
+            .connect_timeout(self.options.heartbeat_interval)
+            .timeout(self.options.heartbeat_interval)
+            .connect()
+            .await
+            .map_err(|e| tonic::Status::unavailable(e.to_string()))?;
+        Ok(AssistantServiceClient::new(channel))
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::metadata::MetadataMap;
+
+pub use scheduler::deadline::{exceeded, remaining};
+
+// Answer a little before the caller gives up, so it sees DEADLINE_EXCEEDED rather than a cancel
+const HEADROOM: Duration = Duration::from_millis(20);
+
+// Deadline of a call from its `grpc-timeout` header
+pub fn from_metadata(metadata: &MetadataMap) -> Option<Instant> {
+    let value = metadata.get("grpc-timeout")?.to_str().ok()?;
+    parse_grpc_timeout(value).map(|timeout| Instant::now() + timeout.saturating_sub(HEADROOM))
+}
+
+// Parse a `grpc-timeout` value such as `500m` or `30S`
+pub fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || value.len() > 9 {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 3600)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_unit() {
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(parse_grpc_timeout("30S"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_grpc_timeout("500m"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_grpc_timeout("250u"), Some(Duration::from_micros(250)));
+        assert_eq!(parse_grpc_timeout("99999999n"), Some(Duration::from_nanos(99_999_999)));
+    }
+
+    #[test]
+    fn rejects_malformed_values() {
+        for value in ["", "S", "10", "10s", "-5S", "1.5S", "123456789S", " 5S"] {
+            assert_eq!(parse_grpc_timeout(value), None, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn deadline_leaves_headroom() {
+        let mut metadata = MetadataMap::new();
+        assert!(from_metadata(&metadata).is_none());
+        metadata.insert("grpc-timeout", "1S".parse().unwrap());
+        let left = from_metadata(&metadata).unwrap() - Instant::now();
+        assert!(left <= Duration::from_secs(1) - HEADROOM);
+        assert!(left > Duration::from_millis(900));
+    }
+}
@@ -0,0 +1,40 @@
+use protos::assistant::assistant_service_server::AssistantServiceServer;
+use scheduler::{Scheduler, ServiceStatus};
+use std::sync::Arc;
+use std::time::Duration;
+use tonic::server::NamedService;
+use tonic_health::server::HealthReporter;
+use tonic_health::ServingStatus;
+use tracing::info;
+
+use crate::GrpcServer;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+// Keep the health status in sync with the scheduler, serving while any instance is running
+pub fn spawn_watcher(mut reporter: HealthReporter, scheduler: Arc<Scheduler>) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let service = <AssistantServiceServer<GrpcServer> as NamedService>::NAME;
+        let mut current = None;
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let running = scheduler.list_instances().await
+                .iter()
+                .any(|i| i.status == ServiceStatus::Running);
+            let status = if running {
+                ServingStatus::Serving
+            } else {
+                ServingStatus::NotServing
+            };
+            if current == Some(status) {
+                continue;
+            }
+            info!("Health status of {} is now {:?}", service, status);
+            // the empty name is the overall server status checked by most load balancers
+            reporter.set_service_status("", status).await;
+            reporter.set_service_status(service, status).await;
+            current = Some(status);
+        }
+    })
+}
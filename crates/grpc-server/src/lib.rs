@@ -1,17 +1,59 @@
+use ::auth::KeyStore;
+use cluster::Membership;
 use protos::assistant::{
+    assistant_service_client::AssistantServiceClient,
     assistant_service_server::{AssistantService, AssistantServiceServer},
-    InfoRequest, InfoResponse, Request, Response,
+    ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, EmbeddingRequest,
+    EmbeddingResponse, HeartbeatRequest, HeartbeatResponse, InfoRequest, InfoResponse,
+    JoinRequest, JoinResponse, ListModelsRequest, ListModelsResponse, ModelInfo, ModelStatus,
+    Request, Response, SpeechRequest, SpeechResponse,
 };
-use scheduler::Scheduler;
+use config::Priority;
+use scheduler::{request_model, Scheduler, SchedulerError, ServiceStatus};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tonic::{transport::Server, Request as TonicRequest, Response as TonicResponse, Status};
+use tokio::time::Instant;
+use tonic::{
+    transport::{Channel, ClientTlsConfig, Server, ServerTlsConfig},
+    Request as TonicRequest, Response as TonicResponse, Status,
+};
 use tracing::{debug, info, warn};
 use tokio_stream::wrappers::ReceiverStream;
 
+pub mod auth;
+pub mod cluster;
+pub mod deadline;
+pub mod health;
+pub mod metrics;
+pub mod tls;
+pub mod typed;
+
+// Set on offloaded requests so the receiving node does not offload them again
+const FORWARDED_HEADER: &str = "x-assistant-forwarded-by";
+
 pub struct GrpcServer {
     scheduler: Arc<Scheduler>,
     max_load: f32,
     remote_servers: Vec<RemoteServerConfig>,
+    membership: Arc<Membership>,
+    tls: Option<ServerTlsConfig>,
+    keys: Option<Arc<KeyStore>>,
+}
+
+// Where a request is served
+enum Placement {
+    Local,
+    // over max_load, offloaded to a peer
+    Busy,
+    // the model is not served here
+    Remote(String),
+}
+
+// A peer or configured remote server requests are offloaded to
+struct RemoteTarget {
+    name: String,
+    addr: String,
+    tls: Option<ClientTlsConfig>,
 }
 
 #[derive(Clone)]
@@ -20,131 +62,345 @@ pub struct RemoteServerConfig {
     pub grpc_addr: String,
     pub weight: u32,
     pub enabled: bool,
+    pub tls: Option<ClientTlsConfig>,
 }
 
 impl GrpcServer {
-    pub fn new(scheduler: Arc<Scheduler>, max_load: f32, remote_servers: Vec<RemoteServerConfig>) -> Self {
+    pub fn new(
+        scheduler: Arc<Scheduler>,
+        max_load: f32,
+        remote_servers: Vec<RemoteServerConfig>,
+        membership: Arc<Membership>,
+    ) -> Self {
         Self { 
             scheduler,
             max_load,
             remote_servers,
+            membership,
+            tls: None,
+            keys: None,
         }
     }
 
+    // Serve over TLS, see tls::server_config
+    pub fn with_tls(mut self, tls: Option<ServerTlsConfig>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    // Require an API key from callers when the store has auth enabled
+    pub fn with_keys(mut self, keys: Arc<KeyStore>) -> Self {
+        self.keys = Some(keys);
+        self
+    }
+
     pub async fn serve(self, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
         let addr = addr.parse()?;
         info!("Starting gRPC server on {}", addr);
 
-        Server::builder()
-            .add_service(AssistantServiceServer::new(self))
+        // standard grpc.health.v1 service, not serving until an instance is running
+        let (mut reporter, health_service) = tonic_health::server::health_reporter();
+        reporter.set_not_serving::<AssistantServiceServer<GrpcServer>>().await;
+        let health_handle = health::spawn_watcher(reporter, self.scheduler.clone());
+
+        // reflection for grpcurl and other generic clients
+        let reflection_service = tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(protos::FILE_DESCRIPTOR_SET)
+            .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+            .build()?;
+
+        let mut builder = Server::builder();
+        if let Some(tls) = self.tls.clone() {
+            builder = builder.tls_config(tls)?;
+        }
+        let mut builder = builder.layer(metrics::MetricsLayer);
+
+        // health and reflection stay open for probes and tooling
+        let interceptor = crate::auth::interceptor(self.keys.clone());
+        let result = builder
+            .add_service(health_service)
+            .add_service(reflection_service)
+            .add_service(AssistantServiceServer::with_interceptor(self, interceptor))
             .serve(addr)
-            .await?;
+            .await;
+        health_handle.abort();
+        result?;
 
         Ok(())
     }
 
-    // try to forward request to remote servers
-    async fn try_remote_forward(&self, request: Request) -> Result<Response, Status> {
+    // Reject a model the caller's key may not use, by its name or an alias of a local model
+    async fn authorize_model<T>(&self, request: &TonicRequest<T>, model: Option<&str>) -> Result<(), Status> {
+        crate::auth::authorize_model(request, model, &self.scheduler.model_configs().await)
+    }
+
+    // Offload targets, discovered peers hosting the model first, then the configured remote servers
+    async fn remote_targets(&self, model: Option<&str>) -> Vec<RemoteTarget> {
+        let mut targets: Vec<RemoteTarget> = self.membership.candidates(model).await
+            .into_iter()
+            .map(|peer| RemoteTarget {
+                name: peer.node_id,
+                addr: peer.grpc_addr,
+                tls: self.membership.tls().cloned(),
+            })
+            .collect();
         for server in &self.remote_servers {
-            if !server.enabled {
+            if !server.enabled || targets.iter().any(|t| t.addr == server.grpc_addr) {
                 continue;
             }
+            targets.push(RemoteTarget {
+                name: server.name.clone(),
+                addr: server.grpc_addr.clone(),
+                tls: server.tls.clone(),
+            });
+        }
+        targets
+    }
+
+    // try to forward request to remote servers, discovered peers hosting the model first
+    async fn try_remote_forward(&self, mut request: Request, deadline: Option<Instant>) -> Result<Response, Status> {
+        request.headers.insert(FORWARDED_HEADER.to_string(), self.membership.node_id().to_string());
 
-            match self.forward_to_remote(&server.grpc_addr, request.clone()).await {
-                Ok(response) => return Ok(response),
-                Err(e) => warn!("Failed to forward to {}: {}", server.name, e),
+        let model = request_model(&request.body);
+        for target in self.remote_targets(model.as_deref()).await {
+            let result = async {
+                let mut client = self.remote_client(&target, deadline).await?;
+                client.forward_request(self.remote_request(request.clone(), deadline)?).await
+            }.await;
+            match result {
+                Ok(response) => {
+                    record_offload(&target.addr, "ok");
+                    return Ok(response.into_inner());
+                }
+                Err(e) => {
+                    record_offload(&target.addr, "error");
+                    warn!("Failed to forward to {}: {}", target.name, e);
+                }
             }
         }
         
-        Err(Status::resource_exhausted("All servers are busy"))
+        Err(SchedulerError::QueueFull.into())
     }
 
-    // forward request to specified remote server
-    async fn forward_to_remote(&self, addr: &str, request: Request) -> Result<Response, Status> {
-        let mut client = protos::assistant::assistant_service_client::AssistantServiceClient::connect(
-            format!("http://{}", addr)
-        ).await.map_err(|e| Status::unavailable(e.to_string()))?;
+    // Streaming variant of try_remote_forward, the first target that accepts the stream serves it
+    async fn try_remote_forward_stream(
+        &self,
+        mut request: Request,
+        deadline: Option<Instant>,
+    ) -> Result<tokio::sync::mpsc::Receiver<Result<Response, Status>>, Status> {
+        request.headers.insert(FORWARDED_HEADER.to_string(), self.membership.node_id().to_string());
 
-        client.forward_request(request)
-            .await
-            .map(|r| r.into_inner())
-    }
+        let model = request_model(&request.body);
+        for target in self.remote_targets(model.as_deref()).await {
+            let result = async {
+                let mut client = self.remote_client(&target, deadline).await?;
+                client.forward_request_stream(self.remote_request(request.clone(), deadline)?).await
+            }.await;
+            let mut stream = match result {
+                Ok(response) => response.into_inner(),
+                Err(e) => {
+                    record_offload(&target.addr, "error");
+                    warn!("Failed to forward stream to {}: {}", target.name, e);
+                    continue;
+                }
+            };
+            record_offload(&target.addr, "ok");
 
-}
+            // relay the remote chunks, an error ends the stream
+            let (tx, rx) = tokio::sync::mpsc::channel(4);
+            tokio::spawn(async move {
+                loop {
+                    let message = match stream.message().await {
+                        Ok(Some(message)) => Ok(message),
+                        Ok(None) => break,
+                        Err(status) => Err(status),
+                    };
+                    let failed = message.is_err();
+                    if tx.send(message).await.is_err() || failed {
+                        break;
+                    }
+                }
+            });
+            return Ok(rx);
+        }
 
-#[tonic::async_trait]
-impl AssistantService for GrpcServer {
-    type ForwardRequestStreamStream = ReceiverStream<Result<Response, Status>>;
+        Err(SchedulerError::QueueFull.into())
+    }
 
-    async fn forward_request(
+    // connect to a remote server within the request budget
+    async fn remote_client(
         &self,
-        request: TonicRequest<Request>,
-    ) -> Result<TonicResponse<Response>, Status> {
-        let request = request.into_inner();
+        target: &RemoteTarget,
+        deadline: Option<Instant>,
+    ) -> Result<AssistantServiceClient<Channel>, Status> {
+        let mut endpoint = tls::endpoint(&target.addr, target.tls.as_ref())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if let Some(timeout) = deadline::remaining(deadline)? {
+            endpoint = endpoint.connect_timeout(timeout);
+        }
+        let channel = endpoint
+            .connect()
+            .await
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        Ok(AssistantServiceClient::new(channel))
+    }
+
+    // the peer gets what is left of the budget as its grpc-timeout
+    fn remote_request(&self, request: Request, deadline: Option<Instant>) -> Result<TonicRequest<Request>, Status> {
+        let mut request = TonicRequest::new(request);
+        if let Some(timeout) = deadline::remaining(deadline)? {
+            request.set_timeout(timeout);
+        }
+        crate::auth::set_api_key(&mut request, self.membership.api_key());
+        Ok(request)
+    }
 
-        // check if local scheduler is busy
-        if self.scheduler.is_busy(self.max_load).await {
-            debug!("Local scheduler is busy, trying remote servers");
-            return Ok(TonicResponse::new(self.try_remote_forward(request).await?));
+    // Where a request is served, see Placement
+    async fn place(&self, request: &Request, priority: Priority, deadline: Option<Instant>) -> Result<Placement, Status> {
+        // requests offloaded by a peer are served locally
+        if request.headers.contains_key(FORWARDED_HEADER) {
+            return Ok(Placement::Local);
+        }
+        // a peer may host a model this node does not have
+        if let Some(model) = request_model(&request.body).filter(|m| !m.is_empty()) {
+            if !self.scheduler.has_model(&model).await {
+                return Ok(Placement::Remote(model));
+            }
+        }
+        match priority {
+            // low priority keys wait for the local pool, like low priority batches
+            Priority::Low => {
+                let wait = self.scheduler.wait_until_idle(self.max_load);
+                match deadline::remaining(deadline)? {
+                    Some(timeout) => tokio::time::timeout(timeout, wait)
+                        .await
+                        .map_err(|_| deadline::exceeded())?,
+                    None => wait.await,
+                }
+                Ok(Placement::Local)
+            }
+            // high priority keys are served locally even when the pool is busy
+            Priority::High => Ok(Placement::Local),
+            Priority::Normal if self.scheduler.is_busy(self.max_load).await => Ok(Placement::Busy),
+            Priority::Normal => Ok(Placement::Local),
+        }
+    }
+
+    // Serve a request locally, or offload it when the local scheduler is busy
+    async fn dispatch(&self, request: Request, priority: Priority, deadline: Option<Instant>) -> Result<Response, Status> {
+        match self.place(&request, priority, deadline).await? {
+            Placement::Local => {}
+            Placement::Busy => {
+                debug!("Local scheduler is busy, trying remote servers");
+                return self.try_remote_forward(request, deadline).await;
+            }
+            Placement::Remote(model) => {
+                debug!("Model {} is not served locally, trying remote servers", model);
+                return self.try_remote_forward(request, deadline).await
+                    .map_err(|_| SchedulerError::ModelNotFound(model).into());
+            }
         }
 
         // forward request to local scheduler
-        match self.scheduler.forward_request(
+        let timeout = deadline::remaining(deadline)?;
+        let forward = self.scheduler.forward_request(
             &request.path,
             &request.method,
             request.body,
             request.headers,
-        ).await {
-            Ok((status, body, headers)) => Ok(TonicResponse::new(Response {
+            timeout,
+        );
+        let result = match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, forward)
+                .await
+                .map_err(|_| deadline::exceeded())?,
+            None => forward.await,
+        };
+        match result {
+            Ok((status, body, headers)) => Ok(Response {
                 status: status as i32,
                 body,
                 headers,
-            })),
-            Err(e) => Err(Status::internal(e.to_string())),
+            }),
+            Err(e) => Err(e.into()),
         }
     }
 
-    async fn forward_request_stream(
+    // Stream a request from the local scheduler, or offload it like dispatch does.
+    // Chunks are sent to the returned receiver
+    async fn dispatch_stream(
         &self,
-        request: TonicRequest<Request>,
-    ) -> Result<TonicResponse<Self::ForwardRequestStreamStream>, Status> {
-        let request = request.into_inner();
-        
-        // check if local scheduler is busy
-        if self.scheduler.is_busy(self.max_load).await {
-            debug!("Local scheduler is busy, trying remote servers");
-            return Err(Status::resource_exhausted("All servers are busy"));
+        request: Request,
+        priority: Priority,
+        deadline: Option<Instant>,
+    ) -> Result<tokio::sync::mpsc::Receiver<Result<Response, Status>>, Status> {
+        match self.place(&request, priority, deadline).await? {
+            Placement::Local => {}
+            Placement::Busy => {
+                debug!("Local scheduler is busy, trying remote servers");
+                return self.try_remote_forward_stream(request, deadline).await;
+            }
+            Placement::Remote(model) => {
+                debug!("Model {} is not served locally, trying remote servers", model);
+                return self.try_remote_forward_stream(request, deadline).await
+                    .map_err(|_| SchedulerError::ModelNotFound(model).into());
+            }
         }
 
         let (tx, rx) = tokio::sync::mpsc::channel(4);
         let scheduler = self.scheduler.clone();
-        let path = request.path.clone();
-        let method = request.method.clone();
-        let body = request.body.clone();
-        let headers = request.headers.clone();
 
         // start background task to handle stream request
         tokio::spawn(async move {
-            match scheduler.forward_request(
-                &path,
-                &method,
-                body,
-                headers,
-            ).await {
-                Ok((status, body, headers)) => {
-                    let _ = tx.send(Ok(Response {
-                        status: status as i32,
-                        body,
-                        headers,
-                    })).await;
-                }
-                Err(e) => {
-                    let _ = tx.send(Err(Status::internal(e.to_string()))).await;
+            let timeout = match deadline::remaining(deadline) {
+                Ok(timeout) => timeout,
+                Err(status) => {
+                    let _ = tx.send(Err(status)).await;
+                    return;
                 }
+            };
+            if let Err(e) = scheduler.forward_request_stream(
+                &request.path,
+                &request.method,
+                request.body,
+                request.headers,
+                timeout,
+                tx.clone(),
+            ).await {
+                let _ = tx.send(Err(e.into())).await;
             }
         });
 
-        Ok(TonicResponse::new(ReceiverStream::new(rx)))
+        Ok(rx)
+    }
+
+}
+
+#[tonic::async_trait]
+impl AssistantService for GrpcServer {
+    type ForwardRequestStreamStream = ReceiverStream<Result<Response, Status>>;
+    type ChatCompletionStreamStream = ReceiverStream<Result<ChatCompletionChunk, Status>>;
+
+    async fn forward_request(
+        &self,
+        request: TonicRequest<Request>,
+    ) -> Result<TonicResponse<Response>, Status> {
+        let deadline = deadline::from_metadata(request.metadata());
+        self.authorize_model(&request, request_model(&request.get_ref().body).as_deref()).await?;
+        let priority = crate::auth::priority(&request);
+        Ok(TonicResponse::new(self.dispatch(request.into_inner(), priority, deadline).await?))
+    }
+
+    async fn forward_request_stream(
+        &self,
+        request: TonicRequest<Request>,
+    ) -> Result<TonicResponse<Self::ForwardRequestStreamStream>, Status> {
+        let deadline = deadline::from_metadata(request.metadata());
+        self.authorize_model(&request, request_model(&request.get_ref().body).as_deref()).await?;
+        let priority = crate::auth::priority(&request);
+        let stream = self.dispatch_stream(request.into_inner(), priority, deadline).await?;
+
+        Ok(TonicResponse::new(ReceiverStream::new(stream)))
     }
 
     async fn get_info(
@@ -174,4 +430,178 @@ impl AssistantService for GrpcServer {
             endpoints,
         }))
     }
-} 
\ No newline at end of file
+
+    async fn join(
+        &self,
+        request: TonicRequest<JoinRequest>,
+    ) -> Result<TonicResponse<JoinResponse>, Status> {
+        let node = request.into_inner().node
+            .ok_or_else(|| Status::invalid_argument("missing node"))?;
+        let peers = self.membership.peers().await;
+        self.membership.observe(node).await;
+
+        Ok(TonicResponse::new(JoinResponse {
+            node: Some(self.membership.local_info().await),
+            peers,
+        }))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: TonicRequest<HeartbeatRequest>,
+    ) -> Result<TonicResponse<HeartbeatResponse>, Status> {
+        let request = request.into_inner();
+        let node = request.node
+            .ok_or_else(|| Status::invalid_argument("missing node"))?;
+        self.membership.observe(node).await;
+        self.membership.observe_gossip(request.peers).await;
+
+        Ok(TonicResponse::new(HeartbeatResponse {
+            node: Some(self.membership.local_info().await),
+            peers: self.membership.peers().await,
+        }))
+    }
+
+    async fn chat_completion(
+        &self,
+        request: TonicRequest<ChatCompletionRequest>,
+    ) -> Result<TonicResponse<ChatCompletionResponse>, Status> {
+        let deadline = deadline::from_metadata(request.metadata());
+        self.authorize_model(&request, Some(request.get_ref().model.as_str())).await?;
+        let body = typed::chat_request_body(request.get_ref(), false);
+        let response = self.dispatch(typed::json_request(typed::CHAT_PATH, body), crate::auth::priority(&request), deadline).await?;
+        typed::check_response(&response)?;
+
+        Ok(TonicResponse::new(typed::chat_response_from_json(&response.body)?))
+    }
+
+    async fn chat_completion_stream(
+        &self,
+        request: TonicRequest<ChatCompletionRequest>,
+    ) -> Result<TonicResponse<Self::ChatCompletionStreamStream>, Status> {
+        let deadline = deadline::from_metadata(request.metadata());
+        self.authorize_model(&request, Some(request.get_ref().model.as_str())).await?;
+        let body = typed::chat_request_body(request.get_ref(), true);
+        let mut upstream = self.dispatch_stream(typed::json_request(typed::CHAT_PATH, body), crate::auth::priority(&request), deadline).await?;
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+
+        // decode the upstream SSE body into typed chunks
+        tokio::spawn(async move {
+            let mut decoder = typed::SseDecoder::default();
+            let mut error_body = None;
+            while let Some(message) = upstream.recv().await {
+                let message = match message {
+                    Ok(message) => message,
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        return;
+                    }
+                };
+                if message.status >= 400 {
+                    error_body.get_or_insert((message.status, Vec::new())).1.extend(message.body);
+                    continue;
+                }
+                for data in decoder.push(&message.body) {
+                    if data == "[DONE]" {
+                        return;
+                    }
+                    let value = match serde_json::from_str::<serde_json::Value>(&data) {
+                        Ok(value) => value,
+                        Err(e) => {
+                            warn!("Invalid stream chunk: {}", e);
+                            continue;
+                        }
+                    };
+                    for chunk in typed::chunks_from_json(&value) {
+                        if tx.send(Ok(chunk)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            if let Some((status, body)) = error_body {
+                let _ = tx.send(Err(typed::status_from_http(status as u16, &body))).await;
+            }
+        });
+
+        Ok(TonicResponse::new(ReceiverStream::new(rx)))
+    }
+
+    async fn embed(
+        &self,
+        request: TonicRequest<EmbeddingRequest>,
+    ) -> Result<TonicResponse<EmbeddingResponse>, Status> {
+        let deadline = deadline::from_metadata(request.metadata());
+        self.authorize_model(&request, Some(request.get_ref().model.as_str())).await?;
+        let body = typed::embedding_request_body(request.get_ref());
+        let response = self.dispatch(typed::json_request(typed::EMBEDDINGS_PATH, body), crate::auth::priority(&request), deadline).await?;
+        typed::check_response(&response)?;
+
+        Ok(TonicResponse::new(typed::embedding_response_from_json(&response.body)?))
+    }
+
+    async fn list_models(
+        &self,
+        _request: TonicRequest<ListModelsRequest>,
+    ) -> Result<TonicResponse<ListModelsResponse>, Status> {
+        let mut models: Vec<ModelInfo> = self.scheduler.list_instances().await
+            .into_iter()
+            .map(|i| ModelInfo {
+                owned_by: i.config.owned_by.clone().unwrap_or_else(|| "assistant".to_string()),
+                id: i.config.name,
+                status: model_status(i.status) as i32,
+                node_id: String::new(),
+            })
+            .collect();
+
+        // models only served by peers
+        let mut seen: HashSet<String> = models.iter().map(|m| m.id.clone()).collect();
+        for peer in self.membership.peers().await {
+            for model in peer.models {
+                if seen.insert(model.clone()) {
+                    models.push(ModelInfo {
+                        id: model,
+                        owned_by: "assistant".to_string(),
+                        status: ModelStatus::Running as i32,
+                        node_id: peer.node_id.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(TonicResponse::new(ListModelsResponse { models }))
+    }
+
+    async fn speech(
+        &self,
+        request: TonicRequest<SpeechRequest>,
+    ) -> Result<TonicResponse<SpeechResponse>, Status> {
+        let deadline = deadline::from_metadata(request.metadata());
+        self.authorize_model(&request, Some(request.get_ref().model.as_str())).await?;
+        let body = typed::speech_request_body(request.get_ref());
+        let response = self.dispatch(typed::json_request(typed::SPEECH_PATH, body), crate::auth::priority(&request), deadline).await?;
+        typed::check_response(&response)?;
+
+        let headers: HashMap<String, String> = response.headers.into_iter()
+            .map(|(k, v)| (k.to_lowercase(), v))
+            .collect();
+        Ok(TonicResponse::new(SpeechResponse {
+            audio: response.body,
+            content_type: headers.get("content-type").cloned().unwrap_or_default(),
+        }))
+    }
+}
+
+fn record_offload(target: &str, outcome: &str) {
+    telemetry::metrics().remote_offloads.with_label_values(&[target, outcome]).inc();
+}
+
+fn model_status(status: ServiceStatus) -> ModelStatus {
+    match status {
+        ServiceStatus::Starting => ModelStatus::Starting,
+        ServiceStatus::Running => ModelStatus::Running,
+        ServiceStatus::Failed => ModelStatus::Failed,
+        ServiceStatus::Stopped => ModelStatus::Stopped,
+    }
+}
+
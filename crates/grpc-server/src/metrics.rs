@@ -0,0 +1,98 @@
+use std::task::{Context, Poll};
+use std::time::Instant;
+use telemetry::metrics;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::Code;
+use tower::Layer;
+
+// Methods served, any other path is counted as unknown so clients cannot add label values
+const METHODS: &[&str] = &[
+    "assistant.AssistantService/ForwardRequest",
+    "assistant.AssistantService/GetInfo",
+    "assistant.AssistantService/ForwardRequestStream",
+    "assistant.AssistantService/Join",
+    "assistant.AssistantService/Heartbeat",
+    "assistant.AssistantService/ChatCompletion",
+    "assistant.AssistantService/ChatCompletionStream",
+    "assistant.AssistantService/Embed",
+    "assistant.AssistantService/ListModels",
+    "assistant.AssistantService/Speech",
+    "grpc.health.v1.Health/Check",
+    "grpc.health.v1.Health/Watch",
+    "grpc.reflection.v1alpha.ServerReflection/ServerReflectionInfo",
+];
+const UNKNOWN_METHOD: &str = "unknown";
+
+fn method_label(path: &str) -> &'static str {
+    let method = path.trim_start_matches('/');
+    METHODS.iter().find(|m| **m == method).copied().unwrap_or(UNKNOWN_METHOD)
+}
+
+// Counts and times the calls of every gRPC service, until the response headers are sent
+#[derive(Clone, Copy, Default)]
+pub struct MetricsLayer;
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = Metered<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Metered { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct Metered<S> {
+    inner: S,
+}
+
+impl<S, B, R> Service<http::Request<B>> for Metered<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let method = method_label(request.uri().path());
+        let started = Instant::now();
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await;
+            // failures are answered with the status in the headers, a stream tells its own at the end
+            let code = match &response {
+                Ok(response) => response.headers()
+                    .get("grpc-status")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<i32>().ok())
+                    .map_or(Code::Ok, Code::from),
+                Err(_) => Code::Internal,
+            };
+            let code = format!("{:?}", code);
+            let labels = [method, code.as_str()];
+            metrics().grpc_requests.with_label_values(&labels).inc();
+            metrics().grpc_request_duration
+                .with_label_values(&labels)
+                .observe(started.elapsed().as_secs_f64());
+            response
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_methods_share_one_label() {
+        assert_eq!(method_label("/assistant.AssistantService/ChatCompletion"), "assistant.AssistantService/ChatCompletion");
+        assert_eq!(method_label("/grpc.health.v1.Health/Check"), "grpc.health.v1.Health/Check");
+        assert_eq!(method_label("/assistant.AssistantService/Made-Up"), UNKNOWN_METHOD);
+        assert_eq!(method_label("/anything/else"), UNKNOWN_METHOD);
+    }
+}
@@ -0,0 +1,44 @@
+use anyhow::{anyhow, Result};
+use config::TlsConfig;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity, ServerTlsConfig};
+
+// Build the listener TLS settings, client certificates are required when a CA is given
+pub fn server_config(tls: &TlsConfig) -> Result<ServerTlsConfig> {
+    let (cert_path, key_path) = match (&tls.cert_path, &tls.key_path) {
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Err(anyhow!("TLS listener needs both cert_path and key_path")),
+    };
+    let identity = Identity::from_pem(std::fs::read(cert_path)?, std::fs::read(key_path)?);
+    let mut config = ServerTlsConfig::new().identity(identity);
+    if let Some(ca_path) = &tls.ca_path {
+        config = config.client_ca_root(Certificate::from_pem(std::fs::read(ca_path)?));
+    }
+    Ok(config)
+}
+
+// Build the dialing TLS settings, the certificate pair is presented for mutual TLS
+pub fn client_config(tls: &TlsConfig) -> Result<ClientTlsConfig> {
+    let mut config = ClientTlsConfig::new();
+    if let Some(ca_path) = &tls.ca_path {
+        config = config.ca_certificate(Certificate::from_pem(std::fs::read(ca_path)?));
+    }
+    match (&tls.cert_path, &tls.key_path) {
+        (Some(cert), Some(key)) => {
+            config = config.identity(Identity::from_pem(std::fs::read(cert)?, std::fs::read(key)?));
+        }
+        (None, None) => {}
+        _ => return Err(anyhow!("Client certificate needs both cert_path and key_path")),
+    }
+    if let Some(domain_name) = &tls.domain_name {
+        config = config.domain_name(domain_name.clone());
+    }
+    Ok(config)
+}
+
+// Endpoint of a gRPC address, dialed over https when TLS is configured
+pub fn endpoint(addr: &str, tls: Option<&ClientTlsConfig>) -> Result<Endpoint, tonic::transport::Error> {
+    match tls {
+        Some(tls) => Endpoint::from_shared(format!("https://{}", addr))?.tls_config(tls.clone()),
+        None => Endpoint::from_shared(format!("http://{}", addr)),
+    }
+}
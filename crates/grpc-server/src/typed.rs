@@ -0,0 +1,369 @@
+// Translation between the typed RPC messages and the OpenAI JSON spoken by llama-api-server
+use protos::assistant::{
+    ChatCompletionChoice, ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse,
+    ChatMessage, Embedding, EmbeddingRequest, EmbeddingResponse, Request, Response, Role,
+    SpeechRequest, Usage,
+};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use tonic::Status;
+
+pub const CHAT_PATH: &str = "/v1/chat/completions";
+pub const EMBEDDINGS_PATH: &str = "/v1/embeddings";
+pub const SPEECH_PATH: &str = "/v1/audio/speech";
+
+// Wrap a JSON body into the generic request envelope
+pub fn json_request(path: &str, body: Value) -> Request {
+    let mut headers = HashMap::new();
+    headers.insert("content-type".to_string(), "application/json".to_string());
+    Request {
+        path: path.to_string(),
+        method: "POST".to_string(),
+        body: body.to_string().into_bytes(),
+        headers,
+    }
+}
+
+pub fn role_name(role: i32) -> &'static str {
+    match Role::try_from(role).unwrap_or(Role::Unspecified) {
+        Role::System => "system",
+        Role::Assistant => "assistant",
+        Role::Tool => "tool",
+        Role::User | Role::Unspecified => "user",
+    }
+}
+
+pub fn role_from_name(name: &str) -> Role {
+    match name {
+        "system" => Role::System,
+        "user" => Role::User,
+        "assistant" => Role::Assistant,
+        "tool" => Role::Tool,
+        _ => Role::Unspecified,
+    }
+}
+
+pub fn chat_request_body(request: &ChatCompletionRequest, stream: bool) -> Value {
+    let messages: Vec<Value> = request.messages.iter().map(|m| {
+        let mut message = json!({
+            "role": role_name(m.role),
+            "content": m.content,
+        });
+        if !m.name.is_empty() {
+            message["name"] = json!(m.name);
+        }
+        message
+    }).collect();
+
+    let mut body = Map::new();
+    if !request.model.is_empty() {
+        body.insert("model".to_string(), json!(request.model));
+    }
+    body.insert("messages".to_string(), json!(messages));
+    body.insert("stream".to_string(), json!(stream));
+    if let Some(v) = request.temperature {
+        body.insert("temperature".to_string(), json!(v));
+    }
+    if let Some(v) = request.top_p {
+        body.insert("top_p".to_string(), json!(v));
+    }
+    if let Some(v) = request.max_tokens {
+        body.insert("max_tokens".to_string(), json!(v));
+    }
+    if !request.stop.is_empty() {
+        body.insert("stop".to_string(), json!(request.stop));
+    }
+    if let Some(v) = request.presence_penalty {
+        body.insert("presence_penalty".to_string(), json!(v));
+    }
+    if let Some(v) = request.frequency_penalty {
+        body.insert("frequency_penalty".to_string(), json!(v));
+    }
+    if !request.user.is_empty() {
+        body.insert("user".to_string(), json!(request.user));
+    }
+    Value::Object(body)
+}
+
+pub fn chat_response_from_json(body: &[u8]) -> Result<ChatCompletionResponse, Status> {
+    let value = parse_json(body)?;
+    let choices = value["choices"].as_array().map(|choices| {
+        choices.iter().map(|c| ChatCompletionChoice {
+            index: c["index"].as_u64().unwrap_or(0) as u32,
+            message: Some(ChatMessage {
+                role: role_from_name(c["message"]["role"].as_str().unwrap_or("assistant")) as i32,
+                content: c["message"]["content"].as_str().unwrap_or("").to_string(),
+                name: String::new(),
+            }),
+            finish_reason: c["finish_reason"].as_str().unwrap_or("").to_string(),
+        }).collect()
+    }).unwrap_or_default();
+
+    Ok(ChatCompletionResponse {
+        id: value["id"].as_str().unwrap_or("").to_string(),
+        model: value["model"].as_str().unwrap_or("").to_string(),
+        created: value["created"].as_i64().unwrap_or(0),
+        choices,
+        usage: usage_from_json(&value["usage"]),
+    })
+}
+
+// Convert one streamed chat chunk, one message per choice
+pub fn chunks_from_json(value: &Value) -> Vec<ChatCompletionChunk> {
+    let id = value["id"].as_str().unwrap_or("").to_string();
+    let model = value["model"].as_str().unwrap_or("").to_string();
+    let created = value["created"].as_i64().unwrap_or(0);
+    let usage = usage_from_json(&value["usage"]);
+
+    let choices = value["choices"].as_array().cloned().unwrap_or_default();
+    if choices.is_empty() {
+        // usage only chunk sent at the end of the stream
+        return match usage {
+            Some(usage) => vec![ChatCompletionChunk {
+                id,
+                model,
+                created,
+                usage: Some(usage),
+                ..Default::default()
+            }],
+            None => vec![],
+        };
+    }
+
+    choices.iter().map(|c| ChatCompletionChunk {
+        id: id.clone(),
+        model: model.clone(),
+        created,
+        index: c["index"].as_u64().unwrap_or(0) as u32,
+        delta: c["delta"]["content"].as_str().unwrap_or("").to_string(),
+        finish_reason: c["finish_reason"].as_str().unwrap_or("").to_string(),
+        usage: usage.clone(),
+    }).collect()
+}
+
+pub fn embedding_request_body(request: &EmbeddingRequest) -> Value {
+    let mut body = json!({ "input": request.input });
+    if !request.model.is_empty() {
+        body["model"] = json!(request.model);
+    }
+    body
+}
+
+pub fn embedding_response_from_json(body: &[u8]) -> Result<EmbeddingResponse, Status> {
+    let value = parse_json(body)?;
+    let data = value["data"].as_array().map(|data| {
+        data.iter().map(|d| Embedding {
+            index: d["index"].as_u64().unwrap_or(0) as u32,
+            embedding: d["embedding"].as_array()
+                .map(|v| v.iter().filter_map(|x| x.as_f64()).map(|x| x as f32).collect())
+                .unwrap_or_default(),
+        }).collect()
+    }).unwrap_or_default();
+
+    Ok(EmbeddingResponse {
+        model: value["model"].as_str().unwrap_or("").to_string(),
+        data,
+        usage: usage_from_json(&value["usage"]),
+    })
+}
+
+pub fn speech_request_body(request: &SpeechRequest) -> Value {
+    let mut body = json!({ "input": request.input });
+    if !request.model.is_empty() {
+        body["model"] = json!(request.model);
+    }
+    if !request.voice.is_empty() {
+        body["voice"] = json!(request.voice);
+    }
+    if !request.response_format.is_empty() {
+        body["response_format"] = json!(request.response_format);
+    }
+    if let Some(speed) = request.speed {
+        body["speed"] = json!(speed);
+    }
+    body
+}
+
+// Turn a backend error response into a gRPC status
+pub fn check_response(response: &Response) -> Result<(), Status> {
+    if response.status < 400 {
+        return Ok(());
+    }
+    Err(status_from_http(response.status as u16, &response.body))
+}
+
+pub fn status_from_http(status: u16, body: &[u8]) -> Status {
+    let message = String::from_utf8_lossy(body).to_string();
+    match status {
+        400 | 422 => Status::invalid_argument(message),
+        401 => Status::unauthenticated(message),
+        403 => Status::permission_denied(message),
+        404 => Status::not_found(message),
+        408 | 504 => Status::deadline_exceeded(message),
+        429 => Status::resource_exhausted(message),
+        503 => Status::unavailable(message),
+        _ => Status::internal(message),
+    }
+}
+
+fn usage_from_json(value: &Value) -> Option<Usage> {
+    if !value.is_object() {
+        return None;
+    }
+    Some(Usage {
+        prompt_tokens: value["prompt_tokens"].as_u64().unwrap_or(0) as u32,
+        completion_tokens: value["completion_tokens"].as_u64().unwrap_or(0) as u32,
+        total_tokens: value["total_tokens"].as_u64().unwrap_or(0) as u32,
+    })
+}
+
+fn parse_json(body: &[u8]) -> Result<Value, Status> {
+    serde_json::from_slice(body)
+        .map_err(|e| Status::internal(format!("Invalid backend response: {}", e)))
+}
+
+// Incremental decoder for a server-sent events body
+#[derive(Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+}
+
+impl SseDecoder {
+    // Feed bytes and return the `data` payloads of the events completed so far
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        while let Some(pos) = find_event_end(&self.buffer) {
+            let event: Vec<u8> = self.buffer.drain(..pos).collect();
+            let event = String::from_utf8_lossy(&event);
+            let data: Vec<&str> = event.lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.trim_start())
+                .collect();
+            if !data.is_empty() {
+                events.push(data.join("\n"));
+            }
+        }
+        events
+    }
+}
+
+// Position right after the blank line terminating the first event
+fn find_event_end(buffer: &[u8]) -> Option<usize> {
+    (0..buffer.len()).find_map(|i| {
+        if buffer[i..].starts_with(b"\n\n") {
+            Some(i + 2)
+        } else if buffer[i..].starts_with(b"\r\n\r\n") {
+            Some(i + 4)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sse_events_split_across_chunks() {
+        let mut decoder = SseDecoder::default();
+        assert!(decoder.push(b"data: {\"a\":").is_empty());
+        assert_eq!(decoder.push(b"1}\n\ndata: [DO"), ["{\"a\":1}"]);
+        assert_eq!(decoder.push(b"NE]\n\n"), ["[DONE]"]);
+    }
+
+    #[test]
+    fn sse_events_join_data_lines_and_skip_comments() {
+        let mut decoder = SseDecoder::default();
+        let events = decoder.push(b": keep-alive\n\nevent: x\r\ndata:one\r\ndata: two\r\n\r\ndata: three\n\n");
+        assert_eq!(events, ["one\ntwo", "three"]);
+    }
+
+    #[test]
+    fn chat_request_keeps_only_set_fields() {
+        let request = ChatCompletionRequest {
+            model: "m".to_string(),
+            messages: vec![
+                ChatMessage { role: Role::System as i32, content: "be brief".to_string(), name: String::new() },
+                ChatMessage { role: Role::User as i32, content: "hi".to_string(), name: "ann".to_string() },
+            ],
+            max_tokens: Some(16),
+            stop: vec!["\n".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(chat_request_body(&request, true), json!({
+            "model": "m",
+            "messages": [
+                { "role": "system", "content": "be brief" },
+                { "role": "user", "content": "hi", "name": "ann" },
+            ],
+            "stream": true,
+            "max_tokens": 16,
+            "stop": ["\n"],
+        }));
+        let body = chat_request_body(&ChatCompletionRequest::default(), false);
+        assert_eq!(body, json!({ "messages": [], "stream": false }));
+    }
+
+    #[test]
+    fn chat_response_is_translated() {
+        let body = json!({
+            "id": "c1",
+            "model": "m",
+            "created": 7,
+            "choices": [{ "index": 0, "message": { "role": "assistant", "content": "hello" }, "finish_reason": "stop" }],
+            "usage": { "prompt_tokens": 3, "completion_tokens": 2, "total_tokens": 5 },
+        });
+        let response = chat_response_from_json(body.to_string().as_bytes()).unwrap();
+        assert_eq!((response.id.as_str(), response.model.as_str(), response.created), ("c1", "m", 7));
+        let message = response.choices[0].message.as_ref().unwrap();
+        assert_eq!((message.role, message.content.as_str()), (Role::Assistant as i32, "hello"));
+        assert_eq!(response.choices[0].finish_reason, "stop");
+        assert_eq!(response.usage.unwrap().total_tokens, 5);
+        assert_eq!(chat_response_from_json(b"not json").unwrap_err().code(), tonic::Code::Internal);
+    }
+
+    #[test]
+    fn stream_chunks_are_translated() {
+        let chunk = json!({ "id": "c1", "model": "m", "choices": [{ "index": 0, "delta": { "content": "he" }, "finish_reason": null }] });
+        let chunks = chunks_from_json(&chunk);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!((chunks[0].delta.as_str(), chunks[0].finish_reason.as_str()), ("he", ""));
+        assert!(chunks[0].usage.is_none());
+
+        let usage = json!({ "id": "c1", "choices": [], "usage": { "prompt_tokens": 3, "completion_tokens": 2, "total_tokens": 5 } });
+        let chunks = chunks_from_json(&usage);
+        assert_eq!(chunks[0].usage.as_ref().unwrap().completion_tokens, 2);
+        assert!(chunks_from_json(&json!({ "choices": [] })).is_empty());
+    }
+
+    #[test]
+    fn embeddings_are_translated() {
+        let request = EmbeddingRequest { model: String::new(), input: vec!["a".to_string()] };
+        assert_eq!(embedding_request_body(&request), json!({ "input": ["a"] }));
+        let body = json!({ "model": "e", "data": [{ "index": 0, "embedding": [0.5, 1.0] }] });
+        let response = embedding_response_from_json(body.to_string().as_bytes()).unwrap();
+        assert_eq!(response.data[0].embedding, [0.5, 1.0]);
+        assert!(response.usage.is_none());
+    }
+
+    #[test]
+    fn http_errors_map_to_statuses() {
+        let codes = [
+            (400, tonic::Code::InvalidArgument),
+            (401, tonic::Code::Unauthenticated),
+            (403, tonic::Code::PermissionDenied),
+            (404, tonic::Code::NotFound),
+            (429, tonic::Code::ResourceExhausted),
+            (503, tonic::Code::Unavailable),
+            (504, tonic::Code::DeadlineExceeded),
+            (500, tonic::Code::Internal),
+        ];
+        for (status, code) in codes {
+            assert_eq!(status_from_http(status, b"failed").code(), code);
+        }
+        let response = Response { status: 200, body: vec![], headers: HashMap::new() };
+        assert!(check_response(&response).is_ok());
+    }
+}
@@ -0,0 +1,211 @@
+// Nodes on 127.0.0.1 join through seeds, gossip their peers, expire stopped nodes and offload by model
+use grpc_server::cluster::{ClusterOptions, Membership};
+use grpc_server::GrpcServer;
+use protos::assistant::{
+    assistant_service_server::{AssistantService, AssistantServiceServer},
+    ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, EmbeddingRequest,
+    EmbeddingResponse, HeartbeatRequest, HeartbeatResponse, InfoRequest, InfoResponse,
+    JoinRequest, JoinResponse, ListModelsRequest, ListModelsResponse, NodeInfo, Request, Response,
+    SpeechRequest, SpeechResponse,
+};
+use scheduler::Scheduler;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{transport::Server, Request as TonicRequest, Response as TonicResponse, Status};
+
+const HEARTBEAT: Duration = Duration::from_millis(100);
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+// A peer that only answers membership calls, advertising models without running instances
+struct Peer(NodeInfo);
+
+#[tonic::async_trait]
+impl AssistantService for Peer {
+    type ForwardRequestStreamStream = ReceiverStream<Result<Response, Status>>;
+    type ChatCompletionStreamStream = ReceiverStream<Result<ChatCompletionChunk, Status>>;
+
+    async fn forward_request(&self, _: TonicRequest<Request>) -> Result<TonicResponse<Response>, Status> {
+        Err(Status::unimplemented("forward"))
+    }
+
+    async fn forward_request_stream(&self, _: TonicRequest<Request>) -> Result<TonicResponse<Self::ForwardRequestStreamStream>, Status> {
+        Err(Status::unimplemented("stream"))
+    }
+
+    async fn get_info(&self, _: TonicRequest<InfoRequest>) -> Result<TonicResponse<InfoResponse>, Status> {
+        Err(Status::unimplemented("info"))
+    }
+
+    async fn join(&self, _: TonicRequest<JoinRequest>) -> Result<TonicResponse<JoinResponse>, Status> {
+        Ok(TonicResponse::new(JoinResponse { node: Some(self.0.clone()), peers: vec![] }))
+    }
+
+    async fn heartbeat(&self, _: TonicRequest<HeartbeatRequest>) -> Result<TonicResponse<HeartbeatResponse>, Status> {
+        Ok(TonicResponse::new(HeartbeatResponse { node: Some(self.0.clone()), peers: vec![] }))
+    }
+
+    async fn chat_completion(&self, _: TonicRequest<ChatCompletionRequest>) -> Result<TonicResponse<ChatCompletionResponse>, Status> {
+        Err(Status::unimplemented("chat"))
+    }
+
+    async fn chat_completion_stream(&self, _: TonicRequest<ChatCompletionRequest>) -> Result<TonicResponse<Self::ChatCompletionStreamStream>, Status> {
+        Err(Status::unimplemented("chat stream"))
+    }
+
+    async fn embed(&self, _: TonicRequest<EmbeddingRequest>) -> Result<TonicResponse<EmbeddingResponse>, Status> {
+        Err(Status::unimplemented("embed"))
+    }
+
+    async fn list_models(&self, _: TonicRequest<ListModelsRequest>) -> Result<TonicResponse<ListModelsResponse>, Status> {
+        Ok(TonicResponse::new(ListModelsResponse { models: vec![] }))
+    }
+
+    async fn speech(&self, _: TonicRequest<SpeechRequest>) -> Result<TonicResponse<SpeechResponse>, Status> {
+        Err(Status::unimplemented("speech"))
+    }
+}
+
+async fn spawn_peer(node_id: &str, models: &[&str], busy: bool) -> (String, JoinHandle<()>) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let info = NodeInfo {
+        node_id: node_id.to_string(),
+        grpc_addr: addr.clone(),
+        models: models.iter().map(|m| m.to_string()).collect(),
+        load: if busy { 1.0 } else { 0.25 },
+        busy,
+        weight: 1,
+    };
+    let handle = tokio::spawn(async move {
+        let _ = Server::builder()
+            .add_service(AssistantServiceServer::new(Peer(info)))
+            .serve_with_incoming(TcpListenerStream::new(listener))
+            .await;
+    });
+    (addr, handle)
+}
+
+// A real node, its server and membership tasks are aborted on stop
+struct Node {
+    membership: Arc<Membership>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Node {
+    fn stop(&self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// A local address nothing listens on yet
+fn free_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+async fn start_node(node_id: &str, seeds: Vec<String>) -> Node {
+    let addr = free_addr();
+    let scheduler = Arc::new(Scheduler::new(std::env::temp_dir().join("assistant-cluster-test"), 4));
+    let options = ClusterOptions {
+        node_id: node_id.to_string(),
+        advertise_addr: addr.clone(),
+        seeds,
+        weight: 1,
+        heartbeat_interval: HEARTBEAT,
+        peer_timeout: TIMEOUT,
+        tls: None,
+        api_key: None,
+    };
+    let membership = Arc::new(Membership::new(options, scheduler.clone(), 0.8));
+    let server = GrpcServer::new(scheduler, 0.8, vec![], membership.clone());
+    let serve = tokio::spawn({
+        let addr = addr.clone();
+        async move {
+            let _ = server.serve(&addr).await;
+        }
+    });
+    wait_for(&addr).await;
+    let heartbeat = membership.clone().spawn();
+    Node { membership, tasks: vec![serve, heartbeat] }
+}
+
+async fn wait_for(addr: &str) {
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Nothing listens on {}", addr);
+}
+
+// Poll until the condition holds, the cluster converges within a few heartbeats
+async fn eventually<F, Fut>(what: &str, condition: F)
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    for _ in 0..100 {
+        if condition().await {
+            return;
+        }
+        tokio::time::sleep(HEARTBEAT).await;
+    }
+    panic!("Timed out waiting for {}", what);
+}
+
+async fn peer_ids(membership: &Membership) -> Vec<String> {
+    let mut ids: Vec<String> = membership.peers().await.into_iter().map(|p| p.node_id).collect();
+    ids.sort();
+    ids
+}
+
+#[tokio::test]
+async fn nodes_join_gossip_expire_and_offload_by_model() {
+    let (llama_addr, _llama) = spawn_peer("llama", &["llama", "llama-alias"], false).await;
+    let (busy_addr, _busy) = spawn_peer("busy", &["llama"], true).await;
+
+    // a knows the model peers, b joins through a and c through b only
+    let a = start_node("a", vec![llama_addr, busy_addr]).await;
+    let b = start_node("b", vec![a.membership.local_info().await.grpc_addr]).await;
+    let c = start_node("c", vec![b.membership.local_info().await.grpc_addr]).await;
+
+    let everyone = ["a", "b", "busy", "c", "llama"];
+    for node in [&a, &b, &c] {
+        let id = node.membership.node_id();
+        let expected: Vec<&str> = everyone.into_iter().filter(|other| *other != id).collect();
+        eventually(&format!("{} to learn the cluster", id), || async {
+            peer_ids(&node.membership).await == expected
+        }).await;
+    }
+
+    // only idle peers hosting the model are offload candidates, by name or alias
+    let ids = |peers: Vec<NodeInfo>| peers.into_iter().map(|p| p.node_id).collect::<Vec<_>>();
+    assert_eq!(ids(c.membership.candidates(Some("llama")).await), ["llama"]);
+    assert_eq!(ids(c.membership.candidates(Some("llama-alias")).await), ["llama"]);
+    assert!(c.membership.candidates(Some("mistral")).await.is_empty());
+    // nodes without running instances are never candidates
+    assert_eq!(ids(c.membership.candidates(None).await), ["llama"]);
+
+    // a stopped node times out everywhere, and is not brought back by gossip
+    b.stop();
+    for node in [&a, &c] {
+        eventually(&format!("{} to expire b", node.membership.node_id()), || async {
+            !peer_ids(&node.membership).await.contains(&"b".to_string())
+        }).await;
+    }
+    tokio::time::sleep(TIMEOUT * 2).await;
+    assert_eq!(peer_ids(&c.membership).await, ["a", "busy", "llama"]);
+    assert_eq!(peer_ids(&a.membership).await, ["busy", "c", "llama"]);
+}
@@ -0,0 +1,209 @@
+use auth::KeyStore;
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use config::{Config, LlamaServerConfig};
+use scheduler::{Scheduler, ServiceInstance};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::ApiError;
+
+const DEFAULT_LOG_LINES: usize = 100;
+
+struct AdminState {
+    scheduler: Arc<Scheduler>,
+    keys: Option<Arc<KeyStore>>,
+    config: Option<Config>,
+}
+
+#[derive(Deserialize)]
+struct LogsQuery {
+    lines: Option<usize>,
+}
+
+// Scheduler operations over REST, only admin keys get through
+pub fn router(scheduler: Arc<Scheduler>, keys: Option<Arc<KeyStore>>, config: Option<Config>) -> Router {
+    let state = Arc::new(AdminState { scheduler, keys, config });
+    Router::new()
+        .route("/instances", get(list_instances).post(start_instance))
+        .route("/instances/:id", get(get_instance).delete(stop_instance))
+        .route("/instances/:id/restart", post(restart_instance))
+        .route("/instances/:id/logs", get(instance_logs))
+        .route("/config", get(effective_config))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_admin))
+        .with_state(state)
+}
+
+// The admin API is closed unless auth is enabled and the key is an admin key
+async fn require_admin(
+    State(state): State<Arc<AdminState>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let keys = match &state.keys {
+        Some(keys) if keys.enabled() => keys,
+        _ => {
+            return Err(ApiError::new(StatusCode::FORBIDDEN, "The admin API needs auth to be enabled"));
+        }
+    };
+    let token = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(auth::bearer);
+    let key = keys.authenticate(token)?;
+    if !key.admin {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            format!("API key {} is not an admin key", key.name),
+        ));
+    }
+    Ok(next.run(req).await)
+}
+
+async fn list_instances(State(state): State<Arc<AdminState>>) -> Json<Value> {
+    let instances: Vec<Value> = state.scheduler.list_instances().await
+        .iter()
+        .map(instance_json)
+        .collect();
+    Json(json!({ "instances": instances }))
+}
+
+async fn get_instance(
+    State(state): State<Arc<AdminState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let instance = find_instance(&state, &id).await?;
+    Ok(Json(instance_json(&instance)))
+}
+
+async fn start_instance(
+    State(state): State<Arc<AdminState>>,
+    body: Bytes,
+) -> Result<(StatusCode, Json<Value>), ApiError> {
+    let config: LlamaServerConfig = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid instance config: {}", e)))?;
+    let instance = state.scheduler.start_instance_with_config(config).await
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok((StatusCode::CREATED, Json(instance_json(&instance))))
+}
+
+async fn stop_instance(
+    State(state): State<Arc<AdminState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    find_instance(&state, &id).await?;
+    state.scheduler.stop_instance(&id).await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn restart_instance(
+    State(state): State<Arc<AdminState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    find_instance(&state, &id).await?;
+    let instance = state.scheduler.restart_instance(&id).await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    Ok(Json(instance_json(&instance)))
+}
+
+async fn instance_logs(
+    State(state): State<Arc<AdminState>>,
+    Path(id): Path<String>,
+    Query(query): Query<LogsQuery>,
+) -> Result<Response, ApiError> {
+    find_instance(&state, &id).await?;
+    let logs = state.scheduler
+        .instance_logs(&id, query.lines.unwrap_or(DEFAULT_LOG_LINES))
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], logs).into_response())
+}
+
+async fn effective_config(State(state): State<Arc<AdminState>>) -> Result<Json<Config>, ApiError> {
+    state.config.as_ref()
+        .map(|config| Json(config.redacted()))
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "No config was given to the HTTP server"))
+}
+
+async fn find_instance(state: &AdminState, id: &str) -> Result<ServiceInstance, ApiError> {
+    state.scheduler.get_instance(id).await
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("Instance {} not found", id)))
+}
+
+fn instance_json(instance: &ServiceInstance) -> Value {
+    let started_at = instance.started_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let uptime = SystemTime::now().duration_since(instance.started_at).unwrap_or_default();
+    json!({
+        "id": instance.id,
+        "model": instance.config.name,
+        "status": format!("{:?}", instance.status).to_lowercase(),
+        "addr": instance.server_addr,
+        "pid": instance.pid,
+        "started_at": started_at.as_secs(),
+        "uptime_secs": uptime.as_secs(),
+        "config": instance.config,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use config::{ApiKeyConfig, AuthConfig};
+    use tower::ServiceExt;
+
+    fn key(secret: &str, admin: bool) -> ApiKeyConfig {
+        ApiKeyConfig {
+            key: secret.to_string(),
+            name: secret.to_string(),
+            admin,
+            ..ApiKeyConfig::default()
+        }
+    }
+
+    fn app(keys: Option<AuthConfig>) -> Router {
+        let scheduler = Arc::new(Scheduler::new(std::env::temp_dir().join("assistant-admin-test"), 1));
+        let keys = keys.map(|config| Arc::new(KeyStore::load(config).unwrap()));
+        router(scheduler, keys, None)
+    }
+
+    fn auth(enabled: bool) -> Option<AuthConfig> {
+        Some(AuthConfig {
+            enabled,
+            keys: vec![key("sk-user", false), key("sk-admin", true)],
+            ..AuthConfig::default()
+        })
+    }
+
+    async fn list(app: Router, token: Option<&str>) -> StatusCode {
+        let mut req = Request::get("/instances");
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        app.oneshot(req.body(Body::empty()).unwrap()).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn closed_unless_auth_is_enabled() {
+        assert_eq!(list(app(None), None).await, StatusCode::FORBIDDEN);
+        assert_eq!(list(app(auth(false)), Some("sk-admin")).await, StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn only_admin_keys_get_through() {
+        assert_eq!(list(app(auth(true)), None).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(list(app(auth(true)), Some("sk-unknown")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(list(app(auth(true)), Some("sk-user")).await, StatusCode::FORBIDDEN);
+        assert_eq!(list(app(auth(true)), Some("sk-admin")).await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,577 @@
+// Anthropic Messages API, translated to the OpenAI chat completions spoken by llama-api-server
+use axum::{
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use config::RouteConfig;
+use http_body_util::BodyExt;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{upstream_error, ApiError};
+use crate::routes::CHAT_PATH;
+use crate::sse;
+use crate::{handle_request, translated_request, Gateway};
+
+pub const MESSAGES_PATH: &str = "/v1/messages";
+
+// POST /v1/messages
+pub async fn messages(
+    gateway: Arc<Gateway>,
+    route: Arc<RouteConfig>,
+    req: Request,
+) -> Result<Response, ApiError> {
+    let (mut parts, body) = req.into_parts();
+    let body = body.collect().await.map_err(ApiError::body)?.to_bytes();
+    let request: Value = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid request body: {}", e)))?;
+    let model = request.get("model").and_then(Value::as_str).unwrap_or_default().to_string();
+    let stream = request.get("stream").and_then(Value::as_bool).unwrap_or(false);
+    let chat = chat_request(&request)?;
+
+    // the SDKs send their key as x-api-key, the gRPC hop only looks at authorization
+    if !parts.headers.contains_key(header::AUTHORIZATION) {
+        let bearer = parts.headers.get("x-api-key")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|key| HeaderValue::from_str(&format!("Bearer {}", key)).ok());
+        if let Some(bearer) = bearer {
+            parts.headers.insert(header::AUTHORIZATION, bearer);
+        }
+    }
+    parts.headers.remove("x-api-key");
+    parts.headers.remove("anthropic-version");
+    parts.headers.remove("anthropic-beta");
+
+    let response = handle_request(translated_request(parts, CHAT_PATH, &chat), gateway, route).await?;
+    // error::dialect gives upstream errors the Messages API shape
+    if !response.status().is_success() {
+        return Err(upstream_error(response).await);
+    }
+    if stream {
+        Ok(sse::relay(response, MessageEvents::new(model), "text/event-stream"))
+    } else {
+        let body = response.into_body().collect().await
+            .map_err(|e| ApiError::internal(format!("Failed to read response body: {}", e)))?
+            .to_bytes();
+        let completion: Value = serde_json::from_slice(&body)
+            .map_err(|e| ApiError::internal(format!("Invalid chat completion: {}", e)))?;
+        Ok((
+            [(header::CONTENT_TYPE, "application/json")],
+            message_response(&completion, &model).to_string(),
+        ).into_response())
+    }
+}
+
+// Messages request to chat completion request
+fn chat_request(request: &Value) -> Result<Value, ApiError> {
+    let invalid = |message: &str| ApiError::new(StatusCode::BAD_REQUEST, message.to_string());
+    let mut chat = Map::new();
+    chat.insert("model".to_string(), request.get("model").cloned().ok_or_else(|| invalid("model is required"))?);
+    let max_tokens = request.get("max_tokens").ok_or_else(|| invalid("max_tokens is required"))?;
+    chat.insert("max_tokens".to_string(), max_tokens.clone());
+
+    let mut messages = Vec::new();
+    match request.get("system") {
+        Some(Value::String(system)) => messages.push(json!({ "role": "system", "content": system })),
+        Some(Value::Array(blocks)) => messages.push(json!({ "role": "system", "content": block_text(blocks) })),
+        _ => {}
+    }
+    let turns = request.get("messages")
+        .and_then(Value::as_array)
+        .ok_or_else(|| invalid("messages is required"))?;
+    for turn in turns {
+        let role = turn.get("role").and_then(Value::as_str).unwrap_or("user");
+        match turn.get("content") {
+            Some(Value::String(text)) => messages.push(json!({ "role": role, "content": text })),
+            Some(Value::Array(blocks)) => chat_messages(role, blocks, &mut messages),
+            _ => return Err(invalid("Message content must be a string or an array of blocks")),
+        }
+    }
+    chat.insert("messages".to_string(), Value::Array(messages));
+
+    for (from, to) in [("stop_sequences", "stop"), ("temperature", "temperature"), ("top_p", "top_p")] {
+        if let Some(value) = request.get(from) {
+            chat.insert(to.to_string(), value.clone());
+        }
+    }
+    if let Some(tools) = request.get("tools").and_then(Value::as_array) {
+        let tools: Vec<Value> = tools.iter()
+            .map(|tool| json!({
+                "type": "function",
+                "function": {
+                    "name": tool.get("name"),
+                    "description": tool.get("description"),
+                    "parameters": tool.get("input_schema"),
+                },
+            }))
+            .collect();
+        chat.insert("tools".to_string(), Value::Array(tools));
+    }
+    if let Some(choice) = request.get("tool_choice") {
+        let choice = match choice.get("type").and_then(Value::as_str) {
+            Some("any") => json!("required"),
+            Some("tool") => json!({ "type": "function", "function": { "name": choice.get("name") } }),
+            Some("none") => json!("none"),
+            _ => json!("auto"),
+        };
+        chat.insert("tool_choice".to_string(), choice);
+    }
+    if request.get("stream").and_then(Value::as_bool).unwrap_or(false) {
+        chat.insert("stream".to_string(), json!(true));
+        chat.insert("stream_options".to_string(), json!({ "include_usage": true }));
+    }
+    Ok(Value::Object(chat))
+}
+
+// Content blocks of one turn, tool results become messages of their own
+fn chat_messages(role: &str, blocks: &[Value], messages: &mut Vec<Value>) {
+    let mut content = Vec::new();
+    let mut tool_calls = Vec::new();
+    for block in blocks {
+        match block.get("type").and_then(Value::as_str) {
+            Some("text") => content.push(json!({ "type": "text", "text": block.get("text") })),
+            Some("image") => {
+                let source = block.get("source");
+                let url = match source.and_then(|s| s.get("type")).and_then(Value::as_str) {
+                    Some("base64") => format!(
+                        "data:{};base64,{}",
+                        source.and_then(|s| s.get("media_type")).and_then(Value::as_str).unwrap_or("image/png"),
+                        source.and_then(|s| s.get("data")).and_then(Value::as_str).unwrap_or_default(),
+                    ),
+                    _ => source.and_then(|s| s.get("url")).and_then(Value::as_str).unwrap_or_default().to_string(),
+                };
+                content.push(json!({ "type": "image_url", "image_url": { "url": url } }));
+            }
+            Some("tool_use") => tool_calls.push(json!({
+                "id": block.get("id"),
+                "type": "function",
+                "function": {
+                    "name": block.get("name"),
+                    "arguments": block.get("input").unwrap_or(&json!({})).to_string(),
+                },
+            })),
+            Some("tool_result") => {
+                let result = match block.get("content") {
+                    Some(Value::Array(blocks)) => block_text(blocks),
+                    Some(Value::String(text)) => text.clone(),
+                    _ => String::new(),
+                };
+                messages.push(json!({
+                    "role": "tool",
+                    "tool_call_id": block.get("tool_use_id"),
+                    "content": result,
+                }));
+            }
+            _ => {}
+        }
+    }
+
+    if content.is_empty() && tool_calls.is_empty() {
+        return;
+    }
+    // plain text stays a string, llama-api-server handles that best
+    let content = if content.iter().all(|c| c["type"] == "text") {
+        json!(content.iter().filter_map(|c| c["text"].as_str()).collect::<Vec<_>>().join("\n"))
+    } else {
+        Value::Array(content)
+    };
+    let mut message = json!({ "role": role, "content": content });
+    if !tool_calls.is_empty() {
+        message["tool_calls"] = Value::Array(tool_calls);
+    }
+    messages.push(message);
+}
+
+fn block_text(blocks: &[Value]) -> String {
+    blocks.iter()
+        .filter_map(|b| b.get("text").and_then(Value::as_str))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Chat completion to message
+fn message_response(completion: &Value, model: &str) -> Value {
+    let choice = &completion["choices"][0];
+    let message = &choice["message"];
+    let mut content = Vec::new();
+    if let Some(text) = message["content"].as_str().filter(|t| !t.is_empty()) {
+        content.push(json!({ "type": "text", "text": text }));
+    }
+    for call in message["tool_calls"].as_array().into_iter().flatten() {
+        let arguments = call["function"]["arguments"].as_str().unwrap_or("{}");
+        content.push(json!({
+            "type": "tool_use",
+            "id": call["id"],
+            "name": call["function"]["name"],
+            "input": serde_json::from_str::<Value>(arguments).unwrap_or_else(|_| json!({})),
+        }));
+    }
+    json!({
+        "id": message_id(completion),
+        "type": "message",
+        "role": "assistant",
+        "model": model,
+        "content": content,
+        "stop_reason": stop_reason(choice["finish_reason"].as_str()),
+        "stop_sequence": null,
+        "usage": {
+            "input_tokens": completion["usage"]["prompt_tokens"].as_u64().unwrap_or(0),
+            "output_tokens": completion["usage"]["completion_tokens"].as_u64().unwrap_or(0),
+        },
+    })
+}
+
+fn message_id(completion: &Value) -> String {
+    match completion["id"].as_str() {
+        Some(id) => format!("msg_{}", id),
+        None => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            format!("msg_{}", now.as_nanos())
+        }
+    }
+}
+
+fn stop_reason(finish_reason: Option<&str>) -> &'static str {
+    match finish_reason {
+        Some("length") => "max_tokens",
+        Some("tool_calls") | Some("function_call") => "tool_use",
+        _ => "end_turn",
+    }
+}
+
+// The block being streamed
+enum Block {
+    Text,
+    // index of the tool call in the chat completion chunks
+    Tool(u64),
+}
+
+// State of a translated stream
+struct MessageEvents {
+    model: String,
+    started: bool,
+    finished: bool,
+    block: Option<Block>,
+    index: usize,
+    stop_reason: &'static str,
+    input_tokens: u64,
+    output_tokens: u64,
+}
+
+impl MessageEvents {
+    fn new(model: String) -> Self {
+        Self {
+            model,
+            started: false,
+            finished: false,
+            block: None,
+            index: 0,
+            stop_reason: "end_turn",
+            input_tokens: 0,
+            output_tokens: 0,
+        }
+    }
+
+    fn start_block(&mut self, out: &mut Vec<u8>, block: Block, content_block: Value) {
+        if self.block.is_some() {
+            self.stop_block(out);
+            self.index += 1;
+        }
+        self.block = Some(block);
+        event(out, "content_block_start", json!({
+            "type": "content_block_start",
+            "index": self.index,
+            "content_block": content_block,
+        }));
+    }
+
+    fn stop_block(&mut self, out: &mut Vec<u8>) {
+        if self.block.take().is_some() {
+            event(out, "content_block_stop", json!({ "type": "content_block_stop", "index": self.index }));
+        }
+    }
+}
+
+impl sse::Translate for MessageEvents {
+    // Events of one `data:` payload of the chat completion stream
+    fn chunk(&mut self, data: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        if data == "[DONE]" {
+            return out;
+        }
+        let Ok(chunk) = serde_json::from_str::<Value>(data) else {
+            return out;
+        };
+        // an error ends the stream, the message is not stopped as if it was complete
+        if chunk["error"].is_object() {
+            self.finished = true;
+            event(&mut out, "error", error_body(StatusCode::BAD_GATEWAY, &chunk["error"]));
+            return out;
+        }
+        if !self.started {
+            self.started = true;
+            event(&mut out, "message_start", json!({
+                "type": "message_start",
+                "message": {
+                    "id": message_id(&chunk),
+                    "type": "message",
+                    "role": "assistant",
+                    "model": self.model,
+                    "content": [],
+                    "stop_reason": null,
+                    "stop_sequence": null,
+                    "usage": { "input_tokens": 0, "output_tokens": 0 },
+                },
+            }));
+        }
+        if let Some(usage) = chunk.get("usage").filter(|u| u.is_object()) {
+            self.input_tokens = usage["prompt_tokens"].as_u64().unwrap_or(self.input_tokens);
+            self.output_tokens = usage["completion_tokens"].as_u64().unwrap_or(self.output_tokens);
+        }
+
+        let choice = &chunk["choices"][0];
+        let delta = &choice["delta"];
+        if let Some(text) = delta["content"].as_str().filter(|t| !t.is_empty()) {
+            if !matches!(self.block, Some(Block::Text)) {
+                self.start_block(&mut out, Block::Text, json!({ "type": "text", "text": "" }));
+            }
+            event(&mut out, "content_block_delta", json!({
+                "type": "content_block_delta",
+                "index": self.index,
+                "delta": { "type": "text_delta", "text": text },
+            }));
+        }
+        for call in delta["tool_calls"].as_array().into_iter().flatten() {
+            let call_index = call["index"].as_u64().unwrap_or(0);
+            if !matches!(self.block, Some(Block::Tool(i)) if i == call_index) {
+                self.start_block(&mut out, Block::Tool(call_index), json!({
+                    "type": "tool_use",
+                    "id": call["id"],
+                    "name": call["function"]["name"],
+                    "input": {},
+                }));
+            }
+            if let Some(arguments) = call["function"]["arguments"].as_str().filter(|a| !a.is_empty()) {
+                event(&mut out, "content_block_delta", json!({
+                    "type": "content_block_delta",
+                    "index": self.index,
+                    "delta": { "type": "input_json_delta", "partial_json": arguments },
+                }));
+            }
+        }
+        if let Some(reason) = choice["finish_reason"].as_str() {
+            self.stop_reason = stop_reason(Some(reason));
+        }
+        out
+    }
+
+    // Close the message once the upstream stream ended
+    fn finish(&mut self) -> Vec<u8> {
+        let mut out = Vec::new();
+        if !self.started || self.finished {
+            return out;
+        }
+        self.finished = true;
+        self.stop_block(&mut out);
+        event(&mut out, "message_delta", json!({
+            "type": "message_delta",
+            "delta": { "stop_reason": self.stop_reason, "stop_sequence": null },
+            "usage": { "input_tokens": self.input_tokens, "output_tokens": self.output_tokens },
+        }));
+        event(&mut out, "message_stop", json!({ "type": "message_stop" }));
+        out
+    }
+}
+
+fn event(out: &mut Vec<u8>, name: &str, data: Value) {
+    out.extend(format!("event: {}\ndata: {}\n\n", name, data).into_bytes());
+}
+
+// Error bodies as the Messages API shapes them, `{"type":"error","error":{"type","message"}}`
+pub fn error_body(status: StatusCode, error: &Value) -> Value {
+    let kind = match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => "invalid_request_error",
+        StatusCode::UNAUTHORIZED => "authentication_error",
+        StatusCode::FORBIDDEN => "permission_error",
+        StatusCode::NOT_FOUND => "not_found_error",
+        StatusCode::PAYLOAD_TOO_LARGE => "request_too_large",
+        StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+        StatusCode::SERVICE_UNAVAILABLE => "overloaded_error",
+        s if s.is_client_error() => "invalid_request_error",
+        _ => "api_error",
+    };
+    json!({
+        "type": "error",
+        "error": { "type": kind, "message": error["message"] },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse::Translate;
+
+    // `(event, data)` pairs of a translated stream
+    fn events(out: &[u8]) -> Vec<(String, Value)> {
+        String::from_utf8_lossy(out)
+            .split("\n\n")
+            .filter(|e| !e.is_empty())
+            .map(|e| {
+                let (name, data) = e.split_once("\ndata: ").unwrap();
+                (name.trim_start_matches("event: ").to_string(), serde_json::from_str(data).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn request_is_translated() {
+        let request = json!({
+            "model": "m",
+            "max_tokens": 64,
+            "system": [{ "type": "text", "text": "be brief" }],
+            "stop_sequences": ["END"],
+            "stream": true,
+            "tools": [{ "name": "add", "description": "Add", "input_schema": { "type": "object" } }],
+            "tool_choice": { "type": "any" },
+            "messages": [
+                { "role": "user", "content": "2 + 3?" },
+                { "role": "assistant", "content": [
+                    { "type": "text", "text": "Adding" },
+                    { "type": "tool_use", "id": "t1", "name": "add", "input": { "a": 2, "b": 3 } },
+                ]},
+                { "role": "user", "content": [{ "type": "tool_result", "tool_use_id": "t1", "content": "5" }] },
+            ],
+        });
+        assert_eq!(chat_request(&request).unwrap(), json!({
+            "model": "m",
+            "max_tokens": 64,
+            "messages": [
+                { "role": "system", "content": "be brief" },
+                { "role": "user", "content": "2 + 3?" },
+                { "role": "assistant", "content": "Adding", "tool_calls": [{
+                    "id": "t1",
+                    "type": "function",
+                    "function": { "name": "add", "arguments": "{\"a\":2,\"b\":3}" },
+                }]},
+                { "role": "tool", "tool_call_id": "t1", "content": "5" },
+            ],
+            "stop": ["END"],
+            "tools": [{ "type": "function", "function": { "name": "add", "description": "Add", "parameters": { "type": "object" } } }],
+            "tool_choice": "required",
+            "stream": true,
+            "stream_options": { "include_usage": true },
+        }));
+    }
+
+    #[test]
+    fn request_needs_model_max_tokens_and_messages() {
+        for request in [
+            json!({ "max_tokens": 1, "messages": [] }),
+            json!({ "model": "m", "messages": [] }),
+            json!({ "model": "m", "max_tokens": 1 }),
+            json!({ "model": "m", "max_tokens": 1, "messages": [{ "role": "user", "content": 1 }] }),
+        ] {
+            assert_eq!(chat_request(&request).unwrap_err().into_response().status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn images_become_image_urls() {
+        let mut messages = Vec::new();
+        chat_messages("user", &[
+            json!({ "type": "text", "text": "What is this?" }),
+            json!({ "type": "image", "source": { "type": "base64", "media_type": "image/jpeg", "data": "AAAA" } }),
+        ], &mut messages);
+        assert_eq!(messages, [json!({ "role": "user", "content": [
+            { "type": "text", "text": "What is this?" },
+            { "type": "image_url", "image_url": { "url": "data:image/jpeg;base64,AAAA" } },
+        ]})]);
+    }
+
+    #[test]
+    fn response_is_translated() {
+        let completion = json!({
+            "id": "c1",
+            "choices": [{ "message": { "content": "Adding", "tool_calls": [{
+                "id": "t1",
+                "function": { "name": "add", "arguments": "{\"a\":2}" },
+            }]}, "finish_reason": "tool_calls" }],
+            "usage": { "prompt_tokens": 10, "completion_tokens": 4 },
+        });
+        assert_eq!(message_response(&completion, "m"), json!({
+            "id": "msg_c1",
+            "type": "message",
+            "role": "assistant",
+            "model": "m",
+            "content": [
+                { "type": "text", "text": "Adding" },
+                { "type": "tool_use", "id": "t1", "name": "add", "input": { "a": 2 } },
+            ],
+            "stop_reason": "tool_use",
+            "stop_sequence": null,
+            "usage": { "input_tokens": 10, "output_tokens": 4 },
+        }));
+        assert_eq!(stop_reason(Some("length")), "max_tokens");
+        assert_eq!(stop_reason(None), "end_turn");
+    }
+
+    #[test]
+    fn stream_is_translated_to_events() {
+        let mut stream = MessageEvents::new("m".to_string());
+        let mut out = Vec::new();
+        for data in [
+            r#"{"id":"c1","choices":[{"delta":{"content":"Hel"}}]}"#,
+            r#"{"id":"c1","choices":[{"delta":{"content":"lo"}}]}"#,
+            r#"{"id":"c1","choices":[{"delta":{"tool_calls":[{"index":0,"id":"t1","function":{"name":"add","arguments":"{\"a\""}}]}}]}"#,
+            r#"{"id":"c1","choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":":2}"}}]},"finish_reason":"tool_calls"}]}"#,
+            r#"{"id":"c1","choices":[],"usage":{"prompt_tokens":10,"completion_tokens":4}}"#,
+            "[DONE]",
+        ] {
+            out.extend(stream.chunk(data));
+        }
+        out.extend(stream.finish());
+        let events = events(&out);
+        let names: Vec<&str> = events.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, [
+            "message_start",
+            "content_block_start", "content_block_delta", "content_block_delta", "content_block_stop",
+            "content_block_start", "content_block_delta", "content_block_delta", "content_block_stop",
+            "message_delta", "message_stop",
+        ]);
+        assert_eq!(events[0].1["message"]["id"], "msg_c1");
+        assert_eq!(events[2].1["delta"], json!({ "type": "text_delta", "text": "Hel" }));
+        assert_eq!(events[5].1["index"], 1);
+        assert_eq!(events[5].1["content_block"]["name"], "add");
+        assert_eq!(events[7].1["delta"], json!({ "type": "input_json_delta", "partial_json": ":2}" }));
+        assert_eq!(events[9].1["delta"]["stop_reason"], "tool_use");
+        assert_eq!(events[9].1["usage"], json!({ "input_tokens": 10, "output_tokens": 4 }));
+        // finished once
+        assert!(stream.finish().is_empty());
+    }
+
+    #[test]
+    fn stream_error_is_an_error_event() {
+        let mut stream = MessageEvents::new("m".to_string());
+        stream.chunk(r#"{"choices":[{"delta":{"content":"Hel"}}]}"#);
+        let events = events(&stream.chunk(r#"{"error":{"message":"Request timed out"}}"#));
+        assert_eq!(events, [("error".to_string(), json!({
+            "type": "error",
+            "error": { "type": "api_error", "message": "Request timed out" },
+        }))]);
+        assert!(stream.finish().is_empty());
+    }
+
+    #[test]
+    fn errors_are_shaped_by_status() {
+        let error = json!({ "message": "slow down" });
+        assert_eq!(error_body(StatusCode::TOO_MANY_REQUESTS, &error), json!({
+            "type": "error",
+            "error": { "type": "rate_limit_error", "message": "slow down" },
+        }));
+        assert_eq!(error_body(StatusCode::NOT_FOUND, &error)["error"]["type"], "not_found_error");
+        assert_eq!(error_body(StatusCode::CONFLICT, &error)["error"]["type"], "invalid_request_error");
+        assert_eq!(error_body(StatusCode::BAD_GATEWAY, &error)["error"]["type"], "api_error");
+    }
+}
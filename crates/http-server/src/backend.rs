@@ -0,0 +1,173 @@
+use protos::assistant::{
+    assistant_service_client::AssistantServiceClient, ListModelsRequest, ModelInfo, Request, Response,
+};
+use scheduler::deadline::{remaining, within};
+use scheduler::{request_model, Scheduler};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+use tonic::metadata::MetadataValue;
+use tonic::transport::Channel;
+use tonic::Status;
+use tonic_health::pb::{health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest};
+use tracing::debug;
+
+// Budget of the readiness check of the gRPC port
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Sends gateway requests to a co-located scheduler, or over a shared gRPC channel
+pub struct Backend {
+    client: AssistantServiceClient<Channel>,
+    health: HealthClient<Channel>,
+    scheduler: Option<Arc<Scheduler>>,
+    max_load: f32,
+}
+
+impl Backend {
+    pub fn new(channel: Channel, scheduler: Option<Arc<Scheduler>>, max_load: f32) -> Self {
+        Self {
+            client: AssistantServiceClient::new(channel.clone()),
+            health: HealthClient::new(channel),
+            scheduler,
+            max_load,
+        }
+    }
+
+    pub fn scheduler(&self) -> Option<&Arc<Scheduler>> {
+        self.scheduler.as_ref()
+    }
+
+    // Ask the gRPC port for its overall health, true when it is serving
+    pub async fn check_grpc(&self) -> Result<bool, Status> {
+        let mut health = self.health.clone();
+        let request = HealthCheckRequest { service: String::new() };
+        let response = tokio::time::timeout(HEALTH_TIMEOUT, health.check(request))
+            .await
+            .map_err(|_| Status::deadline_exceeded("Health check timed out"))??;
+        Ok(response.into_inner().status == ServingStatus::Serving as i32)
+    }
+
+    // Models known to the gRPC server, including the ones of its peers
+    pub async fn list_models(
+        &self,
+        authorization: Option<&str>,
+        deadline: Option<Instant>,
+    ) -> Result<Vec<ModelInfo>, Status> {
+        let mut client = self.client.clone();
+        let mut request = tonic::Request::new(ListModelsRequest {});
+        if let Some(timeout) = remaining(deadline)? {
+            request.set_timeout(timeout);
+        }
+        if let Some(authorization) = authorization.and_then(|v| MetadataValue::try_from(v).ok()) {
+            request.metadata_mut().insert("authorization", authorization);
+        }
+        Ok(within(deadline, client.list_models(request)).await??.into_inner().models)
+    }
+
+    // The co-located scheduler, unless the gRPC server should offload to a peer
+    async fn local(&self, request: &Request) -> Option<&Arc<Scheduler>> {
+        let scheduler = self.scheduler.as_ref()?;
+        if scheduler.is_busy(self.max_load).await {
+            debug!("Local scheduler is busy, going through gRPC");
+            return None;
+        }
+        if let Some(model) = request_model(&request.body).filter(|m| !m.is_empty()) {
+            if !scheduler.has_model(&model).await {
+                debug!("Model {} is not served locally, going through gRPC", model);
+                return None;
+            }
+        }
+        Some(scheduler)
+    }
+
+    pub async fn forward(&self, request: Request, deadline: Option<Instant>) -> Result<Response, Status> {
+        if let Some(scheduler) = self.local(&request).await {
+            let forward = scheduler.forward_request(
+                &request.path,
+                &request.method,
+                request.body,
+                request.headers,
+                remaining(deadline)?,
+            );
+            let (status, body, headers) = within(deadline, forward).await?.map_err(Status::from)?;
+            return Ok(Response {
+                status: status as i32,
+                body,
+                headers,
+            });
+        }
+
+        // the budget travels as grpc-timeout, the gRPC server enforces it on the backend call
+        let mut client = self.client.clone();
+        let request = grpc_request(request, deadline)?;
+        within(deadline, client.forward_request(request))
+            .await?
+            .map(|r| r.into_inner())
+    }
+
+    pub async fn forward_stream(
+        &self,
+        request: Request,
+        deadline: Option<Instant>,
+    ) -> Result<mpsc::Receiver<Result<Response, Status>>, Status> {
+        let (tx, rx) = mpsc::channel(4);
+
+        if let Some(scheduler) = self.local(&request).await {
+            let scheduler = scheduler.clone();
+            let timeout = remaining(deadline)?;
+            tokio::spawn(async move {
+                if let Err(e) = scheduler.forward_request_stream(
+                    &request.path,
+                    &request.method,
+                    request.body,
+                    request.headers,
+                    timeout,
+                    tx.clone(),
+                ).await {
+                    let _ = tx.send(Err(e.into())).await;
+                }
+            });
+            return Ok(rx);
+        }
+
+        let mut client = self.client.clone();
+        let request = grpc_request(request, deadline)?;
+        let mut stream = within(deadline, client.forward_request_stream(request))
+            .await??
+            .into_inner();
+        tokio::spawn(async move {
+            loop {
+                match stream.message().await {
+                    Ok(Some(message)) => {
+                        if tx.send(Ok(message)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => break,
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(rx)
+    }
+}
+
+// Wrap a request for the gRPC port, with the remaining budget and the caller's API key
+fn grpc_request(request: Request, deadline: Option<Instant>) -> Result<tonic::Request<Request>, Status> {
+    let authorization = request.headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case("authorization"))
+        .and_then(|(_, v)| MetadataValue::try_from(v.as_str()).ok());
+    let mut request = tonic::Request::new(request);
+    if let Some(timeout) = remaining(deadline)? {
+        request.set_timeout(timeout);
+    }
+    if let Some(authorization) = authorization {
+        request.metadata_mut().insert("authorization", authorization);
+    }
+    Ok(request)
+}
+
@@ -0,0 +1,200 @@
+use auth::ApiKey;
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, Multipart, Path, Request, State},
+    http::{header, StatusCode},
+    response::Response,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use scheduler::batch::{BatchError, Batches, FileObject};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::error::ApiError;
+
+pub const FILES_PATH: &str = "/v1/files";
+pub const BATCHES_PATH: &str = "/v1/batches";
+// Paths of the router, a route of the table may not take them
+pub const PATHS: &[&str] = &[
+    FILES_PATH,
+    "/v1/files/:id",
+    "/v1/files/:id/content",
+    BATCHES_PATH,
+    "/v1/batches/:id",
+    "/v1/batches/:id/cancel",
+];
+
+#[derive(Deserialize)]
+struct CreateBatch {
+    input_file_id: String,
+    endpoint: String,
+    completion_window: String,
+    metadata: Option<Value>,
+}
+
+// The files and batches API of OpenAI, uploads may be as large as the batch file limit.
+// Files and batches belong to the API key that created them, other keys do not find them
+pub fn router(batches: Arc<Batches>) -> Router {
+    let limit = body_limit(&batches);
+    Router::new()
+        .route(FILES_PATH, post(upload).get(list_files))
+        .route("/v1/files/:id", get(retrieve_file).delete(delete_file))
+        .route("/v1/files/:id/content", get(file_content))
+        .route(BATCHES_PATH, post(create).get(list))
+        .route("/v1/batches/:id", get(retrieve))
+        .route("/v1/batches/:id/cancel", post(cancel))
+        .layer(DefaultBodyLimit::max(limit))
+        .with_state(batches)
+}
+
+// multipart framing comes on top of the file itself
+fn body_limit(batches: &Batches) -> usize {
+    batches.max_file_bytes().saturating_add(64 * 1024)
+}
+
+fn owner(key: Option<&ApiKey>) -> Option<String> {
+    key.map(|key| auth::key_id(key))
+}
+
+// The file in the OpenAI format, without its owner
+fn file_json(file: FileObject) -> Value {
+    json!({
+        "id": file.id,
+        "object": file.object,
+        "bytes": file.bytes,
+        "created_at": file.created_at,
+        "filename": file.filename,
+        "purpose": file.purpose,
+    })
+}
+
+impl From<BatchError> for ApiError {
+    fn from(e: BatchError) -> Self {
+        match e {
+            BatchError::NotFound(_) => ApiError::new(StatusCode::NOT_FOUND, e.to_string()),
+            BatchError::Invalid(_) => ApiError::new(StatusCode::BAD_REQUEST, e.to_string()),
+            _ => ApiError::internal(e.to_string()),
+        }
+    }
+}
+
+// POST /v1/files, a multipart form with `file` and `purpose`
+async fn upload(
+    State(batches): State<Arc<Batches>>,
+    key: Option<Extension<ApiKey>>,
+    mut form: Multipart,
+) -> Result<Json<Value>, ApiError> {
+    let invalid = |e: axum::extract::multipart::MultipartError| {
+        ApiError::new(e.status(), format!("Invalid upload: {}", e.body_text()))
+    };
+    let mut file = None;
+    let mut purpose = None;
+    while let Some(field) = form.next_field().await.map_err(invalid)? {
+        match field.name() {
+            Some("file") => {
+                let filename = field.file_name().unwrap_or("input.jsonl").to_string();
+                file = Some((filename, field.bytes().await.map_err(invalid)?));
+            }
+            Some("purpose") => purpose = Some(field.text().await.map_err(invalid)?),
+            _ => {}
+        }
+    }
+    let (Some((filename, content)), Some(purpose)) = (file, purpose) else {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "Both file and purpose are required"));
+    };
+    let owner = owner(key.as_deref());
+    let file = tokio::task::spawn_blocking(move || batches.upload(&filename, &purpose, &content, owner.as_deref()))
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))??;
+    Ok(Json(file_json(file)))
+}
+
+async fn list_files(
+    State(batches): State<Arc<Batches>>,
+    key: Option<Extension<ApiKey>>,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    let data: Vec<Value> = batches.files(owner.as_deref())?.into_iter().map(file_json).collect();
+    Ok(Json(json!({ "object": "list", "data": data })))
+}
+
+async fn retrieve_file(
+    State(batches): State<Arc<Batches>>,
+    key: Option<Extension<ApiKey>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    Ok(Json(file_json(batches.file(&id, owner.as_deref())?)))
+}
+
+async fn file_content(
+    State(batches): State<Arc<Batches>>,
+    key: Option<Extension<ApiKey>>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    let owner = owner(key.as_deref());
+    let content = batches.file_content(&id, owner.as_deref())?;
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/jsonl")
+        .body(Body::from(content))
+        .map_err(|e| ApiError::internal(e.to_string()))
+}
+
+async fn delete_file(
+    State(batches): State<Arc<Batches>>,
+    key: Option<Extension<ApiKey>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    batches.delete_file(&id, owner.as_deref()).await?;
+    Ok(Json(json!({ "id": id, "object": "file", "deleted": true })))
+}
+
+// POST /v1/batches, the requests of the batch are held to the models of the caller's key
+async fn create(
+    State(batches): State<Arc<Batches>>,
+    key: Option<Extension<ApiKey>>,
+    req: Request,
+) -> Result<Json<Value>, ApiError> {
+    let body = axum::body::to_bytes(req.into_body(), body_limit(&batches))
+        .await
+        .map_err(ApiError::body)?;
+    let create: CreateBatch = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid batch request: {}", e)))?;
+    let owner = owner(key.as_deref());
+    let models = key.map(|Extension(key)| key.models.clone()).unwrap_or_default();
+    let batch = batches.create(
+        &create.input_file_id,
+        &create.endpoint,
+        &create.completion_window,
+        create.metadata,
+        models,
+        owner.as_deref(),
+    ).await?;
+    Ok(Json(json!(batch)))
+}
+
+async fn list(State(batches): State<Arc<Batches>>, key: Option<Extension<ApiKey>>) -> Json<Value> {
+    let owner = owner(key.as_deref());
+    Json(json!({ "object": "list", "data": batches.list(owner.as_deref()).await }))
+}
+
+async fn retrieve(
+    State(batches): State<Arc<Batches>>,
+    key: Option<Extension<ApiKey>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    Ok(Json(json!(batches.get(&id, owner.as_deref()).await?)))
+}
+
+async fn cancel(
+    State(batches): State<Arc<Batches>>,
+    key: Option<Extension<ApiKey>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    Ok(Json(json!(batches.cancel(&id, owner.as_deref()).await?)))
+}
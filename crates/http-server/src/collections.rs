@@ -0,0 +1,263 @@
+use auth::ApiKey;
+use axum::{
+    body::Body,
+    extract::{DefaultBodyLimit, FromRequest, MatchedPath, Multipart, Path, Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::Response,
+    routing::{delete, get, post},
+    Extension, Json, Router,
+};
+use http_body_util::BodyExt;
+use rag::{Match, RagError, RagStore};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+use crate::error::ApiError;
+use crate::routes::CHAT_PATH;
+
+pub const PATH: &str = "/v1/collections";
+// Paths of the router, a route of the table may not take them
+pub const PATHS: &[&str] = &[
+    PATH,
+    "/v1/collections/:name",
+    "/v1/collections/:name/documents",
+    "/v1/collections/:name/documents/:id",
+    "/v1/collections/:name/query",
+];
+
+#[derive(Deserialize)]
+struct CreateCollection {
+    name: String,
+    description: Option<String>,
+}
+
+// A document sent as JSON rather than as a multipart upload
+#[derive(Deserialize)]
+struct TextDocument {
+    filename: Option<String>,
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct Query {
+    query: String,
+    top_k: Option<usize>,
+}
+
+// The `rag` field of a chat completion request
+#[derive(Deserialize)]
+struct Retrieval {
+    collection: String,
+    top_k: Option<usize>,
+}
+
+impl From<RagError> for ApiError {
+    fn from(e: RagError) -> Self {
+        match e {
+            RagError::NotFound(_) => ApiError::new(StatusCode::NOT_FOUND, e.to_string())
+                .with_code("collection_not_found"),
+            RagError::DocumentNotFound(_) => ApiError::new(StatusCode::NOT_FOUND, e.to_string()),
+            RagError::InvalidName(_) | RagError::Exists(_) | RagError::Invalid(_) => {
+                ApiError::new(StatusCode::BAD_REQUEST, e.to_string())
+            }
+            RagError::Embedding(_) => ApiError::new(StatusCode::BAD_GATEWAY, e.to_string()),
+            _ => ApiError::internal(e.to_string()),
+        }
+    }
+}
+
+// Collections of the caller's key, their documents, and searching them
+pub fn router(store: Arc<RagStore>) -> Router {
+    let max_document_bytes = store.max_document_bytes();
+    Router::new()
+        .route(PATH, get(list).post(create))
+        .route("/v1/collections/:name", get(retrieve).delete(remove))
+        .route("/v1/collections/:name/documents", post(upload))
+        .route("/v1/collections/:name/documents/:id", delete(remove_document))
+        .route("/v1/collections/:name/query", post(query))
+        // multipart framing comes on top of the document itself
+        .layer(DefaultBodyLimit::max(max_document_bytes.saturating_add(64 * 1024)))
+        .with_state(store)
+}
+
+// Chat completions with a `rag` field get the best chunks of the collection as a system message
+pub async fn augment(
+    State(store): State<Arc<RagStore>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if req.extensions().get::<MatchedPath>().map(|p| p.as_str()) != Some(CHAT_PATH) {
+        return Ok(next.run(req).await);
+    }
+    let owner = owner(req.extensions().get::<ApiKey>());
+    let (mut parts, body) = req.into_parts();
+    let body = body.collect().await.map_err(ApiError::body)?.to_bytes();
+    let mut request = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Object(request)) if request.contains_key("rag") => request,
+        _ => return Ok(next.run(Request::from_parts(parts, Body::from(body))).await),
+    };
+    let retrieval: Retrieval = serde_json::from_value(request.remove("rag").unwrap_or_default())
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid rag field: {}", e)))?;
+
+    let mut messages = request.get("messages").and_then(Value::as_array).cloned().unwrap_or_default();
+    // the question is the last user message
+    if let Some(position) = messages.iter().rposition(|m| m["role"] == "user") {
+        let question = text(&messages[position]);
+        let top_k = retrieval.top_k.unwrap_or_else(|| store.top_k());
+        let matches = store.search(&retrieval.collection, owner.as_deref(), &question, top_k).await?;
+        if !matches.is_empty() {
+            messages.insert(position, json!({ "role": "system", "content": context(&matches) }));
+        }
+    }
+    request.insert("messages".to_string(), Value::Array(messages));
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Ok(next.run(Request::from_parts(parts, Body::from(Value::Object(request).to_string()))).await)
+}
+
+// The chunks as numbered excerpts, so the answer can cite them
+fn context(matches: &[Match]) -> String {
+    let mut context = String::from("Answer with the help of these excerpts when they are relevant.\n");
+    for (i, m) in matches.iter().enumerate() {
+        context.push_str(&format!("\n[{}] {}\n{}\n", i + 1, m.filename, m.text));
+    }
+    context
+}
+
+fn text(message: &Value) -> String {
+    match &message["content"] {
+        Value::String(text) => text.clone(),
+        Value::Array(parts) => parts.iter()
+            .filter_map(|p| p["text"].as_str())
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => String::new(),
+    }
+}
+
+fn owner(key: Option<&ApiKey>) -> Option<String> {
+    key.map(|key| auth::key_id(key))
+}
+
+async fn list(State(store): State<Arc<RagStore>>, key: Option<Extension<ApiKey>>) -> Json<Value> {
+    let owner = owner(key.as_deref());
+    let data: Vec<Value> = store.list(owner.as_deref()).into_iter().map(collection_json).collect();
+    Json(json!({ "object": "list", "data": data }))
+}
+
+async fn create(
+    State(store): State<Arc<RagStore>>,
+    key: Option<Extension<ApiKey>>,
+    body: axum::body::Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    let create: CreateCollection = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid collection: {}", e)))?;
+    Ok(Json(collection_json(store.create(&create.name, create.description, owner.as_deref())?)))
+}
+
+async fn retrieve(
+    State(store): State<Arc<RagStore>>,
+    key: Option<Extension<ApiKey>>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    Ok(Json(collection_json(store.get(&name, owner.as_deref())?)))
+}
+
+async fn remove(
+    State(store): State<Arc<RagStore>>,
+    key: Option<Extension<ApiKey>>,
+    Path(name): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    store.delete(&name, owner.as_deref())?;
+    Ok(Json(json!({ "id": name, "object": "collection", "deleted": true })))
+}
+
+// POST /v1/collections/{name}/documents, a multipart form with `file`, or `{"filename", "text"}`
+async fn upload(
+    State(store): State<Arc<RagStore>>,
+    key: Option<Extension<ApiKey>>,
+    Path(name): Path<String>,
+    req: Request,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    let multipart = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("multipart/form-data"));
+    let (filename, content) = if multipart {
+        let invalid = |e: axum::extract::multipart::MultipartError| {
+            ApiError::new(e.status(), format!("Invalid upload: {}", e.body_text()))
+        };
+        let mut form = Multipart::from_request(req, &()).await
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, e.body_text()))?;
+        let mut file = None;
+        while let Some(field) = form.next_field().await.map_err(invalid)? {
+            if field.name() == Some("file") {
+                let filename = field.file_name().unwrap_or("document.txt").to_string();
+                file = Some((filename, field.bytes().await.map_err(invalid)?.to_vec()));
+            }
+        }
+        file.ok_or_else(|| ApiError::new(StatusCode::BAD_REQUEST, "A file is required"))?
+    } else {
+        let body = axum::body::to_bytes(req.into_body(), usize::MAX)
+            .await
+            .map_err(ApiError::body)?;
+        let document: TextDocument = serde_json::from_slice(&body)
+            .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid document: {}", e)))?;
+        (document.filename.unwrap_or_else(|| "document.txt".to_string()), document.text.into_bytes())
+    };
+    let document = store.ingest(&name, owner.as_deref(), &filename, content).await?;
+    Ok(Json(document_json(&document)))
+}
+
+async fn remove_document(
+    State(store): State<Arc<RagStore>>,
+    key: Option<Extension<ApiKey>>,
+    Path((name, id)): Path<(String, String)>,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    store.delete_document(&name, owner.as_deref(), &id)?;
+    Ok(Json(json!({ "id": id, "object": "collection.document", "deleted": true })))
+}
+
+// POST /v1/collections/{name}/query, the chunks a chat request would get
+async fn query(
+    State(store): State<Arc<RagStore>>,
+    key: Option<Extension<ApiKey>>,
+    Path(name): Path<String>,
+    body: axum::body::Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    let query: Query = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid query: {}", e)))?;
+    let top_k = query.top_k.unwrap_or_else(|| store.top_k());
+    let matches = store.search(&name, owner.as_deref(), &query.query, top_k).await?;
+    Ok(Json(json!({ "object": "list", "data": matches })))
+}
+
+fn collection_json(collection: rag::Collection) -> Value {
+    json!({
+        "id": collection.name,
+        "object": "collection",
+        "description": collection.description,
+        "created_at": collection.created_at,
+        "updated_at": collection.updated_at,
+        "embedding_model": collection.embedding_model,
+        "documents": collection.documents.iter().map(document_json).collect::<Vec<_>>(),
+    })
+}
+
+fn document_json(document: &rag::Document) -> Value {
+    json!({
+        "id": document.id,
+        "object": "collection.document",
+        "filename": document.filename,
+        "bytes": document.bytes,
+        "chunks": document.chunks,
+        "created_at": document.created_at,
+    })
+}
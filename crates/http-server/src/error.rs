@@ -0,0 +1,198 @@
+use auth::AuthError;
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body_util::BodyExt;
+use http_body_util::LengthLimitError;
+use tonic::{Code, Status};
+
+// An error answered as an OpenAI error body, `{"error":{"message","type","code"}}`
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+    code: Option<&'static str>,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+            code: None,
+        }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+
+    pub fn timeout() -> Self {
+        Self::new(StatusCode::GATEWAY_TIMEOUT, "Request timed out").with_code("timeout")
+    }
+
+    pub fn too_large(limit: usize) -> Self {
+        Self::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Request body is larger than {} bytes", limit),
+        )
+        .with_code("request_too_large")
+    }
+
+    // A request body that could not be read, cut off bodies are over the route limit
+    pub fn body(e: axum::Error) -> Self {
+        let e = e.into_inner();
+        match e.downcast_ref::<LengthLimitError>() {
+            Some(_) => Self::new(StatusCode::PAYLOAD_TOO_LARGE, "Request body is too large")
+                .with_code("request_too_large"),
+            None => Self::internal(format!("Failed to read request body: {}", e)),
+        }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    // The OpenAI error body
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": {
+                "message": self.message,
+                "type": self.kind(),
+                "code": self.code,
+            }
+        })
+    }
+
+    // OpenAI error type of the status, SDKs decide on retries from the status alone
+    fn kind(&self) -> &'static str {
+        match self.status {
+            StatusCode::UNAUTHORIZED => "authentication_error",
+            StatusCode::FORBIDDEN => "permission_error",
+            StatusCode::NOT_FOUND => "not_found_error",
+            StatusCode::TOO_MANY_REQUESTS => "rate_limit_error",
+            StatusCode::GATEWAY_TIMEOUT => "timeout_error",
+            s if s.is_client_error() => "invalid_request_error",
+            _ => "server_error",
+        }
+    }
+}
+
+impl From<Status> for ApiError {
+    fn from(status: Status) -> Self {
+        let message = status.message().to_string();
+        match status.code() {
+            Code::InvalidArgument | Code::OutOfRange | Code::FailedPrecondition => {
+                Self::new(StatusCode::BAD_REQUEST, message)
+            }
+            Code::Unauthenticated => Self::new(StatusCode::UNAUTHORIZED, message),
+            Code::PermissionDenied => Self::new(StatusCode::FORBIDDEN, message),
+            Code::NotFound => Self::new(StatusCode::NOT_FOUND, message).with_code("model_not_found"),
+            Code::ResourceExhausted => {
+                Self::new(StatusCode::TOO_MANY_REQUESTS, message).with_code("server_busy")
+            }
+            Code::DeadlineExceeded | Code::Cancelled => {
+                Self::new(StatusCode::GATEWAY_TIMEOUT, message).with_code("timeout")
+            }
+            Code::Unavailable => {
+                Self::new(StatusCode::SERVICE_UNAVAILABLE, message).with_code("service_unavailable")
+            }
+            _ => Self::internal(message),
+        }
+    }
+}
+
+impl From<AuthError> for ApiError {
+    fn from(e: AuthError) -> Self {
+        match e {
+            AuthError::ModelNotAllowed(..) => {
+                Self::new(StatusCode::FORBIDDEN, e.to_string()).with_code("model_not_allowed")
+            }
+            _ => Self::new(StatusCode::UNAUTHORIZED, e.to_string()).with_code("invalid_api_key"),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (
+            self.status,
+            [(header::CONTENT_TYPE, "application/json")],
+            self.json().to_string(),
+        ).into_response()
+    }
+}
+
+// Fails with an OpenAI error for an upstream error response that does not have that shape already
+pub fn check_upstream(status: u16, body: &[u8]) -> Result<(), ApiError> {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY);
+    if !status.is_client_error() && !status.is_server_error() {
+        return Ok(());
+    }
+    let shaped = serde_json::from_slice::<serde_json::Value>(body)
+        .map(|v| v.get("error").is_some_and(|e| e.is_object()))
+        .unwrap_or(false);
+    if shaped {
+        return Ok(());
+    }
+    let message = String::from_utf8_lossy(body).trim().to_string();
+    let message = if message.is_empty() {
+        status.canonical_reason().unwrap_or("Upstream error").to_string()
+    } else {
+        message
+    };
+    Err(ApiError::new(status, message))
+}
+
+// The error of an upstream response that did not succeed, for routes that translate the answer
+pub async fn upstream_error(response: Response) -> ApiError {
+    let status = response.status();
+    let body = response.into_body().collect().await
+        .map(|b| b.to_bytes())
+        .unwrap_or_default();
+    let message = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|v| v["error"]["message"].as_str().map(|m| m.to_string()))
+        .unwrap_or_else(|| String::from_utf8_lossy(&body).trim().to_string());
+    if message.is_empty() {
+        return ApiError::new(status, status.canonical_reason().unwrap_or("Upstream error"));
+    }
+    ApiError::new(status, message)
+}
+
+// Reshape the OpenAI error bodies of routes speaking another API, rejections of the middleware included
+pub async fn dialect(req: Request, next: Next) -> Response {
+    let path = req.extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_default();
+    let reshape: fn(StatusCode, &serde_json::Value) -> serde_json::Value = match path.as_str() {
+        crate::anthropic::MESSAGES_PATH => crate::anthropic::error_body,
+        p if p.starts_with("/api/") => crate::ollama::error_body,
+        _ => return next.run(req).await,
+    };
+
+    let response = next.run(req).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(body) = body.collect().await.map(|b| b.to_bytes()) else {
+        return Response::from_parts(parts, Body::empty());
+    };
+    let error = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|v| v.get("error").cloned())
+        .filter(|e| e.is_object());
+    let Some(error) = error else {
+        return Response::from_parts(parts, Body::from(body));
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(reshape(status, &error).to_string()))
+}
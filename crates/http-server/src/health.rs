@@ -0,0 +1,127 @@
+use axum::{http::StatusCode, Json};
+use scheduler::ServiceStatus;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::Gateway;
+
+// Liveness, answered as long as the process serves HTTP
+pub async fn healthz() -> Json<Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+// Readiness, every configured model has a running instance and the gRPC port answers
+pub async fn readyz(gateway: Arc<Gateway>) -> (StatusCode, Json<Value>) {
+    let grpc = gateway.backend.check_grpc().await;
+    let grpc_ready = grpc.is_ok();
+
+    let mut instances = Vec::new();
+    let mut missing = Vec::new();
+    let scheduler_ready = match gateway.backend.scheduler() {
+        Some(scheduler) => {
+            let mut running = HashSet::new();
+            for instance in scheduler.list_instances().await {
+                if instance.status == ServiceStatus::Running {
+                    running.insert(instance.config.name.clone());
+                }
+                instances.push(json!({
+                    "id": instance.id,
+                    "model": instance.config.name,
+                    "status": format!("{:?}", instance.status).to_lowercase(),
+                    "addr": instance.server_addr,
+                }));
+            }
+            missing.extend(gateway.models.iter().filter(|m| !running.contains(&m.name)).map(|m| m.name.clone()));
+            missing.is_empty()
+        }
+        // a remote gRPC server is ready once it reports serving
+        None => matches!(grpc, Ok(true)),
+    };
+
+    let ready = grpc_ready && scheduler_ready;
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let body = json!({
+        "status": if ready { "ready" } else { "not_ready" },
+        "grpc": match &grpc {
+            Ok(_) => "reachable".to_string(),
+            Err(e) => format!("unreachable: {}", e),
+        },
+        "missing_models": missing,
+        "instances": instances,
+    });
+    (status, Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::LlamaServerConfig;
+    use scheduler::Scheduler;
+    use std::time::Duration;
+    use tonic::transport::Server;
+
+    // A gRPC port that only answers health checks, serving
+    async fn grpc_port() -> String {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (_, service) = tonic_health::server::health_reporter();
+        tokio::spawn(Server::builder().add_service(service).serve(addr));
+        for _ in 0..100 {
+            if tokio::net::TcpStream::connect(addr).await.is_ok() {
+                return addr.to_string();
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("Nothing listens on {}", addr);
+    }
+
+    fn scheduler() -> Option<Arc<Scheduler>> {
+        Some(Arc::new(Scheduler::new(std::env::temp_dir().join("assistant-health-test"), 1)))
+    }
+
+    fn model(name: &str) -> LlamaServerConfig {
+        LlamaServerConfig {
+            name: name.to_string(),
+            chat_model_path: None,
+            embedding_model_path: None,
+            tts_model_path: None,
+            config_path: None,
+            aliases: vec![],
+            owned_by: None,
+            context_length: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn live_as_long_as_it_serves() {
+        assert_eq!(healthz().await.0, json!({ "status": "ok" }));
+    }
+
+    #[tokio::test]
+    async fn not_ready_while_grpc_is_unreachable() {
+        let (status, Json(body)) = readyz(Gateway::for_tests("127.0.0.1:1", scheduler(), vec![])).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "not_ready");
+        assert!(body["grpc"].as_str().unwrap().starts_with("unreachable"));
+    }
+
+    #[tokio::test]
+    async fn not_ready_until_every_model_runs() {
+        let addr = grpc_port().await;
+        let (status, Json(body)) = readyz(Gateway::for_tests(&addr, scheduler(), vec![model("llama")])).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["grpc"], "reachable");
+        assert_eq!(body["missing_models"], json!(["llama"]));
+
+        let (status, Json(body)) = readyz(Gateway::for_tests(&addr, scheduler(), vec![])).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "ready");
+    }
+
+    #[tokio::test]
+    async fn a_remote_grpc_port_is_ready_once_serving() {
+        let addr = grpc_port().await;
+        let (status, _) = readyz(Gateway::for_tests(&addr, None, vec![model("llama")])).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
@@ -1,100 +1,473 @@
+use auth::{ApiKey, KeyStore};
 use axum::{
-    extract::{Path, Request},
-    http::{HeaderMap, StatusCode, header::{HeaderName, HeaderValue}},
-    response::{Response, IntoResponse},
+    extract::{Request, State},
+    http::{
+        header::{self, HeaderName, HeaderValue},
+        request::Parts,
+        Uri,
+    },
+    middleware::{self, Next},
+    response::Response,
     routing::{get, post},
-    Router, body::Body,
+    body::Body,
+    Router,
 };
 use tokio::net::TcpListener;
 use http_body_util::BodyExt;
-use protos::assistant::assistant_service_client::AssistantServiceClient;
+use config::{default_routes, Config, LlamaServerConfig, RateLimitConfig, RouteConfig, TimeoutConfig};
+use scheduler::{batch::Batches, cache::CACHE_HEADER, semantic::SemanticIndex, Scheduler, CAPABILITY_HEADER};
+use mcp::{McpHost, McpServers};
+use rag::RagStore;
+use session::SessionStore;
+use tonic::transport::{ClientTlsConfig, Endpoint};
 use tower_http::cors::CorsLayer;
 use tracing::{debug, info};
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
-use serde_json;
+use tokio::time::Instant;
 use tokio_stream::wrappers::ReceiverStream;
 
+mod admin;
+mod anthropic;
+mod backend;
+mod batches;
+mod collections;
+pub mod error;
+mod health;
+pub mod metrics;
+mod mcp_server;
+mod models;
+mod ollama;
+pub mod ratelimit;
+mod routes;
+mod semantic;
+mod sessions;
+mod sse;
+mod tools;
+mod ws;
+
+use backend::Backend;
+use error::{check_upstream, ApiError};
+use metrics::StreamTimer;
+use ratelimit::RateLimiter;
+use semantic::SemanticCache;
+use sessions::Sessions;
+use tools::Tools;
+
 pub struct HttpServer {
     grpc_addr: String,
+    tls: Option<ClientTlsConfig>,
+    timeouts: TimeoutConfig,
+    rate_limit: RateLimitConfig,
+    routes: Vec<RouteConfig>,
+    passthrough: bool,
+    scheduler: Option<Arc<Scheduler>>,
+    max_load: f32,
+    keys: Option<Arc<KeyStore>>,
+    models: Vec<LlamaServerConfig>,
+    batches: Option<Arc<Batches>>,
+    sessions: Option<(Arc<SessionStore>, u32)>,
+    semantic: Option<Arc<SemanticIndex>>,
+    rag: Option<Arc<RagStore>>,
+    mcp: Option<Arc<McpServers>>,
+    mcp_host: Option<(Arc<McpHost>, String)>,
+    config: Option<Config>,
+}
+
+// Shared by every route of the gateway
+struct Gateway {
+    backend: Backend,
+    timeouts: TimeoutConfig,
+    // Configured models, listed by /v1/models and needed running for the gateway to be ready
+    models: Vec<LlamaServerConfig>,
+}
+
+impl Gateway {
+    // Configured models and the ones of the co-located scheduler, to resolve aliases
+    async fn model_configs(&self) -> Vec<LlamaServerConfig> {
+        let mut configs = self.models.clone();
+        if let Some(scheduler) = self.backend.scheduler() {
+            for config in scheduler.model_configs().await {
+                if !configs.iter().any(|c| c.name == config.name) {
+                    configs.push(config);
+                }
+            }
+        }
+        configs
+    }
+
+    // A gateway dialing the gRPC port lazily, for the tests of the routes
+    #[cfg(test)]
+    fn for_tests(grpc_addr: &str, scheduler: Option<Arc<Scheduler>>, models: Vec<LlamaServerConfig>) -> Arc<Self> {
+        let endpoint = Endpoint::from_shared(format!("http://{}", grpc_addr)).unwrap();
+        Arc::new(Self {
+            backend: Backend::new(endpoint.connect_lazy(), scheduler, 1.0),
+            timeouts: TimeoutConfig::default(),
+            models,
+        })
+    }
 }
 
 impl HttpServer {
     pub fn new(grpc_addr: String) -> Self {
-        Self { grpc_addr }
+        Self {
+            grpc_addr,
+            tls: None,
+            timeouts: TimeoutConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            routes: default_routes(),
+            passthrough: false,
+            scheduler: None,
+            max_load: 1.0,
+            keys: None,
+            models: vec![],
+            batches: None,
+            sessions: None,
+            semantic: None,
+            rag: None,
+            mcp: None,
+            mcp_host: None,
+            config: None,
+        }
+    }
+
+    // Budgets of the proxied routes
+    pub fn with_timeouts(mut self, timeouts: TimeoutConfig) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    // Limits of callers without an API key
+    pub fn with_rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    // Routes proxied to the backend, passthrough proxies any other /v1 path too
+    pub fn with_routes(mut self, routes: Vec<RouteConfig>, passthrough: bool) -> Self {
+        self.routes = routes;
+        self.passthrough = passthrough;
+        self
+    }
+
+    // Dial the gRPC port over TLS
+    pub fn with_tls(mut self, tls: Option<ClientTlsConfig>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    // Call a co-located scheduler directly, the gRPC port is only used to offload when it is busy
+    pub fn with_scheduler(mut self, scheduler: Arc<Scheduler>, max_load: f32) -> Self {
+        self.scheduler = Some(scheduler);
+        self.max_load = max_load;
+        self
+    }
+
+    // Models listed by /v1/models, /readyz needs a running instance of each
+    pub fn with_models(mut self, models: Vec<LlamaServerConfig>) -> Self {
+        self.models = models;
+        self
+    }
+
+    // Serve /v1/files and /v1/batches from the batch executor
+    pub fn with_batches(mut self, batches: Arc<Batches>) -> Self {
+        self.batches = Some(batches);
+        self
+    }
+
+    // Keep conversations for chat requests with a session_id, and serve /v1/sessions
+    pub fn with_sessions(mut self, store: Arc<SessionStore>, default_context_length: u32) -> Self {
+        self.sessions = Some((store, default_context_length));
+        self
+    }
+
+    // Answer chat and text completions from the answers of similar prompts
+    pub fn with_semantic_cache(mut self, index: Arc<SemanticIndex>) -> Self {
+        self.semantic = Some(index);
+        self
+    }
+
+    // Serve /v1/collections, chat requests with a `rag` field get the best chunks of a collection
+    pub fn with_rag(mut self, store: Arc<RagStore>) -> Self {
+        self.rag = Some(store);
+        self
+    }
+
+    // Chat requests with an `mcp` field may call the tools of the MCP servers
+    pub fn with_mcp(mut self, servers: Arc<McpServers>) -> Self {
+        self.mcp = Some(servers);
+        self
+    }
+
+    // Serve the local models as MCP tools at the path
+    pub fn with_mcp_host(mut self, host: Arc<McpHost>, path: String) -> Self {
+        self.mcp_host = Some((host, path));
+        self
+    }
+
+    // Effective config shown by the admin API
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    // Require an API key from callers when the store has auth enabled
+    pub fn with_keys(mut self, keys: Arc<KeyStore>) -> Self {
+        self.keys = Some(keys);
+        self
     }
 
     pub async fn serve(self, addr: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let grpc_addr = Arc::new(self.grpc_addr);
+        // one lazily connected channel, multiplexed by all requests
+        let endpoint = match &self.tls {
+            Some(tls) => Endpoint::from_shared(format!("https://{}", self.grpc_addr))?
+                .tls_config(tls.clone())?,
+            None => Endpoint::from_shared(format!("http://{}", self.grpc_addr))?,
+        };
+        // the admin API needs the co-located scheduler
+        let admin = self.scheduler.clone()
+            .map(|scheduler| admin::router(scheduler, self.keys.clone(), self.config));
+        // prompts are embedded by a local instance, the scheduler and an embedding model are needed
+        let semantic = match self.semantic {
+            Some(index) => {
+                let scheduler = self.scheduler.clone()
+                    .ok_or("The semantic cache needs the co-located scheduler")?;
+                let embedding_model = index.embedding_model()
+                    .map(|m| m.to_string())
+                    .or_else(|| self.models.iter().find(|m| m.has_embedding_model()).map(|m| m.name.clone()))
+                    .ok_or("The semantic cache needs a model with an embedding_model_path")?;
+                let routes = self.routes.iter()
+                    .filter_map(|r| r.semantic_threshold.map(|t| (r.path.clone(), t)))
+                    .collect();
+                Some(Arc::new(SemanticCache { index, scheduler, embedding_model, routes }))
+            }
+            None => None,
+        };
+        let gateway = Arc::new(Gateway {
+            backend: Backend::new(endpoint.connect_lazy(), self.scheduler, self.max_load),
+            timeouts: self.timeouts,
+            models: self.models,
+        });
         
-        let app = Router::new()
-            .route("/v1/chat/completions", post({
-                let grpc_addr = Arc::clone(&grpc_addr);
-                move |req| handle_request(req, (*grpc_addr).clone())
+        let mut app = routes::router(&self.routes, self.passthrough, &gateway)?
+            .route("/v1/models", get({
+                let gateway = Arc::clone(&gateway);
+                move |req| models::list(gateway, req)
             }))
-            .route("/v1/completions", post({
-                let grpc_addr = Arc::clone(&grpc_addr);
-                move |req| handle_request(req, (*grpc_addr).clone())
+            .route("/v1/models/:id", get({
+                let gateway = Arc::clone(&gateway);
+                move |id, req| models::retrieve(gateway, id, req)
             }))
-            .route("/v1/models", get({
-                let grpc_addr = Arc::clone(&grpc_addr);
-                move |req| handle_request(req, (*grpc_addr).clone())
+            .route(anthropic::MESSAGES_PATH, post({
+                let gateway = Arc::clone(&gateway);
+                let route = routes::translated(&self.routes, anthropic::MESSAGES_PATH);
+                move |req| anthropic::messages(gateway, route, req)
             }))
-            .route("/v1/embeddings", post({
-                let grpc_addr = Arc::clone(&grpc_addr);
-                move |req| handle_request(req, (*grpc_addr).clone())
+            .route(ollama::CHAT, post({
+                let gateway = Arc::clone(&gateway);
+                let route = routes::translated(&self.routes, ollama::CHAT);
+                move |req| ollama::chat(gateway, route, req)
             }))
-            .route("/v1/chunks", post({
-                let grpc_addr = Arc::clone(&grpc_addr);
-                move |req| handle_request(req, (*grpc_addr).clone())
+            .route(ollama::GENERATE, post({
+                let gateway = Arc::clone(&gateway);
+                let route = routes::translated(&self.routes, ollama::GENERATE);
+                move |req| ollama::generate(gateway, route, req)
             }))
-            .route("/v1/audio/speech", post({
-                let grpc_addr = Arc::clone(&grpc_addr);
-                move |req| handle_request(req, (*grpc_addr).clone())
+            .route(ollama::EMBEDDINGS, post({
+                let gateway = Arc::clone(&gateway);
+                let route = routes::translated(&self.routes, ollama::EMBEDDINGS);
+                move |req| ollama::embeddings(gateway, route, req)
             }))
-            .route("/v1/info", get({
-                let grpc_addr = Arc::clone(&grpc_addr);
-                move |req| handle_request(req, (*grpc_addr).clone())
+            .route(ollama::EMBED, post({
+                let gateway = Arc::clone(&gateway);
+                let route = routes::translated(&self.routes, ollama::EMBED);
+                move |req| ollama::embed(gateway, route, req)
             }))
-            .layer(CorsLayer::permissive());
+            .route(ollama::TAGS, get({
+                let gateway = Arc::clone(&gateway);
+                move |req| ollama::tags(gateway, req)
+            }))
+            .route(ollama::SHOW, post({
+                let gateway = Arc::clone(&gateway);
+                move |req| ollama::show(gateway, req)
+            }));
+        let sessions = self.sessions.map(|(store, default_context_length)| {
+            Arc::new(Sessions { store, gateway: Arc::clone(&gateway), default_context_length })
+        });
+
+        let tools = self.mcp.map(|servers| {
+            let route = routes::translated(&self.routes, routes::CHAT_PATH);
+            Arc::new(Tools { servers, gateway: Arc::clone(&gateway), route })
+        });
+
+        // turns of WebSocket chats are rate limited and counted like chat completions
+        let limiter = Arc::new(RateLimiter::new(self.rate_limit));
+        let mut turns = Router::new()
+            .route(routes::CHAT_PATH, post({
+                let gateway = Arc::clone(&gateway);
+                let route = routes::translated(&self.routes, ws::PATH);
+                move |req| handle_request(req, gateway, route)
+            }));
+        if let Some(semantic) = &semantic {
+            turns = turns.route_layer(middleware::from_fn_with_state(semantic.clone(), semantic::lookup));
+        }
+        if let Some(tools) = &tools {
+            turns = turns.route_layer(middleware::from_fn_with_state(tools.clone(), tools::run));
+        }
+        if let Some(store) = &self.rag {
+            turns = turns.route_layer(middleware::from_fn_with_state(store.clone(), collections::augment));
+        }
+        if let Some(sessions) = &sessions {
+            turns = turns.route_layer(middleware::from_fn_with_state(sessions.clone(), sessions::attach));
+        }
+        let turns = turns
+            .route_layer(middleware::from_fn_with_state(limiter.clone(), ratelimit::rate_limit))
+            .route_layer(middleware::from_fn(metrics::track));
+        app = app.route(ws::PATH, get(move |upgrade, addr, headers, key| ws::upgrade(turns, upgrade, addr, headers, key)));
+        if let Some(batches) = self.batches {
+            if let Some(route) = self.routes.iter().find(|r| batches::PATHS.contains(&r.path.as_str())) {
+                return Err(format!("Route {} is served by the batch API", route.path).into());
+            }
+            app = app.merge(batches::router(batches));
+        }
+        if let Some((host, path)) = self.mcp_host {
+            if self.routes.iter().any(|r| r.path == path) {
+                return Err(format!("Route {} is served by the MCP server", path).into());
+            }
+            app = app.merge(mcp_server::router(host, &path));
+        }
+        if let Some(semantic) = semantic {
+            // innermost, it compares the prompt the session history was added to
+            app = app.route_layer(middleware::from_fn_with_state(semantic, semantic::lookup));
+        }
+        if let Some(tools) = tools {
+            // the rounds go to the backend directly, tool results are not cached
+            app = app.route_layer(middleware::from_fn_with_state(tools, tools::run));
+        }
+        if let Some(store) = self.rag {
+            if let Some(route) = self.routes.iter().find(|r| collections::PATHS.contains(&r.path.as_str())) {
+                return Err(format!("Route {} is served by the collections API", route.path).into());
+            }
+            app = app.merge(collections::router(store.clone()));
+            // the excerpts are not part of the history the sessions keep
+            app = app.route_layer(middleware::from_fn_with_state(store, collections::augment));
+        }
+        if let Some(sessions) = sessions {
+            if let Some(route) = self.routes.iter().find(|r| sessions::PATHS.contains(&r.path.as_str())) {
+                return Err(format!("Route {} is served by the session API", route.path).into());
+            }
+            app = app.merge(sessions::router(sessions.store.clone()));
+            // the history is added to the body the outer layers have seen
+            app = app.route_layer(middleware::from_fn_with_state(sessions, sessions::attach));
+        }
+        // runs after authentication, so keys are limited by their own policy
+        app = app.route_layer(middleware::from_fn_with_state(limiter, ratelimit::rate_limit));
+        if let Some(keys) = self.keys {
+            app = app.route_layer(middleware::from_fn_with_state(keys, authenticate));
+        }
+        // outermost, so rejected requests are counted too
+        app = app.route_layer(middleware::from_fn(metrics::track));
+        // before the metrics buffer the body
+        app = app.route_layer(middleware::from_fn_with_state(
+            routes::body_limits(&self.routes),
+            routes::limit_body,
+        ));
+        app = app.route_layer(middleware::from_fn(error::dialect));
+
+        // added after the route layers, scrapes and probes are neither authenticated nor counted
+        app = app.route("/metrics", get(metrics::render))
+            .route("/healthz", get(health::healthz))
+            .route(ollama::VERSION, get(ollama::version))
+            .route("/readyz", get({
+                let gateway = Arc::clone(&gateway);
+                move || health::readyz(gateway)
+            }));
+        if let Some(admin) = admin {
+            app = app.nest("/admin", admin);
+        }
+        let app = app.layer(CorsLayer::permissive());
 
         let listener = TcpListener::bind(addr).await?;
         info!("Starting HTTP server on {}", addr);
 
-        axum::serve(listener, app.into_make_service())
+        axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
             .await?;
 
         Ok(())
     }
 }
 
+// Check the bearer key, or the x-api-key of Anthropic clients, its policy is left in the request extensions.
+// Browsers cannot set headers on WebSocket upgrades, which carry the key in the protocols or the query
+async fn authenticate(
+    State(keys): State<Arc<KeyStore>>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if keys.enabled() {
+        let ws_token = (req.uri().path() == ws::PATH).then(|| ws::token(&req)).flatten();
+        let token = req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(auth::bearer)
+            .or_else(|| req.headers().get("x-api-key").and_then(|v| v.to_str().ok()))
+            .or(ws_token.as_deref());
+        let key = keys.authenticate(token)?;
+        req.extensions_mut().insert(key);
+    }
+    Ok(next.run(req).await)
+}
+
+// The OpenAI request of a translated API, sent to the path with the headers of the original
+fn translated_request(mut parts: Parts, path: &'static str, body: &serde_json::Value) -> Request {
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    parts.uri = Uri::from_static(path);
+    Request::from_parts(parts, Body::from(body.to_string()))
+}
+
 async fn handle_request(
     req: Request<Body>,
-    grpc_addr: String,
-) -> Result<Response<Body>, StatusCode> {
+    gateway: Arc<Gateway>,
+    route: Arc<RouteConfig>,
+) -> Result<Response<Body>, ApiError> {
     debug!("Received request to path: {}", req.uri().path());
 
     // Extract request components
+    let started = Instant::now();
     let path = req.uri().path().to_string();
     let method = req.method().as_str().to_string();
-    let headers = req.headers()
+    let key = req.extensions().get::<ApiKey>().cloned();
+    let mut headers: HashMap<String, String> = req.headers()
         .iter()
         .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
         .collect();
+    if let Some(capability) = route.capability {
+        headers.insert(CAPABILITY_HEADER.to_string(), capability.as_str().to_string());
+    }
     let body = req.into_body().collect().await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(ApiError::body)?
         .to_bytes()
         .to_vec();
 
     // Check if this is a stream request
-    let is_stream = if let Ok(body_str) = String::from_utf8(body.clone()) {
-        if let Ok(json) = serde_json::from_str::<serde_json::Value>(&body_str) {
-            json.get("stream").and_then(|v| v.as_bool()).unwrap_or(false)
-        } else {
-            false
-        }
-    } else {
-        false
-    };
+    let json = serde_json::from_slice::<serde_json::Value>(&body).ok();
+    let is_stream = json.as_ref()
+        .and_then(|json| json.get("stream"))
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    let deadline = route.timeout(&gateway.timeouts, &path, is_stream).map(|t| started + t);
+
+    // Check the model against the key policy
+    let model = json.as_ref()
+        .and_then(|json| json.get("model"))
+        .and_then(|v| v.as_str());
+    if let Some(key) = &key {
+        auth::authorize_model(key, model, &gateway.model_configs().await)?;
+    }
+
+    let mut timer = StreamTimer::new(&path, model.unwrap_or_default());
 
     // Create gRPC request
     let request = protos::assistant::Request {
@@ -104,26 +477,69 @@ async fn handle_request(
         body,
     };
 
-    // Forward to gRPC server
-    let mut client = AssistantServiceClient::connect("http://".to_string() + &grpc_addr)
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
     if is_stream {
-        let stream = client
-            .forward_request_stream(request)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-            .into_inner();
+        let mut stream = gateway.backend.forward_stream(request, deadline).await?;
 
         let (tx, rx) = tokio::sync::mpsc::channel::<Result<Vec<u8>, std::io::Error>>(4);
-        let mut stream = stream;
+
+        // wait for the first chunk, so a backend that never answers still gets a timeout status
+        let first = within(deadline, stream.recv())
+            .await?
+            .transpose()?;
+
+        // an upstream error is answered as a whole rather than as an event stream
+        if let Some(chunk) = first.as_ref().filter(|c| c.status >= 400) {
+            let mut body = chunk.body.clone();
+            while let Some(next) = within(deadline, stream.recv()).await?.transpose()? {
+                body.extend(next.body);
+            }
+            check_upstream(chunk.status as u16, &body)?;
+            return proxy_response(chunk.status as u16, &chunk.headers, body);
+        }
+
+        let cache = first.as_ref().and_then(|c| c.headers.get(CACHE_HEADER).cloned());
 
         // Start background task to handle stream
         tokio::spawn(async move {
-            while let Some(chunk) = stream.message().await.map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "stream error")).unwrap() {
-                let _ = tx.send(Ok(chunk.body)).await;
+            match first {
+                Some(chunk) => {
+                    timer.chunk(started, &chunk.body);
+                    if tx.send(Ok(chunk.body)).await.is_err() {
+                        return;
+                    }
+                }
+                None => return,
+            }
+            // a stream cut short ends with an error event rather than [DONE], so it is not cached
+            let error = loop {
+                let message = match deadline {
+                    Some(deadline) => match tokio::time::timeout_at(deadline, stream.recv()).await {
+                        Ok(message) => message,
+                        Err(_) => {
+                            debug!("Stream exceeded its deadline");
+                            break Some(ApiError::timeout());
+                        }
+                    },
+                    None => stream.recv().await,
+                };
+                match message {
+                    Some(Ok(chunk)) => {
+                        timer.chunk(started, &chunk.body);
+                        if tx.send(Ok(chunk.body)).await.is_err() {
+                            break None;
+                        }
+                    }
+                    None => break None,
+                    Some(Err(e)) => {
+                        debug!("Stream error: {}", e);
+                        break Some(ApiError::from(e));
+                    }
+                }
+            };
+            if let Some(error) = error {
+                let _ = tx.send(Ok(format!("data: {}\n\n", error.json()).into_bytes())).await;
             }
+            timer.finish();
         });
 
         // Create streaming response
@@ -135,33 +551,45 @@ async fn handle_request(
             .header("Content-Type", "text/event-stream")
             .header("Cache-Control", "no-cache")
             .header("Connection", "keep-alive");
+        if let Some(cache) = cache {
+            builder = builder.header(CACHE_HEADER, cache);
+        }
 
         builder
             .body(body)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+            .map_err(|e| ApiError::internal(e.to_string()))
     } else {
-        let response = client
-            .forward_request(request)
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-            .into_inner();
+        let response = gateway.backend.forward(request, deadline).await?;
+        check_upstream(response.status as u16, &response.body)?;
+        proxy_response(response.status as u16, &response.headers, response.body)
+    }
+}
 
-        // Convert back to HTTP response
-        let mut builder = Response::builder()
-            .status(response.status as u16);
-
-        // Add headers
-        let headers = builder.headers_mut().unwrap();
-        for (key, value) in response.headers {
-            if let Ok(name) = HeaderName::from_bytes(key.as_bytes()) {
-                if let Ok(val) = HeaderValue::from_str(&value) {
-                    headers.insert(name, val);
-                }
+// Convert a backend answer back to an HTTP response
+fn proxy_response(
+    status: u16,
+    headers: &HashMap<String, String>,
+    body: Vec<u8>,
+) -> Result<Response<Body>, ApiError> {
+    let mut builder = Response::builder()
+        .status(status);
+
+    // Add headers
+    let response_headers = builder.headers_mut().unwrap();
+    for (key, value) in headers {
+        if let Ok(name) = HeaderName::from_bytes(key.as_bytes()) {
+            if let Ok(val) = HeaderValue::from_str(value) {
+                response_headers.insert(name, val);
             }
         }
-
-        builder
-            .body(Body::from(response.body))
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
     }
-} 
\ No newline at end of file
+
+    builder
+        .body(Body::from(body))
+        .map_err(|e| ApiError::internal(e.to_string()))
+}
+
+// Run a future within the request deadline, 504 once it has passed
+async fn within<F: std::future::Future>(deadline: Option<Instant>, future: F) -> Result<F::Output, ApiError> {
+    scheduler::deadline::within(deadline, future).await.map_err(|_| ApiError::timeout())
+}
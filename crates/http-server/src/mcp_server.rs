@@ -0,0 +1,59 @@
+use auth::ApiKey;
+use axum::{
+    body::Bytes,
+    extract::State,
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Extension, Router,
+};
+use mcp::McpHost;
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::ratelimit::{usage_total, TokenUsage};
+
+// The streamable HTTP endpoint of the gateway's MCP server, without sessions: every POST is
+// answered with JSON, and there is no event stream to GET
+pub fn router(host: Arc<McpHost>, path: &str) -> Router {
+    Router::new()
+        .route(path, post(message).get(|| async { StatusCode::METHOD_NOT_ALLOWED }))
+        .with_state(host)
+}
+
+async fn message(
+    State(host): State<Arc<McpHost>>,
+    key: Option<Extension<ApiKey>>,
+    body: Bytes,
+) -> Response {
+    let Ok(message) = serde_json::from_slice::<Value>(&body) else {
+        return json(StatusCode::BAD_REQUEST, mcp::parse_error());
+    };
+    match host.handle(message, key.as_deref().map(|key| key.as_ref())).await {
+        Some(response) => {
+            let tokens = tokens(&response);
+            let mut response = json(StatusCode::OK, response);
+            if tokens > 0 {
+                response.extensions_mut().insert(TokenUsage(tokens));
+            }
+            response
+        }
+        // notifications and responses
+        None => StatusCode::ACCEPTED.into_response(),
+    }
+}
+
+// Tokens the tools of a response or a batch of them used
+fn tokens(response: &Value) -> u64 {
+    let responses = match response {
+        Value::Array(responses) => responses.iter().collect(),
+        response => vec![response],
+    };
+    responses.into_iter()
+        .filter_map(|r| usage_total(&r["result"]["_meta"]["usage"]))
+        .sum()
+}
+
+fn json(status: StatusCode, body: Value) -> Response {
+    (status, [(header::CONTENT_TYPE, "application/json")], body.to_string()).into_response()
+}
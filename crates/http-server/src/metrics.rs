@@ -0,0 +1,97 @@
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body_util::BodyExt;
+use scheduler::request_model;
+use telemetry::metrics;
+use tokio::time::Instant;
+
+use crate::error::ApiError;
+
+// Count and time requests per route, model and status
+pub async fn track(req: Request, next: Next) -> Result<Response, ApiError> {
+    let started = Instant::now();
+    let route = req.extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+
+    // the body is buffered by the handler anyway
+    let (parts, body) = req.into_parts();
+    let body = body.collect().await
+        .map_err(ApiError::body)?
+        .to_bytes();
+    let model = request_model(&body);
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    // the model of a failed request may be anything the client sent
+    let status = response.status();
+    let model = if status.is_success() { model.unwrap_or_default() } else { String::new() };
+    let labels = [route.as_str(), model.as_str(), status.as_str()];
+    metrics().http_requests.with_label_values(&labels).inc();
+    metrics().http_request_duration
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+    Ok(response)
+}
+
+// Prometheus scrape endpoint
+pub async fn render() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics().render(),
+    )
+}
+
+// Time to first token and streaming rate of one stream
+pub struct StreamTimer {
+    route: String,
+    model: String,
+    first: Option<Instant>,
+    tokens: usize,
+}
+
+impl StreamTimer {
+    pub fn new(route: &str, model: &str) -> Self {
+        Self {
+            route: route.to_string(),
+            model: model.to_string(),
+            first: None,
+            tokens: 0,
+        }
+    }
+
+    // Record a chunk of the event stream, each `data:` event carries one token
+    pub fn chunk(&mut self, started: Instant, body: &[u8]) {
+        if self.first.is_none() {
+            self.first = Some(Instant::now());
+            metrics().time_to_first_token
+                .with_label_values(&[&self.route, &self.model])
+                .observe(started.elapsed().as_secs_f64());
+        }
+        self.tokens += String::from_utf8_lossy(body)
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("data:"))
+            .filter(|data| data.trim() != "[DONE]")
+            .count();
+    }
+
+    // Record the rate once the stream ended
+    pub fn finish(self) {
+        let Some(first) = self.first else {
+            return;
+        };
+        let elapsed = first.elapsed().as_secs_f64();
+        if self.tokens > 1 && elapsed > 0.0 {
+            metrics().stream_tokens_per_second
+                .with_label_values(&[&self.route, &self.model])
+                .observe((self.tokens - 1) as f64 / elapsed);
+        }
+    }
+}
+
@@ -0,0 +1,194 @@
+use auth::ApiKey;
+use axum::{
+    extract::{Path, Request},
+    http::{header, StatusCode},
+    Json,
+};
+use protos::assistant::ModelStatus;
+use scheduler::ServiceStatus;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::error::ApiError;
+use crate::Gateway;
+
+const DEFAULT_OWNER: &str = "assistant";
+
+// One entry of the model list
+pub struct ModelEntry {
+    pub id: String,
+    pub owned_by: String,
+    pub created: u64,
+    pub status: String,
+    pub aliases: Vec<String>,
+    pub context_length: Option<u32>,
+    pub node_id: Option<String>,
+}
+
+impl ModelEntry {
+    pub fn serves(&self, model: &str) -> bool {
+        self.id == model || self.aliases.iter().any(|a| a == model)
+    }
+
+    fn names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.id.as_str()).chain(self.aliases.iter().map(String::as_str))
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "object": "model",
+            "created": self.created,
+            "owned_by": self.owned_by,
+            "status": self.status,
+            "aliases": self.aliases,
+            "context_length": self.context_length,
+            "node_id": self.node_id,
+        })
+    }
+}
+
+// GET /v1/models, local models first then the ones only served by peers
+pub async fn list(gateway: Arc<Gateway>, req: Request) -> Result<Json<Value>, ApiError> {
+    let data: Vec<Value> = collect(&gateway, Caller::new(&gateway, &req)).await?
+        .iter()
+        .map(ModelEntry::to_json)
+        .collect();
+    Ok(Json(json!({ "object": "list", "data": data })))
+}
+
+// GET /v1/models/{id}, aliases resolve to the model they belong to
+pub async fn retrieve(
+    gateway: Arc<Gateway>,
+    Path(id): Path<String>,
+    req: Request,
+) -> Result<Json<Value>, ApiError> {
+    collect(&gateway, Caller::new(&gateway, &req)).await?
+        .iter()
+        .find(|m| m.serves(&id))
+        .map(|m| Json(m.to_json()))
+        .ok_or_else(|| {
+            ApiError::new(StatusCode::NOT_FOUND, format!("Model {} not found", id))
+                .with_code("model_not_found")
+        })
+}
+
+// What the model list depends on, taken from the request before any await
+pub struct Caller {
+    key: Option<ApiKey>,
+    authorization: Option<String>,
+    deadline: Option<Instant>,
+}
+
+impl Caller {
+    pub fn new(gateway: &Gateway, req: &Request) -> Self {
+        Self {
+            key: req.extensions().get::<ApiKey>().cloned(),
+            authorization: req.headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+            deadline: gateway.timeouts.for_path(req.uri().path(), false).map(|t| Instant::now() + t),
+        }
+    }
+}
+
+// Models the caller may use
+pub async fn collect(gateway: &Gateway, caller: Caller) -> Result<Vec<ModelEntry>, ApiError> {
+    let local = gateway.backend.scheduler().is_some();
+    let mut models = local_models(gateway).await;
+
+    // with a co-located scheduler only peers are asked for, otherwise the gRPC server knows all
+    let remote = match gateway.backend.list_models(caller.authorization.as_deref(), caller.deadline).await {
+        Ok(remote) => remote,
+        // the local models are still worth answering with
+        Err(e) if local => {
+            debug!("Failed to list peer models: {}", e);
+            vec![]
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut seen: HashSet<String> = models.iter().map(|m| m.id.clone()).collect();
+    for info in remote {
+        if local && info.node_id.is_empty() {
+            continue;
+        }
+        if seen.insert(info.id.clone()) {
+            models.push(ModelEntry {
+                id: info.id,
+                owned_by: if info.owned_by.is_empty() { DEFAULT_OWNER.to_string() } else { info.owned_by },
+                created: 0,
+                status: ModelStatus::try_from(info.status)
+                    .map(status_name)
+                    .unwrap_or("unknown")
+                    .to_string(),
+                aliases: vec![],
+                context_length: None,
+                node_id: Some(info.node_id).filter(|n| !n.is_empty()),
+            });
+        }
+    }
+
+    if let Some(key) = &caller.key {
+        models.retain(|m| key.allows_names(m.names()));
+    }
+    Ok(models)
+}
+
+// Configured models and the instances of the co-located scheduler, one entry per model
+pub async fn local_models(gateway: &Gateway) -> Vec<ModelEntry> {
+    let Some(scheduler) = gateway.backend.scheduler() else {
+        return vec![];
+    };
+    let instances = scheduler.list_instances().await;
+
+    let mut configs: Vec<_> = gateway.models.clone();
+    for instance in &instances {
+        if !configs.iter().any(|c| c.name == instance.config.name) {
+            configs.push(instance.config.clone());
+        }
+    }
+
+    configs.into_iter()
+        .map(|config| {
+            let serving: Vec<_> = instances.iter()
+                .filter(|i| i.config.name == config.name)
+                .collect();
+            // the best status of the model's instances
+            let status = [ServiceStatus::Running, ServiceStatus::Starting, ServiceStatus::Failed]
+                .into_iter()
+                .find(|s| serving.iter().any(|i| i.status == *s))
+                .unwrap_or(ServiceStatus::Stopped);
+            let created = serving.iter()
+                .map(|i| i.started_at)
+                .min()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default())
+                .as_secs();
+            ModelEntry {
+                owned_by: config.owned_by.clone().unwrap_or_else(|| DEFAULT_OWNER.to_string()),
+                created,
+                status: format!("{:?}", status).to_lowercase(),
+                context_length: config.context_length
+                    .or_else(|| serving.iter().find_map(|i| i.context_length)),
+                aliases: config.aliases,
+                id: config.name,
+                node_id: None,
+            }
+        })
+        .collect()
+}
+
+fn status_name(status: ModelStatus) -> &'static str {
+    match status {
+        ModelStatus::Unspecified => "unknown",
+        ModelStatus::Starting => "starting",
+        ModelStatus::Running => "running",
+        ModelStatus::Failed => "failed",
+        ModelStatus::Stopped => "stopped",
+    }
+}
@@ -0,0 +1,572 @@
+// Ollama API, translated to the OpenAI calls spoken by llama-api-server
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, SecondsFormat, Utc};
+use config::RouteConfig;
+use http_body_util::BodyExt;
+use scheduler::model_of;
+use serde_json::{json, Map, Value};
+use std::sync::Arc;
+use tokio::time::Instant;
+
+use crate::error::{upstream_error, ApiError};
+use crate::models::{self, Caller};
+use crate::routes::{CHAT_PATH, EMBEDDINGS_PATH};
+use crate::sse;
+use crate::{handle_request, translated_request, Gateway};
+
+pub const CHAT: &str = "/api/chat";
+pub const GENERATE: &str = "/api/generate";
+pub const EMBEDDINGS: &str = "/api/embeddings";
+pub const EMBED: &str = "/api/embed";
+pub const TAGS: &str = "/api/tags";
+pub const SHOW: &str = "/api/show";
+pub const VERSION: &str = "/api/version";
+
+const NDJSON: &str = "application/x-ndjson";
+
+// POST /api/chat
+pub async fn chat(gateway: Arc<Gateway>, route: Arc<RouteConfig>, req: Request) -> Result<Response, ApiError> {
+    let started = Instant::now();
+    let (parts, request) = read_json(req).await?;
+    let mut messages = Vec::new();
+    for message in request.get("messages").and_then(Value::as_array).into_iter().flatten() {
+        messages.push(chat_message(message));
+    }
+    let chat = chat_request(&request, messages);
+    complete(gateway, route, parts, chat, &request, Kind::Chat, started).await
+}
+
+// POST /api/generate
+pub async fn generate(gateway: Arc<Gateway>, route: Arc<RouteConfig>, req: Request) -> Result<Response, ApiError> {
+    let started = Instant::now();
+    let (parts, request) = read_json(req).await?;
+    let model = model_of(&request).unwrap_or_default().to_string();
+    let prompt = request.get("prompt").and_then(Value::as_str).unwrap_or_default();
+
+    // an empty prompt only loads the model, it is loaded already
+    if prompt.is_empty() {
+        return Ok(Json(json!({
+            "model": model,
+            "created_at": now(),
+            "response": "",
+            "done": true,
+            "done_reason": "load",
+        })).into_response());
+    }
+
+    let mut messages = Vec::new();
+    if let Some(system) = request.get("system").and_then(Value::as_str) {
+        messages.push(json!({ "role": "system", "content": system }));
+    }
+    messages.push(chat_message(&json!({
+        "role": "user",
+        "content": prompt,
+        "images": request.get("images"),
+    })));
+    let chat = chat_request(&request, messages);
+    complete(gateway, route, parts, chat, &request, Kind::Generate, started).await
+}
+
+// POST /api/embeddings, one prompt
+pub async fn embeddings(gateway: Arc<Gateway>, route: Arc<RouteConfig>, req: Request) -> Result<Response, ApiError> {
+    let (parts, request) = read_json(req).await?;
+    let body = json!({
+        "model": model_of(&request).unwrap_or_default(),
+        "input": request.get("prompt").cloned().unwrap_or_else(|| json!("")),
+    });
+    let response = embed_request(gateway, route, parts, body).await?;
+    let embedding = response["data"][0]["embedding"].clone();
+    Ok(Json(json!({ "embedding": embedding })).into_response())
+}
+
+// POST /api/embed, one input or a batch of them
+pub async fn embed(gateway: Arc<Gateway>, route: Arc<RouteConfig>, req: Request) -> Result<Response, ApiError> {
+    let started = Instant::now();
+    let (parts, request) = read_json(req).await?;
+    let model = model_of(&request).unwrap_or_default().to_string();
+    let body = json!({
+        "model": model,
+        "input": request.get("input").cloned().unwrap_or_else(|| json!("")),
+    });
+    let response = embed_request(gateway, route, parts, body).await?;
+    let embeddings: Vec<Value> = response["data"].as_array()
+        .into_iter()
+        .flatten()
+        .map(|d| d["embedding"].clone())
+        .collect();
+    Ok(Json(json!({
+        "model": model,
+        "embeddings": embeddings,
+        "total_duration": started.elapsed().as_nanos() as u64,
+        "prompt_eval_count": response["usage"]["prompt_tokens"].as_u64().unwrap_or(0),
+    })).into_response())
+}
+
+// GET /api/tags, each alias is listed as a model of its own
+pub async fn tags(gateway: Arc<Gateway>, req: Request) -> Result<Json<Value>, ApiError> {
+    let entries = models::collect(&gateway, Caller::new(&gateway, &req)).await?;
+    let mut tags = Vec::new();
+    for entry in &entries {
+        for name in std::iter::once(&entry.id).chain(&entry.aliases) {
+            tags.push(json!({
+                "name": name,
+                "model": name,
+                "modified_at": timestamp(entry.created),
+                "size": 0,
+                "digest": "",
+                "details": details(),
+            }));
+        }
+    }
+    Ok(Json(json!({ "models": tags })))
+}
+
+// POST /api/show
+pub async fn show(gateway: Arc<Gateway>, req: Request) -> Result<Json<Value>, ApiError> {
+    let caller = Caller::new(&gateway, &req);
+    let (_, request) = read_json(req).await?;
+    let name = request.get("model")
+        .or_else(|| request.get("name"))
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    let entries = models::collect(&gateway, caller).await?;
+    let entry = entries.iter()
+        .find(|m| m.serves(&name))
+        .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, format!("model '{}' not found", name)))?;
+    Ok(Json(json!({
+        "modelfile": "",
+        "parameters": "",
+        "template": "",
+        "details": details(),
+        "model_info": {
+            "general.name": entry.id,
+            "general.context_length": entry.context_length,
+        },
+        "modified_at": timestamp(entry.created),
+    })))
+}
+
+// GET /api/version
+pub async fn version() -> Json<Value> {
+    Json(json!({ "version": env!("CARGO_PKG_VERSION") }))
+}
+
+async fn read_json(req: Request) -> Result<(axum::http::request::Parts, Value), ApiError> {
+    let (parts, body) = req.into_parts();
+    let body = body.collect().await.map_err(ApiError::body)?.to_bytes();
+    let request = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, format!("Invalid request body: {}", e)))?;
+    Ok((parts, request))
+}
+
+// Ollama message to chat message, images are base64 without a media type
+fn chat_message(message: &Value) -> Value {
+    let role = message.get("role").and_then(Value::as_str).unwrap_or("user");
+    let text = message.get("content").and_then(Value::as_str).unwrap_or_default();
+    let images: Vec<&str> = message.get("images")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+        .collect();
+    let content = if images.is_empty() {
+        json!(text)
+    } else {
+        let mut parts = vec![json!({ "type": "text", "text": text })];
+        parts.extend(images.iter().map(|image| json!({
+            "type": "image_url",
+            "image_url": { "url": format!("data:image/png;base64,{}", image) },
+        })));
+        Value::Array(parts)
+    };
+
+    let mut chat = json!({ "role": role, "content": content });
+    // arguments are objects for Ollama, JSON strings for OpenAI
+    let calls: Vec<Value> = message.get("tool_calls")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .map(|(i, call)| json!({
+            "id": format!("call_{}", i),
+            "type": "function",
+            "function": {
+                "name": call["function"]["name"],
+                "arguments": call["function"]["arguments"].to_string(),
+            },
+        }))
+        .collect();
+    if !calls.is_empty() {
+        chat["tool_calls"] = Value::Array(calls);
+    }
+    chat
+}
+
+// Chat completion request of the messages, with the options that have an OpenAI counterpart
+fn chat_request(request: &Value, messages: Vec<Value>) -> Value {
+    let mut chat = Map::new();
+    chat.insert("model".to_string(), json!(model_of(request).unwrap_or_default()));
+    chat.insert("messages".to_string(), Value::Array(messages));
+    if let Some(options) = request.get("options").and_then(Value::as_object) {
+        for (from, to) in [
+            ("temperature", "temperature"),
+            ("top_p", "top_p"),
+            ("num_predict", "max_tokens"),
+            ("stop", "stop"),
+            ("seed", "seed"),
+            ("presence_penalty", "presence_penalty"),
+            ("frequency_penalty", "frequency_penalty"),
+        ] {
+            if let Some(value) = options.get(from) {
+                chat.insert(to.to_string(), value.clone());
+            }
+        }
+    }
+    match request.get("format") {
+        Some(Value::String(format)) if format == "json" => {
+            chat.insert("response_format".to_string(), json!({ "type": "json_object" }));
+        }
+        Some(schema @ Value::Object(_)) => {
+            chat.insert("response_format".to_string(), json!({
+                "type": "json_schema",
+                "json_schema": { "name": "response", "schema": schema },
+            }));
+        }
+        _ => {}
+    }
+    if let Some(tools) = request.get("tools") {
+        chat.insert("tools".to_string(), tools.clone());
+    }
+    // Ollama streams unless told otherwise
+    if request.get("stream").and_then(Value::as_bool).unwrap_or(true) {
+        chat.insert("stream".to_string(), json!(true));
+        chat.insert("stream_options".to_string(), json!({ "include_usage": true }));
+    }
+    Value::Object(chat)
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Chat,
+    Generate,
+}
+
+async fn complete(
+    gateway: Arc<Gateway>,
+    route: Arc<RouteConfig>,
+    parts: axum::http::request::Parts,
+    chat: Value,
+    request: &Value,
+    kind: Kind,
+    started: Instant,
+) -> Result<Response, ApiError> {
+    let model = model_of(request).unwrap_or_default().to_string();
+    let stream = chat.get("stream").and_then(Value::as_bool).unwrap_or(false);
+    let response = handle_request(translated_request(parts, CHAT_PATH, &chat), gateway, route).await?;
+    // error::dialect gives upstream errors the Ollama shape
+    if !response.status().is_success() {
+        return Err(upstream_error(response).await);
+    }
+    if stream {
+        return Ok(sse::relay(response, Lines::new(model, kind, started), NDJSON));
+    }
+
+    let body = response.into_body().collect().await
+        .map_err(|e| ApiError::internal(format!("Failed to read response body: {}", e)))?
+        .to_bytes();
+    let completion: Value = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::internal(format!("Invalid chat completion: {}", e)))?;
+    let choice = &completion["choices"][0];
+    let text = choice["message"]["content"].as_str().unwrap_or_default();
+    let mut answer = json!({ "model": model, "created_at": now() });
+    match kind {
+        Kind::Chat => {
+            let mut message = json!({ "role": "assistant", "content": text });
+            let calls = tool_calls(&choice["message"]["tool_calls"]);
+            if !calls.is_empty() {
+                message["tool_calls"] = Value::Array(calls);
+            }
+            answer["message"] = message;
+        }
+        Kind::Generate => answer["response"] = json!(text),
+    }
+    done(&mut answer, choice["finish_reason"].as_str(), &completion["usage"], started);
+    Ok(Json(answer).into_response())
+}
+
+async fn embed_request(
+    gateway: Arc<Gateway>,
+    route: Arc<RouteConfig>,
+    parts: axum::http::request::Parts,
+    body: Value,
+) -> Result<Value, ApiError> {
+    let response = handle_request(translated_request(parts, EMBEDDINGS_PATH, &body), gateway, route).await?;
+    if !response.status().is_success() {
+        return Err(upstream_error(response).await);
+    }
+    let body = response.into_body().collect().await
+        .map_err(|e| ApiError::internal(format!("Failed to read response body: {}", e)))?
+        .to_bytes();
+    serde_json::from_slice(&body).map_err(|e| ApiError::internal(format!("Invalid embedding response: {}", e)))
+}
+
+// OpenAI tool calls to Ollama ones, with parsed arguments
+fn tool_calls(calls: &Value) -> Vec<Value> {
+    calls.as_array()
+        .into_iter()
+        .flatten()
+        .map(|call| {
+            let arguments = call["function"]["arguments"].as_str().unwrap_or("{}");
+            json!({
+                "function": {
+                    "name": call["function"]["name"],
+                    "arguments": serde_json::from_str::<Value>(arguments).unwrap_or_else(|_| json!({})),
+                },
+            })
+        })
+        .collect()
+}
+
+// Fields of the last object of an answer, durations are in nanoseconds
+fn done(answer: &mut Value, finish_reason: Option<&str>, usage: &Value, started: Instant) {
+    answer["done"] = json!(true);
+    answer["done_reason"] = json!(match finish_reason {
+        Some("length") => "length",
+        _ => "stop",
+    });
+    answer["total_duration"] = json!(started.elapsed().as_nanos() as u64);
+    answer["load_duration"] = json!(0);
+    answer["prompt_eval_count"] = json!(usage["prompt_tokens"].as_u64().unwrap_or(0));
+    answer["eval_count"] = json!(usage["completion_tokens"].as_u64().unwrap_or(0));
+}
+
+fn details() -> Value {
+    json!({
+        "format": "gguf",
+        "family": "",
+        "families": null,
+        "parameter_size": "",
+        "quantization_level": "",
+    })
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true)
+}
+
+fn timestamp(secs: u64) -> String {
+    DateTime::<Utc>::from_timestamp(secs as i64, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// Newline delimited JSON of a chat completion stream
+struct Lines {
+    model: String,
+    kind: Kind,
+    started: Instant,
+    finish_reason: Option<String>,
+    usage: Value,
+    // tool call deltas, sent as a whole once complete
+    calls: Vec<Value>,
+    finished: bool,
+}
+
+impl Lines {
+    fn new(model: String, kind: Kind, started: Instant) -> Self {
+        Self {
+            model,
+            kind,
+            started,
+            finish_reason: None,
+            usage: Value::Null,
+            calls: Vec::new(),
+            finished: false,
+        }
+    }
+
+    fn line(&self, text: &str, calls: Vec<Value>) -> Value {
+        let mut line = json!({ "model": self.model, "created_at": now() });
+        match self.kind {
+            Kind::Chat => {
+                let mut message = json!({ "role": "assistant", "content": text });
+                if !calls.is_empty() {
+                    message["tool_calls"] = Value::Array(calls);
+                }
+                line["message"] = message;
+            }
+            Kind::Generate => line["response"] = json!(text),
+        }
+        line["done"] = json!(false);
+        line
+    }
+}
+
+impl sse::Translate for Lines {
+    fn chunk(&mut self, data: &str) -> Vec<u8> {
+        let Ok(chunk) = serde_json::from_str::<Value>(data) else {
+            return Vec::new();
+        };
+        // an error ends the stream without a done line
+        if chunk["error"].is_object() {
+            self.finished = true;
+            return format!("{}\n", error_body(StatusCode::BAD_GATEWAY, &chunk["error"])).into_bytes();
+        }
+        if chunk["usage"].is_object() {
+            self.usage = chunk["usage"].clone();
+        }
+        let choice = &chunk["choices"][0];
+        if let Some(reason) = choice["finish_reason"].as_str() {
+            self.finish_reason = Some(reason.to_string());
+        }
+        for call in choice["delta"]["tool_calls"].as_array().into_iter().flatten() {
+            let index = call["index"].as_u64().unwrap_or(0) as usize;
+            if self.calls.len() <= index {
+                self.calls.resize(index + 1, json!({ "function": { "name": "", "arguments": "" } }));
+            }
+            let function = &mut self.calls[index]["function"];
+            for field in ["name", "arguments"] {
+                if let Some(part) = call["function"][field].as_str() {
+                    let joined = format!("{}{}", function[field].as_str().unwrap_or_default(), part);
+                    function[field] = json!(joined);
+                }
+            }
+        }
+        match choice["delta"]["content"].as_str().filter(|t| !t.is_empty()) {
+            Some(text) => format!("{}\n", self.line(text, Vec::new())).into_bytes(),
+            None => Vec::new(),
+        }
+    }
+
+    fn finish(&mut self) -> Vec<u8> {
+        if self.finished {
+            return Vec::new();
+        }
+        self.finished = true;
+        let mut out = Vec::new();
+        if !self.calls.is_empty() {
+            let calls = tool_calls(&Value::Array(std::mem::take(&mut self.calls)));
+            out.extend(format!("{}\n", self.line("", calls)).into_bytes());
+        }
+        let mut last = self.line("", Vec::new());
+        done(&mut last, self.finish_reason.as_deref(), &self.usage, self.started);
+        out.extend(format!("{}\n", last).into_bytes());
+        out
+    }
+}
+
+// Error bodies as Ollama shapes them, `{"error":"message"}`
+pub fn error_body(_status: StatusCode, error: &Value) -> Value {
+    json!({ "error": error["message"] })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sse::Translate;
+
+    fn lines(out: &[u8]) -> Vec<Value> {
+        String::from_utf8_lossy(out).lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+    }
+
+    #[test]
+    fn messages_are_translated() {
+        let message = json!({
+            "role": "assistant",
+            "content": "Look",
+            "images": ["AAAA"],
+            "tool_calls": [{ "function": { "name": "add", "arguments": { "a": 2 } } }],
+        });
+        assert_eq!(chat_message(&message), json!({
+            "role": "assistant",
+            "content": [
+                { "type": "text", "text": "Look" },
+                { "type": "image_url", "image_url": { "url": "data:image/png;base64,AAAA" } },
+            ],
+            "tool_calls": [{ "id": "call_0", "type": "function", "function": { "name": "add", "arguments": "{\"a\":2}" } }],
+        }));
+        assert_eq!(chat_message(&json!({ "content": "hi" })), json!({ "role": "user", "content": "hi" }));
+    }
+
+    #[test]
+    fn options_and_format_are_translated() {
+        let request = json!({
+            "model": "m",
+            "options": { "temperature": 0.5, "num_predict": 32, "stop": ["END"], "mirostat": 1 },
+            "format": "json",
+        });
+        let messages = vec![json!({ "role": "user", "content": "hi" })];
+        assert_eq!(chat_request(&request, messages.clone()), json!({
+            "model": "m",
+            "messages": messages,
+            "temperature": 0.5,
+            "max_tokens": 32,
+            "stop": ["END"],
+            "response_format": { "type": "json_object" },
+            "stream": true,
+            "stream_options": { "include_usage": true },
+        }));
+
+        let schema = json!({ "type": "object" });
+        let request = json!({ "model": "m", "format": schema, "stream": false });
+        let chat = chat_request(&request, vec![]);
+        assert_eq!(chat["response_format"]["json_schema"]["schema"], schema);
+        assert!(chat.get("stream").is_none());
+    }
+
+    #[test]
+    fn tool_call_arguments_are_parsed() {
+        let calls = json!([{ "id": "c", "function": { "name": "add", "arguments": "{\"a\":2}" } }]);
+        assert_eq!(tool_calls(&calls), [json!({ "function": { "name": "add", "arguments": { "a": 2 } } })]);
+        let calls = json!([{ "function": { "name": "add", "arguments": "not json" } }]);
+        assert_eq!(tool_calls(&calls)[0]["function"]["arguments"], json!({}));
+    }
+
+    #[test]
+    fn chat_stream_is_translated_to_lines() {
+        let mut stream = Lines::new("m".to_string(), Kind::Chat, Instant::now());
+        let mut out = Vec::new();
+        for data in [
+            r#"{"choices":[{"delta":{"content":"Hi"}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"name":"add","arguments":"{\"a\""}}]}}]}"#,
+            r#"{"choices":[{"delta":{"tool_calls":[{"index":0,"function":{"arguments":":2}"}}]},"finish_reason":"length"}]}"#,
+            r#"{"choices":[],"usage":{"prompt_tokens":6,"completion_tokens":3}}"#,
+            "[DONE]",
+        ] {
+            out.extend(stream.chunk(data));
+        }
+        out.extend(stream.finish());
+        let lines = lines(&out);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["message"]["content"], "Hi");
+        assert_eq!(lines[0]["done"], false);
+        assert_eq!(lines[1]["message"]["tool_calls"], json!([{ "function": { "name": "add", "arguments": { "a": 2 } } }]));
+        let last = &lines[2];
+        assert_eq!((&last["done"], &last["done_reason"]), (&json!(true), &json!("length")));
+        assert_eq!((&last["prompt_eval_count"], &last["eval_count"]), (&json!(6), &json!(3)));
+        assert!(stream.finish().is_empty());
+    }
+
+    #[test]
+    fn generate_stream_uses_response() {
+        let mut stream = Lines::new("m".to_string(), Kind::Generate, Instant::now());
+        let line = lines(&stream.chunk(r#"{"choices":[{"delta":{"content":"Hi"}}]}"#));
+        assert_eq!(line[0]["response"], "Hi");
+        assert!(line[0].get("message").is_none());
+    }
+
+    #[test]
+    fn stream_error_ends_without_done() {
+        let mut stream = Lines::new("m".to_string(), Kind::Chat, Instant::now());
+        let line = lines(&stream.chunk(r#"{"error":{"message":"Request timed out"}}"#));
+        assert_eq!(line, [json!({ "error": "Request timed out" })]);
+        assert!(stream.finish().is_empty());
+    }
+}
@@ -0,0 +1,472 @@
+use auth::ApiKey;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use config::RateLimitConfig;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::error::ApiError;
+
+// Idle budgets are dropped this often
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Who a budget belongs to
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum Subject {
+    Key(String),
+    Ip(IpAddr),
+}
+
+// Requests and tokens per minute, refilled continuously
+struct Bucket {
+    limit: f64,
+    available: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(limit: u32) -> Self {
+        Self {
+            limit: limit as f64,
+            available: limit as f64,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, limit: u32) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.limit = limit as f64;
+        self.available = (self.available + elapsed * self.limit / 60.0).min(self.limit);
+        self.updated = now;
+    }
+
+    // Time until the bucket is full again
+    fn reset(&self) -> Duration {
+        if self.limit <= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(((self.limit - self.available) * 60.0 / self.limit).max(0.0))
+    }
+
+    fn is_full(&self) -> bool {
+        self.available >= self.limit
+    }
+}
+
+#[derive(Default)]
+struct Budget {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+// Limits of a subject, None means unlimited
+#[derive(Clone, Copy)]
+struct Limits {
+    requests: Option<u32>,
+    tokens: Option<u32>,
+}
+
+// Remaining budget of a subject, sent as x-ratelimit-* headers
+struct Snapshot {
+    requests: Option<(u32, u32, Duration)>,
+    tokens: Option<(u32, u32, Duration)>,
+}
+
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    budgets: Mutex<HashMap<Subject, Budget>>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            budgets: Mutex::new(HashMap::new()),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn limits(&self, key: Option<&ApiKey>) -> Limits {
+        match key {
+            Some(key) => Limits {
+                requests: key.requests_per_minute,
+                tokens: key.tokens_per_minute,
+            },
+            None => Limits {
+                requests: (self.config.requests_per_minute > 0).then_some(self.config.requests_per_minute),
+                tokens: (self.config.tokens_per_minute > 0).then_some(self.config.tokens_per_minute),
+            },
+        }
+    }
+
+    // Take one request from the budget, the snapshot tells which limit was hit otherwise
+    fn acquire(&self, subject: &Subject, limits: Limits) -> Result<Snapshot, (Snapshot, &'static str)> {
+        self.sweep();
+        let mut budgets = self.budgets.lock().unwrap();
+        let budget = budgets.entry(subject.clone()).or_default();
+        refill(&mut budget.requests, limits.requests);
+        refill(&mut budget.tokens, limits.tokens);
+
+        // tokens are debited after the response, a budget in debt blocks new requests
+        if budget.requests.as_ref().is_some_and(|b| b.available < 1.0) {
+            return Err((snapshot(budget), "requests"));
+        }
+        if budget.tokens.as_ref().is_some_and(|b| b.available <= 0.0) {
+            return Err((snapshot(budget), "tokens"));
+        }
+        if let Some(bucket) = &mut budget.requests {
+            bucket.available -= 1.0;
+        }
+        Ok(snapshot(budget))
+    }
+
+    fn debit_tokens(&self, subject: &Subject, tokens: u64) {
+        let mut budgets = self.budgets.lock().unwrap();
+        if let Some(bucket) = budgets.get_mut(subject).and_then(|b| b.tokens.as_mut()) {
+            bucket.available -= tokens as f64;
+        }
+    }
+
+    // Drop budgets that refilled completely, they are the same as new ones
+    fn sweep(&self) {
+        let mut last_sweep = self.last_sweep.lock().unwrap();
+        if last_sweep.elapsed() < SWEEP_INTERVAL {
+            return;
+        }
+        *last_sweep = Instant::now();
+        let mut budgets = self.budgets.lock().unwrap();
+        budgets.retain(|_, budget| {
+            for bucket in [&mut budget.requests, &mut budget.tokens].into_iter().flatten() {
+                let limit = bucket.limit as u32;
+                bucket.refill(limit);
+            }
+            ![&budget.requests, &budget.tokens].into_iter().flatten().all(Bucket::is_full)
+        });
+    }
+}
+
+fn refill(bucket: &mut Option<Bucket>, limit: Option<u32>) {
+    match (bucket.as_mut(), limit) {
+        (Some(b), Some(limit)) => b.refill(limit),
+        (None, Some(limit)) => *bucket = Some(Bucket::new(limit)),
+        (_, None) => *bucket = None,
+    }
+}
+
+fn snapshot(budget: &Budget) -> Snapshot {
+    let state = |b: &Bucket| (b.limit as u32, b.available.max(0.0) as u32, b.reset());
+    Snapshot {
+        requests: budget.requests.as_ref().map(state),
+        tokens: budget.tokens.as_ref().map(state),
+    }
+}
+
+// Enforce the budget of the API key, or of the client IP for callers without one
+pub async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let key = req.extensions().get::<ApiKey>().cloned();
+    let limits = limiter.limits(key.as_ref());
+    if limits.requests.is_none() && limits.tokens.is_none() {
+        return next.run(req).await;
+    }
+    let subject = match &key {
+        Some(key) => Subject::Key(key.key.clone()),
+        None => Subject::Ip(addr.ip()),
+    };
+
+    let snapshot = match limiter.acquire(&subject, limits) {
+        Ok(snapshot) => snapshot,
+        Err((snapshot, kind)) => {
+            debug!("Rate limit reached for {} of {}", kind, addr);
+            let mut response = ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Rate limit reached for {}", kind),
+            )
+            .with_code("rate_limit_exceeded")
+            .into_response();
+            set_headers(response.headers_mut(), &snapshot);
+            return response;
+        }
+    };
+
+    let mut response = next.run(req).await;
+    set_headers(response.headers_mut(), &snapshot);
+    if limits.tokens.is_none() {
+        return response;
+    }
+    // usage the handler counted itself, such as the tools of the MCP server
+    if let Some(TokenUsage(tokens)) = response.extensions().get::<TokenUsage>() {
+        limiter.debit_tokens(&subject, *tokens);
+        return response;
+    }
+
+    // debit the usage reported by the backend once it passes by
+    let (parts, body) = response.into_parts();
+    let content_type = parts.headers.get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let scanner = if content_type.contains("event-stream") {
+        UsageScanner::new(Format::Events)
+    } else if content_type.contains("ndjson") {
+        UsageScanner::new(Format::Lines)
+    } else if content_type.contains("json") {
+        UsageScanner::new(Format::Json)
+    } else {
+        return Response::from_parts(parts, body);
+    };
+    let scanner = Arc::new(Mutex::new(scanner));
+    let recorder = scanner.clone();
+    let body = body.into_data_stream().inspect(move |chunk| {
+        if let Ok(chunk) = chunk {
+            recorder.lock().unwrap().push(chunk);
+        }
+    });
+    // debited once the body is over
+    let debit = futures::stream::once(async move {
+        if let Some(tokens) = scanner.lock().unwrap().finish() {
+            limiter.debit_tokens(&subject, tokens);
+        }
+    })
+    .filter_map(|_| async { None::<Result<axum::body::Bytes, axum::Error>> });
+    Response::from_parts(parts, Body::from_stream(body.chain(debit)))
+}
+
+fn set_headers(headers: &mut HeaderMap, snapshot: &Snapshot) {
+    for (name, state) in [("requests", &snapshot.requests), ("tokens", &snapshot.tokens)] {
+        let Some((limit, remaining, reset)) = state else {
+            continue;
+        };
+        let values = [
+            (format!("x-ratelimit-limit-{}", name), limit.to_string()),
+            (format!("x-ratelimit-remaining-{}", name), remaining.to_string()),
+            (format!("x-ratelimit-reset-{}", name), format_reset(*reset)),
+        ];
+        for (header, value) in values {
+            if let (Ok(header), Ok(value)) = (header.parse::<HeaderName>(), HeaderValue::from_str(&value)) {
+                headers.insert(header, value);
+            }
+        }
+    }
+}
+
+// Durations as OpenAI formats them, such as `20ms`, `1s` or `6m0s`
+fn format_reset(reset: Duration) -> String {
+    let millis = reset.as_millis();
+    if millis < 1000 {
+        return format!("{}ms", millis);
+    }
+    let secs = reset.as_secs_f64().ceil() as u64;
+    if secs < 60 {
+        format!("{}s", secs)
+    } else {
+        format!("{}m{}s", secs / 60, secs % 60)
+    }
+}
+
+// Tokens a handler used, left in the response extensions when its body does not carry a usage
+#[derive(Clone, Copy)]
+pub struct TokenUsage(pub u64);
+
+// Finds the usage in a JSON body, in the events of a stream or in JSON lines
+struct UsageScanner {
+    format: Format,
+    buffer: Vec<u8>,
+    tokens: Option<u64>,
+    // input and output tokens of a Messages API stream, told in `message_start` then `message_delta`
+    messages: Option<(u64, u64)>,
+}
+
+#[derive(PartialEq)]
+enum Format {
+    Json,
+    Events,
+    Lines,
+}
+
+impl UsageScanner {
+    fn new(format: Format) -> Self {
+        Self { format, buffer: Vec::new(), tokens: None, messages: None }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        self.buffer.extend_from_slice(chunk);
+        // a JSON body is parsed once it is complete
+        if self.format == Format::Json {
+            return;
+        }
+        // complete lines, the last partial line is kept
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            self.line(&line);
+        }
+    }
+
+    // The tokens of the whole body, once it is over
+    fn finish(&mut self) -> Option<u64> {
+        let rest = std::mem::take(&mut self.buffer);
+        if self.format == Format::Json {
+            return serde_json::from_slice(&rest).ok().and_then(|value| usage_tokens(&value));
+        }
+        self.line(&rest);
+        let messages = self.messages.map(|(input, output)| input + output);
+        match (self.tokens, messages) {
+            (None, None) => None,
+            (tokens, messages) => Some(tokens.unwrap_or(0) + messages.unwrap_or(0)),
+        }
+    }
+
+    // A JSON line, or the `data:` line of an event stream
+    fn line(&mut self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let data = match self.format {
+            Format::Events => line.trim().strip_prefix("data:"),
+            _ => Some(line.trim()),
+        };
+        let Some(value) = data.and_then(|d| serde_json::from_str::<serde_json::Value>(d.trim()).ok()) else {
+            return;
+        };
+        // the counts of a Messages API stream are running totals, the last ones are kept
+        let usage = match value.get("type").and_then(|t| t.as_str()) {
+            Some("message_start") => Some(&value["message"]["usage"]),
+            Some("message_delta") => Some(&value["usage"]),
+            _ => None,
+        };
+        if let Some(usage) = usage {
+            let (input, output) = self.messages.unwrap_or_default();
+            let count = |field: &str, last: u64| usage.get(field).and_then(|t| t.as_u64()).unwrap_or(0).max(last);
+            self.messages = Some((count("input_tokens", input), count("output_tokens", output)));
+            return;
+        }
+        if let Some(found) = usage_tokens(&value) {
+            self.tokens = Some(self.tokens.unwrap_or(0) + found);
+        }
+    }
+}
+
+// `total_tokens` of OpenAI, the input and output tokens of the Messages API, or the eval counts of Ollama
+fn usage_tokens(value: &serde_json::Value) -> Option<u64> {
+    if value.get("done").and_then(|d| d.as_bool()) == Some(true) {
+        let prompt = value.get("prompt_eval_count").and_then(|t| t.as_u64());
+        let eval = value.get("eval_count").and_then(|t| t.as_u64());
+        return (prompt.is_some() || eval.is_some()).then(|| prompt.unwrap_or(0) + eval.unwrap_or(0));
+    }
+    usage_total(value.get("usage")?)
+}
+
+// `total_tokens` of an OpenAI usage, or the input and output tokens of the Messages API
+pub fn usage_total(usage: &serde_json::Value) -> Option<u64> {
+    if let Some(total) = usage.get("total_tokens").and_then(|t| t.as_u64()) {
+        return Some(total);
+    }
+    let input = usage.get("input_tokens").and_then(|t| t.as_u64());
+    let output = usage.get("output_tokens").and_then(|t| t.as_u64());
+    (input.is_some() || output.is_some()).then(|| input.unwrap_or(0) + output.unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan(format: Format, chunks: &[&str]) -> Option<u64> {
+        let mut scanner = UsageScanner::new(format);
+        for chunk in chunks {
+            scanner.push(chunk.as_bytes());
+        }
+        scanner.finish()
+    }
+
+    #[test]
+    fn requests_run_out_and_block() {
+        let limiter = RateLimiter::new(RateLimitConfig::default());
+        let subject = Subject::Key("sk-a".to_string());
+        let limits = Limits { requests: Some(2), tokens: None };
+        assert!(limiter.acquire(&subject, limits).is_ok());
+        let snapshot = limiter.acquire(&subject, limits).ok().unwrap();
+        assert_eq!(snapshot.requests.map(|(limit, remaining, _)| (limit, remaining)), Some((2, 0)));
+        let (_, kind) = limiter.acquire(&subject, limits).err().unwrap();
+        assert_eq!(kind, "requests");
+    }
+
+    #[test]
+    fn token_debt_blocks_until_refilled() {
+        let limiter = RateLimiter::new(RateLimitConfig::default());
+        let subject = Subject::Ip("127.0.0.1".parse().unwrap());
+        let limits = Limits { requests: None, tokens: Some(100) };
+        assert!(limiter.acquire(&subject, limits).is_ok());
+        limiter.debit_tokens(&subject, 150);
+        let (snapshot, kind) = limiter.acquire(&subject, limits).err().unwrap();
+        assert_eq!(kind, "tokens");
+        let (_, remaining, reset) = snapshot.tokens.unwrap();
+        assert_eq!(remaining, 0);
+        assert!(reset > Duration::from_secs(60));
+        // other subjects keep their own budget
+        assert!(limiter.acquire(&Subject::Key("sk-b".to_string()), limits).is_ok());
+    }
+
+    #[test]
+    fn buckets_refill_over_a_minute() {
+        let mut bucket = Bucket::new(60);
+        bucket.available = 0.0;
+        bucket.updated -= Duration::from_secs(10);
+        bucket.refill(60);
+        assert!((bucket.available - 10.0).abs() < 0.5);
+        bucket.updated -= Duration::from_secs(120);
+        bucket.refill(60);
+        assert!(bucket.is_full());
+        assert_eq!(bucket.reset(), Duration::ZERO);
+    }
+
+    #[test]
+    fn json_usage_is_read_once_complete() {
+        let body = r#"{"id":"c1","usage":{"prompt_tokens":5,"completion_tokens":4,"total_tokens":9}}"#;
+        let (head, tail) = body.split_at(20);
+        assert_eq!(scan(Format::Json, &[head, tail]), Some(9));
+        assert_eq!(scan(Format::Json, &[r#"{"input_tokens_only":1}"#]), None);
+        assert_eq!(scan(Format::Json, &[r#"{"usage":{"input_tokens":3,"output_tokens":4}}"#]), Some(7));
+    }
+
+    #[test]
+    fn stream_usage_is_read_from_data_lines() {
+        let chunks = [
+            "data: {\"choices\":[{\"delta\":{\"content\":\"hi\"}}]}\n\n",
+            "data: {\"choices\":[],\"usage\":{\"total",
+            "_tokens\":9}}\n\ndata: [DONE]\n\n",
+        ];
+        assert_eq!(scan(Format::Events, &chunks), Some(9));
+        assert_eq!(scan(Format::Events, &["data: [DONE]\n\n"]), None);
+    }
+
+    #[test]
+    fn messages_stream_counts_start_and_delta() {
+        let chunks = [
+            "event: message_start\ndata: {\"type\":\"message_start\",\"message\":{\"usage\":{\"input_tokens\":25,\"output_tokens\":1}}}\n\n",
+            "event: message_delta\ndata: {\"type\":\"message_delta\",\"usage\":{\"output_tokens\":15}}\n\n",
+            "event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n",
+        ];
+        assert_eq!(scan(Format::Events, &chunks), Some(40));
+    }
+
+    #[test]
+    fn ollama_lines_count_eval_tokens() {
+        let chunks = [
+            "{\"message\":{\"content\":\"hi\"},\"done\":false}\n",
+            "{\"done\":true,\"prompt_eval_count\":6,\"eval_count\":3}",
+        ];
+        assert_eq!(scan(Format::Lines, &chunks), Some(9));
+    }
+}
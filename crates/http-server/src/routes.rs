@@ -0,0 +1,201 @@
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{header, Method},
+    middleware::Next,
+    response::Response,
+    routing::{any, on, MethodFilter},
+    Router,
+};
+use config::{Capability, RouteConfig};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::error::ApiError;
+use crate::ollama;
+use crate::{handle_request, Gateway};
+
+pub const CHAT_PATH: &str = "/v1/chat/completions";
+pub const EMBEDDINGS_PATH: &str = "/v1/embeddings";
+
+// Paths of translated APIs and the route whose budget, body limit and capability they share
+const TRANSLATED: &[(&str, &str)] = &[
+    (crate::anthropic::MESSAGES_PATH, CHAT_PATH),
+    (ollama::CHAT, CHAT_PATH),
+    (ollama::GENERATE, CHAT_PATH),
+    (ollama::EMBEDDINGS, EMBEDDINGS_PATH),
+    (ollama::EMBED, EMBEDDINGS_PATH),
+    (crate::ws::PATH, CHAT_PATH),
+];
+
+// Path of the opt-in catch-all route
+pub const PASSTHROUGH_PATH: &str = "/v1/*path";
+
+// Paths answered by the gateway itself, besides the translated ones, a route of the table may not take them
+const RESERVED_PATHS: &[&str] = &["/v1/models", "/v1/models/:id", ollama::TAGS, ollama::SHOW, ollama::VERSION];
+
+// Proxy every route of the table to the backend, plus any other /v1 path when passthrough is on
+pub fn router(
+    routes: &[RouteConfig],
+    passthrough: bool,
+    gateway: &Arc<Gateway>,
+) -> Result<Router, Box<dyn std::error::Error>> {
+    let mut app = Router::new();
+    let mut paths = HashSet::new();
+    for route in routes {
+        let reserved = RESERVED_PATHS.iter().chain(TRANSLATED.iter().map(|(path, _)| path));
+        if reserved.into_iter().any(|path| *path == route.path) {
+            return Err(format!("Route {} is served by the gateway itself", route.path).into());
+        }
+        if !paths.insert(route.path.as_str()) {
+            return Err(format!("Route {} is defined twice", route.path).into());
+        }
+        let mut filter: Option<MethodFilter> = None;
+        for method in &route.methods {
+            let method = Method::from_bytes(method.to_uppercase().as_bytes())
+                .ok()
+                .and_then(|m| MethodFilter::try_from(m).ok())
+                .ok_or_else(|| format!("Unsupported method {} of route {}", method, route.path))?;
+            filter = Some(filter.map_or(method, |f| f.or(method)));
+        }
+        let Some(filter) = filter else {
+            return Err(format!("Route {} has no methods", route.path).into());
+        };
+        let route = Arc::new(route.clone());
+        let gateway = Arc::clone(gateway);
+        app = app.route(&route.path.clone(), on(filter, move |req| handle_request(req, gateway, route)));
+    }
+
+    if passthrough && !paths.contains(PASSTHROUGH_PATH) {
+        let route = RouteConfig {
+            path: PASSTHROUGH_PATH.to_string(),
+            methods: vec![],
+            capability: None,
+            timeout_secs: None,
+            body_limit: None,
+            semantic_threshold: None,
+        };
+        let route = Arc::new(route);
+        let gateway = Arc::clone(gateway);
+        app = app.route(PASSTHROUGH_PATH, any(move |req| handle_request(req, gateway, route)));
+    }
+    Ok(app)
+}
+
+// Body limits of the route table, by route path
+pub fn body_limits(routes: &[RouteConfig]) -> Arc<HashMap<String, usize>> {
+    let mut limits: HashMap<String, usize> = routes.iter()
+        .filter_map(|r| r.body_limit.map(|limit| (r.path.clone(), limit)))
+        .collect();
+    for (path, source) in TRANSLATED {
+        if let Some(limit) = limits.get(*source).copied() {
+            limits.insert(path.to_string(), limit);
+        }
+    }
+    Arc::new(limits)
+}
+
+// Route of a translated API, the one of the table it shares or a default with the same capability
+pub fn translated(routes: &[RouteConfig], path: &str) -> Arc<RouteConfig> {
+    let source = TRANSLATED.iter()
+        .find(|(p, _)| *p == path)
+        .map(|(_, source)| *source)
+        .unwrap_or(CHAT_PATH);
+    let route = routes.iter()
+        .find(|r| r.path == source)
+        .cloned()
+        .unwrap_or_else(|| {
+            let capability = if source == EMBEDDINGS_PATH { Capability::Embedding } else { Capability::Chat };
+            RouteConfig::new(source, "POST", Some(capability))
+        });
+    Arc::new(route)
+}
+
+// Refuse bodies over the limit of the route, before anything buffers them
+pub async fn limit_body(
+    State(limits): State<Arc<HashMap<String, usize>>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let limit = req.extensions()
+        .get::<MatchedPath>()
+        .and_then(|p| limits.get(p.as_str()))
+        .copied();
+    let Some(limit) = limit else {
+        return Ok(next.run(req).await);
+    };
+
+    let length = req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if length.is_some_and(|length| length > limit) {
+        return Err(ApiError::too_large(limit));
+    }
+    // a body without a length is cut off when it goes over
+    let req = req.map(|body| Body::new(http_body_util::Limited::new(body, limit)));
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::StatusCode;
+    use tower::ServiceExt;
+
+    // Nothing listens on the gRPC port, a proxied request is answered 503
+    fn app(routes: &[RouteConfig], passthrough: bool) -> Router {
+        router(routes, passthrough, &Gateway::for_tests("127.0.0.1:1", None, vec![])).unwrap()
+    }
+
+    async fn status(app: &Router, method: &str, path: &str) -> StatusCode {
+        let req = Request::builder().method(method).uri(path).body(Body::from("{}")).unwrap();
+        app.clone().oneshot(req).await.unwrap().status()
+    }
+
+    fn error(routes: &[RouteConfig]) -> String {
+        let gateway = Gateway::for_tests("127.0.0.1:1", None, vec![]);
+        router(routes, false, &gateway).err().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_tables() {
+        let route = |path: &str, method: &str| RouteConfig::new(path, method, None);
+        assert!(error(&[route("/v1/models", "GET")]).contains("served by the gateway"));
+        assert!(error(&[route(ollama::CHAT, "POST")]).contains("served by the gateway"));
+        assert!(error(&[route("/v1/a", "POST"), route("/v1/a", "GET")]).contains("defined twice"));
+        assert!(error(&[route("/v1/a", "FETCH ME")]).contains("Unsupported method"));
+        let methodless = RouteConfig { methods: vec![], ..route("/v1/a", "POST") };
+        assert!(error(&[methodless]).contains("no methods"));
+    }
+
+    #[tokio::test]
+    async fn routes_answer_their_methods_only() {
+        let app = app(&[RouteConfig::new("/v1/custom", "post", None)], false);
+        assert_eq!(status(&app, "POST", "/v1/custom").await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status(&app, "GET", "/v1/custom").await, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(status(&app, "POST", "/v1/other").await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn passthrough_proxies_any_other_v1_path() {
+        let app = app(&[RouteConfig::new("/v1/custom", "POST", None)], true);
+        assert_eq!(status(&app, "POST", "/v1/custom").await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status(&app, "GET", "/v1/other/path").await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status(&app, "DELETE", "/v1/other").await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status(&app, "POST", "/other").await, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn translated_apis_share_their_route() {
+        let chat = RouteConfig { body_limit: Some(10), ..RouteConfig::new(CHAT_PATH, "POST", None) };
+        let limits = body_limits(&[chat]);
+        assert_eq!(limits.get(ollama::CHAT), Some(&10));
+        assert_eq!(limits.get(crate::anthropic::MESSAGES_PATH), Some(&10));
+        assert_eq!(limits.get(ollama::EMBED), None);
+
+        let route = translated(&[], ollama::EMBED);
+        assert_eq!(route.path, EMBEDDINGS_PATH);
+        assert_eq!(route.capability, Some(Capability::Embedding));
+    }
+}
@@ -0,0 +1,224 @@
+use auth::ApiKey;
+use axum::{
+    body::{Body, Bytes},
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use futures::StreamExt;
+use http_body_util::BodyExt;
+use scheduler::cache::{self, Cached, CACHE_HEADER};
+use scheduler::semantic::SemanticIndex;
+use scheduler::{Scheduler, CAPABILITY_HEADER};
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use telemetry::metrics;
+use tracing::warn;
+
+use crate::error::ApiError;
+use crate::routes::{CHAT_PATH, EMBEDDINGS_PATH};
+
+// Paths whose prompts are embedded
+const PATHS: &[&str] = &[CHAT_PATH, "/v1/completions"];
+// Fields that are compared by similarity, or do not change the answer
+const PROMPT_FIELDS: &[&str] = &["messages", "prompt", "user"];
+// Similarity of the entry that answered a request
+pub const SIMILARITY_HEADER: &str = "x-assistant-cache-similarity";
+// Budget of embedding a prompt
+const EMBED_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct SemanticCache {
+    pub index: Arc<SemanticIndex>,
+    // Prompts are embedded by the local instances only
+    pub scheduler: Arc<Scheduler>,
+    pub embedding_model: String,
+    // Thresholds of the routes that set one
+    pub routes: HashMap<String, f32>,
+}
+
+// Answer chat and text completions from the answer of a similar prompt, and keep the new answers.
+// `cache-control: no-cache` skips the cached answer, `no-store` skips the cache altogether.
+pub async fn lookup(
+    State(cache): State<Arc<SemanticCache>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(path) = req.extensions().get::<MatchedPath>()
+        .map(|p| p.as_str())
+        .filter(|p| PATHS.contains(p))
+        .map(|p| p.to_string())
+    else {
+        return Ok(next.run(req).await);
+    };
+    let key = req.extensions().get::<ApiKey>().cloned();
+    let control = req.headers()
+        .get(header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_ascii_lowercase())
+        .unwrap_or_default();
+    if control.contains("no-store") {
+        metrics().semantic_cache_requests.with_label_values(&["bypass"]).inc();
+        return Ok(next.run(req).await);
+    }
+
+    let (parts, body) = req.into_parts();
+    let body = body.collect().await.map_err(ApiError::body)?.to_bytes();
+    let request = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Object(request)) => request,
+        _ => return Ok(next.run(Request::from_parts(parts, Body::from(body))).await),
+    };
+    let model = request.get("model").and_then(Value::as_str);
+    let threshold = cache.threshold(&path, model, key.as_deref());
+    let text = prompt_text(&request);
+    if threshold > 1.0 || text.is_empty() {
+        return Ok(next.run(Request::from_parts(parts, Body::from(body))).await);
+    }
+
+    let vector = match cache.embed(&text).await {
+        Ok(vector) => vector,
+        Err(e) => {
+            warn!("Failed to embed the prompt for the semantic cache: {}", e);
+            metrics().semantic_cache_requests.with_label_values(&["error"]).inc();
+            return Ok(next.run(Request::from_parts(parts, Body::from(body))).await);
+        }
+    };
+    let key_id = key.as_deref().map(auth::key_id);
+    let scope = scope(&path, key_id.as_deref(), &request);
+    if !control.contains("no-cache") {
+        if let Some((similarity, hit)) = cache.index.find(&scope, &vector, threshold) {
+            metrics().semantic_cache_requests.with_label_values(&["hit"]).inc();
+            return Ok(replay(hit, similarity));
+        }
+    }
+    metrics().semantic_cache_requests.with_label_values(&["miss"]).inc();
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+    let (parts, body) = response.into_parts();
+    let status = parts.status.as_u16();
+    let headers: HashMap<String, String> = parts.headers.get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| (header::CONTENT_TYPE.to_string(), v.to_string()))
+        .into_iter()
+        .collect();
+
+    if request.get("stream").and_then(Value::as_bool).unwrap_or(false) {
+        // kept once the stream is over, when it ran to its [DONE]
+        let chunks = Arc::new(Mutex::new(Some(Vec::new())));
+        let recorder = chunks.clone();
+        let body = body.into_data_stream().inspect(move |chunk| {
+            let mut chunks = recorder.lock().unwrap();
+            let text = chunk.as_ref().ok().and_then(|c| std::str::from_utf8(c).ok());
+            match (chunks.as_mut(), text) {
+                (Some(chunks), Some(text)) => chunks.push(text.to_string()),
+                _ => *chunks = None,
+            }
+        });
+        let stored = futures::stream::once(async move {
+            let chunks = chunks.lock().unwrap().take().filter(|c| cache::stream_complete(c));
+            if let Some(chunks) = chunks {
+                cache.index.put(scope, &vector, Cached { status, headers, chunks });
+            }
+        })
+        .filter_map(|_| async { None::<Result<Bytes, axum::Error>> });
+        return Ok(Response::from_parts(parts, Body::from_stream(body.chain(stored))));
+    }
+
+    let body = body.collect().await
+        .map_err(|e| ApiError::internal(format!("Failed to read response body: {}", e)))?
+        .to_bytes();
+    if let Ok(text) = std::str::from_utf8(&body) {
+        cache.index.put(scope, &vector, Cached { status, headers, chunks: vec![text.to_string()] });
+    }
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+impl SemanticCache {
+    // The most specific threshold: the key's, the model's, the route's, then the default one
+    fn threshold(&self, path: &str, model: Option<&str>, key: Option<&config::ApiKeyConfig>) -> f32 {
+        key.and_then(|k| k.semantic_threshold)
+            .or_else(|| model.and_then(|m| self.index.model_threshold(m)))
+            .or_else(|| self.routes.get(path).copied())
+            .unwrap_or_else(|| self.index.threshold())
+    }
+
+    async fn embed(&self, text: &str) -> Result<Vec<f32>, String> {
+        let body = json!({ "model": self.embedding_model, "input": text });
+        let headers = HashMap::from([
+            (header::CONTENT_TYPE.to_string(), "application/json".to_string()),
+            (CAPABILITY_HEADER.to_string(), "embedding".to_string()),
+        ]);
+        let (status, body, _) = self.scheduler
+            .forward_request(EMBEDDINGS_PATH, "POST", body.to_string().into_bytes(), headers, Some(EMBED_TIMEOUT))
+            .await
+            .map_err(|e| e.to_string())?;
+        if !(200..300).contains(&status) {
+            return Err(format!("embedding request answered {}", status));
+        }
+        let body: Value = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+        body["data"][0]["embedding"].as_array()
+            .map(|v| v.iter().filter_map(Value::as_f64).map(|x| x as f32).collect::<Vec<f32>>())
+            .filter(|v| !v.is_empty())
+            .ok_or_else(|| "embedding missing from the answer".to_string())
+    }
+}
+
+// The text compared by similarity, the messages of a chat or the prompt of a completion
+fn prompt_text(request: &Map<String, Value>) -> String {
+    if let Some(messages) = request.get("messages").and_then(Value::as_array) {
+        return messages.iter()
+            .map(|m| {
+                let content = match &m["content"] {
+                    Value::String(text) => text.clone(),
+                    Value::Array(parts) => parts.iter()
+                        .filter_map(|p| p["text"].as_str())
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    _ => String::new(),
+                };
+                format!("{}: {}", m["role"].as_str().unwrap_or_default(), content)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+    match request.get("prompt") {
+        Some(Value::String(prompt)) => prompt.clone(),
+        Some(Value::Array(prompts)) => prompts.iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => String::new(),
+    }
+}
+
+// Entries only answer requests of the same path and API key, with the same parameters
+fn scope(path: &str, key: Option<&str>, request: &Map<String, Value>) -> String {
+    let mut params = request.clone();
+    for field in PROMPT_FIELDS {
+        params.remove(*field);
+    }
+    format!("{} {} {}", path, key.unwrap_or_default(), Value::Object(params))
+}
+
+fn replay(hit: Cached, similarity: f32) -> Response {
+    let chunks = hit.chunks.into_iter().map(|c| Ok::<_, Infallible>(Bytes::from(c)));
+    let mut response = Response::new(Body::from_stream(futures::stream::iter(chunks)));
+    *response.status_mut() = StatusCode::from_u16(hit.status).unwrap_or(StatusCode::OK);
+    let headers = response.headers_mut();
+    for (name, value) in &hit.headers {
+        if let (Ok(name), Ok(value)) = (header::HeaderName::try_from(name.as_str()), HeaderValue::from_str(value)) {
+            headers.insert(name, value);
+        }
+    }
+    headers.insert(CACHE_HEADER, HeaderValue::from_static("semantic"));
+    if let Ok(value) = HeaderValue::from_str(&format!("{:.4}", similarity)) {
+        headers.insert(SIMILARITY_HEADER, value);
+    }
+    response
+}
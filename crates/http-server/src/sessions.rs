@@ -0,0 +1,244 @@
+use auth::ApiKey;
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Path, Request, State},
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::Response,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use futures::StreamExt;
+use http_body_util::BodyExt;
+use serde_json::{json, Map, Value};
+use scheduler::affinity::SESSION_HEADER;
+use session::{fit, SessionError, SessionStore};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use crate::error::ApiError;
+use crate::routes::CHAT_PATH;
+use crate::sse::{EventLines, Reply};
+use crate::{models, Gateway};
+
+pub const PATH: &str = "/v1/sessions";
+// Paths of the router, a route of the table may not take them
+pub const PATHS: &[&str] = &[PATH, "/v1/sessions/:id", "/v1/sessions/:id/fork"];
+
+pub struct Sessions {
+    pub store: Arc<SessionStore>,
+    pub gateway: Arc<Gateway>,
+    // Context window of models that do not report one
+    pub default_context_length: u32,
+}
+
+impl From<SessionError> for ApiError {
+    fn from(e: SessionError) -> Self {
+        match e {
+            SessionError::NotFound(_) => ApiError::new(StatusCode::NOT_FOUND, e.to_string())
+                .with_code("session_not_found"),
+            SessionError::InvalidId(_) | SessionError::Exists(_) => ApiError::new(StatusCode::BAD_REQUEST, e.to_string()),
+            _ => ApiError::internal(e.to_string()),
+        }
+    }
+}
+
+// Listing, fetching, forking and deleting the sessions of the caller's key
+pub fn router(store: Arc<SessionStore>) -> Router {
+    Router::new()
+        .route(PATH, get(list).post(create))
+        .route("/v1/sessions/:id", get(retrieve).delete(delete))
+        .route("/v1/sessions/:id/fork", post(fork))
+        .with_state(store)
+}
+
+// Chat completions with a session_id get the session history first, and the turn is added to it
+pub async fn attach(
+    State(sessions): State<Arc<Sessions>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if req.extensions().get::<MatchedPath>().map(|p| p.as_str()) != Some(CHAT_PATH) {
+        return Ok(next.run(req).await);
+    }
+    let owner = owner(req.extensions().get::<ApiKey>());
+    let (mut parts, body) = req.into_parts();
+    let body = body.collect().await.map_err(ApiError::body)?.to_bytes();
+    let mut request = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Object(request)) if request.contains_key("session_id") => request,
+        _ => return Ok(next.run(Request::from_parts(parts, Body::from(body))).await),
+    };
+    let Some(Value::String(id)) = request.remove("session_id") else {
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, "session_id must be a string"));
+    };
+
+    let session = sessions.store.get_or_create(&id, owner.as_deref())?;
+    let messages = request.get("messages").and_then(Value::as_array).cloned().unwrap_or_default();
+    let model = request.get("model").and_then(Value::as_str).map(|m| m.to_string());
+    let budget = sessions.budget(model.as_deref(), &request).await;
+    request.insert("messages".to_string(), Value::Array(fit(&session.messages, &messages, budget)));
+    let stream = request.get("stream").and_then(Value::as_bool).unwrap_or(false);
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    // the scheduler keeps the turns of a session on one instance
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        parts.headers.entry(SESSION_HEADER).or_insert(value);
+    }
+    let response = next.run(Request::from_parts(parts, Body::from(Value::Object(request).to_string()))).await;
+    if !response.status().is_success() {
+        return Ok(response);
+    }
+
+    let mut turn = Turn {
+        store: sessions.store.clone(),
+        id,
+        owner,
+        model,
+        messages,
+        reply: Reply::default(),
+    };
+    let (parts, body) = response.into_parts();
+    if stream {
+        // the turn is recorded once the stream is over, when it was not cut short
+        let turn = Arc::new(Mutex::new(turn));
+        let mut lines = EventLines::default();
+        let body = body.into_data_stream().inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                let mut turn = turn.lock().unwrap();
+                for data in lines.push(chunk) {
+                    if let Ok(chunk) = serde_json::from_str::<Value>(&data) {
+                        turn.reply.push(&chunk["choices"][0]);
+                    }
+                }
+            }
+        });
+        return Ok(Response::from_parts(parts, Body::from_stream(body)));
+    }
+
+    let body = body.collect().await
+        .map_err(|e| ApiError::internal(format!("Failed to read response body: {}", e)))?
+        .to_bytes();
+    if let Ok(completion) = serde_json::from_slice::<Value>(&body) {
+        let choice = &completion["choices"][0];
+        turn.reply.message = choice["message"].as_object().cloned().unwrap_or_default();
+        turn.reply.finished = choice["finish_reason"].is_string();
+    }
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+impl Sessions {
+    // Tokens the prompt may take, what is left of the context window after the reply
+    async fn budget(&self, model: Option<&str>, request: &Map<String, Value>) -> usize {
+        let context_length = match model {
+            Some(model) => models::local_models(&self.gateway).await
+                .into_iter()
+                .find(|m| m.serves(model))
+                .and_then(|m| m.context_length),
+            None => None,
+        }
+        .unwrap_or(self.default_context_length) as usize;
+        let reply = ["max_completion_tokens", "max_tokens"].iter()
+            .find_map(|k| request.get(*k).and_then(Value::as_u64))
+            .map(|t| t as usize)
+            .unwrap_or(context_length / 4);
+        context_length.saturating_sub(reply)
+    }
+}
+
+// A turn of a session, written to the store when it is dropped
+struct Turn {
+    store: Arc<SessionStore>,
+    id: String,
+    owner: Option<String>,
+    model: Option<String>,
+    messages: Vec<Value>,
+    reply: Reply,
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        if !self.reply.finished {
+            return;
+        }
+        let mut reply = std::mem::take(&mut self.reply.message);
+        reply.insert("role".to_string(), json!("assistant"));
+        reply.entry("content").or_insert(Value::Null);
+        let mut messages = std::mem::take(&mut self.messages);
+        messages.push(Value::Object(reply));
+        if let Err(e) = self.store.append(&self.id, self.owner.as_deref(), self.model.as_deref(), messages) {
+            warn!("Failed to record the turn of session {}: {}", self.id, e);
+        }
+    }
+}
+
+fn owner(key: Option<&ApiKey>) -> Option<String> {
+    key.map(|key| auth::key_id(key))
+}
+
+async fn list(State(store): State<Arc<SessionStore>>, key: Option<Extension<ApiKey>>) -> Json<Value> {
+    let owner = owner(key.as_deref());
+    let data: Vec<Value> = store.list(owner.as_deref())
+        .into_iter()
+        .map(|s| json!({
+            "id": s.id,
+            "object": "session",
+            "created_at": s.created_at,
+            "updated_at": s.updated_at,
+            "model": s.model,
+            "forked_from": s.forked_from,
+            "message_count": s.messages.len(),
+        }))
+        .collect();
+    Json(json!({ "object": "list", "data": data }))
+}
+
+async fn create(State(store): State<Arc<SessionStore>>, key: Option<Extension<ApiKey>>) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    Ok(Json(session_json(store.create(None, owner.as_deref())?)))
+}
+
+async fn retrieve(
+    State(store): State<Arc<SessionStore>>,
+    key: Option<Extension<ApiKey>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    Ok(Json(session_json(store.get(&id, owner.as_deref())?)))
+}
+
+// POST /v1/sessions/{id}/fork, `{"message_count": n}` keeps only the first n messages
+async fn fork(
+    State(store): State<Arc<SessionStore>>,
+    key: Option<Extension<ApiKey>>,
+    Path(id): Path<String>,
+    body: axum::body::Bytes,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    let count = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .and_then(|v| v["message_count"].as_u64())
+        .map(|n| n as usize);
+    Ok(Json(session_json(store.fork(&id, owner.as_deref(), count)?)))
+}
+
+async fn delete(
+    State(store): State<Arc<SessionStore>>,
+    key: Option<Extension<ApiKey>>,
+    Path(id): Path<String>,
+) -> Result<Json<Value>, ApiError> {
+    let owner = owner(key.as_deref());
+    store.delete(&id, owner.as_deref())?;
+    Ok(Json(json!({ "id": id, "object": "session", "deleted": true })))
+}
+
+fn session_json(session: session::Session) -> Value {
+    json!({
+        "id": session.id,
+        "object": "session",
+        "created_at": session.created_at,
+        "updated_at": session.updated_at,
+        "model": session.model,
+        "forked_from": session.forked_from,
+        "messages": session.messages,
+    })
+}
@@ -0,0 +1,110 @@
+use axum::{
+    body::Body,
+    http::{header, HeaderValue},
+    response::Response,
+};
+use futures::StreamExt;
+use serde_json::{json, Map, Value};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::debug;
+
+// Payloads of the `data:` lines of an event stream, split across chunks as they arrive
+#[derive(Default)]
+pub struct EventLines {
+    buffer: Vec<u8>,
+}
+
+impl EventLines {
+    // Complete `data:` payloads of the chunk, the last partial line is kept for the next one
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(chunk);
+        let mut payloads = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if let Some(data) = line.trim().strip_prefix("data:") {
+                payloads.push(data.trim().to_string());
+            }
+        }
+        payloads
+    }
+}
+
+// The assistant message, put together from the deltas of a stream
+#[derive(Default)]
+pub struct Reply {
+    pub message: Map<String, Value>,
+    pub finished: bool,
+}
+
+impl Reply {
+    pub fn push(&mut self, choice: &Value) {
+        let delta = &choice["delta"];
+        if let Some(text) = delta["content"].as_str() {
+            let content = self.message.entry("content").or_insert_with(|| json!(""));
+            if let Value::String(content) = content {
+                content.push_str(text);
+            }
+        }
+        // tool calls come as fragments of the call at their index
+        for call in delta["tool_calls"].as_array().into_iter().flatten() {
+            let index = call["index"].as_u64().unwrap_or(0) as usize;
+            let calls = self.message.entry("tool_calls").or_insert_with(|| json!([]));
+            let Value::Array(calls) = calls else { continue };
+            while calls.len() <= index {
+                calls.push(json!({ "id": "", "type": "function", "function": { "name": "", "arguments": "" } }));
+            }
+            let entry = &mut calls[index];
+            if let Some(id) = call["id"].as_str() {
+                entry["id"] = json!(id);
+            }
+            for field in ["name", "arguments"] {
+                if let Some(part) = call["function"][field].as_str() {
+                    let text = entry["function"][field].as_str().unwrap_or_default().to_string() + part;
+                    entry["function"][field] = json!(text);
+                }
+            }
+        }
+        if choice["finish_reason"].is_string() {
+            self.finished = true;
+        }
+    }
+}
+
+// Rewrites the payloads of a chat completion stream into another streaming format
+pub trait Translate: Send + 'static {
+    // Output of one `data:` payload
+    fn chunk(&mut self, data: &str) -> Vec<u8>;
+    // Output once the upstream stream ended
+    fn finish(&mut self) -> Vec<u8>;
+}
+
+// Relay an event stream response through a translation
+pub fn relay(response: Response, mut translate: impl Translate, content_type: &'static str) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<Vec<u8>, std::io::Error>>(4);
+    tokio::spawn(async move {
+        let mut stream = body.into_data_stream();
+        let mut lines = EventLines::default();
+        while let Some(chunk) = stream.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    debug!("Stream error: {}", e);
+                    break;
+                }
+            };
+            let out: Vec<u8> = lines.push(&chunk)
+                .iter()
+                .flat_map(|data| translate.chunk(data))
+                .collect();
+            if !out.is_empty() && tx.send(Ok(out)).await.is_err() {
+                return;
+            }
+        }
+        let _ = tx.send(Ok(translate.finish())).await;
+    });
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    Response::from_parts(parts, Body::from_stream(ReceiverStream::new(rx)))
+}
@@ -0,0 +1,357 @@
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{header, request::Parts, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use config::RouteConfig;
+use futures::{future::join_all, StreamExt};
+use http_body_util::BodyExt;
+use mcp::{McpError, McpServers, ToolOutput};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::debug;
+
+use crate::error::ApiError;
+use crate::routes::CHAT_PATH;
+use crate::sse::{EventLines, Reply};
+use crate::{handle_request, Gateway};
+
+type Chunks = Sender<Result<Vec<u8>, std::io::Error>>;
+
+pub struct Tools {
+    pub servers: Arc<McpServers>,
+    pub gateway: Arc<Gateway>,
+    // Route of the chat completions each round is sent to
+    pub route: Arc<RouteConfig>,
+}
+
+// The `mcp` field of a chat completion request, `true` or the servers whose tools are offered
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Selection {
+    All(bool),
+    Servers { servers: Vec<String> },
+}
+
+impl From<McpError> for ApiError {
+    fn from(e: McpError) -> Self {
+        match e {
+            McpError::Config(_) => ApiError::new(StatusCode::BAD_REQUEST, e.to_string()),
+            _ => ApiError::new(StatusCode::BAD_GATEWAY, e.to_string()),
+        }
+    }
+}
+
+// Chat completions with an `mcp` field are offered the tools of the MCP servers, the calls of
+// the model to them are run and answered until it replies without one
+pub async fn run(
+    State(tools): State<Arc<Tools>>,
+    req: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if req.extensions().get::<MatchedPath>().map(|p| p.as_str()) != Some(CHAT_PATH) {
+        return Ok(next.run(req).await);
+    }
+    let (mut parts, body) = req.into_parts();
+    let body = body.collect().await.map_err(ApiError::body)?.to_bytes();
+    let mut request = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Object(request)) if request.contains_key("mcp") => request,
+        _ => return Ok(next.run(Request::from_parts(parts, Body::from(body))).await),
+    };
+    let selection: Selection = serde_json::from_value(request.remove("mcp").unwrap_or_default())
+        .map_err(|_| ApiError::new(StatusCode::BAD_REQUEST, "mcp must be a boolean or {\"servers\": [...]}"))?;
+    parts.headers.remove(header::CONTENT_LENGTH);
+    let names = match selection {
+        Selection::All(false) => None,
+        Selection::All(true) => Some(None),
+        Selection::Servers { servers } => Some(Some(servers)),
+    };
+    let offered = match names {
+        Some(names) => tools.servers.tools(names.as_deref()).await?,
+        None => vec![],
+    };
+
+    // tools of the client keep their names, an MCP tool of the same name is left out
+    let mut functions = request.get("tools").and_then(Value::as_array).cloned().unwrap_or_default();
+    let taken: HashSet<String> = functions.iter()
+        .filter_map(|t| t["function"]["name"].as_str())
+        .map(String::from)
+        .collect();
+    let mut mcp_functions = HashSet::new();
+    for tool in offered.into_iter().filter(|t| !taken.contains(&t.function)) {
+        functions.push(tool.openai());
+        mcp_functions.insert(tool.function);
+    }
+    if mcp_functions.is_empty() {
+        return Ok(next.run(Request::from_parts(parts, Body::from(Value::Object(request).to_string()))).await);
+    }
+    request.insert("tools".to_string(), Value::Array(functions));
+
+    let stream = request.get("stream").and_then(Value::as_bool).unwrap_or(false);
+    let conversation = Conversation { tools, parts, request, functions: mcp_functions };
+    if stream {
+        conversation.stream().await
+    } else {
+        conversation.complete().await
+    }
+}
+
+// A chat request and the tool calls and results added to it round after round
+struct Conversation {
+    tools: Arc<Tools>,
+    parts: Parts,
+    request: Map<String, Value>,
+    // Function names run on MCP servers
+    functions: HashSet<String>,
+}
+
+impl Conversation {
+    // Rounds until the model answers, the answer gets the trace of the calls
+    async fn complete(mut self) -> Result<Response, ApiError> {
+        let rounds = self.tools.servers.max_rounds();
+        let mut trace = vec![];
+        let mut usage = Value::Null;
+        let mut round = 0;
+        loop {
+            round += 1;
+            let last = round >= rounds;
+            let response = self.send(last).await?;
+            if !response.status().is_success() {
+                return Ok(response);
+            }
+            let (mut parts, body) = response.into_parts();
+            let body = body.collect().await
+                .map_err(|e| ApiError::internal(format!("Failed to read response body: {}", e)))?
+                .to_bytes();
+            let Ok(Value::Object(mut completion)) = serde_json::from_slice::<Value>(&body) else {
+                return Ok(Response::from_parts(parts, Body::from(body)));
+            };
+            add_usage(&mut usage, completion.get("usage").unwrap_or(&Value::Null));
+            let message = completion.get("choices")
+                .and_then(|c| c[0]["message"].as_object())
+                .cloned()
+                .unwrap_or_default();
+            if !last && self.runnable(&message) {
+                trace.extend(calls(round, &message));
+                trace.extend(self.call(round, message).await);
+                continue;
+            }
+
+            if usage.is_object() {
+                completion.insert("usage".to_string(), usage);
+            }
+            if !trace.is_empty() {
+                completion.insert("tool_trace".to_string(), Value::Array(trace));
+            }
+            parts.headers.remove(header::CONTENT_LENGTH);
+            return Ok(Response::from_parts(parts, Body::from(Value::Object(completion).to_string())));
+        }
+    }
+
+    // Rounds as one event stream, an error of the first is answered as a whole
+    async fn stream(self) -> Result<Response, ApiError> {
+        let response = self.send(self.tools.servers.max_rounds() <= 1).await?;
+        if !response.status().is_success() {
+            return Ok(response);
+        }
+        let (mut parts, body) = response.into_parts();
+        let (tx, rx) = mpsc::channel(16);
+        tokio::spawn(self.relay(body, tx));
+        parts.headers.remove(header::CONTENT_LENGTH);
+        Ok(Response::from_parts(parts, Body::from_stream(ReceiverStream::new(rx))))
+    }
+
+    // Content is passed through as it comes, the tool calls of a round are held back until it
+    // is known whether the gateway runs them, then replaced by trace chunks
+    async fn relay(mut self, mut body: Body, tx: Chunks) {
+        let rounds = self.tools.servers.max_rounds();
+        let mut usage = Value::Null;
+        let mut round = 1;
+        loop {
+            let last = round >= rounds;
+            let mut reply = Reply::default();
+            let mut held = vec![];
+            let mut lines = EventLines::default();
+            let mut data = body.into_data_stream();
+            while let Some(chunk) = data.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        debug!("Stream error: {}", e);
+                        break;
+                    }
+                };
+                // [DONE] is sent once the last round is over
+                for chunk in lines.push(&chunk).iter().filter_map(|d| serde_json::from_str::<Value>(d).ok()) {
+                    let choice = &chunk["choices"][0];
+                    reply.push(choice);
+                    add_usage(&mut usage, &chunk["usage"]);
+                    let hold = choice["delta"]["tool_calls"].is_array()
+                        || choice["finish_reason"].is_string()
+                        || chunk["usage"].is_object();
+                    if hold {
+                        held.push(chunk);
+                    } else if !send(&tx, &chunk).await {
+                        return;
+                    }
+                }
+            }
+
+            if last || !reply.finished || !self.runnable(&reply.message) {
+                for mut chunk in held {
+                    if chunk["usage"].is_object() {
+                        chunk["usage"] = usage.clone();
+                    }
+                    if !send(&tx, &chunk).await {
+                        return;
+                    }
+                }
+                // a stream cut short is not ended as if it was complete
+                if reply.finished {
+                    let _ = tx.send(Ok(b"data: [DONE]\n\n".to_vec())).await;
+                }
+                return;
+            }
+            for event in calls(round, &reply.message) {
+                if !send(&tx, &trace_chunk(event)).await {
+                    return;
+                }
+            }
+            for event in self.call(round, reply.message).await {
+                if !send(&tx, &trace_chunk(event)).await {
+                    return;
+                }
+            }
+
+            round += 1;
+            let error = match self.send(round >= rounds).await {
+                Ok(response) if response.status().is_success() => {
+                    body = response.into_body();
+                    continue;
+                }
+                Ok(response) => match response.into_body().collect().await {
+                    Ok(body) => serde_json::from_slice::<Value>(&body.to_bytes())
+                        .unwrap_or_else(|_| ApiError::new(StatusCode::BAD_GATEWAY, "Upstream error").json()),
+                    Err(e) => ApiError::internal(format!("Failed to read response body: {}", e)).json(),
+                },
+                Err(e) => e.json(),
+            };
+            // the status was sent with the first round, later errors are a chunk of the stream
+            if send(&tx, &error).await {
+                let _ = tx.send(Ok(b"data: [DONE]\n\n".to_vec())).await;
+            }
+            return;
+        }
+    }
+
+    // One round, the last one may not call tools anymore
+    async fn send(&self, last: bool) -> Result<Response, ApiError> {
+        let mut request = self.request.clone();
+        if last {
+            request.insert("tool_choice".to_string(), json!("none"));
+        }
+        let req = Request::from_parts(self.parts.clone(), Body::from(Value::Object(request).to_string()));
+        handle_request(req, Arc::clone(&self.tools.gateway), Arc::clone(&self.tools.route)).await
+    }
+
+    // Whether the reply calls tools and all of them are MCP tools, other calls are for the client
+    fn runnable(&self, message: &Map<String, Value>) -> bool {
+        let calls = tool_calls(message);
+        !calls.is_empty() && calls.iter().all(|call| {
+            call["function"]["name"].as_str().is_some_and(|name| self.functions.contains(name))
+        })
+    }
+
+    // Run the calls of the reply, it and their results are added to the conversation
+    async fn call(&mut self, round: usize, mut message: Map<String, Value>) -> Vec<Value> {
+        let calls = tool_calls(&message);
+        let servers = &self.tools.servers;
+        let outputs = join_all(calls.iter().map(|call| async move {
+            let name = call["function"]["name"].as_str().unwrap_or_default();
+            servers.call(name, &arguments(call)).await
+        })).await;
+
+        message.insert("role".to_string(), json!("assistant"));
+        message.entry("content").or_insert(Value::Null);
+        self.push(Value::Object(message));
+        let mut events = vec![];
+        for (call, output) in calls.iter().zip(outputs) {
+            // a failed call is reported to the model, which may try another way
+            let output = output.unwrap_or_else(|e| ToolOutput {
+                content: format!("Tool call failed: {}", e),
+                is_error: true,
+            });
+            self.push(json!({ "role": "tool", "tool_call_id": call["id"], "content": output.content }));
+            events.push(json!({
+                "type": "result",
+                "round": round,
+                "id": call["id"],
+                "name": call["function"]["name"],
+                "content": output.content,
+                "is_error": output.is_error,
+            }));
+        }
+        events
+    }
+
+    fn push(&mut self, message: Value) {
+        if let Value::Array(messages) = self.request.entry("messages").or_insert_with(|| json!([])) {
+            messages.push(message);
+        }
+    }
+}
+
+// Trace events of the calls of a reply, before they run
+fn calls(round: usize, message: &Map<String, Value>) -> Vec<Value> {
+    tool_calls(message).iter()
+        .map(|call| json!({
+            "type": "call",
+            "round": round,
+            "id": call["id"],
+            "name": call["function"]["name"],
+            "arguments": arguments(call),
+        }))
+        .collect()
+}
+
+fn tool_calls(message: &Map<String, Value>) -> Vec<Value> {
+    message.get("tool_calls").and_then(Value::as_array).cloned().unwrap_or_default()
+}
+
+// The JSON arguments of a call, some servers send them as an object rather than a string
+fn arguments(call: &Value) -> String {
+    match &call["function"]["arguments"] {
+        Value::String(arguments) => arguments.clone(),
+        Value::Null => String::new(),
+        arguments => arguments.to_string(),
+    }
+}
+
+// Token counts of all rounds
+fn add_usage(total: &mut Value, usage: &Value) {
+    if !usage.is_object() {
+        return;
+    }
+    if !total.is_object() {
+        *total = usage.clone();
+        return;
+    }
+    for field in ["prompt_tokens", "completion_tokens", "total_tokens"] {
+        let sum = total[field].as_u64().unwrap_or(0) + usage[field].as_u64().unwrap_or(0);
+        total[field] = json!(sum);
+    }
+}
+
+fn trace_chunk(event: Value) -> Value {
+    json!({ "object": "chat.completion.chunk", "choices": [], "tool_trace": event })
+}
+
+async fn send(tx: &Chunks, chunk: &Value) -> bool {
+    tx.send(Ok(format!("data: {}\n\n", chunk).into_bytes())).await.is_ok()
+}
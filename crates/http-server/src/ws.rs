@@ -0,0 +1,289 @@
+use auth::ApiKey;
+use axum::{
+    body::Body,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Query, Request,
+    },
+    http::{header, HeaderMap, Method},
+    response::Response,
+    Extension, Router,
+};
+use futures::{SinkExt, StreamExt};
+use http_body_util::BodyExt;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
+use tower::ServiceExt;
+use tracing::debug;
+
+use crate::routes::CHAT_PATH;
+use crate::sse::EventLines;
+
+pub const PATH: &str = "/v1/chat/ws";
+// Subprotocol of browsers, which cannot set headers: `new WebSocket(url, ["bearer", key])`
+const BEARER_PROTOCOL: &str = "bearer";
+
+// Messages of the client, one chat turn runs at a time. The server answers with messages
+// of a `msg_type`: `stream` with the `content` delta, `done` or `error`
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ClientMessage {
+    // A chat completion request, always streamed back
+    Chat { id: Option<String>, request: Value },
+    // Stop the running turn, the one with this id when given
+    Cancel { id: Option<String> },
+}
+
+// What every turn of a connection is sent with
+struct Caller {
+    addr: SocketAddr,
+    authorization: Option<String>,
+    key: Option<ApiKey>,
+}
+
+// The API key of an upgrade request without an Authorization header, from the entry after
+// `bearer` in Sec-WebSocket-Protocol or from the `api_key` query parameter
+pub fn token(req: &Request) -> Option<String> {
+    let protocols = req.headers()
+        .get(header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let mut protocols = protocols.split(',').map(str::trim);
+    if protocols.any(|p| p == BEARER_PROTOCOL) {
+        if let Some(token) = protocols.next() {
+            return Some(token.to_string());
+        }
+    }
+    Query::<HashMap<String, String>>::try_from_uri(req.uri())
+        .ok()
+        .and_then(|Query(mut query)| query.remove("api_key"))
+}
+
+// GET /v1/chat/ws, the connection was authenticated by the upgrade request
+pub async fn upgrade(
+    turns: Router,
+    ws: WebSocketUpgrade,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    key: Option<Extension<ApiKey>>,
+) -> Response {
+    let caller = Caller {
+        addr,
+        authorization: headers.get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string()),
+        key: key.map(|Extension(key)| key),
+    };
+    // browsers drop the connection unless the protocol they offered is accepted
+    ws.protocols([BEARER_PROTOCOL]).on_upgrade(move |socket| session(socket, turns, caller))
+}
+
+async fn session(socket: WebSocket, turns: Router, caller: Caller) {
+    let (mut sink, mut stream) = socket.split();
+    let (tx, mut rx) = mpsc::channel::<Value>(32);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            if sink.send(Message::Text(message.to_string())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let caller = Arc::new(caller);
+    let mut turn: Option<(String, oneshot::Sender<()>)> = None;
+    let mut count = 0u64;
+    while let Some(Ok(message)) = stream.next().await {
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Binary(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let message = match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(message) => message,
+            Err(e) => {
+                let _ = tx.send(error(None, "invalid_message", &format!("Invalid message: {}", e))).await;
+                continue;
+            }
+        };
+        // a turn is over once it dropped its cancel receiver
+        if turn.as_ref().is_some_and(|(_, cancel)| cancel.is_closed()) {
+            turn = None;
+        }
+        match message {
+            ClientMessage::Chat { id, request } => {
+                count += 1;
+                let id = id.unwrap_or_else(|| format!("turn-{}", count));
+                if let Some((running, _)) = &turn {
+                    let message = format!("Turn {} is still running", running);
+                    let _ = tx.send(error(Some(&id), "turn_in_progress", &message)).await;
+                    continue;
+                }
+                let (cancel, cancelled) = oneshot::channel();
+                turn = Some((id.clone(), cancel));
+                let (turns, caller, tx) = (turns.clone(), caller.clone(), tx.clone());
+                tokio::spawn(async move {
+                    let mut cancelled = cancelled;
+                    let last = run_turn(turns, &caller, &id, request, &mut cancelled, &tx).await;
+                    // over before the client hears of it, so its next turn is accepted
+                    drop(cancelled);
+                    if let Some(last) = last {
+                        let _ = tx.send(last).await;
+                    }
+                });
+            }
+            ClientMessage::Cancel { id } => match turn.take() {
+                Some((running, cancel)) if id.as_ref().map_or(true, |id| *id == running) => {
+                    let _ = cancel.send(());
+                }
+                other => {
+                    turn = other;
+                    let _ = tx.send(error(id.as_deref(), "no_turn", "No such turn is running")).await;
+                }
+            },
+        }
+    }
+
+    // dropping the cancel sender stops the running turn
+    drop(turn);
+    drop(tx);
+    let _ = writer.await;
+}
+
+// Stream one chat completion back as `stream` messages, the final `done` or error is returned
+async fn run_turn(
+    turns: Router,
+    caller: &Caller,
+    id: &str,
+    mut request: Value,
+    cancelled: &mut oneshot::Receiver<()>,
+    tx: &mpsc::Sender<Value>,
+) -> Option<Value> {
+    let Some(body) = request.as_object_mut() else {
+        return Some(error(Some(id), "invalid_request", "request must be a JSON object"));
+    };
+    body.insert("stream".to_string(), Value::Bool(true));
+    body.insert("stream_options".to_string(), json!({ "include_usage": true }));
+
+    let mut req = Request::builder()
+        .method(Method::POST)
+        .uri(CHAT_PATH)
+        .header(header::CONTENT_TYPE, "application/json");
+    if let Some(authorization) = &caller.authorization {
+        req = req.header(header::AUTHORIZATION, authorization);
+    }
+    let mut req = req.body(Body::from(request.to_string())).expect("valid chat request");
+    req.extensions_mut().insert(ConnectInfo(caller.addr));
+    if let Some(key) = &caller.key {
+        req.extensions_mut().insert(key.clone());
+    }
+
+    let response = tokio::select! {
+        result = &mut *cancelled => return result.ok().map(|_| done(id, Some("cancelled"), None)),
+        response = turns.oneshot(req) => response.unwrap_or_else(|e| match e {}),
+    };
+    if !response.status().is_success() {
+        let body = response.into_body().collect().await
+            .map(|b| b.to_bytes())
+            .unwrap_or_default();
+        let error = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|v| v.get("error").cloned())
+            .unwrap_or_else(|| json!({ "message": String::from_utf8_lossy(&body) }));
+        return Some(json!({ "msg_type": "error", "id": id, "error": error }));
+    }
+
+    let mut body = response.into_body().into_data_stream();
+    let mut lines = EventLines::default();
+    let mut finish_reason: Option<String> = None;
+    let mut usage: Option<Value> = None;
+    loop {
+        let chunk = tokio::select! {
+            // a dropped sender means the connection is gone
+            result = &mut *cancelled => return result.ok().map(|_| done(id, Some("cancelled"), usage)),
+            chunk = body.next() => chunk,
+        };
+        let chunk = match chunk {
+            Some(Ok(chunk)) => chunk,
+            Some(Err(e)) => {
+                debug!("Stream error: {}", e);
+                return Some(error(Some(id), "stream_error", &e.to_string()));
+            }
+            None => break,
+        };
+        for data in lines.push(&chunk) {
+            let Ok(chunk) = serde_json::from_str::<Value>(&data) else {
+                continue;
+            };
+            if let Some(u) = chunk.get("usage").filter(|u| u.is_object()) {
+                usage = Some(u.clone());
+            }
+            let choice = &chunk["choices"][0];
+            if let Some(reason) = choice["finish_reason"].as_str() {
+                finish_reason = Some(reason.to_string());
+            }
+            let delta = &choice["delta"];
+            let content = delta["content"].as_str().unwrap_or_default();
+            let tool_calls = delta.get("tool_calls").filter(|t| t.is_array());
+            if content.is_empty() && tool_calls.is_none() {
+                continue;
+            }
+            let mut message = json!({ "msg_type": "stream", "id": id, "content": content });
+            if let Some(tool_calls) = tool_calls {
+                message["tool_calls"] = tool_calls.clone();
+            }
+            tx.send(message).await.ok()?;
+        }
+    }
+    Some(done(id, finish_reason.as_deref(), usage))
+}
+
+fn done(id: &str, finish_reason: Option<&str>, usage: Option<Value>) -> Value {
+    json!({ "msg_type": "done", "id": id, "finish_reason": finish_reason, "usage": usage })
+}
+
+fn error(id: Option<&str>, code: &str, message: &str) -> Value {
+    json!({
+        "msg_type": "error",
+        "id": id,
+        "error": { "message": message, "type": "invalid_request_error", "code": code },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade_request(uri: &str, protocols: Option<&str>) -> Request {
+        let mut req = Request::builder().uri(uri);
+        if let Some(protocols) = protocols {
+            req = req.header(header::SEC_WEBSOCKET_PROTOCOL, protocols);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn token_comes_from_the_protocols_or_the_query() {
+        assert_eq!(token(&upgrade_request(PATH, Some("bearer, sk-1"))).as_deref(), Some("sk-1"));
+        assert_eq!(token(&upgrade_request("/v1/chat/ws?api_key=sk-2", None)).as_deref(), Some("sk-2"));
+        // the protocols win over the query
+        assert_eq!(token(&upgrade_request("/v1/chat/ws?api_key=sk-2", Some("bearer,sk-1"))).as_deref(), Some("sk-1"));
+        assert_eq!(token(&upgrade_request(PATH, Some("chat, sk-1"))), None);
+        assert_eq!(token(&upgrade_request(PATH, Some("bearer"))), None);
+        assert_eq!(token(&upgrade_request(PATH, None)), None);
+    }
+
+    #[test]
+    fn messages_have_one_schema() {
+        assert_eq!(done("t1", Some("stop"), None), json!({
+            "msg_type": "done", "id": "t1", "finish_reason": "stop", "usage": null,
+        }));
+        let error = error(Some("t1"), "no_turn", "No such turn is running");
+        assert_eq!(error["msg_type"], "error");
+        assert!(error.get("type").is_none());
+    }
+}
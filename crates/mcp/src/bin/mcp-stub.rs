@@ -0,0 +1,99 @@
+// A minimal MCP server with `echo` and `add` tools, to try the gateway's tool calling locally.
+// It speaks stdio, or streamable HTTP with `--http <addr>`
+use axum::{http::StatusCode, response::{IntoResponse, Response}, routing::post, Json, Router};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.iter().position(|a| a == "--http").and_then(|i| args.get(i + 1)) {
+        Some(addr) => serve_http(addr).await,
+        None => serve_stdio().await,
+    }
+}
+
+async fn serve_stdio() -> std::io::Result<()> {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    let mut stdout = tokio::io::stdout();
+    while let Some(line) = lines.next_line().await? {
+        let Ok(request) = serde_json::from_str::<Value>(&line) else {
+            continue;
+        };
+        if let Some(response) = answer(&request) {
+            stdout.write_all(format!("{}\n", response).as_bytes()).await?;
+            stdout.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+async fn serve_http(addr: &str) -> std::io::Result<()> {
+    let app = Router::new().route("/", post(message));
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await
+}
+
+// Every POST carries one message, notifications are accepted without a body
+async fn message(Json(request): Json<Value>) -> Response {
+    match answer(&request) {
+        Some(response) => ([("mcp-session-id", "mcp-stub")], Json(response)).into_response(),
+        None => StatusCode::ACCEPTED.into_response(),
+    }
+}
+
+// The response to a request, notifications are not answered
+fn answer(request: &Value) -> Option<Value> {
+    let id = request.get("id").cloned()?;
+    Some(match handle(request["method"].as_str().unwrap_or_default(), &request["params"]) {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } }),
+    })
+}
+
+fn handle(method: &str, params: &Value) -> Result<Value, (i64, String)> {
+    match method {
+        "initialize" => Ok(json!({
+            "protocolVersion": mcp::PROTOCOL_VERSION,
+            "capabilities": { "tools": {} },
+            "serverInfo": { "name": "mcp-stub", "version": env!("CARGO_PKG_VERSION") },
+        })),
+        "ping" => Ok(json!({})),
+        "tools/list" => Ok(json!({ "tools": [
+            {
+                "name": "echo",
+                "description": "Returns the given text",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": { "text": { "type": "string" } },
+                    "required": ["text"],
+                },
+            },
+            {
+                "name": "add",
+                "description": "Adds two numbers",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": { "a": { "type": "number" }, "b": { "type": "number" } },
+                    "required": ["a", "b"],
+                },
+            },
+        ]})),
+        "tools/call" => {
+            let arguments = &params["arguments"];
+            match params["name"].as_str() {
+                Some("echo") => Ok(text(arguments["text"].as_str().unwrap_or_default(), false)),
+                Some("add") => match (arguments["a"].as_f64(), arguments["b"].as_f64()) {
+                    (Some(a), Some(b)) => Ok(text(&(a + b).to_string(), false)),
+                    _ => Ok(text("a and b must be numbers", true)),
+                },
+                _ => Err((-32602, format!("Unknown tool {}", params["name"]))),
+            }
+        }
+        _ => Err((-32601, format!("Method {} not found", method))),
+    }
+}
+
+fn text(text: &str, is_error: bool) -> Value {
+    json!({ "content": [{ "type": "text", "text": text }], "isError": is_error })
+}
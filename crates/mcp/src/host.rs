@@ -0,0 +1,461 @@
+use config::{ApiKeyConfig, Capability, LlamaServerConfig};
+use rag::RagStore;
+use scheduler::{Scheduler, ServiceInstance, CAPABILITY_HEADER};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use crate::PROTOCOL_VERSION;
+
+// Revisions a client may ask for, others are answered with ours
+const PROTOCOL_VERSIONS: &[&str] = &["2025-06-18", PROTOCOL_VERSION, "2024-11-05"];
+
+const CHAT_PATH: &str = "/v1/chat/completions";
+const EMBEDDINGS_PATH: &str = "/v1/embeddings";
+const MODELS_URI: &str = "assistant://models";
+const INSTANCES_URI: &str = "assistant://instances";
+
+// JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+type Outcome = Result<Value, (i64, String)>;
+// Text of a tool and the token usage of the model, the outer error for invalid arguments, the
+// inner one for a failure the model sees
+type ToolResult = Result<Result<(String, Option<Value>), String>, (i64, String)>;
+
+// The local models as an MCP server: chat, embed and search tools, and the models and
+// instances as resources, served by the same scheduler as the OpenAI API
+pub struct McpHost {
+    scheduler: Arc<Scheduler>,
+    // Configured models, instances started later are added to them
+    models: Vec<LlamaServerConfig>,
+    rag: Option<Arc<RagStore>>,
+    timeout: Duration,
+}
+
+impl McpHost {
+    pub fn new(scheduler: Arc<Scheduler>, models: Vec<LlamaServerConfig>, timeout: Duration) -> Self {
+        Self { scheduler, models, rag: None, timeout }
+    }
+
+    // Offer a search tool over the document collections
+    pub fn with_rag(mut self, store: Arc<RagStore>) -> Self {
+        self.rag = Some(store);
+        self
+    }
+
+    // Newline delimited messages on stdin and stdout, until stdin is closed
+    pub async fn serve_stdio(self: Arc<Self>) -> std::io::Result<()> {
+        let (tx, mut rx) = mpsc::channel::<Value>(16);
+        let writer = tokio::spawn(async move {
+            let mut stdout = tokio::io::stdout();
+            while let Some(message) = rx.recv().await {
+                let line = format!("{}\n", message);
+                if stdout.write_all(line.as_bytes()).await.is_err() || stdout.flush().await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Some(line) = lines.next_line().await? {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Ok(message) = serde_json::from_str::<Value>(&line) else {
+                let _ = tx.send(parse_error()).await;
+                continue;
+            };
+            // tool calls take a while, messages are answered as they finish
+            let (host, tx) = (self.clone(), tx.clone());
+            tokio::spawn(async move {
+                if let Some(response) = host.handle(message, None).await {
+                    let _ = tx.send(response).await;
+                }
+            });
+        }
+        drop(tx);
+        let _ = writer.await;
+        Ok(())
+    }
+
+    // The answer to a message or a batch of them, none for notifications and responses
+    pub async fn handle(&self, message: Value, key: Option<&ApiKeyConfig>) -> Option<Value> {
+        let Value::Array(batch) = message else {
+            return self.handle_one(message, key).await;
+        };
+        if batch.is_empty() {
+            return Some(error(Value::Null, INVALID_REQUEST, "Empty batch"));
+        }
+        let mut responses = vec![];
+        for message in batch {
+            responses.extend(self.handle_one(message, key).await);
+        }
+        (!responses.is_empty()).then_some(Value::Array(responses))
+    }
+
+    async fn handle_one(&self, message: Value, key: Option<&ApiKeyConfig>) -> Option<Value> {
+        let Some(method) = message["method"].as_str() else {
+            // responses to requests of ours, none are sent
+            return message.get("id").is_none().then(|| error(Value::Null, INVALID_REQUEST, "Not a JSON-RPC request"));
+        };
+        let Some(id) = message.get("id").cloned() else {
+            debug!("MCP notification {}", method);
+            return None;
+        };
+        let params = &message["params"];
+        let outcome = match method {
+            "initialize" => Ok(self.initialize(params)),
+            "ping" => Ok(json!({})),
+            "tools/list" => Ok(json!({ "tools": self.tools() })),
+            "tools/call" => self.call(params, key).await,
+            "resources/list" => Ok(self.resources(key).await),
+            "resources/templates/list" => Ok(json!({ "resourceTemplates": [{
+                "uriTemplate": format!("{}/{{id}}", INSTANCES_URI),
+                "name": "instance",
+                "description": "Status of a model instance",
+                "mimeType": "application/json",
+            }]})),
+            "resources/read" => self.read(params, key).await,
+            _ => Err((METHOD_NOT_FOUND, format!("Method {} not found", method))),
+        };
+        Some(match outcome {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err((code, message)) => error(id, code, &message),
+        })
+    }
+
+    fn initialize(&self, params: &Value) -> Value {
+        let version = params["protocolVersion"].as_str()
+            .filter(|v| PROTOCOL_VERSIONS.contains(v))
+            .unwrap_or(PROTOCOL_VERSION);
+        json!({
+            "protocolVersion": version,
+            "capabilities": { "tools": {}, "resources": {} },
+            "serverInfo": { "name": "assistant", "version": env!("CARGO_PKG_VERSION") },
+            "instructions": "Locally hosted models: chat with them, embed text, and search document collections.",
+        })
+    }
+
+    fn tools(&self) -> Vec<Value> {
+        let chat_models = self.model_names(|m| m.chat_model_path.is_some());
+        let embedding_models = self.model_names(|m| m.embedding_model_path.is_some());
+        let mut tools = vec![
+            json!({
+                "name": "chat",
+                "description": "Ask a local model. Send a prompt, or OpenAI chat messages for a conversation.",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "prompt": { "type": "string", "description": "The user message" },
+                        "system": { "type": "string", "description": "System prompt put before the prompt" },
+                        "messages": { "type": "array", "items": { "type": "object" }, "description": "OpenAI chat messages, instead of a prompt" },
+                        "model": { "type": "string", "description": model_description(&chat_models) },
+                        "max_tokens": { "type": "integer" },
+                        "temperature": { "type": "number" },
+                    },
+                },
+            }),
+            json!({
+                "name": "embed",
+                "description": "Embedding vectors of texts, computed by a local model",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "input": {
+                            "anyOf": [{ "type": "string" }, { "type": "array", "items": { "type": "string" } }],
+                            "description": "A text or a list of texts",
+                        },
+                        "model": { "type": "string", "description": model_description(&embedding_models) },
+                    },
+                    "required": ["input"],
+                },
+            }),
+        ];
+        if self.rag.is_some() {
+            tools.push(json!({
+                "name": "search",
+                "description": "The passages of a document collection most similar to a query",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": {
+                        "collection": { "type": "string" },
+                        "query": { "type": "string" },
+                        "top_k": { "type": "integer", "description": "Number of passages" },
+                    },
+                    "required": ["collection", "query"],
+                },
+            }));
+        }
+        tools
+    }
+
+    // Failures of the tools are results the model sees, unknown tools and bad arguments are errors
+    async fn call(&self, params: &Value, key: Option<&ApiKeyConfig>) -> Outcome {
+        let arguments = match &params["arguments"] {
+            Value::Null => json!({}),
+            arguments => arguments.clone(),
+        };
+        let result = match params["name"].as_str() {
+            Some("chat") => self.chat(&arguments, key).await?,
+            Some("embed") => self.embed(&arguments, key).await?,
+            Some("search") if self.rag.is_some() => self.search(&arguments, key).await?,
+            name => return Err((INVALID_PARAMS, format!("Unknown tool {}", name.unwrap_or_default()))),
+        };
+        Ok(match result {
+            // the usage is kept in `_meta`, for the gateway to debit the key's token budget
+            Ok((text, Some(usage))) => json!({
+                "content": [{ "type": "text", "text": text }],
+                "isError": false,
+                "_meta": { "usage": usage },
+            }),
+            Ok((text, None)) => json!({ "content": [{ "type": "text", "text": text }], "isError": false }),
+            Err(message) => json!({ "content": [{ "type": "text", "text": message }], "isError": true }),
+        })
+    }
+
+    async fn chat(&self, arguments: &Value, key: Option<&ApiKeyConfig>) -> ToolResult {
+        let mut messages = match &arguments["messages"] {
+            Value::Array(messages) => messages.clone(),
+            Value::Null => vec![],
+            _ => return Err((INVALID_PARAMS, "messages must be an array".to_string())),
+        };
+        if let Some(prompt) = arguments["prompt"].as_str() {
+            messages.push(json!({ "role": "user", "content": prompt }));
+        }
+        if messages.is_empty() {
+            return Err((INVALID_PARAMS, "A prompt or messages are required".to_string()));
+        }
+        if let Some(system) = arguments["system"].as_str() {
+            messages.insert(0, json!({ "role": "system", "content": system }));
+        }
+        let mut body = json!({ "messages": messages, "stream": false });
+        for field in ["model", "max_tokens", "temperature"] {
+            if !arguments[field].is_null() {
+                body[field] = arguments[field].clone();
+            }
+        }
+        if let Err(e) = authorize(key, &body) {
+            return Ok(Err(e));
+        }
+        let completion = match self.forward(CHAT_PATH, Capability::Chat, &body).await {
+            Ok(completion) => completion,
+            Err(e) => return Ok(Err(e)),
+        };
+        let message = &completion["choices"][0]["message"];
+        let text = match (message["content"].as_str(), &message["tool_calls"]) {
+            (Some(content), _) => content.to_string(),
+            (None, Value::Array(calls)) => Value::Array(calls.clone()).to_string(),
+            _ => String::new(),
+        };
+        Ok(Ok((text, usage(&completion))))
+    }
+
+    async fn embed(&self, arguments: &Value, key: Option<&ApiKeyConfig>) -> ToolResult {
+        let input = match &arguments["input"] {
+            Value::String(text) => json!([text]),
+            Value::Array(texts) if texts.iter().all(Value::is_string) => json!(texts),
+            _ => return Err((INVALID_PARAMS, "input must be a text or a list of texts".to_string())),
+        };
+        let mut body = json!({ "input": input });
+        if !arguments["model"].is_null() {
+            body["model"] = arguments["model"].clone();
+        }
+        if let Err(e) = authorize(key, &body) {
+            return Ok(Err(e));
+        }
+        let response = match self.forward(EMBEDDINGS_PATH, Capability::Embedding, &body).await {
+            Ok(response) => response,
+            Err(e) => return Ok(Err(e)),
+        };
+        let mut data: Vec<&Value> = response["data"].as_array().map(|d| d.iter().collect()).unwrap_or_default();
+        data.sort_by_key(|d| d["index"].as_u64().unwrap_or(0));
+        let embeddings: Vec<&Value> = data.into_iter().map(|d| &d["embedding"]).collect();
+        let text = json!({ "model": response["model"], "embeddings": embeddings }).to_string();
+        Ok(Ok((text, usage(&response))))
+    }
+
+    async fn search(&self, arguments: &Value, key: Option<&ApiKeyConfig>) -> ToolResult {
+        let Some(store) = &self.rag else {
+            return Err((INVALID_PARAMS, "Unknown tool search".to_string()));
+        };
+        let (Some(collection), Some(query)) = (arguments["collection"].as_str(), arguments["query"].as_str()) else {
+            return Err((INVALID_PARAMS, "collection and query are required".to_string()));
+        };
+        let top_k = arguments["top_k"].as_u64().map(|k| k as usize).unwrap_or_else(|| store.top_k());
+        let owner = key.map(auth::key_id);
+        match store.search(collection, owner.as_deref(), query, top_k).await {
+            Ok(matches) => Ok(Ok((serde_json::to_string_pretty(&matches).unwrap_or_default(), None))),
+            Err(e) => Ok(Err(e.to_string())),
+        }
+    }
+
+    // The JSON answer of a local instance
+    async fn forward(&self, path: &str, capability: Capability, body: &Value) -> Result<Value, String> {
+        let headers = HashMap::from([
+            ("content-type".to_string(), "application/json".to_string()),
+            (CAPABILITY_HEADER.to_string(), capability.as_str().to_string()),
+        ]);
+        let (status, body, _) = self.scheduler
+            .forward_request(path, "POST", body.to_string().into_bytes(), headers, Some(self.timeout))
+            .await
+            .map_err(|e| e.to_string())?;
+        if !(200..300).contains(&status) {
+            return Err(format!("The model answered {}: {}", status, String::from_utf8_lossy(&body)));
+        }
+        serde_json::from_slice(&body).map_err(|e| format!("Invalid answer of the model: {}", e))
+    }
+
+    async fn resources(&self, key: Option<&ApiKeyConfig>) -> Value {
+        let mut resources = vec![
+            json!({
+                "uri": MODELS_URI,
+                "name": "models",
+                "description": "Models served by the assistant, with their status",
+                "mimeType": "application/json",
+            }),
+            json!({
+                "uri": INSTANCES_URI,
+                "name": "instances",
+                "description": "Running model instances and their status",
+                "mimeType": "application/json",
+            }),
+        ];
+        for instance in self.instances(key).await {
+            resources.push(json!({
+                "uri": format!("{}/{}", INSTANCES_URI, instance.id),
+                "name": instance.id,
+                "description": format!("Instance of {}", instance.config.name),
+                "mimeType": "application/json",
+            }));
+        }
+        json!({ "resources": resources })
+    }
+
+    async fn read(&self, params: &Value, key: Option<&ApiKeyConfig>) -> Outcome {
+        let Some(uri) = params["uri"].as_str() else {
+            return Err((INVALID_PARAMS, "uri is required".to_string()));
+        };
+        let content = match uri {
+            MODELS_URI => json!(self.model_list(key).await),
+            INSTANCES_URI => json!(self.instances(key).await.iter().map(instance_json).collect::<Vec<_>>()),
+            _ => {
+                let id = uri.strip_prefix(INSTANCES_URI)
+                    .and_then(|rest| rest.strip_prefix('/'))
+                    .ok_or_else(|| not_found(uri))?;
+                let instance = self.instances(key).await
+                    .into_iter()
+                    .find(|i| i.id == id)
+                    .ok_or_else(|| not_found(uri))?;
+                instance_json(&instance)
+            }
+        };
+        Ok(json!({ "contents": [{
+            "uri": uri,
+            "mimeType": "application/json",
+            "text": serde_json::to_string_pretty(&content).unwrap_or_default(),
+        }]}))
+    }
+
+    // Instances of the models the key may use
+    async fn instances(&self, key: Option<&ApiKeyConfig>) -> Vec<ServiceInstance> {
+        let mut instances: Vec<ServiceInstance> = self.scheduler.list_instances().await
+            .into_iter()
+            .filter(|i| key.map_or(true, |key| key.allows_model(Some(&i.config.name))))
+            .collect();
+        instances.sort_by(|a, b| a.id.cmp(&b.id));
+        instances
+    }
+
+    async fn model_list(&self, key: Option<&ApiKeyConfig>) -> Vec<Value> {
+        let instances = self.instances(key).await;
+        let mut configs: Vec<&LlamaServerConfig> = self.models.iter()
+            .filter(|m| key.map_or(true, |key| key.allows_model(Some(&m.name))))
+            .collect();
+        for instance in &instances {
+            if !configs.iter().any(|c| c.name == instance.config.name) {
+                configs.push(&instance.config);
+            }
+        }
+        configs.into_iter()
+            .map(|config| {
+                let serving: Vec<&ServiceInstance> = instances.iter().filter(|i| i.config.name == config.name).collect();
+                let capabilities: Vec<&str> = serving.first()
+                    .map(|i| i.capabilities.iter().map(Capability::as_str).collect())
+                    .unwrap_or_default();
+                json!({
+                    "id": config.name,
+                    "aliases": config.aliases,
+                    "capabilities": capabilities,
+                    "context_length": config.context_length.or_else(|| serving.iter().find_map(|i| i.context_length)),
+                    "instances": serving.iter().map(|i| json!({ "id": i.id, "status": status(i) })).collect::<Vec<_>>(),
+                })
+            })
+            .collect()
+    }
+
+    fn model_names(&self, filter: impl Fn(&LlamaServerConfig) -> bool) -> Vec<String> {
+        self.models.iter().filter(|m| filter(m)).map(|m| m.name.clone()).collect()
+    }
+}
+
+// The `usage` of an answer of the model
+fn usage(answer: &Value) -> Option<Value> {
+    answer.get("usage").filter(|u| u.is_object()).cloned()
+}
+
+fn model_description(models: &[String]) -> String {
+    match models.first() {
+        Some(_) => format!("One of {}, any of them when omitted", models.join(", ")),
+        None => "Model name, any local model when omitted".to_string(),
+    }
+}
+
+// Whether the key may use the model of the request, a key limited to some models has to name one
+fn authorize(key: Option<&ApiKeyConfig>, body: &Value) -> Result<(), String> {
+    let model = body["model"].as_str();
+    match (key, model) {
+        (Some(key), _) if key.allows_model(model) => Ok(()),
+        (Some(_), Some(model)) => Err(format!("Model {} is not allowed for this key", model)),
+        (Some(_), None) => Err("This key may only use some models, one has to be named".to_string()),
+        (None, _) => Ok(()),
+    }
+}
+
+fn instance_json(instance: &ServiceInstance) -> Value {
+    let started_at = instance.started_at.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let uptime = SystemTime::now().duration_since(instance.started_at).unwrap_or_default();
+    json!({
+        "id": instance.id,
+        "model": instance.config.name,
+        "status": status(instance),
+        "capabilities": instance.capabilities.iter().map(Capability::as_str).collect::<Vec<_>>(),
+        "context_length": instance.context_length,
+        "started_at": started_at.as_secs(),
+        "uptime_secs": uptime.as_secs(),
+    })
+}
+
+fn status(instance: &ServiceInstance) -> String {
+    format!("{:?}", instance.status).to_lowercase()
+}
+
+fn not_found(uri: &str) -> (i64, String) {
+    (INVALID_PARAMS, format!("Resource {} not found", uri))
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+// The answer to a message that is not JSON
+pub fn parse_error() -> Value {
+    error(Value::Null, PARSE_ERROR, "Parse error")
+}
@@ -0,0 +1,278 @@
+use config::{McpConfig, McpServerConfig};
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use telemetry::metrics;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+mod host;
+mod transport;
+
+pub use host::{parse_error, McpHost};
+use transport::{Http, Stdio, Transport};
+
+// Revision of the protocol spoken to servers
+pub const PROTOCOL_VERSION: &str = "2025-03-26";
+// Between the server and tool names of a function offered to the model
+pub const SEPARATOR: &str = "__";
+// Longest function name OpenAI compatible clients accept
+const MAX_FUNCTION_NAME: usize = 64;
+// Pages of tools/list read from one server
+const MAX_TOOL_PAGES: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum McpError {
+    #[error("{0}")]
+    Config(String),
+    #[error("Unknown tool {0}")]
+    UnknownTool(String),
+    #[error("MCP transport error: {0}")]
+    Transport(String),
+    #[error("MCP request {0} timed out")]
+    Timeout(String),
+    #[error("MCP error {code}: {message}")]
+    Rpc { code: i64, message: String },
+    #[error("MCP transport error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid MCP message: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, McpError>;
+
+// A tool of an MCP server, offered to the model as a function
+#[derive(Debug, Serialize, Clone)]
+pub struct Tool {
+    pub server: String,
+    pub name: String,
+    // `<server>__<name>`, restricted to the characters function names allow
+    pub function: String,
+    pub description: Option<String>,
+    pub input_schema: Value,
+}
+
+impl Tool {
+    // The entry of an OpenAI `tools` array
+    pub fn openai(&self) -> Value {
+        json!({
+            "type": "function",
+            "function": {
+                "name": self.function,
+                "description": self.description.clone().unwrap_or_default(),
+                "parameters": self.input_schema,
+            }
+        })
+    }
+}
+
+// Result of a tool call, as the text given back to the model
+#[derive(Debug, Serialize, Clone)]
+pub struct ToolOutput {
+    pub content: String,
+    pub is_error: bool,
+}
+
+struct Connection {
+    transport: Transport,
+    tools: Vec<Tool>,
+}
+
+struct Server {
+    config: McpServerConfig,
+    // connected on first use and again after a transport error
+    connection: Mutex<Option<Arc<Connection>>>,
+}
+
+// The configured MCP servers and their tools
+pub struct McpServers {
+    servers: Vec<Server>,
+    timeout: Duration,
+    max_rounds: usize,
+}
+
+impl McpServers {
+    pub fn new(config: &McpConfig) -> Result<Self> {
+        let mut names = HashSet::new();
+        let mut servers = vec![];
+        for server in config.servers.iter().filter(|s| s.enabled) {
+            if server.name.is_empty() || !names.insert(server.name.clone()) {
+                return Err(McpError::Config(format!("MCP server names must be unique and not empty: {:?}", server.name)));
+            }
+            if server.command.is_some() == server.url.is_some() {
+                return Err(McpError::Config(format!("MCP server {} needs either a command or a url", server.name)));
+            }
+            servers.push(Server { config: server.clone(), connection: Mutex::new(None) });
+        }
+        Ok(Self {
+            servers,
+            timeout: Duration::from_secs(config.call_timeout_secs.max(1)),
+            max_rounds: config.max_rounds.max(1),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    pub fn max_rounds(&self) -> usize {
+        self.max_rounds
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.servers.iter().map(|s| s.config.name.clone()).collect()
+    }
+
+    // Connect to every server ahead of the first request
+    pub async fn connect_all(&self) {
+        for server in &self.servers {
+            if let Err(e) = self.connection(server).await {
+                warn!("Failed to connect to MCP server {}: {}", server.config.name, e);
+            }
+        }
+    }
+
+    // Tools of the named servers, or of all of them; unreachable servers are skipped
+    pub async fn tools(&self, names: Option<&[String]>) -> Result<Vec<Tool>> {
+        if let Some(names) = names {
+            if let Some(unknown) = names.iter().find(|n| !self.servers.iter().any(|s| &s.config.name == *n)) {
+                return Err(McpError::Config(format!("Unknown MCP server {}", unknown)));
+            }
+        }
+        let mut tools = vec![];
+        for server in &self.servers {
+            if names.is_some_and(|names| !names.contains(&server.config.name)) {
+                continue;
+            }
+            match self.connection(server).await {
+                Ok(connection) => tools.extend(connection.tools.iter().cloned()),
+                Err(e) => warn!("Skipping the tools of MCP server {}: {}", server.config.name, e),
+            }
+        }
+        Ok(tools)
+    }
+
+    // Run the tool behind a function name with the JSON arguments the model wrote
+    pub async fn call(&self, function: &str, arguments: &str) -> Result<ToolOutput> {
+        let server = self.servers.iter()
+            .find(|s| function.starts_with(&format!("{}{}", sanitize(&s.config.name), SEPARATOR)))
+            .ok_or_else(|| McpError::UnknownTool(function.to_string()))?;
+        let connection = self.connection(server).await?;
+        let tool = connection.tools.iter()
+            .find(|t| t.function == function)
+            .ok_or_else(|| McpError::UnknownTool(function.to_string()))?;
+        let arguments: Value = match arguments.trim() {
+            "" => json!({}),
+            arguments => serde_json::from_str(arguments)?,
+        };
+
+        let params = json!({ "name": tool.name, "arguments": arguments });
+        let outcome = connection.transport.request("tools/call", params, self.timeout).await;
+        let result = match outcome {
+            Ok(result) => result,
+            Err(e) => {
+                metrics().mcp_tool_calls.with_label_values(&[&server.config.name, "failed"]).inc();
+                if matches!(e, McpError::Transport(_) | McpError::Io(_)) {
+                    server.connection.lock().await.take();
+                }
+                return Err(e);
+            }
+        };
+        let output = ToolOutput {
+            content: content_text(&result),
+            is_error: result["isError"].as_bool().unwrap_or(false),
+        };
+        let outcome = if output.is_error { "error" } else { "ok" };
+        metrics().mcp_tool_calls.with_label_values(&[&server.config.name, outcome]).inc();
+        Ok(output)
+    }
+
+    async fn connection(&self, server: &Server) -> Result<Arc<Connection>> {
+        let mut connection = server.connection.lock().await;
+        if let Some(current) = connection.as_ref().filter(|c| c.transport.is_open()) {
+            return Ok(current.clone());
+        }
+        let connected = Arc::new(self.connect(&server.config).await?);
+        info!("Connected to MCP server {} with {} tools", server.config.name, connected.tools.len());
+        *connection = Some(connected.clone());
+        Ok(connected)
+    }
+
+    async fn connect(&self, config: &McpServerConfig) -> Result<Connection> {
+        let transport = match (&config.command, &config.url) {
+            (Some(command), _) => Transport::Stdio(Stdio::spawn(&config.name, command, &config.args, &config.env)?),
+            (None, Some(url)) => Transport::Http(Http::new(url, &config.headers)),
+            (None, None) => return Err(McpError::Config(format!("MCP server {} needs either a command or a url", config.name))),
+        };
+
+        let initialize = json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": {},
+            "clientInfo": { "name": "assistant", "version": env!("CARGO_PKG_VERSION") },
+        });
+        transport.request("initialize", initialize, self.timeout).await?;
+        transport.notify("notifications/initialized").await?;
+
+        let mut tools = vec![];
+        let mut cursor: Option<String> = None;
+        for _ in 0..MAX_TOOL_PAGES {
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let page = transport.request("tools/list", params, self.timeout).await?;
+            for tool in page["tools"].as_array().into_iter().flatten() {
+                let Some(name) = tool["name"].as_str() else {
+                    continue;
+                };
+                tools.push(Tool {
+                    server: config.name.clone(),
+                    name: name.to_string(),
+                    function: function_name(&config.name, name),
+                    description: tool["description"].as_str().map(String::from),
+                    input_schema: match &tool["inputSchema"] {
+                        Value::Null => json!({ "type": "object", "properties": {} }),
+                        schema => schema.clone(),
+                    },
+                });
+            }
+            cursor = page["nextCursor"].as_str().map(String::from);
+            if cursor.is_none() {
+                break;
+            }
+        }
+        Ok(Connection { transport, tools })
+    }
+}
+
+// The function name of a tool, as the model sees it
+pub fn function_name(server: &str, tool: &str) -> String {
+    let mut name = format!("{}{}{}", sanitize(server), SEPARATOR, sanitize(tool));
+    name.truncate(MAX_FUNCTION_NAME);
+    name
+}
+
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+// Text of the content items of a tool result, other items are kept as JSON
+fn content_text(result: &Value) -> String {
+    let mut parts = vec![];
+    for item in result["content"].as_array().into_iter().flatten() {
+        match item["text"].as_str() {
+            Some(text) if item["type"] == "text" => parts.push(text.to_string()),
+            _ => parts.push(item.to_string()),
+        }
+    }
+    if parts.is_empty() {
+        if let Some(structured) = result.get("structuredContent") {
+            parts.push(structured.to_string());
+        }
+    }
+    parts.join("\n")
+}
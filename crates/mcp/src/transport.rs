@@ -0,0 +1,222 @@
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::process::Stdio as Piped;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::oneshot;
+use tracing::{debug, warn};
+
+use crate::{McpError, Result};
+
+// Header of the session an HTTP server assigns on initialize
+const SESSION_HEADER: &str = "mcp-session-id";
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<Result<Value>>>>>;
+
+// JSON-RPC over the stdio of a child process or over HTTP
+pub enum Transport {
+    Stdio(Stdio),
+    Http(Http),
+}
+
+impl Transport {
+    pub async fn request(&self, method: &str, params: Value, timeout: Duration) -> Result<Value> {
+        let request = tokio::time::timeout(timeout, async {
+            match self {
+                Transport::Stdio(stdio) => stdio.request(method, params).await,
+                Transport::Http(http) => http.request(method, params).await,
+            }
+        });
+        request.await.map_err(|_| McpError::Timeout(method.to_string()))?
+    }
+
+    pub async fn notify(&self, method: &str) -> Result<()> {
+        let message = json!({ "jsonrpc": "2.0", "method": method });
+        match self {
+            Transport::Stdio(stdio) => stdio.send(&message).await,
+            Transport::Http(http) => http.post(&message).await.map(|_| ()),
+        }
+    }
+
+    // Whether requests may still be sent, a stdio server may have exited
+    pub fn is_open(&self) -> bool {
+        match self {
+            Transport::Stdio(stdio) => stdio.open.load(Ordering::Relaxed),
+            Transport::Http(_) => true,
+        }
+    }
+}
+
+// Newline delimited messages on the stdin and stdout of the server
+pub struct Stdio {
+    stdin: tokio::sync::Mutex<ChildStdin>,
+    pending: Pending,
+    next_id: AtomicU64,
+    open: Arc<AtomicBool>,
+    // killed when the transport is dropped
+    _child: Child,
+}
+
+impl Stdio {
+    pub fn spawn(name: &str, command: &str, args: &[String], env: &HashMap<String, String>) -> Result<Self> {
+        let mut child = Command::new(command)
+            .args(args)
+            .envs(env)
+            .stdin(Piped::piped())
+            .stdout(Piped::piped())
+            .stderr(Piped::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| McpError::Transport(format!("Failed to start {}: {}", command, e)))?;
+        let stdin = child.stdin.take().expect("piped stdin");
+        let stdout = child.stdout.take().expect("piped stdout");
+
+        let pending: Pending = Arc::new(Mutex::new(HashMap::new()));
+        let open = Arc::new(AtomicBool::new(true));
+        let (reader_pending, reader_open, name) = (pending.clone(), open.clone(), name.to_string());
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                match serde_json::from_str::<Value>(&line) {
+                    Ok(message) => dispatch(&reader_pending, message),
+                    Err(_) => debug!("MCP server {} wrote a line that is not JSON: {}", name, line),
+                }
+            }
+            warn!("MCP server {} closed its output", name);
+            reader_open.store(false, Ordering::Relaxed);
+            for (_, waiter) in reader_pending.lock().unwrap().drain() {
+                let _ = waiter.send(Err(McpError::Transport(format!("MCP server {} exited", name))));
+            }
+        });
+
+        Ok(Self {
+            stdin: tokio::sync::Mutex::new(stdin),
+            pending,
+            next_id: AtomicU64::new(1),
+            open,
+            _child: child,
+        })
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        if let Err(e) = self.send(&message).await {
+            self.pending.lock().unwrap().remove(&id);
+            return Err(e);
+        }
+        rx.await.map_err(|_| McpError::Transport("MCP server went away".to_string()))?
+    }
+
+    async fn send(&self, message: &Value) -> Result<()> {
+        if !self.open.load(Ordering::Relaxed) {
+            return Err(McpError::Transport("MCP server exited".to_string()));
+        }
+        let mut line = message.to_string();
+        line.push('\n');
+        let mut stdin = self.stdin.lock().await;
+        stdin.write_all(line.as_bytes()).await?;
+        stdin.flush().await?;
+        Ok(())
+    }
+}
+
+// Hand a response to the request waiting for it, requests and notifications of the server are ignored
+fn dispatch(pending: &Pending, message: Value) {
+    let Some(id) = message.get("id").and_then(Value::as_u64) else {
+        return;
+    };
+    if message.get("method").is_some() {
+        return;
+    }
+    if let Some(waiter) = pending.lock().unwrap().remove(&id) {
+        let _ = waiter.send(result(message));
+    }
+}
+
+// The streamable HTTP transport, each message is a POST answered by JSON or an event stream
+pub struct Http {
+    client: reqwest::Client,
+    url: String,
+    headers: HashMap<String, String>,
+    session: Mutex<Option<String>>,
+    next_id: AtomicU64,
+}
+
+impl Http {
+    pub fn new(url: &str, headers: &HashMap<String, String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.to_string(),
+            headers: headers.clone(),
+            session: Mutex::new(None),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let message = json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params });
+        let body = self.post(&message).await?;
+        // an event stream may carry requests of the server before the response
+        body.into_iter()
+            .find(|m| m.get("id").and_then(Value::as_u64) == Some(id) && m.get("method").is_none())
+            .map(result)
+            .unwrap_or_else(|| Err(McpError::Transport(format!("No response to {}", method))))
+    }
+
+    // The messages of the answer
+    async fn post(&self, message: &Value) -> Result<Vec<Value>> {
+        let mut request = self.client.post(&self.url)
+            .header("accept", "application/json, text/event-stream")
+            .json(message);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        if let Some(session) = self.session.lock().unwrap().clone() {
+            request = request.header(SESSION_HEADER, session);
+        }
+        let response = request.send().await.map_err(|e| McpError::Transport(e.to_string()))?;
+        if let Some(session) = response.headers().get(SESSION_HEADER).and_then(|v| v.to_str().ok()) {
+            *self.session.lock().unwrap() = Some(session.to_string());
+        }
+        let status = response.status();
+        let event_stream = response.headers()
+            .get("content-type")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/event-stream"));
+        let text = response.text().await.map_err(|e| McpError::Transport(e.to_string()))?;
+        if !status.is_success() {
+            return Err(McpError::Transport(format!("MCP server answered {}: {}", status, text)));
+        }
+        if event_stream {
+            return Ok(text.lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .filter_map(|data| serde_json::from_str(data.trim()).ok())
+                .collect());
+        }
+        // notifications are answered with 202 and no body
+        if text.trim().is_empty() {
+            return Ok(vec![]);
+        }
+        match serde_json::from_str::<Value>(&text)? {
+            Value::Array(messages) => Ok(messages),
+            message => Ok(vec![message]),
+        }
+    }
+}
+
+fn result(mut message: Value) -> Result<Value> {
+    if let Some(error) = message.get("error") {
+        return Err(McpError::Rpc {
+            code: error["code"].as_i64().unwrap_or_default(),
+            message: error["message"].as_str().unwrap_or_default().to_string(),
+        });
+    }
+    Ok(message.get_mut("result").map(Value::take).unwrap_or(Value::Null))
+}
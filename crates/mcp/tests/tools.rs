@@ -0,0 +1,200 @@
+// The gateway runs the tools of mcp-stub, over stdio and over HTTP, for chat completions with an `mcp` field
+use config::{McpConfig, McpServerConfig};
+use http_server::HttpServer;
+use mcp::McpServers;
+use protos::assistant::{
+    assistant_service_server::{AssistantService, AssistantServiceServer},
+    ChatCompletionChunk, ChatCompletionRequest, ChatCompletionResponse, EmbeddingRequest,
+    EmbeddingResponse, HeartbeatRequest, HeartbeatResponse, InfoRequest, InfoResponse,
+    JoinRequest, JoinResponse, ListModelsRequest, ListModelsResponse, Request, Response,
+    SpeechRequest, SpeechResponse,
+};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::process::{Child, Command};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{transport::Server, Request as TonicRequest, Response as TonicResponse, Status};
+
+const STUB: &str = env!("CARGO_BIN_EXE_mcp-stub");
+
+// A backend whose model calls the `add` tool it is offered, then answers with the result
+struct Backend;
+
+fn completion(message: Value, finish_reason: &str) -> Value {
+    json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "choices": [{ "index": 0, "message": message, "finish_reason": finish_reason }],
+        "usage": { "prompt_tokens": 10, "completion_tokens": 5, "total_tokens": 15 },
+    })
+}
+
+fn answer(request: &Value) -> Value {
+    let messages = request["messages"].as_array().cloned().unwrap_or_default();
+    if let Some(result) = messages.last().filter(|m| m["role"] == "tool") {
+        return completion(json!({ "role": "assistant", "content": format!("The sum is {}", result["content"].as_str().unwrap()) }), "stop");
+    }
+    let add = request["tools"].as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|t| t["function"]["name"].as_str())
+        .find(|name| name.ends_with("__add"));
+    match add {
+        Some(name) if request["tool_choice"] != "none" => completion(json!({
+            "role": "assistant",
+            "content": null,
+            "tool_calls": [{
+                "id": "call-1",
+                "type": "function",
+                "function": { "name": name, "arguments": "{\"a\":2,\"b\":3}" },
+            }],
+        }), "tool_calls"),
+        _ => completion(json!({ "role": "assistant", "content": "No tools" }), "stop"),
+    }
+}
+
+#[tonic::async_trait]
+impl AssistantService for Backend {
+    type ForwardRequestStreamStream = ReceiverStream<Result<Response, Status>>;
+    type ChatCompletionStreamStream = ReceiverStream<Result<ChatCompletionChunk, Status>>;
+
+    async fn forward_request(&self, request: TonicRequest<Request>) -> Result<TonicResponse<Response>, Status> {
+        let request: Value = serde_json::from_slice(&request.into_inner().body)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        Ok(TonicResponse::new(Response {
+            status: 200,
+            body: answer(&request).to_string().into_bytes(),
+            headers: HashMap::from([("content-type".to_string(), "application/json".to_string())]),
+        }))
+    }
+
+    async fn forward_request_stream(&self, _: TonicRequest<Request>) -> Result<TonicResponse<Self::ForwardRequestStreamStream>, Status> {
+        Err(Status::unimplemented("stream"))
+    }
+
+    async fn get_info(&self, _: TonicRequest<InfoRequest>) -> Result<TonicResponse<InfoResponse>, Status> {
+        Err(Status::unimplemented("info"))
+    }
+
+    async fn join(&self, _: TonicRequest<JoinRequest>) -> Result<TonicResponse<JoinResponse>, Status> {
+        Err(Status::unimplemented("join"))
+    }
+
+    async fn heartbeat(&self, _: TonicRequest<HeartbeatRequest>) -> Result<TonicResponse<HeartbeatResponse>, Status> {
+        Err(Status::unimplemented("heartbeat"))
+    }
+
+    async fn chat_completion(&self, _: TonicRequest<ChatCompletionRequest>) -> Result<TonicResponse<ChatCompletionResponse>, Status> {
+        Err(Status::unimplemented("chat"))
+    }
+
+    async fn chat_completion_stream(&self, _: TonicRequest<ChatCompletionRequest>) -> Result<TonicResponse<Self::ChatCompletionStreamStream>, Status> {
+        Err(Status::unimplemented("chat stream"))
+    }
+
+    async fn embed(&self, _: TonicRequest<EmbeddingRequest>) -> Result<TonicResponse<EmbeddingResponse>, Status> {
+        Err(Status::unimplemented("embed"))
+    }
+
+    async fn list_models(&self, _: TonicRequest<ListModelsRequest>) -> Result<TonicResponse<ListModelsResponse>, Status> {
+        Ok(TonicResponse::new(ListModelsResponse { models: vec![] }))
+    }
+
+    async fn speech(&self, _: TonicRequest<SpeechRequest>) -> Result<TonicResponse<SpeechResponse>, Status> {
+        Err(Status::unimplemented("speech"))
+    }
+}
+
+// A local address nothing listens on yet
+fn free_addr() -> String {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+async fn wait_for(addr: &str) {
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("Nothing listens on {}", addr);
+}
+
+// Killed when the test ends, passed or not
+struct Stub(Child);
+
+impl Drop for Stub {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[tokio::test]
+async fn runs_tools_over_stdio_and_http() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let grpc_addr = listener.local_addr().unwrap().to_string();
+    tokio::spawn(Server::builder()
+        .add_service(AssistantServiceServer::new(Backend))
+        .serve_with_incoming(TcpListenerStream::new(listener)));
+
+    let stub_addr = free_addr();
+    let _stub = Stub(Command::new(STUB).args(["--http", &stub_addr]).spawn().unwrap());
+    wait_for(&stub_addr).await;
+
+    let server = |name: &str| McpServerConfig {
+        name: name.to_string(),
+        command: None,
+        args: vec![],
+        env: HashMap::new(),
+        url: None,
+        headers: HashMap::new(),
+        enabled: true,
+    };
+    let config = McpConfig {
+        servers: vec![
+            McpServerConfig { command: Some(STUB.to_string()), ..server("local") },
+            McpServerConfig { url: Some(format!("http://{}/", stub_addr)), ..server("remote") },
+        ],
+        ..McpConfig::default()
+    };
+    let servers = Arc::new(McpServers::new(&config).unwrap());
+
+    let http_addr = free_addr();
+    tokio::spawn({
+        let http_addr = http_addr.clone();
+        async move {
+            HttpServer::new(grpc_addr).with_mcp(servers).serve(&http_addr).await.unwrap();
+        }
+    });
+    wait_for(&http_addr).await;
+
+    let client = reqwest::Client::new();
+    for name in ["local", "remote"] {
+        let response = client.post(format!("http://{}/v1/chat/completions", http_addr))
+            .json(&json!({
+                "model": "default",
+                "messages": [{ "role": "user", "content": "What is 2 + 3?" }],
+                "mcp": { "servers": [name] },
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        let completion: Value = response.json().await.unwrap();
+
+        assert_eq!(completion["choices"][0]["message"]["content"], "The sum is 5");
+        let trace = completion["tool_trace"].as_array().unwrap();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0]["type"], "call");
+        assert_eq!(trace[0]["name"], format!("{}__add", name));
+        assert_eq!(trace[1]["type"], "result");
+        assert_eq!(trace[1]["content"], "5");
+        assert_eq!(trace[1]["is_error"], false);
+        // both rounds are counted
+        assert_eq!(completion["usage"]["total_tokens"], 30);
+    }
+}
@@ -1,4 +1,4 @@
-use tonic_build;
+use std::{env, path::PathBuf};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     // tonic_build::configure()
@@ -9,9 +9,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     //     .build_client(true)
     //     .compile_protos(GRPC_PROTO_FILES, &["./vendor"])
     //     .expect("Failed to generate GRPC bindings");
+    // descriptor set used by gRPC server reflection
+    let descriptor_path = PathBuf::from(env::var("OUT_DIR")?).join("assistant_descriptor.bin");
     tonic_build::configure()
         .protoc_arg("--experimental_allow_proto3_optional")
         .out_dir("./src")
+        .file_descriptor_set_path(descriptor_path)
         .type_attribute(".", "#[derive(serde::Serialize, serde::Deserialize)]")
         .build_server(true)
         .build_client(true)
@@ -55,6 +55,327 @@ pub struct InfoResponse {
     #[prost(string, repeated, tag = "3")]
     pub endpoints: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// Cluster member description
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct NodeInfo {
+    #[prost(string, tag = "1")]
+    pub node_id: ::prost::alloc::string::String,
+    /// Address peers should dial
+    #[prost(string, tag = "2")]
+    pub grpc_addr: ::prost::alloc::string::String,
+    /// Models served by running instances
+    #[prost(string, repeated, tag = "3")]
+    pub models: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    /// Running instances / max instances
+    #[prost(float, tag = "4")]
+    pub load: f32,
+    /// Load is above the node's max_load
+    #[prost(bool, tag = "5")]
+    pub busy: bool,
+    /// Preference when several peers are eligible
+    #[prost(uint32, tag = "6")]
+    pub weight: u32,
+}
+/// Join request message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct JoinRequest {
+    #[prost(message, optional, tag = "1")]
+    pub node: ::core::option::Option<NodeInfo>,
+}
+/// Join response message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct JoinResponse {
+    #[prost(message, optional, tag = "1")]
+    pub node: ::core::option::Option<NodeInfo>,
+    /// Members known to the seed
+    #[prost(message, repeated, tag = "2")]
+    pub peers: ::prost::alloc::vec::Vec<NodeInfo>,
+}
+/// Heartbeat request message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HeartbeatRequest {
+    #[prost(message, optional, tag = "1")]
+    pub node: ::core::option::Option<NodeInfo>,
+    #[prost(message, repeated, tag = "2")]
+    pub peers: ::prost::alloc::vec::Vec<NodeInfo>,
+}
+/// Heartbeat response message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct HeartbeatResponse {
+    #[prost(message, optional, tag = "1")]
+    pub node: ::core::option::Option<NodeInfo>,
+    #[prost(message, repeated, tag = "2")]
+    pub peers: ::prost::alloc::vec::Vec<NodeInfo>,
+}
+/// Chat message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChatMessage {
+    #[prost(enumeration = "Role", tag = "1")]
+    pub role: i32,
+    #[prost(string, tag = "2")]
+    pub content: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub name: ::prost::alloc::string::String,
+}
+/// Chat completion request message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChatCompletionRequest {
+    #[prost(string, tag = "1")]
+    pub model: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub messages: ::prost::alloc::vec::Vec<ChatMessage>,
+    #[prost(float, optional, tag = "3")]
+    pub temperature: ::core::option::Option<f32>,
+    #[prost(float, optional, tag = "4")]
+    pub top_p: ::core::option::Option<f32>,
+    #[prost(int32, optional, tag = "5")]
+    pub max_tokens: ::core::option::Option<i32>,
+    #[prost(string, repeated, tag = "6")]
+    pub stop: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(float, optional, tag = "7")]
+    pub presence_penalty: ::core::option::Option<f32>,
+    #[prost(float, optional, tag = "8")]
+    pub frequency_penalty: ::core::option::Option<f32>,
+    #[prost(string, tag = "9")]
+    pub user: ::prost::alloc::string::String,
+}
+/// Token usage of a request
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Usage {
+    #[prost(uint32, tag = "1")]
+    pub prompt_tokens: u32,
+    #[prost(uint32, tag = "2")]
+    pub completion_tokens: u32,
+    #[prost(uint32, tag = "3")]
+    pub total_tokens: u32,
+}
+/// Chat completion choice
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChatCompletionChoice {
+    #[prost(uint32, tag = "1")]
+    pub index: u32,
+    #[prost(message, optional, tag = "2")]
+    pub message: ::core::option::Option<ChatMessage>,
+    #[prost(string, tag = "3")]
+    pub finish_reason: ::prost::alloc::string::String,
+}
+/// Chat completion response message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChatCompletionResponse {
+    #[prost(string, tag = "1")]
+    pub id: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub model: ::prost::alloc::string::String,
+    #[prost(int64, tag = "3")]
+    pub created: i64,
+    #[prost(message, repeated, tag = "4")]
+    pub choices: ::prost::alloc::vec::Vec<ChatCompletionChoice>,
+    #[prost(message, optional, tag = "5")]
+    pub usage: ::core::option::Option<Usage>,
+}
+/// Chat completion stream chunk
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ChatCompletionChunk {
+    #[prost(string, tag = "1")]
+    pub id: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub model: ::prost::alloc::string::String,
+    #[prost(int64, tag = "3")]
+    pub created: i64,
+    #[prost(uint32, tag = "4")]
+    pub index: u32,
+    /// Content added by this chunk
+    #[prost(string, tag = "5")]
+    pub delta: ::prost::alloc::string::String,
+    /// Set on the last chunk of a choice
+    #[prost(string, tag = "6")]
+    pub finish_reason: ::prost::alloc::string::String,
+    /// Set when the backend reports usage
+    #[prost(message, optional, tag = "7")]
+    pub usage: ::core::option::Option<Usage>,
+}
+/// Embedding request message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct EmbeddingRequest {
+    #[prost(string, tag = "1")]
+    pub model: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag = "2")]
+    pub input: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+/// Embedding of one input
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Embedding {
+    #[prost(uint32, tag = "1")]
+    pub index: u32,
+    #[prost(float, repeated, tag = "2")]
+    pub embedding: ::prost::alloc::vec::Vec<f32>,
+}
+/// Embedding response message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct EmbeddingResponse {
+    #[prost(string, tag = "1")]
+    pub model: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub data: ::prost::alloc::vec::Vec<Embedding>,
+    #[prost(message, optional, tag = "3")]
+    pub usage: ::core::option::Option<Usage>,
+}
+/// List models request message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListModelsRequest {}
+/// Model description
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ModelInfo {
+    #[prost(string, tag = "1")]
+    pub id: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub owned_by: ::prost::alloc::string::String,
+    #[prost(enumeration = "ModelStatus", tag = "3")]
+    pub status: i32,
+    /// Peer serving the model, empty when local
+    #[prost(string, tag = "4")]
+    pub node_id: ::prost::alloc::string::String,
+}
+/// List models response message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListModelsResponse {
+    #[prost(message, repeated, tag = "1")]
+    pub models: ::prost::alloc::vec::Vec<ModelInfo>,
+}
+/// Speech request message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SpeechRequest {
+    #[prost(string, tag = "1")]
+    pub model: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub input: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub voice: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub response_format: ::prost::alloc::string::String,
+    #[prost(float, optional, tag = "5")]
+    pub speed: ::core::option::Option<f32>,
+}
+/// Speech response message
+#[derive(serde::Serialize, serde::Deserialize)]
+#[allow(clippy::derive_partial_eq_without_eq)]
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SpeechResponse {
+    #[prost(bytes = "vec", tag = "1")]
+    pub audio: ::prost::alloc::vec::Vec<u8>,
+    #[prost(string, tag = "2")]
+    pub content_type: ::prost::alloc::string::String,
+}
+/// Chat message role
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum Role {
+    Unspecified = 0,
+    System = 1,
+    User = 2,
+    Assistant = 3,
+    Tool = 4,
+}
+impl Role {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Role::Unspecified => "ROLE_UNSPECIFIED",
+            Role::System => "ROLE_SYSTEM",
+            Role::User => "ROLE_USER",
+            Role::Assistant => "ROLE_ASSISTANT",
+            Role::Tool => "ROLE_TOOL",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "ROLE_UNSPECIFIED" => Some(Self::Unspecified),
+            "ROLE_SYSTEM" => Some(Self::System),
+            "ROLE_USER" => Some(Self::User),
+            "ROLE_ASSISTANT" => Some(Self::Assistant),
+            "ROLE_TOOL" => Some(Self::Tool),
+            _ => None,
+        }
+    }
+}
+/// Model instance status
+#[derive(serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ModelStatus {
+    Unspecified = 0,
+    Starting = 1,
+    Running = 2,
+    Failed = 3,
+    Stopped = 4,
+}
+impl ModelStatus {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            ModelStatus::Unspecified => "MODEL_STATUS_UNSPECIFIED",
+            ModelStatus::Starting => "MODEL_STATUS_STARTING",
+            ModelStatus::Running => "MODEL_STATUS_RUNNING",
+            ModelStatus::Failed => "MODEL_STATUS_FAILED",
+            ModelStatus::Stopped => "MODEL_STATUS_STOPPED",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "MODEL_STATUS_UNSPECIFIED" => Some(Self::Unspecified),
+            "MODEL_STATUS_STARTING" => Some(Self::Starting),
+            "MODEL_STATUS_RUNNING" => Some(Self::Running),
+            "MODEL_STATUS_FAILED" => Some(Self::Failed),
+            "MODEL_STATUS_STOPPED" => Some(Self::Stopped),
+            _ => None,
+        }
+    }
+}
 /// Generated client implementations.
 pub mod assistant_service_client {
     #![allow(unused_variables, dead_code, missing_docs, clippy::let_unit_value)]
@@ -215,6 +536,184 @@ pub mod assistant_service_client {
                 );
             self.inner.server_streaming(req, path, codec).await
         }
+        /// Join the cluster through a seed node
+        pub async fn join(
+            &mut self,
+            request: impl tonic::IntoRequest<super::JoinRequest>,
+        ) -> std::result::Result<tonic::Response<super::JoinResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/assistant.AssistantService/Join",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("assistant.AssistantService", "Join"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Exchange liveness, load and known peers with another node
+        pub async fn heartbeat(
+            &mut self,
+            request: impl tonic::IntoRequest<super::HeartbeatRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::HeartbeatResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/assistant.AssistantService/Heartbeat",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("assistant.AssistantService", "Heartbeat"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Typed chat completion
+        pub async fn chat_completion(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ChatCompletionRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ChatCompletionResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/assistant.AssistantService/ChatCompletion",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("assistant.AssistantService", "ChatCompletion"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Typed chat completion, streamed as deltas
+        pub async fn chat_completion_stream(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ChatCompletionRequest>,
+        ) -> std::result::Result<
+            tonic::Response<tonic::codec::Streaming<super::ChatCompletionChunk>>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/assistant.AssistantService/ChatCompletionStream",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(
+                    GrpcMethod::new("assistant.AssistantService", "ChatCompletionStream"),
+                );
+            self.inner.server_streaming(req, path, codec).await
+        }
+        /// Typed embeddings
+        pub async fn embed(
+            &mut self,
+            request: impl tonic::IntoRequest<super::EmbeddingRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::EmbeddingResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/assistant.AssistantService/Embed",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("assistant.AssistantService", "Embed"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Models served by this node and its peers
+        pub async fn list_models(
+            &mut self,
+            request: impl tonic::IntoRequest<super::ListModelsRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ListModelsResponse>,
+            tonic::Status,
+        > {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/assistant.AssistantService/ListModels",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("assistant.AssistantService", "ListModels"));
+            self.inner.unary(req, path, codec).await
+        }
+        /// Text to speech
+        pub async fn speech(
+            &mut self,
+            request: impl tonic::IntoRequest<super::SpeechRequest>,
+        ) -> std::result::Result<tonic::Response<super::SpeechResponse>, tonic::Status> {
+            self.inner
+                .ready()
+                .await
+                .map_err(|e| {
+                    tonic::Status::new(
+                        tonic::Code::Unknown,
+                        format!("Service was not ready: {}", e.into()),
+                    )
+                })?;
+            let codec = tonic::codec::ProstCodec::default();
+            let path = http::uri::PathAndQuery::from_static(
+                "/assistant.AssistantService/Speech",
+            );
+            let mut req = request.into_request();
+            req.extensions_mut()
+                .insert(GrpcMethod::new("assistant.AssistantService", "Speech"));
+            self.inner.unary(req, path, codec).await
+        }
     }
 }
 /// Generated server implementations.
@@ -248,18 +747,74 @@ pub mod assistant_service_server {
             tonic::Response<Self::ForwardRequestStreamStream>,
             tonic::Status,
         >;
-    }
-    /// Service for handling model requests
-    #[derive(Debug)]
-    pub struct AssistantServiceServer<T: AssistantService> {
-        inner: _Inner<T>,
-        accept_compression_encodings: EnabledCompressionEncodings,
-        send_compression_encodings: EnabledCompressionEncodings,
-        max_decoding_message_size: Option<usize>,
-        max_encoding_message_size: Option<usize>,
-    }
-    struct _Inner<T>(Arc<T>);
-    impl<T: AssistantService> AssistantServiceServer<T> {
+        /// Join the cluster through a seed node
+        async fn join(
+            &self,
+            request: tonic::Request<super::JoinRequest>,
+        ) -> std::result::Result<tonic::Response<super::JoinResponse>, tonic::Status>;
+        /// Exchange liveness, load and known peers with another node
+        async fn heartbeat(
+            &self,
+            request: tonic::Request<super::HeartbeatRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::HeartbeatResponse>,
+            tonic::Status,
+        >;
+        /// Typed chat completion
+        async fn chat_completion(
+            &self,
+            request: tonic::Request<super::ChatCompletionRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ChatCompletionResponse>,
+            tonic::Status,
+        >;
+        /// Server streaming response type for the ChatCompletionStream method.
+        type ChatCompletionStreamStream: tonic::codegen::tokio_stream::Stream<
+                Item = std::result::Result<super::ChatCompletionChunk, tonic::Status>,
+            >
+            + Send
+            + 'static;
+        /// Typed chat completion, streamed as deltas
+        async fn chat_completion_stream(
+            &self,
+            request: tonic::Request<super::ChatCompletionRequest>,
+        ) -> std::result::Result<
+            tonic::Response<Self::ChatCompletionStreamStream>,
+            tonic::Status,
+        >;
+        /// Typed embeddings
+        async fn embed(
+            &self,
+            request: tonic::Request<super::EmbeddingRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::EmbeddingResponse>,
+            tonic::Status,
+        >;
+        /// Models served by this node and its peers
+        async fn list_models(
+            &self,
+            request: tonic::Request<super::ListModelsRequest>,
+        ) -> std::result::Result<
+            tonic::Response<super::ListModelsResponse>,
+            tonic::Status,
+        >;
+        /// Text to speech
+        async fn speech(
+            &self,
+            request: tonic::Request<super::SpeechRequest>,
+        ) -> std::result::Result<tonic::Response<super::SpeechResponse>, tonic::Status>;
+    }
+    /// Service for handling model requests
+    #[derive(Debug)]
+    pub struct AssistantServiceServer<T: AssistantService> {
+        inner: _Inner<T>,
+        accept_compression_encodings: EnabledCompressionEncodings,
+        send_compression_encodings: EnabledCompressionEncodings,
+        max_decoding_message_size: Option<usize>,
+        max_encoding_message_size: Option<usize>,
+    }
+    struct _Inner<T>(Arc<T>);
+    impl<T: AssistantService> AssistantServiceServer<T> {
         pub fn new(inner: T) -> Self {
             Self::from_arc(Arc::new(inner))
         }
@@ -470,6 +1025,333 @@ pub mod assistant_service_server {
                     };
                     Box::pin(fut)
                 }
+                "/assistant.AssistantService/Join" => {
+                    #[allow(non_camel_case_types)]
+                    struct JoinSvc<T: AssistantService>(pub Arc<T>);
+                    impl<
+                        T: AssistantService,
+                    > tonic::server::UnaryService<super::JoinRequest> for JoinSvc<T> {
+                        type Response = super::JoinResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::JoinRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AssistantService>::join(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = JoinSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/assistant.AssistantService/Heartbeat" => {
+                    #[allow(non_camel_case_types)]
+                    struct HeartbeatSvc<T: AssistantService>(pub Arc<T>);
+                    impl<
+                        T: AssistantService,
+                    > tonic::server::UnaryService<super::HeartbeatRequest>
+                    for HeartbeatSvc<T> {
+                        type Response = super::HeartbeatResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::HeartbeatRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AssistantService>::heartbeat(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = HeartbeatSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/assistant.AssistantService/ChatCompletion" => {
+                    #[allow(non_camel_case_types)]
+                    struct ChatCompletionSvc<T: AssistantService>(pub Arc<T>);
+                    impl<
+                        T: AssistantService,
+                    > tonic::server::UnaryService<super::ChatCompletionRequest>
+                    for ChatCompletionSvc<T> {
+                        type Response = super::ChatCompletionResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ChatCompletionRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AssistantService>::chat_completion(&inner, request)
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ChatCompletionSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/assistant.AssistantService/ChatCompletionStream" => {
+                    #[allow(non_camel_case_types)]
+                    struct ChatCompletionStreamSvc<T: AssistantService>(pub Arc<T>);
+                    impl<
+                        T: AssistantService,
+                    > tonic::server::ServerStreamingService<super::ChatCompletionRequest>
+                    for ChatCompletionStreamSvc<T> {
+                        type Response = super::ChatCompletionChunk;
+                        type ResponseStream = T::ChatCompletionStreamStream;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::ResponseStream>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ChatCompletionRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AssistantService>::chat_completion_stream(
+                                        &inner,
+                                        request,
+                                    )
+                                    .await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ChatCompletionStreamSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.server_streaming(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/assistant.AssistantService/Embed" => {
+                    #[allow(non_camel_case_types)]
+                    struct EmbedSvc<T: AssistantService>(pub Arc<T>);
+                    impl<
+                        T: AssistantService,
+                    > tonic::server::UnaryService<super::EmbeddingRequest>
+                    for EmbedSvc<T> {
+                        type Response = super::EmbeddingResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::EmbeddingRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AssistantService>::embed(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = EmbedSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/assistant.AssistantService/ListModels" => {
+                    #[allow(non_camel_case_types)]
+                    struct ListModelsSvc<T: AssistantService>(pub Arc<T>);
+                    impl<
+                        T: AssistantService,
+                    > tonic::server::UnaryService<super::ListModelsRequest>
+                    for ListModelsSvc<T> {
+                        type Response = super::ListModelsResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::ListModelsRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AssistantService>::list_models(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = ListModelsSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
+                "/assistant.AssistantService/Speech" => {
+                    #[allow(non_camel_case_types)]
+                    struct SpeechSvc<T: AssistantService>(pub Arc<T>);
+                    impl<
+                        T: AssistantService,
+                    > tonic::server::UnaryService<super::SpeechRequest>
+                    for SpeechSvc<T> {
+                        type Response = super::SpeechResponse;
+                        type Future = BoxFuture<
+                            tonic::Response<Self::Response>,
+                            tonic::Status,
+                        >;
+                        fn call(
+                            &mut self,
+                            request: tonic::Request<super::SpeechRequest>,
+                        ) -> Self::Future {
+                            let inner = Arc::clone(&self.0);
+                            let fut = async move {
+                                <T as AssistantService>::speech(&inner, request).await
+                            };
+                            Box::pin(fut)
+                        }
+                    }
+                    let accept_compression_encodings = self.accept_compression_encodings;
+                    let send_compression_encodings = self.send_compression_encodings;
+                    let max_decoding_message_size = self.max_decoding_message_size;
+                    let max_encoding_message_size = self.max_encoding_message_size;
+                    let inner = self.inner.clone();
+                    let fut = async move {
+                        let inner = inner.0;
+                        let method = SpeechSvc(inner);
+                        let codec = tonic::codec::ProstCodec::default();
+                        let mut grpc = tonic::server::Grpc::new(codec)
+                            .apply_compression_config(
+                                accept_compression_encodings,
+                                send_compression_encodings,
+                            )
+                            .apply_max_message_size_config(
+                                max_decoding_message_size,
+                                max_encoding_message_size,
+                            );
+                        let res = grpc.unary(method, req).await;
+                        Ok(res)
+                    };
+                    Box::pin(fut)
+                }
                 _ => {
                     Box::pin(async move {
                         Ok(
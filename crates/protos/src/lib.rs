@@ -1 +1,4 @@
 pub mod assistant;
+
+// Encoded descriptor set of service.proto, used by gRPC server reflection
+pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/assistant_descriptor.bin"));
@@ -0,0 +1,104 @@
+// Text of a document cut into overlapping chunks, at paragraph, sentence or word boundaries when possible
+pub fn split(text: &str, size: usize, overlap: usize) -> Vec<String> {
+    let text = text.trim();
+    let size = size.max(1);
+    let overlap = overlap.min(size / 2);
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let mut end = forward(text, start, size);
+        if end < text.len() {
+            end = boundary(text, start, end);
+        }
+        let chunk = text[start..end].trim();
+        if !chunk.is_empty() {
+            chunks.push(chunk.to_string());
+        }
+        if end >= text.len() {
+            break;
+        }
+        // the next chunk starts `overlap` characters back, at the start of a word
+        let mut next = back(text, end, overlap);
+        if let Some(space) = text[next..end].find(char::is_whitespace) {
+            next += space;
+        }
+        start = if next > start { next } else { end };
+    }
+    chunks
+}
+
+// The last paragraph, line, sentence or word break of the second half of the window
+fn boundary(text: &str, start: usize, end: usize) -> usize {
+    let window = &text[start..end];
+    let half = window.len() / 2;
+    for separator in ["\n\n", "\n", ". ", " "] {
+        if let Some(i) = window.rfind(separator).filter(|i| *i >= half) {
+            return start + i + separator.len();
+        }
+    }
+    end
+}
+
+// Byte offset `count` characters after `from`
+fn forward(text: &str, from: usize, count: usize) -> usize {
+    text[from..].char_indices().nth(count).map_or(text.len(), |(i, _)| from + i)
+}
+
+// Byte offset `count` characters before `from`
+fn back(text: &str, from: usize, count: usize) -> usize {
+    text[..from].char_indices().rev().take(count).last().map_or(from, |(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_text_is_one_chunk() {
+        assert_eq!(split("  Hello world.  ", 100, 10), ["Hello world."]);
+        assert!(split(" \n ", 100, 10).is_empty());
+    }
+
+    #[test]
+    fn chunks_end_at_paragraphs_before_sentences_and_words() {
+        let text = "First paragraph is here.\n\nSecond one. It has two sentences and more words";
+        let chunks = split(text, 40, 0);
+        assert_eq!(chunks[0], "First paragraph is here.");
+        assert_eq!(chunks[1], "Second one. It has two sentences and");
+        assert_eq!(chunks[2], "more words");
+    }
+
+    #[test]
+    fn chunks_overlap_at_word_starts() {
+        let words: Vec<String> = (0..40).map(|i| format!("w{:02}", i)).collect();
+        let text = words.join(" ");
+        let chunks = split(&text, 40, 10);
+        assert!(chunks.len() > 3);
+        for pair in chunks.windows(2) {
+            let last = pair[0].split(' ').last().unwrap();
+            // the next chunk repeats the end of the previous one, from a whole word
+            assert!(pair[1].split(' ').any(|w| w == last), "{:?}", pair);
+            assert!(pair[1].starts_with('w'));
+        }
+        assert!(chunks.iter().all(|c| c.chars().count() <= 40));
+        assert!(chunks.last().unwrap().ends_with("w39"));
+    }
+
+    #[test]
+    fn overlap_is_at_most_half_a_chunk() {
+        let text = "a ".repeat(100);
+        let chunks = split(&text, 10, 50);
+        // progress is made even when asked to overlap more than a chunk
+        assert!(chunks.len() < 100);
+        assert!(chunks.concat().replace(' ', "").len() >= 100);
+    }
+
+    #[test]
+    fn text_without_breaks_is_cut_at_characters() {
+        let text = "é".repeat(25);
+        let chunks = split(&text, 10, 0);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0].chars().count(), 10);
+        assert_eq!(chunks[2].chars().count(), 5);
+    }
+}
@@ -0,0 +1,18 @@
+use crate::{RagError, Result};
+
+// Text of an uploaded document: plain text, markdown, or the text of a PDF
+pub async fn text(filename: &str, content: Vec<u8>) -> Result<String> {
+    let is_pdf = content.starts_with(b"%PDF-") || filename.to_ascii_lowercase().ends_with(".pdf");
+    if !is_pdf {
+        return String::from_utf8(content)
+            .map(|text| text.replace("\r\n", "\n"))
+            .map_err(|_| RagError::Invalid(format!("{} is neither text, markdown nor a PDF", filename)));
+    }
+    // the parser panics on some malformed files, it runs on its own thread
+    let extracted = tokio::task::spawn_blocking(move || pdf_extract::extract_text_from_mem(&content)).await;
+    match extracted {
+        Ok(Ok(text)) => Ok(text),
+        Ok(Err(e)) => Err(RagError::Invalid(format!("Failed to read the text of {}: {}", filename, e))),
+        Err(_) => Err(RagError::Invalid(format!("Failed to read the text of {}", filename))),
+    }
+}
@@ -0,0 +1,342 @@
+use config::storage::{now, write_atomic};
+use config::RagConfig;
+use scheduler::semantic::{dot, normalized};
+use scheduler::{Scheduler, CAPABILITY_HEADER};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::warn;
+use uuid::Uuid;
+
+mod chunk;
+mod extract;
+
+pub use chunk::split;
+
+const EMBEDDINGS_PATH: &str = "/v1/embeddings";
+// Chunks embedded by one request
+const EMBED_BATCH: usize = 16;
+// Budget of one embedding request
+const EMBED_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, thiserror::Error)]
+pub enum RagError {
+    #[error("Collection {0} not found")]
+    NotFound(String),
+    #[error("Document {0} not found")]
+    DocumentNotFound(String),
+    #[error("Invalid collection name {0}")]
+    InvalidName(String),
+    #[error("Collection {0} already exists")]
+    Exists(String),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Failed to embed: {0}")]
+    Embedding(String),
+    #[error("Collection storage error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Collection storage error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, RagError>;
+
+// Named set of documents, searched by the embeddings of their chunks
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Collection {
+    pub name: String,
+    pub description: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    // Identity of the API key that created it (auth::key_id), only that key sees it
+    pub owner: Option<String>,
+    // Queries are embedded by the model the chunks were
+    pub embedding_model: String,
+    pub documents: Vec<Document>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    chunks: Vec<Chunk>,
+}
+
+impl Collection {
+    // collections created without a key are not shared with the keys
+    fn visible_to(&self, owner: Option<&str>) -> bool {
+        self.owner.as_deref() == owner
+    }
+
+    // The collection without its chunks
+    fn summary(&self) -> Collection {
+        Collection {
+            name: self.name.clone(),
+            description: self.description.clone(),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            owner: self.owner.clone(),
+            embedding_model: self.embedding_model.clone(),
+            documents: self.documents.clone(),
+            chunks: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Document {
+    pub id: String,
+    pub filename: String,
+    pub bytes: usize,
+    pub chunks: usize,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Chunk {
+    document_id: String,
+    index: usize,
+    text: String,
+    // of unit length, so the similarity is a dot product
+    vector: Vec<f32>,
+}
+
+// A chunk found for a query, the most similar first
+#[derive(Debug, Serialize, Clone)]
+pub struct Match {
+    pub document_id: String,
+    pub filename: String,
+    pub index: usize,
+    pub text: String,
+    pub score: f32,
+}
+
+// Collections in memory, written to one file each when a directory is configured
+pub struct RagStore {
+    config: RagConfig,
+    scheduler: Arc<Scheduler>,
+    embedding_model: String,
+    collections: RwLock<HashMap<String, Collection>>,
+}
+
+impl RagStore {
+    pub fn open(config: &RagConfig, scheduler: Arc<Scheduler>, embedding_model: String) -> Result<Self> {
+        let mut collections = HashMap::new();
+        if let Some(dir) = &config.dir {
+            fs::create_dir_all(dir)?;
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+                match fs::read(&path).map_err(RagError::from).and_then(|b| Ok(serde_json::from_slice::<Collection>(&b)?)) {
+                    Ok(collection) => {
+                        collections.insert(collection.name.clone(), collection);
+                    }
+                    Err(e) => warn!("Skipping collection {:?}: {}", path, e),
+                }
+            }
+        }
+        Ok(Self {
+            config: config.clone(),
+            scheduler,
+            embedding_model,
+            collections: RwLock::new(collections),
+        })
+    }
+
+    // Chunks given as context when the request does not say
+    pub fn top_k(&self) -> usize {
+        self.config.top_k
+    }
+
+    pub fn max_document_bytes(&self) -> usize {
+        self.config.max_document_bytes
+    }
+
+    pub fn create(&self, name: &str, description: Option<String>, owner: Option<&str>) -> Result<Collection> {
+        let name = valid_name(name)?.to_string();
+        let mut collections = self.collections.write().unwrap();
+        if collections.contains_key(&name) {
+            return Err(RagError::Exists(name));
+        }
+        let now = now();
+        let collection = Collection {
+            name,
+            description,
+            created_at: now,
+            updated_at: now,
+            owner: owner.map(|o| o.to_string()),
+            embedding_model: self.embedding_model.clone(),
+            documents: vec![],
+            chunks: vec![],
+        };
+        self.save(&collection)?;
+        collections.insert(collection.name.clone(), collection.clone());
+        Ok(collection)
+    }
+
+    // Collections of the owner, by name
+    pub fn list(&self, owner: Option<&str>) -> Vec<Collection> {
+        let mut collections: Vec<Collection> = self.collections.read().unwrap()
+            .values()
+            .filter(|c| c.visible_to(owner))
+            .map(Collection::summary)
+            .collect();
+        collections.sort_by(|a, b| a.name.cmp(&b.name));
+        collections
+    }
+
+    pub fn get(&self, name: &str, owner: Option<&str>) -> Result<Collection> {
+        self.collections.read().unwrap()
+            .get(name)
+            .filter(|c| c.visible_to(owner))
+            .map(Collection::summary)
+            .ok_or_else(|| RagError::NotFound(name.to_string()))
+    }
+
+    pub fn delete(&self, name: &str, owner: Option<&str>) -> Result<()> {
+        let mut collections = self.collections.write().unwrap();
+        if !collections.get(name).is_some_and(|c| c.visible_to(owner)) {
+            return Err(RagError::NotFound(name.to_string()));
+        }
+        collections.remove(name);
+        if let Some(dir) = &self.config.dir {
+            match fs::remove_file(dir.join(format!("{}.json", name))) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // Add a document: its text is cut into chunks, which are embedded by the local embedding instance
+    pub async fn ingest(&self, name: &str, owner: Option<&str>, filename: &str, content: Vec<u8>) -> Result<Document> {
+        let model = self.get(name, owner)?.embedding_model;
+        if content.len() > self.config.max_document_bytes {
+            return Err(RagError::Invalid(format!("{} is over {} bytes", filename, self.config.max_document_bytes)));
+        }
+        let bytes = content.len();
+        let text = extract::text(filename, content).await?;
+        let texts = split(&text, self.config.chunk_size, self.config.chunk_overlap);
+        if texts.is_empty() {
+            return Err(RagError::Invalid(format!("{} has no text", filename)));
+        }
+        let mut vectors = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(EMBED_BATCH) {
+            vectors.extend(self.embed(&model, batch).await?);
+        }
+
+        let document = Document {
+            id: format!("doc_{}", Uuid::new_v4().simple()),
+            filename: filename.to_string(),
+            bytes,
+            chunks: texts.len(),
+            created_at: now(),
+        };
+        let chunks = texts.into_iter().zip(vectors).enumerate().map(|(index, (text, vector))| Chunk {
+            document_id: document.id.clone(),
+            index,
+            text,
+            vector,
+        });
+        let mut collections = self.collections.write().unwrap();
+        // the collection may have gone while the chunks were embedded
+        let collection = collections.get_mut(name)
+            .filter(|c| c.visible_to(owner))
+            .ok_or_else(|| RagError::NotFound(name.to_string()))?;
+        collection.chunks.extend(chunks);
+        collection.documents.push(document.clone());
+        collection.updated_at = now();
+        self.save(collection)?;
+        Ok(document)
+    }
+
+    pub fn delete_document(&self, name: &str, owner: Option<&str>, id: &str) -> Result<()> {
+        let mut collections = self.collections.write().unwrap();
+        let collection = collections.get_mut(name)
+            .filter(|c| c.visible_to(owner))
+            .ok_or_else(|| RagError::NotFound(name.to_string()))?;
+        if !collection.documents.iter().any(|d| d.id == id) {
+            return Err(RagError::DocumentNotFound(id.to_string()));
+        }
+        collection.documents.retain(|d| d.id != id);
+        collection.chunks.retain(|c| c.document_id != id);
+        collection.updated_at = now();
+        self.save(collection)
+    }
+
+    // The `top_k` chunks of the collection most similar to the query
+    pub async fn search(&self, name: &str, owner: Option<&str>, query: &str, top_k: usize) -> Result<Vec<Match>> {
+        let model = self.get(name, owner)?.embedding_model;
+        let vector = self.embed(&model, &[query.to_string()]).await?.pop().unwrap_or_default();
+
+        let collections = self.collections.read().unwrap();
+        let collection = collections.get(name)
+            .filter(|c| c.visible_to(owner))
+            .ok_or_else(|| RagError::NotFound(name.to_string()))?;
+        let mut scored: Vec<(f32, &Chunk)> = collection.chunks.iter()
+            .filter(|c| c.vector.len() == vector.len())
+            .map(|c| (dot(&c.vector, &vector), c))
+            .collect();
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        let filenames: HashMap<&str, &str> = collection.documents.iter()
+            .map(|d| (d.id.as_str(), d.filename.as_str()))
+            .collect();
+        Ok(scored.into_iter()
+            .take(top_k)
+            .map(|(score, chunk)| Match {
+                document_id: chunk.document_id.clone(),
+                filename: filenames.get(chunk.document_id.as_str()).copied().unwrap_or_default().to_string(),
+                index: chunk.index,
+                text: chunk.text.clone(),
+                score,
+            })
+            .collect())
+    }
+
+    // Unit vectors of the texts, in their order
+    async fn embed(&self, model: &str, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let body = json!({ "model": model, "input": texts });
+        let headers = HashMap::from([
+            ("content-type".to_string(), "application/json".to_string()),
+            (CAPABILITY_HEADER.to_string(), "embedding".to_string()),
+        ]);
+        let (status, body, _) = self.scheduler
+            .forward_request(EMBEDDINGS_PATH, "POST", body.to_string().into_bytes(), headers, Some(EMBED_TIMEOUT))
+            .await
+            .map_err(|e| RagError::Embedding(e.to_string()))?;
+        if !(200..300).contains(&status) {
+            return Err(RagError::Embedding(format!("embedding request answered {}: {}", status, String::from_utf8_lossy(&body))));
+        }
+        let body: Value = serde_json::from_slice(&body).map_err(|e| RagError::Embedding(e.to_string()))?;
+        let mut data: Vec<&Value> = body["data"].as_array().map(|d| d.iter().collect()).unwrap_or_default();
+        data.sort_by_key(|d| d["index"].as_u64().unwrap_or(0));
+        let vectors: Vec<Vec<f32>> = data.into_iter()
+            .map(|d| {
+                let vector: Vec<f32> = d["embedding"].as_array()
+                    .map(|v| v.iter().filter_map(Value::as_f64).map(|x| x as f32).collect())
+                    .unwrap_or_default();
+                normalized(&vector).unwrap_or_default()
+            })
+            .collect();
+        if vectors.len() != texts.len() || vectors.iter().any(Vec::is_empty) {
+            return Err(RagError::Embedding(format!("expected {} embeddings from {}", texts.len(), model)));
+        }
+        Ok(vectors)
+    }
+
+    fn save(&self, collection: &Collection) -> Result<()> {
+        let Some(dir) = &self.config.dir else {
+            return Ok(());
+        };
+        Ok(write_atomic(&dir.join(format!("{}.json", collection.name)), &serde_json::to_vec(collection)?)?)
+    }
+}
+
+// Names are file names on disk, so they may not reach outside the directory
+fn valid_name(name: &str) -> Result<&str> {
+    if name.is_empty() || name.len() > 128 || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(RagError::InvalidName(name.to_string()));
+    }
+    Ok(name)
+}
+
@@ -0,0 +1,95 @@
+// Pins conversations to the instance that served them, so its prompt cache is reused
+use config::AffinityConfig;
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Conversation a request belongs to, when the client names it
+pub const SESSION_HEADER: &str = "x-session-id";
+// Characters of a completion prompt that identify its conversation
+const PROMPT_PREFIX: usize = 512;
+// Requests in flight a pinned instance may have over the least busy one before the
+// conversation moves, so conversations sharing a prefix do not pile up on one instance
+pub const MAX_IMBALANCE: i64 = 2;
+
+// The conversation of a request, by its session header or the start of its prompt
+pub fn key(headers: &HashMap<String, String>, body: &[u8]) -> Option<u64> {
+    let mut hasher = DefaultHasher::new();
+    let session = headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(SESSION_HEADER))
+        .map(|(_, v)| v.as_str())
+        .filter(|v| !v.is_empty());
+    if let Some(session) = session {
+        session.hash(&mut hasher);
+        return Some(hasher.finish());
+    }
+
+    let body: Value = serde_json::from_slice(body).ok()?;
+    body.get("model").and_then(Value::as_str).hash(&mut hasher);
+    if let Some(messages) = body.get("messages").and_then(Value::as_array) {
+        // later turns only add messages after the first question, it stays the same
+        let first = messages.iter().position(|m| m["role"] == "user")?;
+        for message in &messages[..=first] {
+            message.to_string().hash(&mut hasher);
+        }
+    } else {
+        let prompt = body.get("prompt").and_then(Value::as_str)?;
+        prompt.chars().take(PROMPT_PREFIX).collect::<String>().hash(&mut hasher);
+    }
+    Some(hasher.finish())
+}
+
+struct Pin {
+    instance: String,
+    used: Instant,
+}
+
+pub struct Pins {
+    ttl: Duration,
+    max_entries: usize,
+    pins: Mutex<HashMap<u64, Pin>>,
+}
+
+impl Pins {
+    pub fn new(config: &AffinityConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.ttl_secs),
+            max_entries: config.max_entries.max(1),
+            pins: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // The instance the conversation is pinned to, when it is still one of the candidates
+    pub fn get(&self, key: u64, candidates: &[&str]) -> Option<String> {
+        let mut pins = self.pins.lock().unwrap();
+        let pin = pins.get_mut(&key)
+            .filter(|p| p.used.elapsed() < self.ttl && candidates.contains(&p.instance.as_str()))?;
+        pin.used = Instant::now();
+        Some(pin.instance.clone())
+    }
+
+    // Conversations pinned to the instance and still fresh
+    pub fn pinned(&self, instance: &str) -> usize {
+        self.pins.lock().unwrap()
+            .values()
+            .filter(|p| p.instance == instance && p.used.elapsed() < self.ttl)
+            .count()
+    }
+
+    pub fn pin(&self, key: u64, instance: &str) {
+        let mut pins = self.pins.lock().unwrap();
+        if pins.len() >= self.max_entries && !pins.contains_key(&key) {
+            pins.retain(|_, p| p.used.elapsed() < self.ttl);
+            if pins.len() >= self.max_entries {
+                let oldest = pins.iter().min_by_key(|(_, p)| p.used).map(|(k, _)| *k);
+                if let Some(oldest) = oldest {
+                    pins.remove(&oldest);
+                }
+            }
+        }
+        pins.insert(key, Pin { instance: instance.to_string(), used: Instant::now() });
+    }
+}
@@ -0,0 +1,830 @@
+// Offline batches in the OpenAI Batch API format, run against the local instances
+use config::storage::{now, write_atomic};
+use config::{BatchConfig, Capability, Priority};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use telemetry::{metrics, GaugeGuard};
+use tokio::sync::{RwLock, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+use crate::{Scheduler, SchedulerError, CAPABILITY_HEADER};
+
+// Endpoints a batch may target, with the capability their requests need
+const ENDPOINTS: &[(&str, Capability)] = &[
+    ("/v1/chat/completions", Capability::Chat),
+    ("/v1/completions", Capability::Chat),
+    ("/v1/embeddings", Capability::Embedding),
+];
+const COMPLETION_WINDOW: &str = "24h";
+const COMPLETION_WINDOW_SECS: u64 = 24 * 60 * 60;
+// Attempts of a request whose instance is not up yet or busy, as right after a restart
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, thiserror::Error)]
+pub enum BatchError {
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("{0}")]
+    Invalid(String),
+    #[error("Batch storage error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Batch storage error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileObject {
+    pub id: String,
+    pub object: String,
+    pub bytes: u64,
+    pub created_at: u64,
+    pub filename: String,
+    pub purpose: String,
+    // Identity of the API key that uploaded it (auth::key_id), only that key sees it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+impl FileObject {
+    // files uploaded without a key are not shared with the keys
+    fn visible_to(&self, owner: Option<&str>) -> bool {
+        self.owner.as_deref() == owner
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchStatus {
+    Validating,
+    Failed,
+    InProgress,
+    Finalizing,
+    Completed,
+    Expired,
+    Cancelling,
+    Cancelled,
+}
+
+impl BatchStatus {
+    fn is_active(&self) -> bool {
+        matches!(self, BatchStatus::Validating | BatchStatus::InProgress | BatchStatus::Finalizing | BatchStatus::Cancelling)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RequestCounts {
+    pub total: u64,
+    pub completed: u64,
+    pub failed: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Batch {
+    pub id: String,
+    pub object: String,
+    pub endpoint: String,
+    pub errors: Option<Value>,
+    pub input_file_id: String,
+    pub completion_window: String,
+    pub status: BatchStatus,
+    pub output_file_id: Option<String>,
+    pub error_file_id: Option<String>,
+    pub created_at: u64,
+    pub in_progress_at: Option<u64>,
+    pub expires_at: Option<u64>,
+    pub finalizing_at: Option<u64>,
+    pub completed_at: Option<u64>,
+    pub failed_at: Option<u64>,
+    pub expired_at: Option<u64>,
+    pub cancelling_at: Option<u64>,
+    pub cancelled_at: Option<u64>,
+    pub request_counts: RequestCounts,
+    pub metadata: Option<Value>,
+}
+
+// What is kept on disk for a batch
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Record {
+    batch: Batch,
+    // Models the creating API key may use, all when empty
+    #[serde(default)]
+    models: Vec<String>,
+    // Identity of the API key that created it (auth::key_id), only that key sees it
+    #[serde(default)]
+    owner: Option<String>,
+}
+
+impl Record {
+    fn visible_to(&self, owner: Option<&str>) -> bool {
+        self.owner.as_deref() == owner
+    }
+}
+
+// One line of an input file
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    custom_id: String,
+    method: String,
+    url: String,
+    body: Value,
+}
+
+// Batches and their files, persisted under one directory so a restart resumes them
+pub struct Batches {
+    scheduler: Arc<Scheduler>,
+    config: BatchConfig,
+    max_load: f32,
+    files_dir: PathBuf,
+    batches_dir: PathBuf,
+    records: RwLock<HashMap<String, Record>>,
+    cancels: Mutex<HashMap<String, Arc<AtomicBool>>>,
+    // shared by all batches, the concurrency cap
+    permits: Arc<Semaphore>,
+}
+
+impl Batches {
+    pub fn open(
+        dir: impl AsRef<Path>,
+        scheduler: Arc<Scheduler>,
+        config: BatchConfig,
+        max_load: f32,
+    ) -> Result<Self, BatchError> {
+        let files_dir = dir.as_ref().join("files");
+        let batches_dir = dir.as_ref().join("batches");
+        fs::create_dir_all(&files_dir)?;
+        fs::create_dir_all(&batches_dir)?;
+
+        let mut records = HashMap::new();
+        for entry in fs::read_dir(&batches_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            match fs::read(&path).map_err(BatchError::from).and_then(|b| Ok(serde_json::from_slice::<Record>(&b)?)) {
+                Ok(record) => {
+                    records.insert(record.batch.id.clone(), record);
+                }
+                Err(e) => warn!("Skipping batch state {:?}: {}", path, e),
+            }
+        }
+
+        Ok(Self {
+            scheduler,
+            permits: Arc::new(Semaphore::new(config.concurrency.max(1))),
+            config,
+            max_load,
+            files_dir,
+            batches_dir,
+            records: RwLock::new(records),
+            cancels: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // Largest input file accepted
+    pub fn max_file_bytes(&self) -> usize {
+        self.config.max_file_bytes
+    }
+
+    // Pick up the batches that were running when the process stopped
+    pub async fn resume(self: &Arc<Self>) {
+        let active: Vec<String> = self.records.read().await
+            .values()
+            .filter(|r| r.batch.status.is_active())
+            .map(|r| r.batch.id.clone())
+            .collect();
+        for id in active {
+            info!("Resuming batch {}", id);
+            self.spawn(id);
+        }
+    }
+
+    pub fn upload(&self, filename: &str, purpose: &str, content: &[u8], owner: Option<&str>) -> Result<FileObject, BatchError> {
+        if purpose != "batch" {
+            return Err(BatchError::Invalid(format!("Unsupported file purpose {}", purpose)));
+        }
+        if content.len() > self.config.max_file_bytes {
+            return Err(BatchError::Invalid(format!(
+                "File is larger than {} bytes",
+                self.config.max_file_bytes
+            )));
+        }
+        let file = FileObject {
+            id: format!("file-{}", Uuid::new_v4().simple()),
+            object: "file".to_string(),
+            bytes: content.len() as u64,
+            created_at: now(),
+            filename: filename.to_string(),
+            purpose: purpose.to_string(),
+            owner: owner.map(|o| o.to_string()),
+        };
+        fs::write(self.content_path(&file.id), content)?;
+        self.save_file(&file)?;
+        Ok(file)
+    }
+
+    // Files of the owner, the most recent first
+    pub fn files(&self, owner: Option<&str>) -> Result<Vec<FileObject>, BatchError> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(&self.files_dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) == Some("json") {
+                let file = serde_json::from_slice::<FileObject>(&fs::read(&path)?)?;
+                if file.visible_to(owner) {
+                    files.push(file);
+                }
+            }
+        }
+        files.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        Ok(files)
+    }
+
+    // Files of other owners are not found
+    pub fn file(&self, id: &str, owner: Option<&str>) -> Result<FileObject, BatchError> {
+        let path = self.files_dir.join(format!("{}.json", file_name(id)?));
+        let not_found = || BatchError::NotFound(format!("File {}", id));
+        match fs::read(&path) {
+            Ok(bytes) => Some(serde_json::from_slice::<FileObject>(&bytes)?)
+                .filter(|f| f.visible_to(owner))
+                .ok_or_else(not_found),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(not_found()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn file_content(&self, id: &str, owner: Option<&str>) -> Result<Vec<u8>, BatchError> {
+        self.file(id, owner)?;
+        Ok(fs::read(self.content_path(id))?)
+    }
+
+    pub async fn delete_file(&self, id: &str, owner: Option<&str>) -> Result<(), BatchError> {
+        self.file(id, owner)?;
+        let in_use = self.records.read().await.values().any(|r| {
+            r.batch.status.is_active()
+                && [Some(&r.batch.input_file_id), r.batch.output_file_id.as_ref(), r.batch.error_file_id.as_ref()]
+                    .contains(&Some(&id.to_string()))
+        });
+        if in_use {
+            return Err(BatchError::Invalid(format!("File {} is used by a running batch", id)));
+        }
+        fs::remove_file(self.content_path(id))?;
+        fs::remove_file(self.files_dir.join(format!("{}.json", id)))?;
+        Ok(())
+    }
+
+    // Register a batch, it is validated and run in the background
+    pub async fn create(
+        self: &Arc<Self>,
+        input_file_id: &str,
+        endpoint: &str,
+        completion_window: &str,
+        metadata: Option<Value>,
+        models: Vec<String>,
+        owner: Option<&str>,
+    ) -> Result<Batch, BatchError> {
+        if !ENDPOINTS.iter().any(|(e, _)| *e == endpoint) {
+            return Err(BatchError::Invalid(format!("Unsupported batch endpoint {}", endpoint)));
+        }
+        if completion_window != COMPLETION_WINDOW {
+            return Err(BatchError::Invalid(format!("Unsupported completion window {}", completion_window)));
+        }
+        let input = self.file(input_file_id, owner)?;
+        if input.purpose != "batch" {
+            return Err(BatchError::Invalid(format!("File {} is not a batch input file", input_file_id)));
+        }
+
+        let created_at = now();
+        let batch = Batch {
+            id: format!("batch_{}", Uuid::new_v4().simple()),
+            object: "batch".to_string(),
+            endpoint: endpoint.to_string(),
+            errors: None,
+            input_file_id: input_file_id.to_string(),
+            completion_window: completion_window.to_string(),
+            status: BatchStatus::Validating,
+            output_file_id: None,
+            error_file_id: None,
+            created_at,
+            in_progress_at: None,
+            expires_at: Some(created_at + COMPLETION_WINDOW_SECS),
+            finalizing_at: None,
+            completed_at: None,
+            failed_at: None,
+            expired_at: None,
+            cancelling_at: None,
+            cancelled_at: None,
+            request_counts: RequestCounts::default(),
+            metadata,
+        };
+        let record = Record { batch: batch.clone(), models, owner: owner.map(|o| o.to_string()) };
+        self.save(&record)?;
+        self.records.write().await.insert(batch.id.clone(), record);
+        self.spawn(batch.id.clone());
+        Ok(batch)
+    }
+
+    // Batches of the owner, the most recent first
+    pub async fn list(&self, owner: Option<&str>) -> Vec<Batch> {
+        let mut batches: Vec<Batch> = self.records.read().await
+            .values()
+            .filter(|r| r.visible_to(owner))
+            .map(|r| r.batch.clone())
+            .collect();
+        batches.sort_by(|a, b| b.created_at.cmp(&a.created_at));
+        batches
+    }
+
+    // Batches of other owners are not found
+    pub async fn get(&self, id: &str, owner: Option<&str>) -> Result<Batch, BatchError> {
+        self.records.read().await
+            .get(id)
+            .filter(|r| r.visible_to(owner))
+            .map(|r| r.batch.clone())
+            .ok_or_else(|| BatchError::NotFound(format!("Batch {}", id)))
+    }
+
+    // Stop sending the requests of a batch, the ones in flight still finish
+    pub async fn cancel(&self, id: &str, owner: Option<&str>) -> Result<Batch, BatchError> {
+        self.get(id, owner).await?;
+        let batch = self.update(id, |b| {
+            if b.status.is_active() && b.status != BatchStatus::Cancelling {
+                b.status = BatchStatus::Cancelling;
+                b.cancelling_at = Some(now());
+            }
+        }).await?;
+        if let Some(flag) = self.cancels.lock().unwrap().get(id) {
+            flag.store(true, Ordering::SeqCst);
+        }
+        Ok(batch)
+    }
+
+    fn spawn(self: &Arc<Self>, id: String) {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.cancels.lock().unwrap().insert(id.clone(), cancelled.clone());
+        let batches = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = batches.run(&id, &cancelled).await {
+                warn!("Batch {} failed: {}", id, e);
+                let _ = batches.update(&id, |b| {
+                    b.status = BatchStatus::Failed;
+                    b.failed_at = Some(now());
+                    b.errors = Some(errors(vec![json!({ "code": "internal_error", "message": e.to_string() })]));
+                }).await;
+            }
+            batches.cancels.lock().unwrap().remove(&id);
+        });
+    }
+
+    async fn run(self: &Arc<Self>, id: &str, cancelled: &AtomicBool) -> Result<(), BatchError> {
+        let record = self.records.read().await
+            .get(id)
+            .cloned()
+            .ok_or_else(|| BatchError::NotFound(format!("Batch {}", id)))?;
+        let batch = record.batch;
+        if batch.status == BatchStatus::Cancelling {
+            self.update(id, |b| {
+                b.status = BatchStatus::Cancelled;
+                b.cancelled_at = Some(now());
+            }).await?;
+            return Ok(());
+        }
+
+        // validating
+        let owner = record.owner.as_deref();
+        let content = self.file_content(&batch.input_file_id, owner)?;
+        let requests = match validate(&content, &batch.endpoint, &record.models) {
+            Ok(requests) => requests,
+            Err(problems) => {
+                self.update(id, |b| {
+                    b.status = BatchStatus::Failed;
+                    b.failed_at = Some(now());
+                    b.errors = Some(errors(problems));
+                }).await?;
+                return Ok(());
+            }
+        };
+
+        // in progress, results written before a restart are kept
+        let output_id = batch.output_file_id.clone().unwrap_or_else(|| format!("file-{}", Uuid::new_v4().simple()));
+        let error_id = batch.error_file_id.clone().unwrap_or_else(|| format!("file-{}", Uuid::new_v4().simple()));
+        let (done, completed, failed) = {
+            let (mut done, completed) = done_requests(&self.content_path(&output_id));
+            let (failed_ids, failed) = done_requests(&self.content_path(&error_id));
+            done.extend(failed_ids);
+            (done, completed, failed)
+        };
+        let total = requests.len() as u64;
+        self.update(id, |b| {
+            b.status = BatchStatus::InProgress;
+            b.in_progress_at.get_or_insert(now());
+            b.output_file_id = Some(output_id.clone());
+            b.error_file_id = Some(error_id.clone());
+            b.request_counts = RequestCounts { total, completed, failed };
+        }).await?;
+
+        let sink = Arc::new(Sink {
+            output: Mutex::new(append(&self.content_path(&output_id))?),
+            errors: Mutex::new(append(&self.content_path(&error_id))?),
+        });
+        let capability = ENDPOINTS.iter()
+            .find(|(e, _)| *e == batch.endpoint)
+            .map(|(_, c)| *c);
+        let expires_at = batch.expires_at.unwrap_or(u64::MAX);
+        let mut expired = false;
+        let mut tasks = JoinSet::new();
+        for request in requests.into_iter().filter(|r| !done.contains(&r.custom_id)) {
+            let permit = self.permits.clone().acquire_owned().await
+                .expect("batch semaphore is never closed");
+            // low priority batches let interactive requests go first
+            let mut queued = None;
+            while self.config.priority == Priority::Low
+                && !cancelled.load(Ordering::SeqCst)
+                && self.scheduler.is_busy(self.max_load).await
+            {
+                queued.get_or_insert_with(|| GaugeGuard::new(metrics().queue_depth.clone()));
+                tokio::time::sleep(crate::BUSY_POLL).await;
+            }
+            drop(queued);
+            if cancelled.load(Ordering::SeqCst) {
+                break;
+            }
+            if now() >= expires_at {
+                expired = true;
+                break;
+            }
+
+            let batches = self.clone();
+            let sink = sink.clone();
+            let id = id.to_string();
+            tasks.spawn(async move {
+                let ok = batches.send(request, capability, &sink).await;
+                drop(permit);
+                let _ = batches.update(&id, |b| {
+                    if ok {
+                        b.request_counts.completed += 1;
+                    } else {
+                        b.request_counts.failed += 1;
+                    }
+                }).await;
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+
+        // finalizing, empty result files are dropped
+        self.update(id, |b| {
+            if b.status == BatchStatus::InProgress {
+                b.status = BatchStatus::Finalizing;
+                b.finalizing_at = Some(now());
+            }
+        }).await?;
+        drop(sink);
+        let output_file_id = self.finish_file(&output_id, &format!("{}_output.jsonl", id), owner)?;
+        let error_file_id = self.finish_file(&error_id, &format!("{}_error.jsonl", id), owner)?;
+        let batch = self.update(id, |b| {
+            b.output_file_id = output_file_id;
+            b.error_file_id = error_file_id;
+            let now = now();
+            if b.status == BatchStatus::Cancelling {
+                b.status = BatchStatus::Cancelled;
+                b.cancelled_at = Some(now);
+            } else if expired {
+                b.status = BatchStatus::Expired;
+                b.expired_at = Some(now);
+            } else {
+                b.status = BatchStatus::Completed;
+                b.completed_at = Some(now);
+            }
+        }).await?;
+        info!(
+            "Batch {} {:?}: {} completed, {} failed of {}",
+            id, batch.status, batch.request_counts.completed, batch.request_counts.failed, batch.request_counts.total
+        );
+        Ok(())
+    }
+
+    // Send one request, its result goes to the output file and failures to the error file
+    async fn send(&self, request: BatchRequest, capability: Option<Capability>, sink: &Sink) -> bool {
+        let mut headers = HashMap::new();
+        headers.insert("content-type".to_string(), "application/json".to_string());
+        if let Some(capability) = capability {
+            headers.insert(CAPABILITY_HEADER.to_string(), capability.as_str().to_string());
+        }
+        let timeout = (self.config.request_timeout_secs > 0)
+            .then(|| Duration::from_secs(self.config.request_timeout_secs));
+        let body = request.body.to_string().into_bytes();
+        let mut attempt = 1;
+        let result = loop {
+            let result = self.scheduler.forward_request(
+                &request.url,
+                &request.method,
+                body.clone(),
+                headers.clone(),
+                timeout,
+            ).await;
+            match result {
+                Err(SchedulerError::NoInstance | SchedulerError::QueueFull | SchedulerError::Upstream(_))
+                    if attempt < ATTEMPTS =>
+                {
+                    tokio::time::sleep(RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+
+        let line_id = format!("batch_req_{}", Uuid::new_v4().simple());
+        let (ok, line) = match result {
+            Ok((status, body, _)) => {
+                let body = serde_json::from_slice::<Value>(&body)
+                    .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&body).to_string()));
+                let ok = (200..300).contains(&status);
+                (ok, json!({
+                    "id": line_id,
+                    "custom_id": request.custom_id,
+                    "response": {
+                        "status_code": status,
+                        "request_id": Uuid::new_v4().simple().to_string(),
+                        "body": body,
+                    },
+                    "error": null,
+                }))
+            }
+            Err(e) => {
+                debug!("Batch request {} failed: {}", request.custom_id, e);
+                (false, json!({
+                    "id": line_id,
+                    "custom_id": request.custom_id,
+                    "response": null,
+                    "error": { "code": error_code(&e), "message": e.to_string() },
+                }))
+            }
+        };
+        let file = if ok { &sink.output } else { &sink.errors };
+        // one write per line, a crash cuts at most the last one short
+        if let Err(e) = file.lock().unwrap().write_all(format!("{}\n", line).as_bytes()) {
+            warn!("Failed to write batch result: {}", e);
+        }
+        ok
+    }
+
+    // Register a result file of the batch owner, None when it stayed empty
+    fn finish_file(&self, id: &str, filename: &str, owner: Option<&str>) -> Result<Option<String>, BatchError> {
+        let path = self.content_path(id);
+        let bytes = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        if bytes == 0 {
+            let _ = fs::remove_file(&path);
+            return Ok(None);
+        }
+        self.save_file(&FileObject {
+            id: id.to_string(),
+            object: "file".to_string(),
+            bytes,
+            created_at: now(),
+            filename: filename.to_string(),
+            purpose: "batch_output".to_string(),
+            owner: owner.map(|o| o.to_string()),
+        })?;
+        Ok(Some(id.to_string()))
+    }
+
+    // Change a batch and persist it
+    async fn update(&self, id: &str, change: impl FnOnce(&mut Batch)) -> Result<Batch, BatchError> {
+        let mut records = self.records.write().await;
+        let record = records.get_mut(id).ok_or_else(|| BatchError::NotFound(format!("Batch {}", id)))?;
+        change(&mut record.batch);
+        self.save(record)?;
+        Ok(record.batch.clone())
+    }
+
+    fn save(&self, record: &Record) -> Result<(), BatchError> {
+        Ok(write_atomic(&self.batches_dir.join(format!("{}.json", record.batch.id)), &serde_json::to_vec(record)?)?)
+    }
+
+    fn save_file(&self, file: &FileObject) -> Result<(), BatchError> {
+        Ok(write_atomic(&self.files_dir.join(format!("{}.json", file.id)), &serde_json::to_vec(file)?)?)
+    }
+
+    fn content_path(&self, id: &str) -> PathBuf {
+        self.files_dir.join(format!("{}.jsonl", id))
+    }
+}
+
+struct Sink {
+    output: Mutex<File>,
+    errors: Mutex<File>,
+}
+
+// Parse the input lines, or describe every problem found
+fn validate(content: &[u8], endpoint: &str, models: &[String]) -> Result<Vec<BatchRequest>, Vec<Value>> {
+    let mut requests = Vec::new();
+    let mut problems = Vec::new();
+    let mut ids = HashSet::new();
+    for (i, line) in content.split(|b| *b == b'\n').enumerate() {
+        let line_number = i + 1;
+        if line.iter().all(|b| b.is_ascii_whitespace()) {
+            continue;
+        }
+        let mut problem = |code: &str, message: String| {
+            problems.push(json!({ "code": code, "message": message, "line": line_number }));
+        };
+        let request: BatchRequest = match serde_json::from_slice(line) {
+            Ok(request) => request,
+            Err(e) => {
+                problem("invalid_json_line", format!("Invalid request line: {}", e));
+                continue;
+            }
+        };
+        if !ids.insert(request.custom_id.clone()) {
+            problem("duplicate_custom_id", format!("custom_id {} is used twice", request.custom_id));
+        }
+        if !request.method.eq_ignore_ascii_case("POST") {
+            problem("invalid_method", format!("Method {} is not supported", request.method));
+        }
+        if request.url != endpoint {
+            problem("mismatched_endpoint", format!("URL {} does not match the batch endpoint {}", request.url, endpoint));
+        }
+        let model = request.body.get("model").and_then(Value::as_str).unwrap_or_default();
+        if !request.body.is_object() {
+            problem("invalid_body", "Request body must be a JSON object".to_string());
+        } else if !models.is_empty() && !models.iter().any(|m| m == model) {
+            problem("model_not_allowed", format!("Model {} is not allowed for this API key", model));
+        }
+        requests.push(request);
+    }
+    if requests.is_empty() && problems.is_empty() {
+        problems.push(json!({ "code": "empty_file", "message": "The input file has no requests", "line": null }));
+    }
+    if problems.is_empty() {
+        Ok(requests)
+    } else {
+        Err(problems)
+    }
+}
+
+// custom_ids already answered in a result file, and their count. Only complete lines count,
+// `append` drops a partial last one and its request runs again
+fn done_requests(path: &Path) -> (HashSet<String>, u64) {
+    let mut ids = HashSet::new();
+    let content = fs::read(path).unwrap_or_default();
+    for line in content[..complete_len(&content)].split(|b| *b == b'\n') {
+        if let Some(id) = serde_json::from_slice::<Value>(line).ok()
+            .and_then(|v| v.get("custom_id").and_then(Value::as_str).map(|s| s.to_string()))
+        {
+            ids.insert(id);
+        }
+    }
+    let count = ids.len() as u64;
+    (ids, count)
+}
+
+// Length of the lines that end with a newline
+fn complete_len(content: &[u8]) -> usize {
+    content.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1)
+}
+
+fn errors(data: Vec<Value>) -> Value {
+    json!({ "object": "list", "data": data })
+}
+
+fn error_code(e: &SchedulerError) -> &'static str {
+    match e {
+        SchedulerError::NoInstance => "no_instance",
+        SchedulerError::ModelNotFound(_) => "model_not_found",
+        SchedulerError::Unsupported(..) => "unsupported_model",
+        SchedulerError::QueueFull => "server_busy",
+        SchedulerError::Timeout => "timeout",
+        SchedulerError::Upstream(_) => "upstream_error",
+    }
+}
+
+// File ids name files on disk, so they may not reach outside the directory
+fn file_name(id: &str) -> Result<&str, BatchError> {
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(BatchError::NotFound(format!("File {}", id)));
+    }
+    Ok(id)
+}
+
+// Open a result file to add lines to, after the last complete one
+fn append(path: &Path) -> std::io::Result<File> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let content = fs::read(path)?;
+    let len = complete_len(&content);
+    if len < content.len() {
+        file.set_len(len as u64)?;
+    }
+    Ok(file)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENDPOINT: &str = "/v1/chat/completions";
+
+    fn line(id: &str, model: &str) -> String {
+        json!({
+            "custom_id": id,
+            "method": "POST",
+            "url": ENDPOINT,
+            "body": { "model": model, "messages": [{ "role": "user", "content": "hi" }] },
+        })
+        .to_string()
+    }
+
+    fn codes(problems: Vec<Value>) -> Vec<(String, Value)> {
+        problems.into_iter()
+            .map(|p| (p["code"].as_str().unwrap().to_string(), p["line"].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn validate_accepts_requests_and_skips_blank_lines() {
+        let content = format!("{}\n\n{}\n", line("a", "m"), line("b", "m"));
+        let requests = validate(content.as_bytes(), ENDPOINT, &[]).ok().unwrap();
+        let ids: Vec<&str> = requests.iter().map(|r| r.custom_id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+    }
+
+    #[test]
+    fn validate_reports_every_problem_with_its_line() {
+        let content = [
+            line("a", "m"),
+            "not json".to_string(),
+            line("a", "m"),
+            line("c", "m").replace("POST", "GET"),
+            line("d", "m").replace(ENDPOINT, "/v1/embeddings"),
+            line("e", "other"),
+        ]
+        .join("\n");
+        let problems = validate(content.as_bytes(), ENDPOINT, &["m".to_string()]).err().unwrap();
+        assert_eq!(codes(problems), [
+            ("invalid_json_line".to_string(), json!(2)),
+            ("duplicate_custom_id".to_string(), json!(3)),
+            ("invalid_method".to_string(), json!(4)),
+            ("mismatched_endpoint".to_string(), json!(5)),
+            ("model_not_allowed".to_string(), json!(6)),
+        ]);
+    }
+
+    #[test]
+    fn validate_rejects_an_empty_file() {
+        let problems = validate(b"\n  \n", ENDPOINT, &[]).err().unwrap();
+        assert_eq!(codes(problems), [("empty_file".to_string(), Value::Null)]);
+    }
+
+    #[test]
+    fn resume_drops_a_partial_last_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("output.jsonl");
+        let a = json!({ "custom_id": "a" }).to_string();
+        let b = json!({ "custom_id": "b" }).to_string();
+        // a crash left b without its newline
+        fs::write(&path, format!("{}\n{}", a, b)).unwrap();
+
+        let (done, count) = done_requests(&path);
+        assert_eq!(count, 1);
+        assert!(done.contains("a") && !done.contains("b"));
+
+        let mut file = append(&path).unwrap();
+        file.write_all(format!("{}\n", b).as_bytes()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n{}\n", a, b));
+        assert_eq!(done_requests(&path).1, 2);
+    }
+
+    #[test]
+    fn files_are_only_visible_to_their_owner() {
+        let dir = tempfile::tempdir().unwrap();
+        let scheduler = Arc::new(Scheduler::new(dir.path().to_path_buf(), 1));
+        let batches = Batches::open(dir.path(), scheduler, BatchConfig::default(), 1.0).unwrap();
+        let content = line("a", "m");
+        let file = batches.upload("in.jsonl", "batch", content.as_bytes(), Some("key_a")).unwrap();
+
+        assert_eq!(batches.file_content(&file.id, Some("key_a")).unwrap(), content.as_bytes());
+        assert_eq!(batches.files(Some("key_a")).unwrap().len(), 1);
+        for owner in [Some("key_b"), None] {
+            assert!(batches.files(owner).unwrap().is_empty());
+            assert!(matches!(batches.file(&file.id, owner), Err(BatchError::NotFound(_))));
+            assert!(matches!(batches.file_content(&file.id, owner), Err(BatchError::NotFound(_))));
+        }
+    }
+
+    #[test]
+    fn resume_starts_from_a_missing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("errors.jsonl");
+        assert_eq!(done_requests(&path).1, 0);
+        append(&path).unwrap();
+        assert!(path.exists());
+    }
+}
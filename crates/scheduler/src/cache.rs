@@ -0,0 +1,344 @@
+// Answers of deterministic requests, replayed for identical requests
+use config::storage::{now, write_atomic};
+use config::CacheConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use telemetry::metrics;
+use tracing::warn;
+
+// Tells the caller whether the answer came from the cache
+pub const CACHE_HEADER: &str = "x-assistant-cache";
+// Paths whose answers may be cached
+const CHAT_PATHS: &[&str] = &["/v1/chat/completions", "/v1/completions"];
+const EMBEDDINGS_PATH: &str = "/v1/embeddings";
+// Fields that do not change the answer
+const IGNORED_FIELDS: &[&str] = &["user"];
+
+// A cached answer, a non-streamed one is a single chunk
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Cached {
+    pub status: u16,
+    pub headers: HashMap<String, String>,
+    pub chunks: Vec<String>,
+}
+
+impl Cached {
+    pub fn body(&self) -> Vec<u8> {
+        self.chunks.concat().into_bytes()
+    }
+}
+
+// Whether a streamed answer ran to its end, a stream cut short has no [DONE]
+pub fn stream_complete(chunks: &[String]) -> bool {
+    chunks.concat().trim_end().ends_with("data: [DONE]")
+}
+
+// What the cache does for a request
+pub struct Lookup {
+    pub key: String,
+    // the answer, unless the caller asked for a fresh one
+    pub hit: Option<Cached>,
+    // whether the answer may be stored
+    pub store: bool,
+}
+
+impl Lookup {
+    pub fn result(&self) -> &'static str {
+        match (&self.hit, self.store) {
+            (Some(_), _) => "hit",
+            (None, true) => "miss",
+            (None, false) => "bypass",
+        }
+    }
+}
+
+struct Entry {
+    cached: Cached,
+    stored_at: u64,
+    used: Instant,
+}
+
+// On disk layout of an entry
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    key: String,
+    stored_at: u64,
+    cached: Cached,
+}
+
+pub struct ResponseCache {
+    ttl: Duration,
+    max_entries: usize,
+    max_entry_bytes: usize,
+    dir: Option<PathBuf>,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl ResponseCache {
+    pub fn open(config: &CacheConfig) -> std::io::Result<Self> {
+        // every answer would be stale as soon as it is stored
+        if config.ttl_secs == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "scheduler.cache.ttl_secs must be greater than 0, set enabled = false to turn the cache off",
+            ));
+        }
+        let cache = Self {
+            ttl: Duration::from_secs(config.ttl_secs),
+            max_entries: config.max_entries.max(1),
+            max_entry_bytes: config.max_entry_bytes,
+            dir: config.dir.clone(),
+            entries: Mutex::new(HashMap::new()),
+        };
+        if let Some(dir) = &cache.dir {
+            fs::create_dir_all(dir)?;
+            let mut entries = cache.entries.lock().unwrap();
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let stored = fs::read(&path).ok().and_then(|b| serde_json::from_slice::<Stored>(&b).ok());
+                match stored {
+                    Some(stored) if !cache.expired(stored.stored_at) => {
+                        entries.insert(stored.key, Entry {
+                            cached: stored.cached,
+                            stored_at: stored.stored_at,
+                            used: Instant::now(),
+                        });
+                    }
+                    _ => {
+                        let _ = fs::remove_file(&path);
+                    }
+                }
+            }
+            // the cap may have been lowered since, the oldest entries go first
+            if entries.len() > cache.max_entries {
+                let mut by_age: Vec<(u64, String)> = entries.iter()
+                    .map(|(k, e)| (e.stored_at, k.clone()))
+                    .collect();
+                by_age.sort();
+                for (_, key) in by_age.into_iter().take(entries.len() - cache.max_entries) {
+                    entries.remove(&key);
+                    cache.remove_file(&key);
+                }
+            }
+            metrics().cache_entries.set(entries.len() as i64);
+        }
+        Ok(cache)
+    }
+
+    // The cache key of a cacheable request, and its cached answer.
+    // `cache-control: no-cache` skips the cached answer, `no-store` skips the cache altogether.
+    pub fn lookup(&self, path: &str, method: &str, headers: &HashMap<String, String>, body: &[u8]) -> Option<Lookup> {
+        let key = key(path, method, body)?;
+        let control = headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case("cache-control"))
+            .map(|(_, v)| v.to_ascii_lowercase())
+            .unwrap_or_default();
+        let store = !control.contains("no-store");
+        let hit = if store && !control.contains("no-cache") { self.get(&key) } else { None };
+        let lookup = Lookup { key, hit, store };
+        metrics().cache_requests.with_label_values(&[lookup.result()]).inc();
+        Some(lookup)
+    }
+
+    fn get(&self, key: &str) -> Option<Cached> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        if self.expired(entry.stored_at) {
+            entries.remove(key);
+            metrics().cache_entries.set(entries.len() as i64);
+            self.remove_file(key);
+            return None;
+        }
+        entry.used = Instant::now();
+        Some(entry.cached.clone())
+    }
+
+    // Keep a successful answer, the least recently used entry makes room for it
+    pub fn put(&self, key: String, cached: Cached) {
+        if !(200..300).contains(&cached.status) || cached.chunks.iter().map(String::len).sum::<usize>() > self.max_entry_bytes {
+            return;
+        }
+        let stored_at = now();
+        if let Some(dir) = &self.dir {
+            let stored = Stored { key: key.clone(), stored_at, cached: cached.clone() };
+            let written = serde_json::to_vec(&stored)
+                .map_err(std::io::Error::from)
+                .and_then(|bytes| write_atomic(&dir.join(file_name(&key)), &bytes));
+            if let Err(e) = written {
+                warn!("Failed to write cache entry: {}", e);
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let oldest = entries.iter().min_by_key(|(_, e)| e.used).map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+                self.remove_file(&oldest);
+            }
+        }
+        entries.insert(key, Entry { cached, stored_at, used: Instant::now() });
+        metrics().cache_entries.set(entries.len() as i64);
+    }
+
+    fn expired(&self, stored_at: u64) -> bool {
+        now().saturating_sub(stored_at) >= self.ttl.as_secs()
+    }
+
+    fn remove_file(&self, key: &str) {
+        if let Some(dir) = &self.dir {
+            let _ = fs::remove_file(dir.join(file_name(key)));
+        }
+    }
+}
+
+// Path and body with sorted fields, for deterministic requests only:
+// embeddings, and completions asked for with a temperature of 0
+fn key(path: &str, method: &str, body: &[u8]) -> Option<String> {
+    if !method.eq_ignore_ascii_case("POST") {
+        return None;
+    }
+    let Value::Object(mut body) = serde_json::from_slice::<Value>(body).ok()? else {
+        return None;
+    };
+    if CHAT_PATHS.contains(&path) {
+        if body.get("temperature").and_then(Value::as_f64) != Some(0.0) {
+            return None;
+        }
+    } else if path != EMBEDDINGS_PATH {
+        return None;
+    }
+    for field in IGNORED_FIELDS {
+        body.remove(*field);
+    }
+    // serde_json maps keep their keys sorted, so equal bodies print the same
+    Some(format!("{} {}", path, Value::Object(body)))
+}
+
+fn file_name(key: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    format!("{:016x}.json", hasher.finish())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const CHAT: &str = "/v1/chat/completions";
+
+    fn body(value: Value) -> Vec<u8> {
+        value.to_string().into_bytes()
+    }
+
+    fn answer(text: &str) -> Cached {
+        Cached { status: 200, headers: HashMap::new(), chunks: vec![text.to_string()] }
+    }
+
+    #[test]
+    fn key_only_for_deterministic_requests() {
+        let chat = json!({ "model": "m", "temperature": 0, "messages": [] });
+        assert!(key(CHAT, "POST", &body(chat.clone())).is_some());
+        assert!(key(CHAT, "post", &body(chat.clone())).is_some());
+        assert!(key(CHAT, "GET", &body(chat.clone())).is_none());
+        assert!(key("/v1/audio/speech", "POST", &body(chat)).is_none());
+        assert!(key(CHAT, "POST", &body(json!({ "model": "m", "temperature": 0.7 }))).is_none());
+        assert!(key(CHAT, "POST", &body(json!({ "model": "m" }))).is_none());
+        assert!(key(EMBEDDINGS_PATH, "POST", &body(json!({ "input": "a" }))).is_some());
+        assert!(key(EMBEDDINGS_PATH, "POST", b"[1, 2]").is_none());
+        assert!(key(EMBEDDINGS_PATH, "POST", b"not json").is_none());
+    }
+
+    #[test]
+    fn key_ignores_field_order_and_user() {
+        let a = key(CHAT, "POST", br#"{"model":"m","temperature":0,"user":"ann","messages":[]}"#);
+        let b = key(CHAT, "POST", br#"{"messages":[],"temperature":0,"model":"m"}"#);
+        assert_eq!(a, b);
+        let c = key(CHAT, "POST", br#"{"messages":[],"temperature":0,"model":"other"}"#);
+        assert_ne!(a, c);
+        let d = key("/v1/completions", "POST", br#"{"messages":[],"temperature":0,"model":"m"}"#);
+        assert_ne!(a, d);
+    }
+
+    #[test]
+    fn cache_control_skips_or_bypasses() {
+        let cache = ResponseCache::open(&CacheConfig::default()).unwrap();
+        let request = body(json!({ "input": "a" }));
+        let lookup = cache.lookup(EMBEDDINGS_PATH, "POST", &HashMap::new(), &request).unwrap();
+        assert_eq!(lookup.result(), "miss");
+        cache.put(lookup.key, answer("{}"));
+        assert_eq!(cache.lookup(EMBEDDINGS_PATH, "POST", &HashMap::new(), &request).unwrap().result(), "hit");
+
+        let control = |value: &str| HashMap::from([("Cache-Control".to_string(), value.to_string())]);
+        assert_eq!(cache.lookup(EMBEDDINGS_PATH, "POST", &control("no-cache"), &request).unwrap().result(), "miss");
+        assert_eq!(cache.lookup(EMBEDDINGS_PATH, "POST", &control("no-store"), &request).unwrap().result(), "bypass");
+    }
+
+    #[test]
+    fn least_recently_used_entries_make_room() {
+        let config = CacheConfig { max_entries: 2, max_entry_bytes: 10, ..CacheConfig::default() };
+        let cache = ResponseCache::open(&config).unwrap();
+        cache.put("a".to_string(), answer("a"));
+        cache.put("b".to_string(), answer("b"));
+        assert!(cache.get("a").is_some());
+        cache.put("c".to_string(), answer("c"));
+        assert!(cache.get("a").is_some() && cache.get("c").is_some());
+        assert!(cache.get("b").is_none());
+        // failures and large answers are not kept
+        cache.put("d".to_string(), Cached { status: 500, ..answer("d") });
+        cache.put("e".to_string(), answer("too large to keep"));
+        assert!(cache.get("d").is_none() && cache.get("e").is_none());
+    }
+
+    #[test]
+    fn entries_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CacheConfig { dir: Some(dir.path().to_path_buf()), ..CacheConfig::default() };
+        ResponseCache::open(&config).unwrap().put("a".to_string(), answer("kept"));
+        let cache = ResponseCache::open(&config).unwrap();
+        assert_eq!(cache.get("a").unwrap().body(), b"kept");
+
+        // entries older than the ttl are dropped on open
+        let old = Stored { key: "a".to_string(), stored_at: now() - 7200, cached: answer("old") };
+        write_atomic(&dir.path().join(file_name("a")), &serde_json::to_vec(&old).unwrap()).unwrap();
+        assert!(ResponseCache::open(&config).unwrap().get("a").is_none());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn reopening_keeps_the_newest_entries_within_the_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = CacheConfig { dir: Some(dir.path().to_path_buf()), ..CacheConfig::default() };
+        for (i, key) in ["a", "b", "c"].into_iter().enumerate() {
+            let stored = Stored { key: key.to_string(), stored_at: now() - 30 + i as u64, cached: answer(key) };
+            write_atomic(&dir.path().join(file_name(key)), &serde_json::to_vec(&stored).unwrap()).unwrap();
+        }
+        let cache = ResponseCache::open(&CacheConfig { max_entries: 2, ..config }).unwrap();
+        assert!(cache.get("a").is_none());
+        assert!(cache.get("b").is_some() && cache.get("c").is_some());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn a_zero_ttl_is_rejected() {
+        let config = CacheConfig { ttl_secs: 0, ..CacheConfig::default() };
+        assert!(ResponseCache::open(&config).is_err());
+    }
+
+    #[test]
+    fn only_finished_streams_are_complete() {
+        let chunks = |c: &[&str]| c.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert!(stream_complete(&chunks(&["data: {}\n\n", "data: [DONE]\n\n"])));
+        assert!(!stream_complete(&chunks(&["data: {}\n\n"])));
+        assert!(!stream_complete(&chunks(&["data: {}\n\n", "data: {\"error\":{}}\n\n"])));
+    }
+}
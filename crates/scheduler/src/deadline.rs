@@ -0,0 +1,52 @@
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::Instant;
+use tonic::Status;
+
+// Budget left before the deadline, an error once it has passed
+pub fn remaining(deadline: Option<Instant>) -> Result<Option<Duration>, Status> {
+    match deadline {
+        Some(deadline) => {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(exceeded());
+            }
+            Ok(Some(deadline - now))
+        }
+        None => Ok(None),
+    }
+}
+
+// Run a future within the deadline
+pub async fn within<F: Future>(deadline: Option<Instant>, future: F) -> Result<F::Output, Status> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future)
+            .await
+            .map_err(|_| exceeded()),
+        None => Ok(future.await),
+    }
+}
+
+pub fn exceeded() -> Status {
+    Status::deadline_exceeded("Deadline exceeded")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passed_deadline_is_exceeded() {
+        assert_eq!(remaining(None).unwrap(), None);
+        assert!(remaining(Some(Instant::now() + Duration::from_secs(5))).unwrap().is_some());
+        let status = remaining(Some(Instant::now())).unwrap_err();
+        assert_eq!(status.code(), tonic::Code::DeadlineExceeded);
+    }
+
+    #[tokio::test]
+    async fn futures_past_the_deadline_are_exceeded() {
+        assert_eq!(within(None, async { 1 }).await.unwrap(), 1);
+        let later = within(Some(Instant::now()), tokio::time::sleep(Duration::from_secs(5))).await;
+        assert_eq!(later.unwrap_err().code(), tonic::Code::DeadlineExceeded);
+    }
+}
@@ -1,21 +1,33 @@
 use anyhow::Result;
-use config::LlamaServerConfig;
+use config::{AffinityConfig, CacheConfig, Capability, LlamaServerConfig};
 use serde::Deserialize;
-use std::{collections::HashMap, path::Path};
+use std::{collections::{HashMap, HashSet}, path::Path};
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use tokio::sync::{RwLock, mpsc};
-use tracing::{debug, info, warn};
+use tracing::{debug, warn};
 use uuid::Uuid;
 use std::sync::Arc;
-use toml;
+use std::time::{Duration, SystemTime};
 use tonic::Status;
 use protos::assistant::Response;
+use telemetry::{metrics, GaugeGuard};
+
+pub mod affinity;
+pub mod batch;
+pub mod cache;
+pub mod deadline;
+pub mod semantic;
 
 const DEFAULT_LLAMA_WASM_PATH:&str = "/etc/assistant/bin/llama-api-server.wasm";
 const DEFAULT_WASM_URL:&str = "https://github.com/LlamaEdge/LlamaEdge/releases/latest/download/llama-api-server.wasm";
+// How often low priority work checks whether the scheduler is still busy
+const BUSY_POLL: Duration = Duration::from_secs(1);
+// Capability a forwarded request needs, taken off the headers before they go upstream
+pub const CAPABILITY_HEADER: &str = "x-assistant-capability";
 
 #[derive(Debug, Deserialize)]
+#[allow(dead_code)]
 struct TomlConfig {
     server: ServerConfig,
     chat: Option<ChatConfig>,
@@ -30,6 +42,7 @@ struct ServerConfig {
 
 #[derive(Debug, Deserialize)]
 struct ChatConfig {
+    ctx_size: Option<u32>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -40,6 +53,48 @@ struct EmbeddingConfig {
 struct TtsConfig {
 }
 
+// Errors of request forwarding, each maps to one gRPC code
+#[derive(Debug, thiserror::Error)]
+pub enum SchedulerError {
+    #[error("No available running instances")]
+    NoInstance,
+    #[error("Model {0} not found")]
+    ModelNotFound(String),
+    #[error("Model {0} does not serve {1} requests")]
+    Unsupported(String, &'static str),
+    #[error("All servers are busy")]
+    QueueFull,
+    // the upstream call did not finish within its budget
+    #[error("Upstream request timed out")]
+    Timeout,
+    #[error("Upstream error: {0}")]
+    Upstream(String),
+}
+
+impl From<reqwest::Error> for SchedulerError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_timeout() {
+            SchedulerError::Timeout
+        } else {
+            SchedulerError::Upstream(e.to_string())
+        }
+    }
+}
+
+impl From<SchedulerError> for Status {
+    fn from(e: SchedulerError) -> Self {
+        let message = e.to_string();
+        match e {
+            SchedulerError::NoInstance => Status::unavailable(message),
+            SchedulerError::ModelNotFound(_) => Status::not_found(message),
+            SchedulerError::Unsupported(..) => Status::invalid_argument(message),
+            SchedulerError::QueueFull => Status::resource_exhausted(message),
+            SchedulerError::Timeout => Status::deadline_exceeded(message),
+            SchedulerError::Upstream(_) => Status::unavailable(message),
+        }
+    }
+}
+
 // Service instance running llama-api-server
 #[derive(Debug, Clone)]
 pub struct ServiceInstance {
@@ -47,6 +102,14 @@ pub struct ServiceInstance {
     pub config: LlamaServerConfig,
     pub server_addr: String,
     pub status: ServiceStatus,
+    pub started_at: SystemTime,
+    // Process of the llama-api-server, once spawned
+    pub pid: Option<u32>,
+    // stdout and stderr of the process
+    pub log_path: PathBuf,
+    pub context_length: Option<u32>,
+    // Sections of the llama-api-server config, or model paths given in the llama_servers entry
+    pub capabilities: Vec<Capability>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -62,6 +125,10 @@ pub struct Scheduler {
     instances: Arc<RwLock<HashMap<String, ServiceInstance>>>,
     config_dir: PathBuf,
     max_instances: usize,
+    // Models started so far, starting one again counts as a restart
+    started_models: RwLock<HashSet<String>>,
+    affinity: Option<affinity::Pins>,
+    cache: Option<cache::ResponseCache>,
 }
 
 impl Scheduler {
@@ -70,7 +137,24 @@ impl Scheduler {
             instances: Arc::new(RwLock::new(HashMap::new())),
             config_dir,
             max_instances,
+            started_models: RwLock::new(HashSet::new()),
+            affinity: None,
+            cache: None,
+        }
+    }
+
+    // Answer identical deterministic requests from a cache
+    pub fn with_cache(mut self, config: &CacheConfig) -> std::io::Result<Self> {
+        if config.enabled {
+            self.cache = Some(cache::ResponseCache::open(config)?);
         }
+        Ok(self)
+    }
+
+    // Send the requests of a conversation to the same instance while it runs
+    pub fn with_affinity(mut self, config: &AffinityConfig) -> Self {
+        self.affinity = config.enabled.then(|| affinity::Pins::new(config));
+        self
     }
 
     // Load and start all model instances from config directory
@@ -100,27 +184,48 @@ impl Scheduler {
         
         // Read server address from config file
         let config_path = config.config_path.clone().unwrap_or("".to_string());
-        let server_addr = if !config_path.is_empty() {
+        let toml_config: TomlConfig = if !config_path.is_empty() {
             let config_content = std::fs::read_to_string(&config_path)?;
-            let toml_config: TomlConfig = toml::from_str(&config_content)?;
-            toml_config.server.socket_addr
+            toml::from_str(&config_content)?
         } else {
-            panic!("Config file not found");
+            return Err(anyhow::anyhow!("Config file not found"));
         };
+        let has_path = |path: &Option<String>| path.as_ref().is_some_and(|p| !p.is_empty());
+        let capabilities = [
+            (Capability::Chat, toml_config.chat.is_some() || has_path(&config.chat_model_path)),
+            (Capability::Embedding, toml_config.embedding.is_some() || has_path(&config.embedding_model_path)),
+            (Capability::Tts, toml_config.tts.is_some() || has_path(&config.tts_model_path)),
+        ]
+            .into_iter()
+            .filter_map(|(capability, has)| has.then_some(capability))
+            .collect();
         
         // Create instance
         let instance = ServiceInstance {
             id: id.clone(),
             config: config.clone(),
-            server_addr,
+            server_addr: toml_config.server.socket_addr,
             status: ServiceStatus::Starting,
+            started_at: SystemTime::now(),
+            pid: None,
+            log_path: self.config_dir.join(format!("{}.log", id)),
+            context_length: config.context_length.or(toml_config.chat.and_then(|c| c.ctx_size)),
+            capabilities,
         };
      
+        // Register before spawning, the task updates the status of the registered instance
+        self.instances.write().await.insert(id.clone(), instance.clone());
+        metrics().set_instance_status(&id, &config.name, "starting");
+        if !self.started_models.write().await.insert(config.name.clone()) {
+            metrics().instance_restarts.with_label_values(&[&config.name]).inc();
+        }
+
         // Start llama-api-server process
         tokio::spawn({
             let config_path = config.config_path.clone().unwrap_or("".to_string());
             let id = id.clone();
             let instances = self.instances.clone();
+            let log_path = instance.log_path.clone();
             async move {
                 debug!("Starting llama-api-server with config: {:?}", config_path);
                 // if file does not exist, download it
@@ -192,11 +297,13 @@ impl Scheduler {
     
                 // create a channel to send status to main thread
                 let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+                let pids = instances.clone();
                 let instances = instances.clone();
                 let id_clone = id.clone();
                 debug!("change status to running");
                 if let Some(instance) = instances.write().await.get_mut(&id_clone) {
                     instance.status = ServiceStatus::Running;
+                    metrics().set_instance_status(&id_clone, &instance.config.name, "running");
                 }
                 // start a task to monitor the status of the instance
                 let monitor_handle = tokio::spawn(async move {
@@ -205,14 +312,36 @@ impl Scheduler {
                         let mut instances = instances.write().await;
                         if let Some(instance) = instances.get_mut(&id_clone) {
                             instance.status = ServiceStatus::Stopped;
+                            metrics().set_instance_status(&id_clone, &instance.config.name, "stopped");
                         }
                     }
                 });
 
-                // not print command's log
-                command.stdout(Stdio::null());
+                // the stdin of the assistant carries MCP messages in stdio mode
+                command.stdin(Stdio::null());
+                // keep the output for the admin API
+                match std::fs::File::create(&log_path).and_then(|log| Ok((log.try_clone()?, log))) {
+                    Ok((stdout, stderr)) => {
+                        command.stdout(stdout).stderr(stderr);
+                    }
+                    Err(e) => {
+                        warn!("Failed to create log file {:?}: {}", log_path, e);
+                        command.stdout(Stdio::null());
+                    }
+                }
                 debug!("Running command: {:?}", command);
-                let status = command.status();
+                let status = match command.spawn() {
+                    Ok(mut child) => {
+                        if let Some(instance) = pids.write().await.get_mut(&id) {
+                            instance.pid = Some(child.id());
+                        }
+                        tokio::task::spawn_blocking(move || child.wait())
+                            .await
+                            .map_err(std::io::Error::other)
+                            .and_then(|status| status)
+                    }
+                    Err(e) => Err(e),
+                };
                 debug!("Command status: {:?}", status);
                 
                 // send a signal to the monitor task
@@ -220,8 +349,6 @@ impl Scheduler {
                 let _ = monitor_handle.await;
             }
         });
-
-        self.instances.write().await.insert(id.clone(), instance.clone());
         
         Ok(instance)
     }
@@ -233,12 +360,16 @@ impl Scheduler {
         
         if let Some(instance) = instances.get_mut(id) {
             // Send termination signal
-            let _ = Command::new("kill")
-                .arg("-TERM")
-                .arg(id)
-                .status();
+            if let Some(pid) = instance.pid {
+                let _ = Command::new("kill")
+                    .arg("-TERM")
+                    .arg(pid.to_string())
+                    .status();
+            }
                 
             instance.status = ServiceStatus::Stopped;
+            metrics().remove_instance(id, &instance.config.name);
+            let log_path = instance.log_path.clone();
             instances.remove(id);
             
             // Remove config file
@@ -246,11 +377,36 @@ impl Scheduler {
             if config_path.exists() {
                 std::fs::remove_file(config_path)?;
             }
+            if log_path.exists() {
+                std::fs::remove_file(log_path)?;
+            }
         }
         
         Ok(())
     }
 
+    // Stop an instance and start a new one with the same config
+    pub async fn restart_instance(&self, id: &str) -> Result<ServiceInstance> {
+        let config = self.get_instance(id).await
+            .map(|i| i.config)
+            .ok_or_else(|| anyhow::anyhow!("Instance {} not found", id))?;
+        self.stop_instance(id).await?;
+        self.start_instance_with_config(config).await
+    }
+
+    // Last lines of the output of an instance
+    pub async fn instance_logs(&self, id: &str, lines: usize) -> Result<String> {
+        let instance = self.get_instance(id).await
+            .ok_or_else(|| anyhow::anyhow!("Instance {} not found", id))?;
+        let content = match std::fs::read(&instance.log_path) {
+            Ok(content) => String::from_utf8_lossy(&content).into_owned(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let all: Vec<&str> = content.lines().collect();
+        Ok(all[all.len().saturating_sub(lines)..].join("\n"))
+    }
+
     // Get instance by ID
     pub async fn get_instance(&self, id: &str) -> Option<ServiceInstance> {
         let instances = self.instances.read().await;
@@ -258,11 +414,41 @@ impl Scheduler {
     }
 
     // Get all instances
+    // Configs of the registered instances, one per model
+    pub async fn model_configs(&self) -> Vec<LlamaServerConfig> {
+        let instances = self.instances.read().await;
+        let mut configs: Vec<LlamaServerConfig> = Vec::new();
+        for instance in instances.values() {
+            if !configs.iter().any(|c| c.name == instance.config.name) {
+                configs.push(instance.config.clone());
+            }
+        }
+        configs
+    }
+
     pub async fn list_instances(&self) -> Vec<ServiceInstance> {
         let instances = self.instances.read().await;
         instances.values().cloned().collect()
     }
 
+    // Get the names and aliases of models served by running instances
+    pub async fn list_models(&self) -> Vec<String> {
+        let instances = self.instances.read().await;
+        let mut models: Vec<String> = instances.values()
+            .filter(|i| i.status == ServiceStatus::Running)
+            .flat_map(|i| std::iter::once(&i.config.name).chain(&i.config.aliases).cloned())
+            .collect();
+        models.sort();
+        models.dedup();
+        models
+    }
+
+    // Whether an instance of the model is registered, running or not
+    pub async fn has_model(&self, model: &str) -> bool {
+        let instances = self.instances.read().await;
+        instances.values().any(|i| i.config.serves(model))
+    }
+
     // check current load status
     pub async fn check_load(&self) -> f32 {
         let instances = self.instances.read().await;
@@ -278,14 +464,37 @@ impl Scheduler {
         self.check_load().await >= max_load
     }
 
+    // Wait until the load is below max_load, low priority work lets other requests go first
+    pub async fn wait_until_idle(&self, max_load: f32) {
+        let mut queued = None;
+        while self.is_busy(max_load).await {
+            queued.get_or_insert_with(|| GaugeGuard::new(metrics().queue_depth.clone()));
+            tokio::time::sleep(BUSY_POLL).await;
+        }
+    }
+
     // Forward request to appropriate instance or return error if too busy
-    pub async fn forward_request(&self,path: &str, method: &str, body: Vec<u8>, headers: HashMap<String, String>) -> Result<(u16, Vec<u8>, HashMap<String, String>)> {
-        let instances = self.instances.read().await;
-        // debug!("Instances: {:?}", instances);
-        let instance = instances.values()
-            .find(|i| i.status == ServiceStatus::Running)
-            .ok_or_else(|| anyhow::anyhow!("No available running instances"))?;
-        // debug!("Instance: {:?}", instance);
+    // The timeout bounds the whole upstream exchange, body included
+    pub async fn forward_request(
+        &self,
+        path: &str,
+        method: &str,
+        body: Vec<u8>,
+        mut headers: HashMap<String, String>,
+        timeout: Option<Duration>,
+    ) -> Result<(u16, Vec<u8>, HashMap<String, String>), SchedulerError> {
+        let capability = headers.remove(CAPABILITY_HEADER).and_then(|c| Capability::parse(&c));
+        let lookup = self.cache.as_ref().and_then(|c| c.lookup(path, method, &headers, &body));
+        if let Some(hit) = lookup.as_ref().and_then(|l| l.hit.clone()) {
+            let mut headers = hit.headers.clone();
+            headers.insert(cache::CACHE_HEADER.to_string(), "hit".to_string());
+            return Ok((hit.status, hit.body(), headers));
+        }
+        let conversation = affinity::key(&headers, &body);
+        let instance = self.pick_instance(request_model(&body).as_deref(), capability, conversation).await?;
+        let _in_flight = GaugeGuard::new(
+            metrics().instance_in_flight.with_label_values(&[&instance.id, &instance.config.name]),
+        );
         // no proxy
         let client = reqwest::Client::builder()
             .no_proxy()
@@ -294,23 +503,32 @@ impl Scheduler {
         let url = format!("http://{}{}", instance.server_addr, path);
         // debug!("Forwarding request to: {}", url);
         let mut request = client
-            .request(reqwest::Method::from_bytes(method.as_bytes())?, url)
+            .request(request_method(method)?, url)
             .body(body);
             
         // Add headers
         for (key, value) in headers {
             request = request.header(key, value);
         }
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
 
         let response = request.send().await?;
 
         let status = response.status().as_u16();
-        let headers = response.headers()
+        let mut headers: HashMap<String, String> = response.headers()
             .iter()
             .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
             .collect();
         let body = response.bytes().await?.to_vec();
         // debug!("Response body: {:?}", String::from_utf8_lossy(&body));
+        if let Some(lookup) = lookup {
+            if let (true, Some(cache), Ok(text)) = (lookup.store, &self.cache, std::str::from_utf8(&body)) {
+                cache.put(lookup.key.clone(), cached(status, &headers, vec![text.to_string()]));
+            }
+            headers.insert(cache::CACHE_HEADER.to_string(), lookup.result().to_string());
+        }
         Ok((status, body, headers))
     }
 
@@ -320,13 +538,33 @@ impl Scheduler {
         path: &str,
         method: &str,
         body: Vec<u8>,
-        headers: HashMap<String, String>,
+        mut headers: HashMap<String, String>,
+        timeout: Option<Duration>,
         tx: mpsc::Sender<Result<Response, Status>>,
-    ) -> Result<()> {
-        let instances = self.instances.read().await;
-        let instance = instances.values()
-            .find(|i| i.status == ServiceStatus::Running)
-            .ok_or_else(|| anyhow::anyhow!("No available running instances"))?;
+    ) -> Result<(), SchedulerError> {
+        let capability = headers.remove(CAPABILITY_HEADER).and_then(|c| Capability::parse(&c));
+        let lookup = self.cache.as_ref().and_then(|c| c.lookup(path, method, &headers, &body));
+        if let Some(hit) = lookup.as_ref().and_then(|l| l.hit.clone()) {
+            // replayed chunk by chunk, as it was streamed
+            let mut headers = hit.headers.clone();
+            headers.insert(cache::CACHE_HEADER.to_string(), "hit".to_string());
+            for chunk in hit.chunks {
+                let message = Response {
+                    status: hit.status as i32,
+                    body: chunk.into_bytes(),
+                    headers: std::mem::take(&mut headers),
+                };
+                if tx.send(Ok(message)).await.is_err() {
+                    break;
+                }
+            }
+            return Ok(());
+        }
+        let conversation = affinity::key(&headers, &body);
+        let instance = self.pick_instance(request_model(&body).as_deref(), capability, conversation).await?;
+        let _in_flight = GaugeGuard::new(
+            metrics().instance_in_flight.with_label_values(&[&instance.id, &instance.config.name]),
+        );
 
         let client = reqwest::Client::builder()
             .no_proxy()
@@ -336,28 +574,182 @@ impl Scheduler {
         // debug!("Forwarding stream request to: {}", url);
         
         let mut request = client
-            .request(reqwest::Method::from_bytes(method.as_bytes())?, url)
+            .request(request_method(method)?, url)
             .body(body);
             
         // Add headers
         for (key, value) in headers {
             request = request.header(key, value);
         }
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
 
-        let response = request.send().await?;
+        let mut response = request.send().await?;
         let status = response.status().as_u16();
-        let headers = response.headers()
+        let mut headers: HashMap<String, String> = response.headers()
             .iter()
             .map(|(k, v)| (k.to_string(), v.to_str().unwrap_or("").to_string()))
             .collect();
+        if let Some(lookup) = &lookup {
+            headers.insert(cache::CACHE_HEADER.to_string(), lookup.result().to_string());
+        }
 
-        let body = response.bytes().await?.to_vec();
-        let _ = tx.send(Ok(Response {
-            status: status as i32,
-            body,
-            headers,
-        })).await;
+        // relay body chunks as they arrive, headers are only sent with the first one
+        let mut first = true;
+        // the chunks are kept for the cache while they are text, and the stream is complete
+        let mut chunks = lookup.as_ref().filter(|l| l.store).map(|_| Vec::new());
+        while let Some(chunk) = response.chunk().await? {
+            if let Some(kept) = &mut chunks {
+                match std::str::from_utf8(&chunk) {
+                    Ok(text) => kept.push(text.to_string()),
+                    Err(_) => chunks = None,
+                }
+            }
+            let message = Response {
+                status: status as i32,
+                body: chunk.to_vec(),
+                headers: if first { headers.clone() } else { HashMap::new() },
+            };
+            first = false;
+            if tx.send(Ok(message)).await.is_err() {
+                debug!("Stream receiver dropped, stop forwarding");
+                chunks = None;
+                break;
+            }
+        }
+        let chunks = chunks.filter(|c| cache::stream_complete(c));
+        if let (Some(lookup), Some(cache), Some(chunks)) = (lookup, &self.cache, chunks) {
+            cache.put(lookup.key, cached(status, &headers, chunks));
+        }
 
         Ok(())
     }
-} 
\ No newline at end of file
+
+    // Pick a running instance serving the model, any running one when no model is named
+    async fn pick_instance(
+        &self,
+        model: Option<&str>,
+        capability: Option<Capability>,
+        conversation: Option<u64>,
+    ) -> Result<ServiceInstance, SchedulerError> {
+        let instances = self.instances.read().await;
+        let model = model.filter(|m| !m.is_empty());
+        if let Some(model) = model {
+            if !instances.values().any(|i| i.config.serves(model)) {
+                return Err(SchedulerError::ModelNotFound(model.to_string()));
+            }
+            if let Some(capability) = capability {
+                if !instances.values().any(|i| i.config.serves(model) && i.capabilities.contains(&capability)) {
+                    return Err(SchedulerError::Unsupported(model.to_string(), capability.as_str()));
+                }
+            }
+        }
+        let candidates: Vec<&ServiceInstance> = instances.values()
+            .filter(|i| model.map_or(true, |m| i.config.serves(m)))
+            .filter(|i| capability.map_or(true, |c| i.capabilities.contains(&c)))
+            .filter(|i| i.status == ServiceStatus::Running)
+            .collect();
+        let (Some(pins), Some(conversation)) = (&self.affinity, conversation) else {
+            return candidates.first().map(|i| (*i).clone()).ok_or(SchedulerError::NoInstance);
+        };
+
+        let in_flight = |i: &ServiceInstance| {
+            metrics().instance_in_flight.with_label_values(&[&i.id, &i.config.name]).get()
+        };
+        let least = candidates.iter().map(|i| in_flight(i)).min().unwrap_or_default();
+        let ids: Vec<&str> = candidates.iter().map(|i| i.id.as_str()).collect();
+        if let Some(id) = pins.get(conversation, &ids) {
+            if let Some(instance) = candidates.iter().find(|i| i.id == id) {
+                // an overloaded instance gives the conversation up
+                if in_flight(instance) <= least + affinity::MAX_IMBALANCE {
+                    return Ok((*instance).clone());
+                }
+            }
+        }
+        // a new conversation, or one whose instance is gone or overloaded, goes to the least busy instance
+        let instance = candidates.iter()
+            .min_by_key(|i| (in_flight(i), pins.pinned(&i.id)))
+            .ok_or(SchedulerError::NoInstance)?;
+        pins.pin(conversation, &instance.id);
+        Ok((*instance).clone())
+    }
+}
+
+// An answer to cache, with the headers that describe its body
+fn cached(status: u16, headers: &HashMap<String, String>, chunks: Vec<String>) -> cache::Cached {
+    let headers = headers.iter()
+        .filter(|(k, _)| k.eq_ignore_ascii_case("content-type"))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+    cache::Cached { status, headers, chunks }
+}
+
+fn request_method(method: &str) -> Result<reqwest::Method, SchedulerError> {
+    reqwest::Method::from_bytes(method.as_bytes())
+        .map_err(|e| SchedulerError::Upstream(e.to_string()))
+}
+
+// The model named by a JSON request
+pub fn model_of(request: &serde_json::Value) -> Option<&str> {
+    request.get("model")?.as_str()
+}
+
+// The model named by a JSON request body
+pub fn request_model(body: &[u8]) -> Option<String> {
+    model_of(&serde_json::from_slice(body).ok()?).map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn running(id: &str) -> ServiceInstance {
+        ServiceInstance {
+            id: id.to_string(),
+            config: LlamaServerConfig {
+                name: "m".to_string(),
+                chat_model_path: None,
+                embedding_model_path: None,
+                tts_model_path: None,
+                config_path: None,
+                aliases: vec![],
+                owned_by: None,
+                context_length: None,
+            },
+            server_addr: String::new(),
+            status: ServiceStatus::Running,
+            started_at: SystemTime::now(),
+            pid: None,
+            log_path: PathBuf::new(),
+            context_length: None,
+            capabilities: vec![Capability::Chat],
+        }
+    }
+
+    fn set_in_flight(id: &str, value: i64) {
+        metrics().instance_in_flight.with_label_values(&[id, "m"]).set(value);
+    }
+
+    #[tokio::test]
+    async fn pins_hold_until_the_instance_is_overloaded() {
+        let affinity = AffinityConfig { enabled: true, ..AffinityConfig::default() };
+        let scheduler = Scheduler::new(PathBuf::new(), 2).with_affinity(&affinity);
+        for id in ["pin-a", "pin-b"] {
+            scheduler.instances.write().await.insert(id.to_string(), running(id));
+            set_in_flight(id, 0);
+        }
+        let pick = || async { scheduler.pick_instance(Some("m"), None, Some(7)).await.unwrap().id };
+
+        let pinned = pick().await;
+        let other = if pinned == "pin-a" { "pin-b" } else { "pin-a" };
+        set_in_flight(&pinned, affinity::MAX_IMBALANCE);
+        assert_eq!(pick().await, pinned);
+
+        // past the imbalance the conversation moves, and stays on its new instance
+        set_in_flight(&pinned, affinity::MAX_IMBALANCE + 1);
+        assert_eq!(pick().await, other);
+        set_in_flight(&pinned, 0);
+        assert_eq!(pick().await, other);
+    }
+}
@@ -0,0 +1,169 @@
+// Answers of earlier prompts, found by the cosine similarity of their embeddings
+use config::storage::{now, write_atomic};
+use config::SemanticCacheConfig;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use telemetry::metrics;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::cache::Cached;
+
+struct Entry {
+    id: String,
+    // requests only match entries of their own scope
+    scope: String,
+    // of unit length, so the similarity is a dot product
+    vector: Vec<f32>,
+    cached: Cached,
+    stored_at: u64,
+    used: Instant,
+}
+
+// On disk layout of an entry
+#[derive(Serialize, Deserialize)]
+struct Stored {
+    scope: String,
+    vector: Vec<f32>,
+    stored_at: u64,
+    cached: Cached,
+}
+
+// A flat index searched entry by entry, it is kept small by max_entries
+pub struct SemanticIndex {
+    config: SemanticCacheConfig,
+    ttl: Duration,
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl SemanticIndex {
+    pub fn open(config: &SemanticCacheConfig) -> std::io::Result<Self> {
+        let index = Self {
+            config: config.clone(),
+            ttl: Duration::from_secs(config.ttl_secs),
+            entries: Mutex::new(Vec::new()),
+        };
+        if let Some(dir) = &config.dir {
+            fs::create_dir_all(dir)?;
+            let mut entries = index.entries.lock().unwrap();
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                let id = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+                let stored = fs::read(&path).ok().and_then(|b| serde_json::from_slice::<Stored>(&b).ok());
+                match stored {
+                    Some(stored) if !index.expired(stored.stored_at) => entries.push(Entry {
+                        id,
+                        scope: stored.scope,
+                        vector: stored.vector,
+                        cached: stored.cached,
+                        stored_at: stored.stored_at,
+                        used: Instant::now(),
+                    }),
+                    _ => {
+                        let _ = fs::remove_file(&path);
+                    }
+                }
+            }
+            metrics().semantic_cache_entries.set(entries.len() as i64);
+        }
+        Ok(index)
+    }
+
+    // Model the prompts are embedded with, when one is configured
+    pub fn embedding_model(&self) -> Option<&str> {
+        self.config.embedding_model.as_deref()
+    }
+
+    pub fn threshold(&self) -> f32 {
+        self.config.threshold
+    }
+
+    // Threshold set for the model, if any
+    pub fn model_threshold(&self, model: &str) -> Option<f32> {
+        self.config.models.get(model).copied()
+    }
+
+    // The most similar answer of the scope, when its similarity reaches the threshold
+    pub fn find(&self, scope: &str, vector: &[f32], threshold: f32) -> Option<(f32, Cached)> {
+        let vector = normalized(vector)?;
+        let mut entries = self.entries.lock().unwrap();
+        let expired: Vec<String> = entries.iter()
+            .filter(|e| self.expired(e.stored_at))
+            .map(|e| e.id.clone())
+            .collect();
+        if !expired.is_empty() {
+            entries.retain(|e| !expired.contains(&e.id));
+            metrics().semantic_cache_entries.set(entries.len() as i64);
+            for id in &expired {
+                self.remove_file(id);
+            }
+        }
+
+        let (similarity, entry) = entries.iter_mut()
+            .filter(|e| e.scope == scope && e.vector.len() == vector.len())
+            .map(|e| (dot(&e.vector, &vector), e))
+            .max_by(|(a, _), (b, _)| a.total_cmp(b))?;
+        if similarity < threshold {
+            return None;
+        }
+        entry.used = Instant::now();
+        Some((similarity, entry.cached.clone()))
+    }
+
+    // Keep a successful answer, the least recently used entry makes room for it
+    pub fn put(&self, scope: String, vector: &[f32], cached: Cached) {
+        if !(200..300).contains(&cached.status) || cached.chunks.iter().map(String::len).sum::<usize>() > self.config.max_entry_bytes {
+            return;
+        }
+        let Some(vector) = normalized(vector) else {
+            return;
+        };
+        let id = Uuid::new_v4().simple().to_string();
+        let stored_at = now();
+        if let Some(dir) = &self.config.dir {
+            let stored = Stored { scope: scope.clone(), vector: vector.clone(), stored_at, cached: cached.clone() };
+            let written = serde_json::to_vec(&stored)
+                .map_err(std::io::Error::from)
+                .and_then(|bytes| write_atomic(&dir.join(format!("{}.json", id)), &bytes));
+            if let Err(e) = written {
+                warn!("Failed to write semantic cache entry: {}", e);
+            }
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.config.max_entries.max(1) {
+            let oldest = entries.iter()
+                .enumerate()
+                .min_by_key(|(_, e)| e.used)
+                .map(|(i, _)| i);
+            if let Some(oldest) = oldest {
+                let entry = entries.swap_remove(oldest);
+                self.remove_file(&entry.id);
+            }
+        }
+        entries.push(Entry { id, scope, vector, cached, stored_at, used: Instant::now() });
+        metrics().semantic_cache_entries.set(entries.len() as i64);
+    }
+
+    fn expired(&self, stored_at: u64) -> bool {
+        now().saturating_sub(stored_at) >= self.ttl.as_secs()
+    }
+
+    fn remove_file(&self, id: &str) {
+        if let Some(dir) = &self.config.dir {
+            let _ = fs::remove_file(dir.join(format!("{}.json", id)));
+        }
+    }
+}
+
+// The vector scaled to a length of 1, so the dot product of two is their cosine similarity
+pub fn normalized(vector: &[f32]) -> Option<Vec<f32>> {
+    let norm = dot(vector, vector).sqrt();
+    (norm > 0.0 && norm.is_finite()).then(|| vector.iter().map(|x| x / norm).collect())
+}
+
+pub fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
@@ -0,0 +1,349 @@
+use config::storage::{now, write_atomic};
+use config::SessionConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use tracing::warn;
+use uuid::Uuid;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("Session {0} not found")]
+    NotFound(String),
+    #[error("Invalid session id {0}")]
+    InvalidId(String),
+    #[error("Session {0} already exists")]
+    Exists(String),
+    #[error("Session storage error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Session storage error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, SessionError>;
+
+// A conversation, its messages in the chat completion format
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+    pub id: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    // Identity of the API key that created it (auth::key_id), only that key sees it
+    pub owner: Option<String>,
+    pub model: Option<String>,
+    pub forked_from: Option<String>,
+    pub messages: Vec<Value>,
+}
+
+impl Session {
+    fn new(id: String, owner: Option<&str>) -> Self {
+        let now = now();
+        Self {
+            id,
+            created_at: now,
+            updated_at: now,
+            owner: owner.map(|o| o.to_string()),
+            model: None,
+            forked_from: None,
+            messages: vec![],
+        }
+    }
+
+    // sessions created without a key are not shared with the keys
+    fn visible_to(&self, owner: Option<&str>) -> bool {
+        self.owner.as_deref() == owner
+    }
+}
+
+// Sessions in memory, written to one file each when a directory is configured
+pub struct SessionStore {
+    dir: Option<PathBuf>,
+    sessions: RwLock<HashMap<String, Session>>,
+}
+
+impl SessionStore {
+    pub fn open(config: &SessionConfig) -> Result<Self> {
+        let mut sessions = HashMap::new();
+        if let Some(dir) = &config.dir {
+            fs::create_dir_all(dir)?;
+            for entry in fs::read_dir(dir)? {
+                let path = entry?.path();
+                if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                    continue;
+                }
+                match fs::read(&path).map_err(SessionError::from).and_then(|b| Ok(serde_json::from_slice::<Session>(&b)?)) {
+                    Ok(session) => {
+                        sessions.insert(session.id.clone(), session);
+                    }
+                    Err(e) => warn!("Skipping session {:?}: {}", path, e),
+                }
+            }
+        }
+        Ok(Self {
+            dir: config.dir.clone(),
+            sessions: RwLock::new(sessions),
+        })
+    }
+
+    // A new empty session, with a random id unless one is given
+    pub fn create(&self, id: Option<&str>, owner: Option<&str>) -> Result<Session> {
+        let id = match id {
+            Some(id) => valid_id(id)?.to_string(),
+            None => format!("sess_{}", Uuid::new_v4().simple()),
+        };
+        let mut sessions = self.sessions.write().unwrap();
+        if sessions.contains_key(&id) {
+            return Err(SessionError::Exists(id));
+        }
+        let session = Session::new(id, owner);
+        self.save(&session)?;
+        sessions.insert(session.id.clone(), session.clone());
+        Ok(session)
+    }
+
+    // The session, or a new one with this id when there is none yet
+    pub fn get_or_create(&self, id: &str, owner: Option<&str>) -> Result<Session> {
+        match self.get(id, owner) {
+            Err(SessionError::NotFound(_)) if !self.sessions.read().unwrap().contains_key(id) => {
+                self.create(Some(id), owner)
+            }
+            result => result,
+        }
+    }
+
+    pub fn get(&self, id: &str, owner: Option<&str>) -> Result<Session> {
+        self.sessions.read().unwrap()
+            .get(id)
+            .filter(|s| s.visible_to(owner))
+            .cloned()
+            .ok_or_else(|| SessionError::NotFound(id.to_string()))
+    }
+
+    // Sessions of the owner, the most recently used first
+    pub fn list(&self, owner: Option<&str>) -> Vec<Session> {
+        let mut sessions: Vec<Session> = self.sessions.read().unwrap()
+            .values()
+            .filter(|s| s.visible_to(owner))
+            .cloned()
+            .collect();
+        sessions.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
+        sessions
+    }
+
+    // Add the messages of a finished turn
+    pub fn append(&self, id: &str, owner: Option<&str>, model: Option<&str>, messages: Vec<Value>) -> Result<()> {
+        let mut sessions = self.sessions.write().unwrap();
+        let session = sessions.get_mut(id)
+            .filter(|s| s.visible_to(owner))
+            .ok_or_else(|| SessionError::NotFound(id.to_string()))?;
+        session.messages.extend(messages);
+        if let Some(model) = model {
+            session.model = Some(model.to_string());
+        }
+        session.updated_at = now();
+        self.save(session)
+    }
+
+    // A copy of the session, of its first `count` messages when given
+    pub fn fork(&self, id: &str, owner: Option<&str>, count: Option<usize>) -> Result<Session> {
+        let source = self.get(id, owner)?;
+        let mut session = Session::new(format!("sess_{}", Uuid::new_v4().simple()), owner);
+        session.model = source.model;
+        session.forked_from = Some(source.id);
+        session.messages = source.messages;
+        session.messages.truncate(count.unwrap_or(usize::MAX));
+        self.save(&session)?;
+        self.sessions.write().unwrap().insert(session.id.clone(), session.clone());
+        Ok(session)
+    }
+
+    pub fn delete(&self, id: &str, owner: Option<&str>) -> Result<()> {
+        let mut sessions = self.sessions.write().unwrap();
+        if !sessions.get(id).is_some_and(|s| s.visible_to(owner)) {
+            return Err(SessionError::NotFound(id.to_string()));
+        }
+        sessions.remove(id);
+        if let Some(dir) = &self.dir {
+            match fs::remove_file(dir.join(format!("{}.json", id))) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn save(&self, session: &Session) -> Result<()> {
+        let Some(dir) = &self.dir else {
+            return Ok(());
+        };
+        Ok(write_atomic(&dir.join(format!("{}.json", session.id)), &serde_json::to_vec(session)?)?)
+    }
+}
+
+// The history followed by the new messages, without the oldest turns that do not fit the budget.
+// A system message of the new messages replaces the ones of the history.
+pub fn fit(history: &[Value], messages: &[Value], budget: usize) -> Vec<Value> {
+    let is_system = |m: &&Value| role(m) == "system";
+    let system: Vec<&Value> = if messages.iter().any(|m| is_system(&m)) {
+        vec![]
+    } else {
+        history.iter().filter(is_system).collect()
+    };
+    let turns: Vec<&Value> = history.iter().filter(|m| !is_system(m)).collect();
+
+    let fixed: usize = system.iter().copied().chain(messages).map(estimate_tokens).sum();
+    let mut used: usize = fixed + turns.iter().copied().map(estimate_tokens).sum::<usize>();
+    let mut start = 0;
+    while start < turns.len() && used > budget {
+        used -= estimate_tokens(turns[start]);
+        start += 1;
+    }
+    // the history restarts at a question, not at an answer or tool result left without it
+    while start < turns.len() && role(turns[start]) != "user" {
+        start += 1;
+    }
+
+    system.into_iter()
+        .chain(turns[start..].iter().copied())
+        .chain(messages)
+        .cloned()
+        .collect()
+}
+
+// Rough token count of a message, four characters per token
+pub fn estimate_tokens(message: &Value) -> usize {
+    let text: usize = match &message["content"] {
+        Value::String(text) => text.len(),
+        Value::Array(parts) => parts.iter()
+            .map(|p| p["text"].as_str().map_or(0, str::len))
+            .sum(),
+        _ => 0,
+    };
+    let calls: usize = message["tool_calls"].as_array()
+        .into_iter()
+        .flatten()
+        .map(|c| c["function"].to_string().len())
+        .sum();
+    // role and separators
+    4 + (text + calls).div_ceil(4)
+}
+
+fn role(message: &Value) -> &str {
+    message["role"].as_str().unwrap_or_default()
+}
+
+// Ids name files on disk, so they may not reach outside the directory
+fn valid_id(id: &str) -> Result<&str> {
+    if id.is_empty() || id.len() > 128 || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(SessionError::InvalidId(id.to_string()));
+    }
+    Ok(id)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn message(role: &str, text: &str) -> Value {
+        json!({ "role": role, "content": text })
+    }
+
+    fn texts(messages: &[Value]) -> Vec<&str> {
+        messages.iter().map(|m| m["content"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn fit_keeps_everything_within_budget() {
+        let history = [message("system", "s"), message("user", "q1"), message("assistant", "a1")];
+        let fitted = fit(&history, &[message("user", "q2")], 1000);
+        assert_eq!(texts(&fitted), ["s", "q1", "a1", "q2"]);
+    }
+
+    #[test]
+    fn fit_drops_the_oldest_turns_first() {
+        let long = "x".repeat(400);
+        let history = [
+            message("system", "s"),
+            message("user", &long),
+            message("assistant", &long),
+            message("user", "q2"),
+            message("assistant", "a2"),
+        ];
+        let messages = [message("user", "q3")];
+        let budget = [&history[0], &history[3], &history[4], &messages[0]].into_iter()
+            .map(estimate_tokens)
+            .sum();
+        assert_eq!(texts(&fit(&history, &messages, budget)), ["s", "q2", "a2", "q3"]);
+        // the system message and the new messages are always kept
+        assert_eq!(texts(&fit(&history, &messages, 0)), ["s", "q3"]);
+    }
+
+    #[test]
+    fn fit_restarts_at_a_question() {
+        let history = [
+            message("user", &"x".repeat(400)),
+            message("assistant", "a1"),
+            json!({ "role": "tool", "tool_call_id": "t", "content": "r" }),
+            message("user", "q2"),
+        ];
+        let budget = history[1..].iter().chain([&message("user", "q3")]).map(estimate_tokens).sum();
+        assert_eq!(texts(&fit(&history, &[message("user", "q3")], budget)), ["q2", "q3"]);
+    }
+
+    #[test]
+    fn fit_replaces_the_system_message() {
+        let history = [message("system", "old"), message("user", "q1")];
+        let fitted = fit(&history, &[message("system", "new"), message("user", "q2")], 1000);
+        assert_eq!(texts(&fitted), ["q1", "new", "q2"]);
+    }
+
+    #[test]
+    fn tokens_are_estimated_from_text_and_calls() {
+        assert_eq!(estimate_tokens(&message("user", "")), 4);
+        assert_eq!(estimate_tokens(&message("user", "abcde")), 6);
+        let parts = json!({ "role": "user", "content": [{ "type": "text", "text": "abcd" }, { "type": "image_url" }] });
+        assert_eq!(estimate_tokens(&parts), 5);
+        let call = json!({ "role": "assistant", "content": null, "tool_calls": [{ "function": { "name": "f" } }] });
+        assert!(estimate_tokens(&call) > 4);
+    }
+
+    #[test]
+    fn sessions_are_only_visible_to_their_owner() {
+        let store = SessionStore::open(&SessionConfig::default()).unwrap();
+        let owned = store.create(None, Some("key_a")).unwrap();
+        let keyless = store.create(Some("open"), None).unwrap();
+
+        assert!(store.get(&owned.id, Some("key_a")).is_ok());
+        assert!(matches!(store.get(&owned.id, Some("key_b")), Err(SessionError::NotFound(_))));
+        assert!(store.get(&owned.id, None).is_err());
+        // sessions created without a key are hidden from the keys
+        assert!(store.get(&keyless.id, None).is_ok());
+        assert!(store.get(&keyless.id, Some("key_a")).is_err());
+        assert_eq!(store.list(Some("key_a")).len(), 1);
+        // and a key cannot take over their id
+        assert!(matches!(store.get_or_create("open", Some("key_a")), Err(SessionError::NotFound(_))));
+        assert!(store.delete(&owned.id, Some("key_b")).is_err());
+    }
+
+    #[test]
+    fn sessions_are_kept_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SessionConfig { dir: Some(dir.path().to_path_buf()), ..SessionConfig::default() };
+        let store = SessionStore::open(&config).unwrap();
+        store.create(Some("s1"), Some("key_a")).unwrap();
+        store.append("s1", Some("key_a"), Some("m"), vec![message("user", "q1")]).unwrap();
+        let fork = store.fork("s1", Some("key_a"), Some(0)).unwrap();
+        assert!(matches!(store.create(Some("../x"), None), Err(SessionError::InvalidId(_))));
+
+        let store = SessionStore::open(&config).unwrap();
+        let session = store.get("s1", Some("key_a")).unwrap();
+        assert_eq!((session.model.as_deref(), session.messages.len()), (Some("m"), 1));
+        let fork = store.get(&fork.id, Some("key_a")).unwrap();
+        assert_eq!((fork.forked_from.as_deref(), fork.messages.len()), (Some("s1"), 0));
+    }
+}
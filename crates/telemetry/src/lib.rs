@@ -0,0 +1,184 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+
+// Buckets of request durations and time to first token, in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+const RATE_BUCKETS: &[f64] = &[1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0];
+
+// Instance statuses reported by the status gauge
+pub const INSTANCE_STATUSES: &[&str] = &["starting", "running", "failed", "stopped"];
+
+// Metrics of the whole process, served at /metrics
+pub struct Metrics {
+    registry: Registry,
+    // route, model, status
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    // route, model
+    pub time_to_first_token: HistogramVec,
+    pub stream_tokens_per_second: HistogramVec,
+    // method, code
+    pub grpc_requests: IntCounterVec,
+    pub grpc_request_duration: HistogramVec,
+    // requests waiting for the instances to have room
+    pub queue_depth: IntGauge,
+    // instance, model
+    pub instance_in_flight: IntGaugeVec,
+    // instance, model, status
+    pub instance_status: IntGaugeVec,
+    // model
+    pub instance_restarts: IntCounterVec,
+    // target, outcome
+    pub remote_offloads: IntCounterVec,
+    // result: hit, miss or bypass
+    pub cache_requests: IntCounterVec,
+    pub cache_entries: IntGauge,
+    // result: hit, miss, bypass or error
+    pub semantic_cache_requests: IntCounterVec,
+    pub semantic_cache_entries: IntGauge,
+    // server, outcome: ok, error or failed
+    pub mcp_tool_calls: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("assistant".to_string()), None)
+            .expect("valid registry prefix");
+        let metrics = Self {
+            http_requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests answered by the gateway"),
+                &["route", "model", "status"],
+            ).unwrap(),
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "Time until the response headers were sent")
+                    .buckets(LATENCY_BUCKETS.to_vec()),
+                &["route", "model", "status"],
+            ).unwrap(),
+            time_to_first_token: HistogramVec::new(
+                HistogramOpts::new("stream_time_to_first_token_seconds", "Time until the first chunk of a stream")
+                    .buckets(LATENCY_BUCKETS.to_vec()),
+                &["route", "model"],
+            ).unwrap(),
+            stream_tokens_per_second: HistogramVec::new(
+                HistogramOpts::new("stream_tokens_per_second", "Tokens streamed per second after the first one")
+                    .buckets(RATE_BUCKETS.to_vec()),
+                &["route", "model"],
+            ).unwrap(),
+            grpc_requests: IntCounterVec::new(
+                Opts::new("grpc_requests_total", "gRPC calls answered by the server"),
+                &["method", "code"],
+            ).unwrap(),
+            grpc_request_duration: HistogramVec::new(
+                HistogramOpts::new("grpc_request_duration_seconds", "Time until the response headers of a gRPC call were sent")
+                    .buckets(LATENCY_BUCKETS.to_vec()),
+                &["method", "code"],
+            ).unwrap(),
+            queue_depth: IntGauge::new("scheduler_queue_depth", "Low priority requests waiting for the instances to have room").unwrap(),
+            instance_in_flight: IntGaugeVec::new(
+                Opts::new("instance_in_flight_requests", "Requests being served by an instance"),
+                &["instance", "model"],
+            ).unwrap(),
+            instance_status: IntGaugeVec::new(
+                Opts::new("instance_status", "1 for the current status of an instance"),
+                &["instance", "model", "status"],
+            ).unwrap(),
+            instance_restarts: IntCounterVec::new(
+                Opts::new("instance_restarts_total", "Instances started for a model that was started before"),
+                &["model"],
+            ).unwrap(),
+            remote_offloads: IntCounterVec::new(
+                Opts::new("remote_offloads_total", "Requests offloaded to peers and remote servers"),
+                &["target", "outcome"],
+            ).unwrap(),
+            cache_requests: IntCounterVec::new(
+                Opts::new("cache_requests_total", "Cacheable requests by cache result"),
+                &["result"],
+            ).unwrap(),
+            cache_entries: IntGauge::new("cache_entries", "Responses held by the response cache").unwrap(),
+            semantic_cache_requests: IntCounterVec::new(
+                Opts::new("semantic_cache_requests_total", "Requests looked up in the semantic cache by result"),
+                &["result"],
+            ).unwrap(),
+            semantic_cache_entries: IntGauge::new("semantic_cache_entries", "Responses held by the semantic cache").unwrap(),
+            mcp_tool_calls: IntCounterVec::new(
+                Opts::new("mcp_tool_calls_total", "Tool calls of the model run on MCP servers"),
+                &["server", "outcome"],
+            ).unwrap(),
+            registry,
+        };
+
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(metrics.http_requests.clone()),
+            Box::new(metrics.http_request_duration.clone()),
+            Box::new(metrics.time_to_first_token.clone()),
+            Box::new(metrics.stream_tokens_per_second.clone()),
+            Box::new(metrics.grpc_requests.clone()),
+            Box::new(metrics.grpc_request_duration.clone()),
+            Box::new(metrics.queue_depth.clone()),
+            Box::new(metrics.instance_in_flight.clone()),
+            Box::new(metrics.instance_status.clone()),
+            Box::new(metrics.instance_restarts.clone()),
+            Box::new(metrics.remote_offloads.clone()),
+            Box::new(metrics.cache_requests.clone()),
+            Box::new(metrics.cache_entries.clone()),
+            Box::new(metrics.semantic_cache_requests.clone()),
+            Box::new(metrics.semantic_cache_entries.clone()),
+            Box::new(metrics.mcp_tool_calls.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).expect("metric registered once");
+        }
+        metrics
+    }
+
+    // Mark the current status of an instance
+    pub fn set_instance_status(&self, instance: &str, model: &str, status: &str) {
+        for s in INSTANCE_STATUSES {
+            self.instance_status
+                .with_label_values(&[instance, model, s])
+                .set((*s == status) as i64);
+        }
+    }
+
+    // Forget a removed instance
+    pub fn remove_instance(&self, instance: &str, model: &str) {
+        for s in INSTANCE_STATUSES {
+            let _ = self.instance_status.remove_label_values(&[instance, model, s]);
+        }
+        let _ = self.instance_in_flight.remove_label_values(&[instance, model]);
+    }
+
+    // Prometheus text exposition of all metrics
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            return format!("# failed to encode metrics: {}\n", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+// Increments a gauge for as long as it is held
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
@@ -1,12 +1,18 @@
 use anyhow::Result;
-use grpc_server::GrpcServer;
+use auth::KeyStore;
+use grpc_server::{cluster::{ClusterOptions, Membership}, tls, GrpcServer};
 use http_server::HttpServer;
-use scheduler::Scheduler;
+use scheduler::{batch::Batches, semantic::SemanticIndex, Scheduler};
+use mcp::{McpHost, McpServers};
+use rag::RagStore;
+use session::SessionStore;
 use std::sync::Arc;
 use tokio::signal;
-use tracing::{info, warn, Level};
+use tracing::{info, warn};
 use clap::{Parser, ArgAction};
 use config::{Config, generate_example_config};
+use std::path::PathBuf;
+use std::time::Duration;
 use tracing_subscriber::{filter::LevelFilter, prelude::*};
 
 const DEFAULT_MODEL_CONFIG: &str = include_str!("../default.toml");
@@ -17,6 +23,14 @@ struct Cli {
     /// Print default model configuration and exit
     #[arg(long = "model-config", action = ArgAction::SetTrue)]
     print_model_config: bool,
+
+    /// Path of the configuration file, defaults to /etc/assistant/config.toml
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Serve the local models as an MCP server on stdin and stdout instead of the HTTP and gRPC servers
+    #[arg(long = "mcp-stdio", action = ArgAction::SetTrue)]
+    mcp_stdio: bool,
 }
 
 #[tokio::main]
@@ -34,17 +48,31 @@ async fn main() -> Result<()> {
         .with_target("h2", LevelFilter::OFF)
         .with_target("hyper", LevelFilter::OFF)
         .with_target("tower", LevelFilter::OFF)
+        .with_target("rustls", LevelFilter::OFF)
         .with_default(LevelFilter::DEBUG);
 
+    // stdout carries the MCP messages in stdio mode
+    let mcp_stdio = cli.mcp_stdio;
+    let writer = move || -> Box<dyn std::io::Write> {
+        if mcp_stdio {
+            Box::new(std::io::stderr())
+        } else {
+            Box::new(std::io::stdout())
+        }
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
+        .with(tracing_subscriber::fmt::layer().with_writer(writer).with_ansi(!mcp_stdio))
         .with(filter)
         .init();
 
     info!("Starting assistant service");
     
     // Load configuration
-    let config = match Config::load() {
+    let loaded = match &cli.config {
+        Some(path) => Config::load_from(path),
+        None => Config::load(),
+    };
+    let config = match loaded {
         Ok(config) => config,
         Err(e) => {
             warn!("Failed to load config: {}, using default", e);
@@ -57,24 +85,116 @@ async fn main() -> Result<()> {
     let scheduler = Arc::new(Scheduler::new(
         config.scheduler.config_dir.clone(),
         config.scheduler.max_instances,
-    ));
+    ).with_affinity(&config.scheduler.affinity)
+        .with_cache(&config.scheduler.cache)?);
 
     // Create config directory if it doesn't exist
     std::fs::create_dir_all(&config.scheduler.config_dir)?;
 
     // Load model instances from config directory
-    if let Err(e) = scheduler.load_instances(config.llama_servers).await {
+    if let Err(e) = scheduler.load_instances(config.llama_servers.clone()).await {
         warn!("Failed to load model instances: {}", e);
     }
 
+    // Document collections, their chunks are embedded by a local instance
+    let rag = if config.rag.enabled {
+        let embedding_model = config.rag.embedding_model.clone()
+            .or_else(|| config.llama_servers.iter().find(|m| m.has_embedding_model()).map(|m| m.name.clone()))
+            .ok_or_else(|| anyhow::anyhow!("RAG needs a model with an embedding_model_path"))?;
+        Some(Arc::new(RagStore::open(&config.rag, scheduler.clone(), embedding_model)?))
+    } else {
+        None
+    };
+
+    // The local models for MCP hosts, on stdio or at a path of the HTTP server
+    let mcp_host = if cli.mcp_stdio || config.mcp.expose.enabled {
+        let host = McpHost::new(
+            scheduler.clone(),
+            config.llama_servers.clone(),
+            Duration::from_secs(config.mcp.expose.timeout_secs.max(1)),
+        );
+        Some(Arc::new(match &rag {
+            Some(store) => host.with_rag(store.clone()),
+            None => host,
+        }))
+    } else {
+        None
+    };
+
+    // An MCP host that spawned the assistant talks to it on stdio until it closes stdin
+    if let (true, Some(host)) = (cli.mcp_stdio, mcp_host.clone()) {
+        info!("Serving MCP over stdio");
+        tokio::select! {
+            served = host.serve_stdio() => {
+                if let Err(e) = served {
+                    warn!("MCP stdio error: {}", e);
+                }
+            }
+            _ = signal::ctrl_c() => info!("Received shutdown signal"),
+        }
+        stop_instances(&scheduler).await;
+        return Ok(());
+    }
+
+    // API keys shared by the gRPC and HTTP servers
+    let keys = Arc::new(KeyStore::load(config.auth.clone())?);
+    let keys_handle = keys.clone().spawn_watcher();
+
     // Start gRPC server
-    let remote_servers = config.remote_servers.into_iter().map(|cfg| grpc_server::RemoteServerConfig {
-        name: cfg.name,
-        grpc_addr: cfg.grpc_addr,
-        weight: cfg.weight,
-        enabled: cfg.enabled,
-    }).collect();
-    let grpc_server = GrpcServer::new(scheduler.clone(), config.scheduler.max_load, remote_servers);
+    let peer_tls = config.cluster.tls.as_ref().map(tls::client_config).transpose()?;
+    let mut remote_servers = Vec::new();
+    for cfg in &config.remote_servers {
+        let tls = match &cfg.tls {
+            Some(tls) => Some(tls::client_config(tls)?),
+            None => peer_tls.clone(),
+        };
+        remote_servers.push(grpc_server::RemoteServerConfig {
+            name: cfg.name.clone(),
+            grpc_addr: cfg.grpc_addr.clone(),
+            weight: cfg.weight,
+            enabled: cfg.enabled,
+            tls,
+        });
+    }
+
+    // Join the cluster, static remote servers are used as seeds as well
+    let mut seeds = config.cluster.seeds.clone();
+    for server in &config.remote_servers {
+        if server.enabled && !seeds.contains(&server.grpc_addr) {
+            seeds.push(server.grpc_addr.clone());
+        }
+    }
+    // peers cannot dial a wildcard listen address, so a clustered node has to say where it is
+    let advertise_addr = match &config.cluster.advertise_addr {
+        Some(addr) => addr.clone(),
+        None if seeds.is_empty() => config.server.grpc_addr.replace("0.0.0.0", "127.0.0.1"),
+        None if is_wildcard(&config.server.grpc_addr) => {
+            return Err(anyhow::anyhow!(
+                "cluster.advertise_addr is required when grpc_addr {} listens on all interfaces and seeds or remote servers are configured",
+                config.server.grpc_addr,
+            ));
+        }
+        None => config.server.grpc_addr.clone(),
+    };
+    let cluster_options = ClusterOptions {
+        node_id: config.cluster.node_id.clone()
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string()),
+        advertise_addr,
+        seeds,
+        weight: config.cluster.weight,
+        heartbeat_interval: Duration::from_secs(config.cluster.heartbeat_interval_secs.max(1)),
+        peer_timeout: Duration::from_secs(config.cluster.peer_timeout_secs.max(1)),
+        tls: peer_tls,
+        api_key: config.cluster.api_key.clone(),
+    };
+    info!("Cluster node {} advertised at {}", cluster_options.node_id, cluster_options.advertise_addr);
+    let membership = Arc::new(Membership::new(cluster_options, scheduler.clone(), config.scheduler.max_load));
+    let membership_handle = membership.clone().spawn();
+
+    let server_tls = config.server.tls.as_ref().map(tls::server_config).transpose()?;
+    let grpc_server = GrpcServer::new(scheduler.clone(), config.scheduler.max_load, remote_servers, membership)
+        .with_tls(server_tls)
+        .with_keys(keys.clone());
     let grpc_addr = config.server.grpc_addr.clone();
     
     let grpc_handle = tokio::spawn(async move {
@@ -84,9 +204,83 @@ async fn main() -> Result<()> {
         }
     });
 
+    // Batch executor, unfinished batches of the last run are picked up again
+    let batches = if config.scheduler.batch.enabled {
+        let dir = config.scheduler.batch.dir.clone()
+            .unwrap_or_else(|| config.scheduler.config_dir.join("batches"));
+        let batches = Arc::new(Batches::open(
+            dir,
+            scheduler.clone(),
+            config.scheduler.batch.clone(),
+            config.scheduler.max_load,
+        )?);
+        batches.resume().await;
+        Some(batches)
+    } else {
+        None
+    };
+
+    // Conversations kept for chat requests with a session_id
+    let sessions = if config.sessions.enabled {
+        Some(Arc::new(SessionStore::open(&config.sessions)?))
+    } else {
+        None
+    };
+
+    // Answers of similar prompts, embedded by a local instance
+    let semantic = if config.scheduler.cache.semantic.enabled {
+        Some(Arc::new(SemanticIndex::open(&config.scheduler.cache.semantic)?))
+    } else {
+        None
+    };
+
+    // Tools of MCP servers, connected in the background so the first request does not wait
+    let mcp = McpServers::new(&config.mcp)?;
+    let mcp = if mcp.is_empty() {
+        None
+    } else {
+        let mcp = Arc::new(mcp);
+        let servers = mcp.clone();
+        tokio::spawn(async move { servers.connect_all().await });
+        Some(mcp)
+    };
+
     // Start HTTP server (if enabled)
-    let http_handle = if let Some(http_addr) = config.server.http_addr {
-        let http_server = HttpServer::new(config.server.grpc_addr.clone());
+    let http_handle = if let Some(http_addr) = config.server.http_addr.clone() {
+        let gateway_tls = config.server.gateway_tls.as_ref().map(tls::client_config).transpose()?;
+        let http_server = HttpServer::new(config.server.grpc_addr.clone())
+            .with_tls(gateway_tls)
+            .with_timeouts(config.server.timeouts.clone())
+            .with_rate_limit(config.server.rate_limit.clone())
+            .with_routes(config.server.routes.clone(), config.server.passthrough)
+            .with_scheduler(scheduler.clone(), config.scheduler.max_load)
+            .with_models(config.llama_servers.clone())
+            .with_keys(keys.clone())
+            .with_config(config.clone());
+        let http_server = match &sessions {
+            Some(store) => http_server.with_sessions(store.clone(), config.sessions.default_context_length),
+            None => http_server,
+        };
+        let http_server = match &semantic {
+            Some(index) => http_server.with_semantic_cache(index.clone()),
+            None => http_server,
+        };
+        let http_server = match &rag {
+            Some(store) => http_server.with_rag(store.clone()),
+            None => http_server,
+        };
+        let http_server = match &mcp {
+            Some(servers) => http_server.with_mcp(servers.clone()),
+            None => http_server,
+        };
+        let http_server = match &mcp_host {
+            Some(host) if config.mcp.expose.enabled => http_server.with_mcp_host(host.clone(), config.mcp.expose.path.clone()),
+            _ => http_server,
+        };
+        let http_server = match batches {
+            Some(batches) => http_server.with_batches(batches),
+            None => http_server,
+        };
         
         Some(tokio::spawn(async move {
             info!("Starting HTTP server on {}", http_addr);
@@ -109,20 +303,34 @@ async fn main() -> Result<()> {
     // Graceful shutdown
     info!("Starting graceful shutdown");
 
-    // Stop all model instances
-    let instances = scheduler.list_instances().await;
-    for instance in instances {
-        if let Err(e) = scheduler.stop_instance(&instance.id).await {
-            warn!("Failed to stop instance {}: {}", instance.id, e);
-        }
-    }
+    stop_instances(&scheduler).await;
 
     // Cancel server tasks
     if let Some(http_handle) = http_handle {
         http_handle.abort();
     }
     grpc_handle.abort();
+    membership_handle.abort();
+    if let Some(keys_handle) = keys_handle {
+        keys_handle.abort();
+    }
 
     info!("Shutdown completed");
     Ok(())
-} 
\ No newline at end of file
+} 
+
+// Stop all model instances
+async fn stop_instances(scheduler: &Scheduler) {
+    for instance in scheduler.list_instances().await {
+        if let Err(e) = scheduler.stop_instance(&instance.id).await {
+            warn!("Failed to stop instance {}: {}", instance.id, e);
+        }
+    }
+}
+
+// Whether a listen address binds every interface
+fn is_wildcard(addr: &str) -> bool {
+    addr.parse::<std::net::SocketAddr>()
+        .map(|addr| addr.ip().is_unspecified())
+        .unwrap_or(false)
+}